@@ -0,0 +1,336 @@
+use std::ffi::CString;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::Sender;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use alsa;
+use alsa::poll::Descriptors;
+use alsa::seq::{
+    EvCtrl, EvNote, EvQueueControl, Event, EventType, PortCap, PortInfo, PortType, Seq,
+};
+
+use errors::*;
+
+use midi_controller::MidiControllerType;
+use usb_midi::{
+    Continue, MidiMessage, Start, Stop, SystemExclusive, SystemExlusiveId, TimingClock,
+};
+
+/// ALSA sequencer client, which lets other applications (e.g. a DAW or `aconnect`) talk to the
+/// synthesizer without taking exclusive ownership of a USB device.
+///
+/// The client exposes three ports:
+/// - "keyboard": events written to this port are handled like events from the keyboard
+/// - "controls": events written to this port are handled like events from the control panel, but
+///   parameters changed by them have to be taken over by the physical knobs again
+/// - "feedback": controller feedback (LEDs, knob rings, ...) is sent to subscribers of this port
+pub struct AlsaSeqClient {
+    seq: Mutex<Seq>,
+    keyboard_port: i32,
+    controls_port: i32,
+    feedback_port: i32,
+}
+
+impl AlsaSeqClient {
+    pub fn open(name: &str) -> Result<Self> {
+        let seq = Seq::open(None, None, true).chain_err(|| "Failed to open ALSA sequencer")?;
+        seq.set_client_name(&c_string(name)?)?;
+
+        let keyboard_port = create_port(&seq, "keyboard", PortCap::WRITE | PortCap::SUBS_WRITE)?;
+        let controls_port = create_port(&seq, "controls", PortCap::WRITE | PortCap::SUBS_WRITE)?;
+        let feedback_port = create_port(&seq, "feedback", PortCap::READ | PortCap::SUBS_READ)?;
+
+        Ok(Self {
+            seq: Mutex::new(seq),
+            keyboard_port,
+            controls_port,
+            feedback_port,
+        })
+    }
+
+    pub fn listen(&self, tx: &Sender<(MidiMessage, MidiControllerType, Instant)>) -> Result<()> {
+        let mut fds = {
+            let seq = self.seq.lock().unwrap();
+            (&*seq, Some(alsa::Direction::Capture)).get()?
+        };
+
+        loop {
+            if ::TERMINATION_REQUEST.load(Ordering::Acquire) {
+                return Ok(());
+            }
+
+            // Wait without holding the lock, so that feedback can be sent in the meantime
+            if alsa::poll::poll(&mut fds, 100)? == 0 {
+                continue;
+            }
+
+            let seq = self.seq.lock().unwrap();
+            let mut input = seq.input();
+            while input.event_input_pending(true)? > 0 {
+                let event = input.event_input()?;
+                let timestamp = Instant::now();
+
+                let source = match event.get_dest().port {
+                    port if port == self.keyboard_port => MidiControllerType::Keyboard,
+                    port if port == self.controls_port => MidiControllerType::Automation,
+                    _ => continue,
+                };
+
+                // Other events (e.g. port subscriptions) are ignored
+                if let Some(midi_message) = event_to_midi_message(&event) {
+                    tx.send((midi_message, source, timestamp))?;
+                }
+            }
+        }
+    }
+
+    pub fn send_message(&self, msg: MidiMessage) -> Result<usize> {
+        let mut event = midi_message_to_event(msg);
+        event.set_source(self.feedback_port);
+        event.set_subs();
+        event.set_direct();
+
+        let seq = self.seq.lock().unwrap();
+        Ok(seq.event_output_direct(&mut event)? as usize)
+    }
+}
+
+fn c_string(name: &str) -> Result<CString> {
+    CString::new(name).chain_err(|| format!("Invalid ALSA name: {}", name))
+}
+
+fn create_port(seq: &Seq, name: &str, capability: PortCap) -> Result<i32> {
+    let mut port_info = PortInfo::empty()?;
+    port_info.set_capability(capability);
+    port_info.set_type(PortType::MIDI_GENERIC | PortType::APPLICATION);
+    port_info.set_name(&c_string(name)?);
+    seq.create_port(&port_info)
+        .chain_err(|| format!("Failed to create ALSA sequencer port '{}'", name))?;
+
+    Ok(port_info.get_port())
+}
+
+fn event_to_midi_message(event: &Event) -> Option<MidiMessage> {
+    let note = |status: u8| {
+        event
+            .get_data::<EvNote>()
+            .map(|note| [status | note.channel, note.note, note.velocity])
+    };
+    let ctrl = |status: u8| {
+        event
+            .get_data::<EvCtrl>()
+            .map(|ctrl| [status | ctrl.channel, ctrl.param as u8, ctrl.value as u8])
+    };
+
+    let bytes = match event.get_type() {
+        EventType::Noteoff => note(0x80),
+        EventType::Noteon => note(0x90),
+        EventType::Keypress => note(0xA0),
+        EventType::Controller => ctrl(0xB0),
+        EventType::Pgmchange => event
+            .get_data::<EvCtrl>()
+            .map(|ctrl| [0xC0 | ctrl.channel, ctrl.value as u8, 0]),
+        EventType::Chanpress => event
+            .get_data::<EvCtrl>()
+            .map(|ctrl| [0xD0 | ctrl.channel, ctrl.value as u8, 0]),
+        EventType::Pitchbend => event.get_data::<EvCtrl>().map(|ctrl| {
+            let value = (ctrl.value + 8192) as u16;
+            [
+                0xE0 | ctrl.channel,
+                (value & 0x7F) as u8,
+                (value >> 7) as u8,
+            ]
+        }),
+        EventType::Sysex => return event.get_ext().and_then(parse_system_exclusive),
+        EventType::Clock => return Some(TimingClock::create()),
+        EventType::Start => return Some(Start::create()),
+        EventType::Continue => return Some(Continue::create()),
+        EventType::Stop => return Some(Stop::create()),
+        _ => None,
+    };
+
+    bytes.map(|bytes| MidiMessage::from_bytes(&bytes))
+}
+
+fn parse_system_exclusive(bytes: &[u8]) -> Option<MidiMessage> {
+    // Strip SOX and EOX
+    let bytes = match (bytes.first(), bytes.last()) {
+        (Some(&0xF0), Some(&0xF7)) if bytes.len() >= 3 => &bytes[1..bytes.len() - 1],
+        _ => return None,
+    };
+
+    match bytes[0] {
+        0 if bytes.len() >= 3 => Some(SystemExclusive::create(
+            SystemExlusiveId::TwoByte(bytes[1], bytes[2]),
+            bytes[3..].to_vec(),
+        )),
+        0 => None,
+        id => Some(SystemExclusive::create(
+            SystemExlusiveId::OneByte(id),
+            bytes[1..].to_vec(),
+        )),
+    }
+}
+
+fn midi_message_to_event(msg: MidiMessage) -> Event<'static> {
+    let bytes = raw_midi_bytes(msg);
+    let channel = bytes[0] & 0x0F;
+
+    let note = |event_type| {
+        Event::new(
+            event_type,
+            &EvNote {
+                channel,
+                note: bytes[1],
+                velocity: bytes[2],
+                off_velocity: 0,
+                duration: 0,
+            },
+        )
+    };
+    let real_time = |event_type| {
+        Event::new(
+            event_type,
+            &EvQueueControl {
+                queue: 0,
+                value: (),
+            },
+        )
+    };
+    let ctrl = |event_type, param: u8, value: i32| {
+        Event::new(
+            event_type,
+            &EvCtrl {
+                channel,
+                param: u32::from(param),
+                value,
+            },
+        )
+    };
+
+    match bytes[0] & 0xF0 {
+        0x80 => note(EventType::Noteoff),
+        0x90 => note(EventType::Noteon),
+        0xA0 => note(EventType::Keypress),
+        0xB0 => ctrl(EventType::Controller, bytes[1], i32::from(bytes[2])),
+        0xC0 => ctrl(EventType::Pgmchange, 0, i32::from(bytes[1])),
+        0xD0 => ctrl(EventType::Chanpress, 0, i32::from(bytes[1])),
+        0xE0 => {
+            let value = i32::from(bytes[2]) << 7 | i32::from(bytes[1]);
+            ctrl(EventType::Pitchbend, 0, value - 8192)
+        }
+        _ => match bytes[0] {
+            0xF8 => real_time(EventType::Clock),
+            0xFA => real_time(EventType::Start),
+            0xFB => real_time(EventType::Continue),
+            0xFC => real_time(EventType::Stop),
+            _ => Event::new_ext(EventType::Sysex, bytes),
+        },
+    }
+}
+
+/// Converts a MIDI message to its byte stream representation, by stripping the headers from the
+/// USB-MIDI event packets.
+fn raw_midi_bytes(msg: MidiMessage) -> Vec<u8> {
+    let packets = msg.serialize().collect::<Vec<_>>();
+
+    let mut bytes = vec![];
+    for packet in packets.chunks(4) {
+        let length = match packet[0] & 0x0F {
+            0x5 | 0xF => 1,
+            0x6 | 0xC | 0xD => 2,
+            _ => 3,
+        };
+        bytes.extend(packet[1..].iter().take(length));
+    }
+
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use usb_midi::{ControlChange, NoteOff, NoteOn, PitchBend, ProgramChange};
+
+    macro_rules! round_trip {
+        ($msg:expr) => {
+            let event = midi_message_to_event($msg);
+            assert_eq!(Some($msg), event_to_midi_message(&event));
+        };
+    }
+
+    #[test]
+    fn note_on_event() {
+        let event = midi_message_to_event(NoteOn::create(3, 0x3C, 0x64));
+
+        assert_eq!(EventType::Noteon, event.get_type());
+        let note = event.get_data::<EvNote>().unwrap();
+        assert_eq!(3, note.channel);
+        assert_eq!(0x3C, note.note);
+        assert_eq!(0x64, note.velocity);
+    }
+
+    #[test]
+    fn note_on_with_velocity_0_is_note_off() {
+        let event = Event::new(
+            EventType::Noteon,
+            &EvNote {
+                channel: 0,
+                note: 0x3C,
+                velocity: 0,
+                off_velocity: 0,
+                duration: 0,
+            },
+        );
+
+        assert_eq!(
+            Some(NoteOff::create(0, 0x3C, 64)),
+            event_to_midi_message(&event)
+        );
+    }
+
+    #[test]
+    fn channel_messages_round_trip() {
+        round_trip!(NoteOn::create(0, 0x33, 0x7F));
+        round_trip!(NoteOff::create(15, 0x40, 0x10));
+        round_trip!(ControlChange::create(1, 0x31, 64));
+        round_trip!(ProgramChange::create(2, 17));
+        round_trip!(PitchBend::create(0, 0x2000));
+        round_trip!(PitchBend::create(0, 0x3FFF));
+        round_trip!(PitchBend::create(0, 0));
+    }
+
+    #[test]
+    fn system_exclusive_round_trip() {
+        round_trip!(SystemExclusive::create(
+            SystemExlusiveId::OneByte(0x47),
+            vec![0x7F, 0x29, 0x60, 0x00, 0x04, 0x42, 0x00, 0x00, 0x00],
+        ));
+    }
+
+    #[test]
+    fn system_real_time_round_trip() {
+        round_trip!(TimingClock::create());
+        round_trip!(Start::create());
+        round_trip!(Continue::create());
+        round_trip!(Stop::create());
+    }
+
+    #[test]
+    fn raw_bytes_of_system_exclusive() {
+        let bytes = raw_midi_bytes(SystemExclusive::create(
+            SystemExlusiveId::OneByte(0x47),
+            vec![0x01, 0x02, 0x03],
+        ));
+
+        assert_eq!(vec![0xF0, 0x47, 0x01, 0x02, 0x03, 0xF7], bytes);
+    }
+
+    #[test]
+    fn malformed_system_exclusive_is_ignored() {
+        assert_eq!(None, parse_system_exclusive(&[0xF0, 0x47, 0x01]));
+        assert_eq!(None, parse_system_exclusive(&[0xF0, 0xF7]));
+    }
+}
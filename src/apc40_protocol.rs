@@ -0,0 +1,551 @@
+//! Typed model of the Akai APC40 MkII: its buttons, knobs and faders, and the messages that
+//! control its LEDs.
+//!
+//! Note and controller numbers are those of the Alternate Ableton Live mode, in which the host
+//! controls all LEDs.
+
+use std::sync::mpsc::Sender;
+
+use usb_midi::{ControlChange, MidiMessage, NoteOn, SystemExclusive, SystemExlusiveId};
+
+use errors::Result;
+
+pub const TRACKS: u8 = 8;
+pub const CLIP_ROWS: u8 = 5;
+pub const CLIP_COLUMNS: u8 = 8;
+/// Device knobs are banked per track, the last bank belongs to the master track
+pub const DEVICE_BANKS: u8 = 9;
+
+const NOTE_RECORD_ARM: u8 = 0x30;
+const NOTE_SOLO: u8 = 0x31;
+const NOTE_ACTIVATOR: u8 = 0x32;
+const NOTE_TRACK_SELECT: u8 = 0x33;
+const NOTE_CLIP_STOP: u8 = 0x34;
+const NOTE_CROSSFADE_ASSIGN: u8 = 0x42;
+const NOTE_SCENE_LAUNCH: u8 = 0x52;
+
+const CC_TRACK_FADER: u8 = 0x07;
+const CC_TEMPO: u8 = 0x0D;
+const CC_MASTER_FADER: u8 = 0x0E;
+const CC_CROSSFADER: u8 = 0x0F;
+const CC_DEVICE_KNOB: u8 = 0x10;
+const CC_DEVICE_RING_STYLE: u8 = 0x18;
+const CC_CUE_LEVEL: u8 = 0x2F;
+const CC_TRACK_KNOB: u8 = 0x30;
+const CC_TRACK_RING_STYLE: u8 = 0x38;
+
+/// Operating mode, selected with the introduction message.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[allow(dead_code)]
+pub enum Mode {
+    /// The APC40 controls its LEDs itself
+    Generic = 0x40,
+    AbletonLive = 0x41,
+    /// The host controls all LEDs
+    AlternateAbletonLive = 0x42,
+}
+
+/// Returns the introduction message that switches the APC40 to a mode.
+pub fn introduction(mode: Mode) -> MidiMessage {
+    SystemExclusive::create(
+        SystemExlusiveId::OneByte(0x47),
+        vec![0x7F, 0x29, 0x60, 0x00, 0x04, mode as u8, 0x00, 0x00, 0x00],
+    )
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Button {
+    /// Button of the clip grid, row 0 is the top row
+    Clip {
+        row: u8,
+        column: u8,
+    },
+    /// Scene launch button, row 0 is the top row
+    SceneLaunch(u8),
+    ClipStop(u8),
+    TrackSelect(u8),
+    Activator(u8),
+    Solo(u8),
+    RecordArm(u8),
+    CrossfadeAssign(u8),
+    MasterSelect,
+    StopAllClips,
+    DeviceLeft,
+    DeviceRight,
+    BankLeft,
+    BankRight,
+    DeviceOnOff,
+    DeviceLock,
+    ClipDeviceView,
+    DetailView,
+    Pan,
+    Sends,
+    User,
+    Metronome,
+    Play,
+    Record,
+    Session,
+    BankSelectUp,
+    BankSelectDown,
+    BankSelectRight,
+    BankSelectLeft,
+    Shift,
+    TapTempo,
+    NudgeMinus,
+    NudgePlus,
+    Bank,
+}
+
+/// Buttons that are not bound to a track, with their note numbers.
+const GLOBAL_BUTTONS: [(Button, u8); 26] = [
+    (Button::DeviceLeft, 0x3A),
+    (Button::DeviceRight, 0x3B),
+    (Button::BankLeft, 0x3C),
+    (Button::BankRight, 0x3D),
+    (Button::DeviceOnOff, 0x3E),
+    (Button::DeviceLock, 0x3F),
+    (Button::ClipDeviceView, 0x40),
+    (Button::DetailView, 0x41),
+    (Button::MasterSelect, 0x50),
+    (Button::StopAllClips, 0x51),
+    (Button::Pan, 0x57),
+    (Button::Sends, 0x58),
+    (Button::User, 0x59),
+    (Button::Metronome, 0x5A),
+    (Button::Play, 0x5B),
+    (Button::Record, 0x5D),
+    (Button::BankSelectUp, 0x5E),
+    (Button::BankSelectDown, 0x5F),
+    (Button::BankSelectRight, 0x60),
+    (Button::BankSelectLeft, 0x61),
+    (Button::Shift, 0x62),
+    (Button::TapTempo, 0x63),
+    (Button::NudgeMinus, 0x64),
+    (Button::NudgePlus, 0x65),
+    (Button::Session, 0x66),
+    (Button::Bank, 0x67),
+];
+
+impl Button {
+    /// Returns the button that sends a note, if any.
+    pub fn from_note(channel: u8, note_number: u8) -> Option<Button> {
+        let track = channel;
+        let button = match note_number {
+            0x00..=0x27 => Button::Clip {
+                row: CLIP_ROWS - 1 - note_number / CLIP_COLUMNS,
+                column: note_number % CLIP_COLUMNS,
+            },
+            0x52..=0x56 => Button::SceneLaunch(note_number - NOTE_SCENE_LAUNCH),
+            NOTE_RECORD_ARM if track < TRACKS => Button::RecordArm(track),
+            NOTE_SOLO if track < TRACKS => Button::Solo(track),
+            NOTE_ACTIVATOR if track < TRACKS => Button::Activator(track),
+            NOTE_TRACK_SELECT if track < TRACKS => Button::TrackSelect(track),
+            NOTE_CLIP_STOP if track < TRACKS => Button::ClipStop(track),
+            NOTE_CROSSFADE_ASSIGN if track < TRACKS => Button::CrossfadeAssign(track),
+            _ => GLOBAL_BUTTONS
+                .iter()
+                .find(|&&(_, note)| note == note_number)
+                .map(|&(button, _)| button)?,
+        };
+
+        Some(button)
+    }
+
+    /// Returns the channel and note number of the button, or `None` if the APC40 does not have
+    /// the button (e.g. the clip in a row beyond the grid).
+    pub fn note(self) -> Option<(u8, u8)> {
+        match self {
+            Button::Clip { row, column } if row < CLIP_ROWS && column < CLIP_COLUMNS => {
+                Some((0, (CLIP_ROWS - 1 - row) * CLIP_COLUMNS + column))
+            }
+            Button::SceneLaunch(row) if row < CLIP_ROWS => Some((0, NOTE_SCENE_LAUNCH + row)),
+            Button::RecordArm(track) if track < TRACKS => Some((track, NOTE_RECORD_ARM)),
+            Button::Solo(track) if track < TRACKS => Some((track, NOTE_SOLO)),
+            Button::Activator(track) if track < TRACKS => Some((track, NOTE_ACTIVATOR)),
+            Button::TrackSelect(track) if track < TRACKS => Some((track, NOTE_TRACK_SELECT)),
+            Button::ClipStop(track) if track < TRACKS => Some((track, NOTE_CLIP_STOP)),
+            Button::CrossfadeAssign(track) if track < TRACKS => {
+                Some((track, NOTE_CROSSFADE_ASSIGN))
+            }
+            button => GLOBAL_BUTTONS
+                .iter()
+                .find(|&&(b, _)| b == button)
+                .map(|&(_, note)| (0, note)),
+        }
+    }
+}
+
+/// Knob with an LED ring.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Knob {
+    /// Knob above the clip grid
+    Track(u8),
+    /// Knob of the device section, banked per track
+    Device { bank: u8, index: u8 },
+}
+
+impl Knob {
+    /// Returns the knob that sends a control change, if any.
+    pub fn from_control(channel: u8, control_number: u8) -> Option<Knob> {
+        match control_number {
+            0x30..=0x37 => Some(Knob::Track(control_number - CC_TRACK_KNOB)),
+            0x10..=0x17 if channel < DEVICE_BANKS => Some(Knob::Device {
+                bank: channel,
+                index: control_number - CC_DEVICE_KNOB,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Returns the channel and controller number of the knob.
+    pub fn control(self) -> (u8, u8) {
+        match self {
+            Knob::Track(index) => (0, CC_TRACK_KNOB + index),
+            Knob::Device { bank, index } => (bank, CC_DEVICE_KNOB + index),
+        }
+    }
+
+    fn ring_style_control(self) -> (u8, u8) {
+        match self {
+            Knob::Track(index) => (0, CC_TRACK_RING_STYLE + index),
+            Knob::Device { bank, index } => (bank, CC_DEVICE_RING_STYLE + index),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fader {
+    Track(u8),
+    Master,
+    Crossfader,
+}
+
+/// Input from the APC40.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Input {
+    ButtonPressed(Button),
+    ButtonReleased(Button),
+    /// Knob moved to an absolute position (0 to 127)
+    KnobTurned(Knob, u8),
+    FaderMoved(Fader, u8),
+    /// Tempo encoder turned by a number of steps (negative means counterclockwise)
+    TempoTurned(i8),
+    /// Cue level encoder turned by a number of steps (negative means counterclockwise)
+    CueLevelTurned(i8),
+}
+
+impl Input {
+    pub fn parse(message: &MidiMessage) -> Option<Input> {
+        match *message {
+            MidiMessage::NoteOn(ref note_on) => {
+                Button::from_note(note_on.channel(), note_on.note_number())
+                    .map(Input::ButtonPressed)
+            }
+            MidiMessage::NoteOff(ref note_off) => {
+                Button::from_note(note_off.channel(), note_off.note_number())
+                    .map(Input::ButtonReleased)
+            }
+            MidiMessage::ControlChange(ref control_change) => {
+                let channel = control_change.channel();
+                let value = control_change.control_value();
+                match control_change.control_number() {
+                    CC_TRACK_FADER if channel < TRACKS => {
+                        Some(Input::FaderMoved(Fader::Track(channel), value))
+                    }
+                    CC_MASTER_FADER => Some(Input::FaderMoved(Fader::Master, value)),
+                    CC_CROSSFADER => Some(Input::FaderMoved(Fader::Crossfader, value)),
+                    CC_TEMPO => Some(Input::TempoTurned(relative_steps(value))),
+                    CC_CUE_LEVEL => Some(Input::CueLevelTurned(relative_steps(value))),
+                    control_number => Knob::from_control(channel, control_number)
+                        .map(|knob| Input::KnobTurned(knob, value)),
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Converts the value of a relative encoder (7-bit two's complement) to a number of steps.
+fn relative_steps(value: u8) -> i8 {
+    if value < 0x40 {
+        value as i8
+    } else {
+        (i16::from(value) - 0x80) as i8
+    }
+}
+
+/// Color of an RGB button, as index into the palette of the APC40.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Color(pub u8);
+
+// Not all colors of the palette are used
+#[allow(dead_code)]
+impl Color {
+    pub const BLACK: Color = Color(0);
+    pub const DARK_GREY: Color = Color(1);
+    pub const GREY: Color = Color(2);
+    pub const WHITE: Color = Color(3);
+    pub const RED: Color = Color(5);
+    pub const ORANGE: Color = Color(9);
+    pub const YELLOW: Color = Color(13);
+    pub const GREEN: Color = Color(21);
+    pub const CYAN: Color = Color(33);
+    pub const BLUE: Color = Color(45);
+    pub const PURPLE: Color = Color(49);
+    pub const PINK: Color = Color(57);
+}
+
+/// Speed of an animated LED, in notes of the tempo of the APC40.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[allow(dead_code)]
+pub enum Rate {
+    TwentyFourth = 0,
+    Sixteenth = 1,
+    Eighth = 2,
+    Quarter = 3,
+    Half = 4,
+}
+
+/// How an RGB button shows its color.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LedMode {
+    Solid,
+    /// Fades from the color to the solid color set before
+    #[allow(dead_code)]
+    OneShot(Rate),
+    #[allow(dead_code)]
+    Pulse(Rate),
+    /// Alternates between the color and the solid color set before
+    Blink(Rate),
+}
+
+impl LedMode {
+    fn channel(self) -> u8 {
+        match self {
+            LedMode::Solid => 0,
+            LedMode::OneShot(rate) => 1 + rate as u8,
+            LedMode::Pulse(rate) => 6 + rate as u8,
+            LedMode::Blink(rate) => 11 + rate as u8,
+        }
+    }
+}
+
+/// State of a single-colored button.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ButtonLed {
+    Off = 0,
+    On = 0x7F,
+    #[allow(dead_code)]
+    Blink = 2,
+}
+
+/// How the LED ring of a knob shows its value.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RingStyle {
+    Off = 0,
+    /// A single LED
+    Single = 1,
+    /// LEDs from the minimum up to the value
+    Volume = 2,
+    /// LEDs from the center to the value
+    #[allow(dead_code)]
+    Pan = 3,
+}
+
+/// Controls the LEDs of the APC40 by sending messages to it.
+pub struct Apc40Output {
+    tx: Sender<MidiMessage>,
+}
+
+impl Apc40Output {
+    pub fn new(tx: Sender<MidiMessage>) -> Self {
+        Self { tx }
+    }
+
+    /// Sets the color of a button of the clip grid.
+    pub fn set_clip(&self, row: u8, column: u8, color: Color, mode: LedMode) -> Result<()> {
+        let button = Button::Clip { row, column };
+        let (_, note_number) = match button.note() {
+            Some(note) => note,
+            None => bail!("APC40 has no button {:?}", button),
+        };
+        self.tx
+            .send(NoteOn::create(mode.channel(), note_number, color.0))?;
+
+        Ok(())
+    }
+
+    /// Turns off all buttons of the clip grid.
+    pub fn clear_clips(&self) -> Result<()> {
+        for row in 0..CLIP_ROWS {
+            for column in 0..CLIP_COLUMNS {
+                self.set_clip(row, column, Color::BLACK, LedMode::Solid)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sets the LED of a single-colored button. Buttons of the clip grid are shown in white.
+    pub fn set_button(&self, button: Button, led: ButtonLed) -> Result<()> {
+        if let Button::Clip { row, column } = button {
+            return match led {
+                ButtonLed::Off => self.set_clip(row, column, Color::BLACK, LedMode::Solid),
+                ButtonLed::On => self.set_clip(row, column, Color::WHITE, LedMode::Solid),
+                ButtonLed::Blink => {
+                    self.set_clip(row, column, Color::BLACK, LedMode::Solid)?;
+                    self.set_clip(row, column, Color::WHITE, LedMode::Blink(Rate::Quarter))
+                }
+            };
+        }
+
+        let (channel, note_number) = match button.note() {
+            Some(note) => note,
+            None => bail!("APC40 has no button {:?}", button),
+        };
+        self.tx
+            .send(NoteOn::create(channel, note_number, led as u8))?;
+
+        Ok(())
+    }
+
+    /// Shows a value (0 to 127) on the LED ring of a knob. The knob continues from this value
+    /// when it is turned.
+    pub fn set_knob(&self, knob: Knob, value: u8) -> Result<()> {
+        let (channel, control_number) = knob.control();
+        self.tx
+            .send(ControlChange::create(channel, control_number, value))?;
+
+        Ok(())
+    }
+
+    pub fn set_ring_style(&self, knob: Knob, style: RingStyle) -> Result<()> {
+        let (channel, control_number) = knob.ring_style_control();
+        self.tx
+            .send(ControlChange::create(channel, control_number, style as u8))?;
+
+        Ok(())
+    }
+
+    /// Sends a message of the MIDI clock, which the APC40 uses as the tempo of blinking and
+    /// pulsing LEDs.
+    pub fn send_clock(&self, message: MidiMessage) -> Result<()> {
+        self.tx.send(message)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::mpsc;
+
+    use usb_midi::NoteOff;
+
+    #[test]
+    fn buttons_and_notes() {
+        assert_eq!(
+            Some(Button::Clip { row: 0, column: 0 }),
+            Button::from_note(0, 0x20)
+        );
+        assert_eq!(
+            Some(Button::Clip { row: 4, column: 7 }),
+            Button::from_note(0, 0x07)
+        );
+        assert_eq!(Some(Button::SceneLaunch(4)), Button::from_note(0, 0x56));
+        assert_eq!(Some(Button::Solo(3)), Button::from_note(3, 0x31));
+        assert_eq!(Some(Button::DetailView), Button::from_note(0, 0x41));
+        assert_eq!(None, Button::from_note(0, 0x5C));
+        assert_eq!(None, Button::from_note(8, 0x31));
+
+        for channel in 0..TRACKS {
+            for note_number in 0..0x80 {
+                if let Some(button) = Button::from_note(channel, note_number) {
+                    let (c, n) = button.note().unwrap();
+                    assert_eq!(note_number, n);
+                    assert!(c == channel || c == 0);
+                }
+            }
+        }
+
+        assert_eq!(None, Button::Clip { row: 5, column: 0 }.note());
+        assert_eq!(None, Button::Solo(8).note());
+    }
+
+    #[test]
+    fn parse_input() {
+        assert_eq!(
+            Some(Input::ButtonPressed(Button::Play)),
+            Input::parse(&NoteOn::create(0, 0x5B, 0x7F))
+        );
+        assert_eq!(
+            Some(Input::ButtonReleased(Button::TrackSelect(2))),
+            Input::parse(&NoteOff::create(2, 0x33, 0x7F))
+        );
+        assert_eq!(
+            Some(Input::KnobTurned(Knob::Track(1), 64)),
+            Input::parse(&ControlChange::create(0, 0x31, 64))
+        );
+        assert_eq!(
+            Some(Input::KnobTurned(Knob::Device { bank: 8, index: 7 }, 1)),
+            Input::parse(&ControlChange::create(8, 0x17, 1))
+        );
+        assert_eq!(
+            Some(Input::FaderMoved(Fader::Track(5), 100)),
+            Input::parse(&ControlChange::create(5, 0x07, 100))
+        );
+        assert_eq!(
+            Some(Input::FaderMoved(Fader::Crossfader, 10)),
+            Input::parse(&ControlChange::create(0, 0x0F, 10))
+        );
+        assert_eq!(
+            Some(Input::TempoTurned(-2)),
+            Input::parse(&ControlChange::create(0, 0x0D, 0x7E))
+        );
+        assert_eq!(
+            Some(Input::CueLevelTurned(3)),
+            Input::parse(&ControlChange::create(0, 0x2F, 0x03))
+        );
+        assert_eq!(None, Input::parse(&ControlChange::create(0, 0x50, 0)));
+    }
+
+    #[test]
+    fn led_messages() {
+        let (tx, rx) = mpsc::channel();
+        let output = Apc40Output::new(tx);
+
+        output.set_clip(0, 2, Color::GREEN, LedMode::Solid).unwrap();
+        output
+            .set_clip(4, 0, Color::RED, LedMode::Pulse(Rate::Eighth))
+            .unwrap();
+        output
+            .set_clip(4, 0, Color::RED, LedMode::Blink(Rate::Half))
+            .unwrap();
+        output
+            .set_button(Button::ClipStop(6), ButtonLed::Blink)
+            .unwrap();
+        output
+            .set_knob(Knob::Device { bank: 2, index: 3 }, 99)
+            .unwrap();
+        output
+            .set_ring_style(Knob::Device { bank: 2, index: 3 }, RingStyle::Pan)
+            .unwrap();
+        output
+            .set_ring_style(Knob::Track(7), RingStyle::Volume)
+            .unwrap();
+        assert!(output.set_clip(5, 0, Color::GREEN, LedMode::Solid).is_err());
+
+        let expected = vec![
+            NoteOn::create(0, 0x22, 21),
+            NoteOn::create(8, 0x00, 5),
+            NoteOn::create(15, 0x00, 5),
+            NoteOn::create(6, 0x34, 2),
+            ControlChange::create(2, 0x13, 99),
+            ControlChange::create(2, 0x1B, 3),
+            ControlChange::create(0, 0x3F, 2),
+        ];
+        assert_eq!(expected, rx.try_iter().collect::<Vec<_>>());
+    }
+}
@@ -1,11 +1,12 @@
-error_chain!{
+error_chain! {
     foreign_links {
         UsbError(::libusb::Error);
         PortAudioError(::portaudio::Error);
-        MidiMessageRxChannelError(::std::sync::mpsc::SendError<(::usb_midi::MidiMessage, ::midi_controller::MidiControllerType)>);
+        MidiMessageRxChannelError(::std::sync::mpsc::SendError<(::usb_midi::MidiMessage, ::midi_controller::MidiControllerType, ::std::time::Instant)>);
         MidiMessageTxChannelError(::std::sync::mpsc::SendError<::usb_midi::MidiMessage>);
-        SynthControlChannelError(::std::sync::mpsc::SendError<::synth::dispatcher::SynthControl>);
         CtrlCError(::ctrlc::Error);
+        IoError(::std::io::Error);
+        AlsaError(::alsa::Error) #[cfg(feature = "alsa")];
     }
 
     errors {
@@ -18,5 +19,20 @@ error_chain!{
             description("MIDI operation not supported"),
             display("MIDI operation not supported")
         }
+
+        AudioDeviceNotFound(device: String) {
+            description("Audio device not found"),
+            display("Audio device not found: '{}'", device)
+        }
+
+        InvalidArgument(arg: String) {
+            description("Invalid command line argument"),
+            display("Invalid command line argument: '{}'", arg)
+        }
+
+        SynthControlChannelDisconnected {
+            description("Synthesizer control channel disconnected"),
+            display("Synthesizer control channel disconnected")
+        }
     }
 }
@@ -0,0 +1,306 @@
+use std::collections::VecDeque;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+use usb_midi::MidiMessage;
+
+use errors::*;
+
+/// Maximum number of messages sent to a controller with one transfer
+pub const MAX_MESSAGES_PER_TRANSFER: usize = 16;
+
+/// Minimum time between two transfers to a controller. Updates that arrive in the meantime are
+/// merged. Messages of the MIDI clock are not delayed.
+pub const TRANSFER_INTERVAL: Duration = Duration::from_millis(5);
+
+/// LED (or LED ring) of a controller that is set by a message. Later messages for the same LED
+/// replace earlier ones.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Led {
+    /// Set by note on and note off messages (the channel may select a mode of the LED, so
+    /// it is part of the key)
+    Note {
+        channel: u8,
+        note_number: u8,
+    },
+    Control {
+        channel: u8,
+        control_number: u8,
+    },
+}
+
+impl Led {
+    fn of(message: &MidiMessage) -> Option<Led> {
+        match *message {
+            MidiMessage::NoteOn(ref note_on) => Some(Led::Note {
+                channel: note_on.channel(),
+                note_number: note_on.note_number(),
+            }),
+            MidiMessage::NoteOff(ref note_off) => Some(Led::Note {
+                channel: note_off.channel(),
+                note_number: note_off.note_number(),
+            }),
+            MidiMessage::ControlChange(ref control_change) => Some(Led::Control {
+                channel: control_change.channel(),
+                control_number: control_change.control_number(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Messages waiting to be sent to a controller, in the order they were queued.
+pub struct FeedbackQueue {
+    pending: VecDeque<(Option<Led>, MidiMessage)>,
+}
+
+impl FeedbackQueue {
+    pub fn new() -> Self {
+        Self {
+            pending: VecDeque::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Queues a message. If a message for the same LED is still pending, it is dropped. The new
+    /// message is queued at the end nevertheless, as it must stay behind the messages for other
+    /// modes of the LED (e.g. a solid color set after a blinking one). Other messages (e.g.
+    /// system exclusive) are always queued.
+    pub fn push(&mut self, message: MidiMessage) {
+        let led = Led::of(&message);
+
+        if led.is_some() {
+            self.pending.retain(|pending| pending.0 != led);
+        }
+
+        self.pending.push_back((led, message));
+    }
+
+    /// Removes up to `max` of the oldest messages.
+    pub fn pop_batch(&mut self, max: usize) -> Vec<MidiMessage> {
+        let n = max.min(self.pending.len());
+        self.pending
+            .drain(..n)
+            .map(|(_, message)| message)
+            .collect()
+    }
+}
+
+/// Returns whether a message is part of the MIDI clock, which has to be sent without delay.
+fn is_clock(message: &MidiMessage) -> bool {
+    matches!(
+        *message,
+        MidiMessage::TimingClock(_)
+            | MidiMessage::Start(_)
+            | MidiMessage::Continue(_)
+            | MidiMessage::Stop(_)
+    )
+}
+
+/// Receives messages for a controller and passes them on to `send` in batches, at most once per
+/// `interval`. Messages of the MIDI clock are passed on as soon as they are received. Returns
+/// when the channel is disconnected and all messages have been sent.
+pub fn forward<F>(rx: &Receiver<MidiMessage>, interval: Duration, mut send: F) -> Result<()>
+where
+    F: FnMut(Vec<MidiMessage>) -> Result<()>,
+{
+    let mut queue = FeedbackQueue::new();
+    let mut next_transfer = Instant::now();
+    let mut disconnected = false;
+
+    while !(disconnected && queue.is_empty()) {
+        // Wait for new messages, but only until the next transfer is due if some are pending
+        let received = if disconnected {
+            None
+        } else if queue.is_empty() {
+            rx.recv().ok()
+        } else {
+            let now = Instant::now();
+            if now < next_transfer {
+                match rx.recv_timeout(next_transfer - now) {
+                    Ok(message) => Some(message),
+                    Err(RecvTimeoutError::Timeout) => None,
+                    Err(RecvTimeoutError::Disconnected) => {
+                        disconnected = true;
+                        None
+                    }
+                }
+            } else {
+                None
+            }
+        };
+
+        match received {
+            Some(message) => {
+                for message in Some(message).into_iter().chain(rx.try_iter()) {
+                    if is_clock(&message) {
+                        send(vec![message])?;
+                    } else {
+                        queue.push(message);
+                    }
+                }
+            }
+            None if queue.is_empty() => disconnected = true,
+            None => {}
+        }
+
+        if !queue.is_empty() && Instant::now() >= next_transfer {
+            send(queue.pop_batch(MAX_MESSAGES_PER_TRANSFER))?;
+            next_transfer = Instant::now() + interval;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::mpsc;
+    use std::thread;
+
+    use usb_midi::{
+        ControlChange, NoteOff, NoteOn, SystemExclusive, SystemExlusiveId, TimingClock,
+    };
+
+    #[test]
+    fn updates_of_the_same_led_are_merged() {
+        let mut queue = FeedbackQueue::new();
+
+        queue.push(ControlChange::create(0, 0x30, 10));
+        queue.push(NoteOn::create(0, 0x33, 0x7F));
+        queue.push(ControlChange::create(0, 0x30, 11));
+        queue.push(ControlChange::create(1, 0x30, 12));
+        queue.push(NoteOff::create(0, 0x33, 0x00));
+        queue.push(ControlChange::create(0, 0x30, 13));
+
+        assert_eq!(
+            vec![
+                ControlChange::create(1, 0x30, 12),
+                NoteOff::create(0, 0x33, 0x00),
+                ControlChange::create(0, 0x30, 13),
+            ],
+            queue.pop_batch(10)
+        );
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn led_modes_keep_their_order() {
+        let mut queue = FeedbackQueue::new();
+
+        // Blinking pad (solid color and blink on another channel), then set to a solid color
+        queue.push(NoteOn::create(0, 0x20, 0x00));
+        queue.push(NoteOn::create(13, 0x20, 0x03));
+        queue.push(NoteOn::create(0, 0x20, 0x15));
+
+        assert_eq!(
+            vec![
+                NoteOn::create(13, 0x20, 0x03),
+                NoteOn::create(0, 0x20, 0x15)
+            ],
+            queue.pop_batch(10)
+        );
+    }
+
+    #[test]
+    fn other_messages_are_not_merged() {
+        let mut queue = FeedbackQueue::new();
+        let sysex = SystemExclusive::create(SystemExlusiveId::OneByte(0x47), vec![0x7F]);
+
+        queue.push(sysex.clone());
+        queue.push(sysex.clone());
+
+        assert_eq!(vec![sysex.clone(), sysex], queue.pop_batch(10));
+    }
+
+    #[test]
+    fn batches_are_limited() {
+        let mut queue = FeedbackQueue::new();
+        for note_number in 0..5 {
+            queue.push(NoteOn::create(0, note_number, 1));
+        }
+
+        assert_eq!(3, queue.pop_batch(3).len());
+        assert_eq!(
+            vec![NoteOn::create(0, 3, 1), NoteOn::create(0, 4, 1)],
+            queue.pop_batch(3)
+        );
+    }
+
+    #[test]
+    fn forward_merges_updates_while_rate_limited() {
+        let (tx, rx) = mpsc::channel();
+
+        let sender = thread::spawn(move || {
+            for value in 0..100 {
+                tx.send(ControlChange::create(0, 0x30, value)).unwrap();
+            }
+            tx.send(NoteOn::create(0, 0x41, 0x7F)).unwrap();
+        });
+        sender.join().unwrap();
+
+        let mut batches = vec![];
+        forward(&rx, Duration::from_millis(10), |batch| {
+            batches.push(batch);
+            Ok(())
+        })
+        .unwrap();
+
+        assert_eq!(
+            vec![vec![
+                ControlChange::create(0, 0x30, 99),
+                NoteOn::create(0, 0x41, 0x7F),
+            ]],
+            batches
+        );
+    }
+
+    #[test]
+    fn forward_does_not_delay_clock() {
+        let (tx, rx) = mpsc::channel();
+        tx.send(NoteOn::create(0, 0x41, 0x7F)).unwrap();
+        tx.send(NoteOn::create(0, 0x42, 0x7F)).unwrap();
+        tx.send(TimingClock::create()).unwrap();
+        drop(tx);
+
+        let mut batches = vec![];
+        forward(&rx, Duration::from_millis(10), |batch| {
+            batches.push(batch);
+            Ok(())
+        })
+        .unwrap();
+
+        assert_eq!(
+            vec![
+                vec![TimingClock::create()],
+                vec![NoteOn::create(0, 0x41, 0x7F), NoteOn::create(0, 0x42, 0x7F)],
+            ],
+            batches
+        );
+    }
+
+    #[test]
+    fn forward_sends_all_messages_before_returning() {
+        let (tx, rx) = mpsc::channel();
+        for note_number in 0..40 {
+            tx.send(NoteOn::create(0, note_number, 1)).unwrap();
+        }
+        drop(tx);
+
+        let mut sent = vec![];
+        forward(&rx, Duration::from_millis(1), |batch| {
+            assert!(batch.len() <= MAX_MESSAGES_PER_TRANSFER);
+            sent.extend(batch);
+            Ok(())
+        })
+        .unwrap();
+
+        assert_eq!(40, sent.len());
+        assert_eq!(NoteOn::create(0, 39, 1), sent[39]);
+    }
+}
@@ -9,6 +9,9 @@ extern crate itertools;
 extern crate libusb;
 extern crate portaudio;
 
+#[cfg(feature = "alsa")]
+extern crate alsa;
+
 #[macro_use]
 extern crate error_chain;
 
@@ -16,20 +19,38 @@ extern crate error_chain;
 #[macro_use]
 mod testing;
 
+#[cfg(feature = "alsa")]
+mod alsa_seq;
+mod apc40_protocol;
 mod errors;
+mod feedback;
 mod midi_controller;
+mod options;
 mod synth;
 mod usb_midi;
 
+use std::env;
 use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
 use std::sync::mpsc;
 use std::sync::Arc;
 
+#[cfg(feature = "alsa")]
+use alsa_seq::AlsaSeqClient;
 use midi_controller::{AkaiAPC40MkII, MAudioKeystation49e, MidiControllerType, UsbMidiController};
+use options::Options;
+use usb_midi::MidiMessage;
 
 use synth::audio_driver::AudioDriver;
+use synth::control_surface::apc40::Apc40;
 use synth::dispatcher::Dispatcher;
-use synth::synthesizer::Synthesizer;
+use synth::mapping::Mappings;
+use synth::patch::PatchBank;
+use synth::sequencer::Pattern;
+use synth::settings::Settings;
+use synth::spsc;
+use synth::synthesizer::{Synthesizer, CONTROL_QUEUE_CAPACITY};
+use synth::tuning::TuningBank;
+use synth::zone::Zones;
 
 use error_chain::ChainedError;
 use errors::ErrorKind::*;
@@ -38,6 +59,12 @@ use errors::*;
 pub static TERMINATION_REQUEST: AtomicBool = ATOMIC_BOOL_INIT;
 
 fn run() -> Result<()> {
+    let options = Options::parse(env::args().skip(1))?;
+
+    if options.list_audio_devices {
+        return AudioDriver::new()?.list_devices();
+    }
+
     // Setup signal handler
     ctrlc::set_handler(|| {
         println!("\nTermination requested. Stopping now...");
@@ -53,8 +80,8 @@ fn run() -> Result<()> {
         let mut threads = vec![];
 
         let (device2host_tx, device2host_rx) = mpsc::channel();
-        let (host2controls_tx, host2controls_rx) = mpsc::channel();
-        let (synth_ctrl_tx, synth_ctrl_rx) = mpsc::channel();
+        let (host2controls_tx, host2controls_rx) = mpsc::channel::<MidiMessage>();
+        let (synth_ctrl_tx, synth_ctrl_rx) = spsc::channel(CONTROL_QUEUE_CAPACITY);
 
         // Setup MIDI controllers
         let keystation = match MAudioKeystation49e::open(&usb_context) {
@@ -68,16 +95,41 @@ fn run() -> Result<()> {
             },
         };
 
-        let apc40 = Arc::new(UsbMidiController::new(
-            AkaiAPC40MkII::open(&usb_context).chain_err(|| "Could not open Akai APC40 MkII")?,
-        ));
+        let apc40 = match AkaiAPC40MkII::open(&usb_context) {
+            Ok(apc40) => Some(Arc::new(UsbMidiController::new(apc40))),
+            Err(e) => match *e.kind() {
+                MidiControllerNotConnected => {
+                    println!("Control panel not connected, continue without it...");
+                    None
+                }
+                _ => return Err(e).chain_err(|| "Could not open Akai APC40 MkII"),
+            },
+        };
+
+        #[cfg(feature = "alsa")]
+        let alsa_client = Arc::new(
+            AlsaSeqClient::open("midi-synth")
+                .chain_err(|| "Could not open ALSA sequencer client")?,
+        );
+
+        let mut settings = Settings::load(options.config_dir.join("settings.txt"))?;
+        if let Some(concert_pitch) = options.concert_pitch {
+            settings.concert_pitch = concert_pitch;
+            settings.save()?;
+        }
+        println!("Concert pitch: a' = {} Hz", settings.concert_pitch);
 
         // Create Synthesizer
-        let synthesizer = Synthesizer::new(synth_ctrl_rx);
+        let mut synthesizer = Synthesizer::new(
+            synth_ctrl_rx,
+            options.audio.sample_rate,
+            &options.ramp_times,
+        );
+        synthesizer.set_concert_pitch(settings.concert_pitch);
 
         // Setup Portaudio
         let mut audio = AudioDriver::new()?;
-        audio.start(synthesizer)?;
+        audio.start(synthesizer, &options.audio)?;
 
         // Setup threads that listen to MIDI events from the controllers
         if let Some(keystation) = keystation {
@@ -90,28 +142,69 @@ fn run() -> Result<()> {
             threads.push(keyboard_thread);
         }
 
-        let apc40_cloned = apc40.clone();
-        let controls_rx_thread = scope.spawn(move || {
-            let result = apc40_cloned.listen(&device2host_tx, MidiControllerType::ControlPanel);
-            TERMINATION_REQUEST.store(true, Ordering::Release);
-            result
-        });
-        threads.push(controls_rx_thread);
+        // Setup thread that listens to MIDI events from other applications
+        #[cfg(feature = "alsa")]
+        {
+            let alsa_client = alsa_client.clone();
+            let alsa_tx = device2host_tx.clone();
+            let alsa_rx_thread = scope.spawn(move || {
+                let result = alsa_client.listen(&alsa_tx);
+                TERMINATION_REQUEST.store(true, Ordering::Release);
+                result
+            });
+            threads.push(alsa_rx_thread);
+        }
+
+        if let Some(ref apc40) = apc40 {
+            let apc40 = apc40.clone();
+            let controls_rx_thread = scope.spawn(move || {
+                let result = apc40.listen(&device2host_tx, MidiControllerType::ControlPanel);
+                TERMINATION_REQUEST.store(true, Ordering::Release);
+                result
+            });
+            threads.push(controls_rx_thread);
+        }
 
         // Setup thread that transmits MIDI events to APC controller
         let controls_tx_thread = scope.spawn(move || {
-            while let Ok(midi_message) = host2controls_rx.recv() {
-                match apc40.send_message(midi_message) {
-                    Ok(_) => {}
-                    Err(e) => return Err(e),
-                }
-            }
-            Ok(())
+            feedback::forward(
+                &host2controls_rx,
+                feedback::TRANSFER_INTERVAL,
+                |midi_messages| {
+                    // Feedback to other applications is best effort, it must not stop the
+                    // feedback to the control panel
+                    #[cfg(feature = "alsa")]
+                    for midi_message in &midi_messages {
+                        if let Err(e) = alsa_client.send_message(midi_message.clone()) {
+                            println!("Failed to send feedback to ALSA sequencer: {}", e);
+                        }
+                    }
+
+                    if let Some(ref apc40) = apc40 {
+                        apc40.send_messages(midi_messages)?;
+                    }
+                    Ok(())
+                },
+            )
         });
         threads.push(controls_tx_thread);
 
         // Create dispatcher
-        let mut dispatcher = Dispatcher::new(device2host_rx, host2controls_tx, synth_ctrl_tx);
+        let mappings = Mappings::load(options.config_dir.join("mappings.txt"))?;
+        let patches = PatchBank::new(Some(options.config_dir.join("patches")));
+        let pattern = Pattern::load(options.config_dir.join("pattern.txt"))?;
+        let zones = Zones::load(options.config_dir.join("zones.txt"))?;
+        let mut dispatcher = Dispatcher::new(
+            device2host_rx,
+            Apc40::new(host2controls_tx),
+            synth_ctrl_tx,
+            mappings,
+            patches,
+            pattern,
+            options.mpe_zones,
+            TuningBank::new(Some(options.config_dir.join("tunings"))),
+            zones,
+        );
         let dispatcher_thread = scope.spawn(move || dispatcher.start());
         threads.push(dispatcher_thread);
 
@@ -1,6 +1,6 @@
 use std::sync::atomic::Ordering;
 use std::sync::mpsc::Sender;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use itertools::Itertools;
 use libusb;
@@ -8,12 +8,16 @@ use libusb;
 use errors::ErrorKind::{MidiControllerNotConnected, MidiOperationNotSupported};
 use errors::*;
 
-use usb_midi::{MidiMessage, MidiParseStatus, SystemExclusive, SystemExlusiveId, UsbMidiParser};
+use apc40_protocol;
+use usb_midi::{MidiMessage, MidiParseStatus, UsbMidiParser};
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum MidiControllerType {
     Keyboard,
     ControlPanel,
+    /// Messages of another application for the controls of the control panel (e.g. automation of
+    /// a DAW), which change parameters without moving the physical controls
+    Automation,
 }
 
 /// Utility function to discover all endpoints of a USB device.
@@ -96,7 +100,7 @@ impl<T: UsbMidiDevice> UsbMidiController<T> {
 
     pub fn listen(
         &self,
-        tx: &Sender<(MidiMessage, MidiControllerType)>,
+        tx: &Sender<(MidiMessage, MidiControllerType, Instant)>,
         source: MidiControllerType,
     ) -> Result<()> {
         let mut buf: [u8; 256] = [0; 256];
@@ -125,10 +129,13 @@ impl<T: UsbMidiDevice> UsbMidiController<T> {
             };
             end += read;
 
+            // Timestamp all messages of this transfer with the time they were received
+            let timestamp = Instant::now();
+
             while begin < end {
                 match usb_midi_parser.parse(&buf[begin..end]) {
                     (MidiParseStatus::Complete(packet), n) => {
-                        tx.send((packet.into_midi_message(), source))?;
+                        tx.send((packet.into_midi_message(), source, timestamp))?;
                         begin += n;
                     }
                     (MidiParseStatus::Incomplete, n) => {
@@ -159,13 +166,14 @@ impl<T: UsbMidiDevice> UsbMidiController<T> {
         }
     }
 
-    pub fn send_message(&self, msg: MidiMessage) -> Result<usize> {
-        let mut buf = [0u8; 32];
+    /// Sends several messages with as few bulk transfers as possible.
+    pub fn send_messages(&self, msgs: Vec<MidiMessage>) -> Result<usize> {
+        let mut buf = [0u8; 64];
 
         let mut sent = 0;
 
         let mut i = 0;
-        for byte in msg.serialize() {
+        for byte in msgs.into_iter().flat_map(MidiMessage::serialize) {
             buf[i] = byte;
             i += 1;
 
@@ -213,10 +221,7 @@ impl<'ctx> AkaiAPC40MkII<'ctx> {
     pub fn open(context: &'ctx libusb::Context) -> Result<Self> {
         let handle = open_device(context, 0x9e8, 0x29, 1)?;
 
-        let init_message = SystemExclusive::create(
-            SystemExlusiveId::OneByte(0x47),
-            vec![0x7F, 0x29, 0x60, 0x00, 0x04, 0x42, 0x00, 0x00, 0x00],
-        );
+        let init_message = apc40_protocol::introduction(apc40_protocol::Mode::AlternateAbletonLive);
 
         let mut buf = [0u8; 16];
         for (i, byte) in init_message.serialize().enumerate() {
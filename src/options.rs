@@ -0,0 +1,241 @@
+use std::env;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use synth::audio_driver::AudioSettings;
+use synth::mpe::MpeZones;
+use synth::settings::{MAX_CONCERT_PITCH, MIN_CONCERT_PITCH};
+use synth::synthesizer::RampTimes;
+
+use errors::ErrorKind::InvalidArgument;
+use errors::*;
+
+/// Options given on the command line.
+#[derive(Debug, PartialEq)]
+pub struct Options {
+    pub audio: AudioSettings,
+    pub ramp_times: RampTimes,
+    /// MPE zones of the keyboard until it configures them
+    pub mpe_zones: MpeZones,
+    /// Frequency of a' in Hz, saved to the settings
+    pub concert_pitch: Option<f32>,
+    /// Print the available audio output devices and exit
+    pub list_audio_devices: bool,
+    /// Directory where settings (e.g. MIDI mappings) are stored
+    pub config_dir: PathBuf,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        let config_dir = match env::var_os("HOME") {
+            Some(home) => PathBuf::from(home).join(".midi-synth"),
+            None => PathBuf::from(".midi-synth"),
+        };
+
+        Options {
+            audio: AudioSettings::default(),
+            ramp_times: RampTimes::default(),
+            mpe_zones: MpeZones::default(),
+            concert_pitch: None,
+            list_audio_devices: false,
+            config_dir,
+        }
+    }
+}
+
+impl Options {
+    /// Parses the command line arguments (without the program name):
+    ///
+    /// - `--sample-rate <Hz>`
+    /// - `--buffer-size <frames>`
+    /// - `--device <index or name>`
+    /// - `--list-devices`
+    /// - `--volume-ramp <ms>`
+    /// - `--tune-ramp <ms>`
+    /// - `--pressure-ramp <ms>`
+    /// - `--mpe-lower <member channels>`
+    /// - `--mpe-upper <member channels>`
+    /// - `--concert-pitch <Hz>`
+    /// - `--config-dir <path>`
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Self> {
+        let mut options = Options::default();
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--sample-rate" => {
+                    options.audio.sample_rate = parse_value(&arg, args.next(), |&rate| rate > 0.0)?
+                }
+                "--buffer-size" => {
+                    options.audio.frames_per_buffer =
+                        parse_value(&arg, args.next(), |&frames| frames > 0)?
+                }
+                "--device" => {
+                    options.audio.device =
+                        Some(parse_value(&arg, args.next(), |device: &String| {
+                            !device.is_empty()
+                        })?);
+                }
+                "--list-devices" => options.list_audio_devices = true,
+                "--volume-ramp" => {
+                    options.ramp_times.volume =
+                        Duration::from_millis(parse_value(&arg, args.next(), |_| true)?)
+                }
+                "--tune-ramp" => {
+                    options.ramp_times.master_tune =
+                        Duration::from_millis(parse_value(&arg, args.next(), |_| true)?)
+                }
+                "--pressure-ramp" => {
+                    options.ramp_times.pressure =
+                        Duration::from_millis(parse_value(&arg, args.next(), |_| true)?)
+                }
+                "--mpe-lower" => {
+                    let members = parse_value(&arg, args.next(), |&members| members <= 15)?;
+                    options.mpe_zones.configure(0, members)
+                }
+                "--mpe-upper" => {
+                    let members = parse_value(&arg, args.next(), |&members| members <= 15)?;
+                    options.mpe_zones.configure(15, members)
+                }
+                "--concert-pitch" => {
+                    options.concert_pitch = Some(parse_value(&arg, args.next(), |pitch| {
+                        (MIN_CONCERT_PITCH..=MAX_CONCERT_PITCH).contains(pitch)
+                    })?)
+                }
+                "--config-dir" => {
+                    options.config_dir = parse_value(&arg, args.next(), |dir: &PathBuf| {
+                        !dir.as_os_str().is_empty()
+                    })?
+                }
+                _ => bail!(InvalidArgument(arg)),
+            }
+        }
+
+        Ok(options)
+    }
+}
+
+fn parse_value<T: FromStr>(
+    arg: &str,
+    value: Option<String>,
+    is_valid: fn(&T) -> bool,
+) -> Result<T> {
+    let value = value.unwrap_or_default();
+    match value.parse() {
+        Ok(parsed) if is_valid(&parsed) => Ok(parsed),
+        _ => bail!(InvalidArgument(format!("{} {}", arg, value))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use synth::audio_driver::{DEFAULT_FRAMES_PER_BUFFER, DEFAULT_SAMPLE_RATE};
+
+    fn parse(args: &[&str]) -> Result<Options> {
+        Options::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn defaults_without_arguments() {
+        let options = parse(&[]).unwrap();
+
+        assert_eq!(DEFAULT_SAMPLE_RATE, options.audio.sample_rate);
+        assert_eq!(DEFAULT_FRAMES_PER_BUFFER, options.audio.frames_per_buffer);
+        assert_eq!(None, options.audio.device);
+        assert!(!options.list_audio_devices);
+        assert_eq!(RampTimes::default(), options.ramp_times);
+        assert_eq!(MpeZones::default(), options.mpe_zones);
+        assert_eq!(None, options.concert_pitch);
+    }
+
+    #[test]
+    fn audio_settings() {
+        let options = parse(&[
+            "--sample-rate",
+            "48000",
+            "--buffer-size",
+            "128",
+            "--device",
+            "USB Audio",
+        ])
+        .unwrap();
+
+        assert_eq!(
+            AudioSettings {
+                sample_rate: 48_000.0,
+                frames_per_buffer: 128,
+                device: Some("USB Audio".to_string()),
+            },
+            options.audio
+        );
+
+        assert!(parse(&["--list-devices"]).unwrap().list_audio_devices);
+        assert_eq!(
+            PathBuf::from("/tmp/synth"),
+            parse(&["--config-dir", "/tmp/synth"]).unwrap().config_dir
+        );
+    }
+
+    #[test]
+    fn ramp_times() {
+        let options = parse(&[
+            "--volume-ramp",
+            "0",
+            "--tune-ramp",
+            "50",
+            "--pressure-ramp",
+            "15",
+        ])
+        .unwrap();
+
+        assert_eq!(
+            RampTimes {
+                volume: Duration::from_millis(0),
+                master_tune: Duration::from_millis(50),
+                pressure: Duration::from_millis(15),
+            },
+            options.ramp_times
+        );
+    }
+
+    #[test]
+    fn concert_pitch() {
+        let options = parse(&["--concert-pitch", "415"]).unwrap();
+        assert_eq!(Some(415.0), options.concert_pitch);
+    }
+
+    #[test]
+    fn mpe_zones() {
+        let options = parse(&["--mpe-lower", "7", "--mpe-upper", "5"]).unwrap();
+        assert_eq!(MpeZones { lower: 7, upper: 5 }, options.mpe_zones);
+
+        // The zone given last wins if they overlap
+        let options = parse(&["--mpe-upper", "7", "--mpe-lower", "15"]).unwrap();
+        assert_eq!(
+            MpeZones {
+                lower: 15,
+                upper: 0
+            },
+            options.mpe_zones
+        );
+    }
+
+    #[test]
+    fn invalid_arguments() {
+        assert!(parse(&["--sample-rate"]).is_err());
+        assert!(parse(&["--sample-rate", "fast"]).is_err());
+        assert!(parse(&["--sample-rate", "0"]).is_err());
+        assert!(parse(&["--buffer-size", "-64"]).is_err());
+        assert!(parse(&["--buffer-size", "0"]).is_err());
+        assert!(parse(&["--device"]).is_err());
+        assert!(parse(&["--config-dir"]).is_err());
+        assert!(parse(&["--volume-ramp", "-1"]).is_err());
+        assert!(parse(&["--tune-ramp", "0.5"]).is_err());
+        assert!(parse(&["--volume", "11"]).is_err());
+        assert!(parse(&["--mpe-lower", "16"]).is_err());
+        assert!(parse(&["--concert-pitch", "400"]).is_err());
+    }
+}
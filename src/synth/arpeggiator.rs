@@ -0,0 +1,524 @@
+use std::time::{Duration, Instant};
+
+use synth::step_clock::StepClock;
+
+/// Clock pulses per step for each value of `ParameterId::ArpeggiatorRate` (1/4, 1/8,
+/// 1/8 triplet, 1/16)
+const RATE_PULSES: [u32; 4] = [24, 12, 8, 6];
+
+/// Order in which the arpeggiator plays the notes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Order {
+    Up,
+    Down,
+    /// Up and down again, without repeating the highest and lowest note
+    UpDown,
+    Random,
+    /// In the order the keys were pressed
+    AsPlayed,
+}
+
+impl Order {
+    /// Converts a value of `ParameterId::ArpeggiatorOrder`.
+    pub fn from_value(value: f32) -> Self {
+        match value.round() as u8 {
+            0 => Order::Up,
+            1 => Order::Down,
+            2 => Order::UpDown,
+            3 => Order::Random,
+            _ => Order::AsPlayed,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArpeggiatorNote {
+    On(u8),
+    Off(u8),
+}
+
+/// Plays the held notes one after another.
+///
+/// Steps are timed by an internal tempo or by an external MIDI clock. The arpeggiator does not
+/// run a thread of its own: `next_event()` tells when `run()` has to be called next, and notes
+/// are returned with the time they are due.
+pub struct Arpeggiator {
+    enabled: bool,
+    order: Order,
+    octaves: u8,
+    /// Length of the notes, as fraction of a step
+    gate: f32,
+    clock: StepClock,
+    latch: bool,
+    /// Keys that are held down, in the order they were pressed
+    held: Vec<u8>,
+    /// Notes the pattern is built from, i.e. the held keys (or the last chord in latch mode)
+    notes: Vec<u8>,
+    step: usize,
+    playing: Option<u8>,
+    note_off_at: Option<Instant>,
+    random_state: u32,
+}
+
+impl Arpeggiator {
+    pub fn new() -> Self {
+        Self {
+            enabled: false,
+            order: Order::Up,
+            octaves: 1,
+            gate: 0.5,
+            clock: StepClock::new(RATE_PULSES[3]),
+            latch: false,
+            held: vec![],
+            notes: vec![],
+            step: 0,
+            playing: None,
+            note_off_at: None,
+            random_state: 0x2545_F491,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Keys that are held down, in the order they were pressed.
+    pub fn held_notes(&self) -> &[u8] {
+        &self.held
+    }
+
+    /// Starts or stops the arpeggiator. Keys are tracked while it is disabled, so that the
+    /// pattern starts with the held keys.
+    pub fn set_enabled(&mut self, enabled: bool, now: Instant) {
+        self.enabled = enabled;
+        self.notes = if enabled { self.held.clone() } else { vec![] };
+        self.restart(now);
+    }
+
+    pub fn set_order(&mut self, order: Order) {
+        self.order = order;
+    }
+
+    pub fn set_octaves(&mut self, octaves: u8) {
+        self.octaves = octaves.clamp(1, 4);
+    }
+
+    pub fn set_gate(&mut self, gate: f32) {
+        self.gate = gate.clamp(0.0, 1.0);
+    }
+
+    pub fn set_tempo(&mut self, tempo: f32) {
+        self.clock.set_tempo(tempo);
+    }
+
+    /// Sets the rate from a value of `ParameterId::ArpeggiatorRate`.
+    pub fn set_rate(&mut self, rate: usize) {
+        self.clock
+            .set_pulses_per_step(RATE_PULSES[rate.min(RATE_PULSES.len() - 1)]);
+    }
+
+    /// Selects whether steps are timed by MIDI clock (see `clock()`) or by the internal tempo.
+    pub fn set_external_clock(&mut self, external_clock: bool, now: Instant) {
+        if external_clock != self.clock.is_external() {
+            self.clock.set_external(external_clock);
+            self.restart(now);
+        }
+    }
+
+    /// In latch mode the pattern keeps running after the keys are released, until a new chord
+    /// is played.
+    pub fn set_latch(&mut self, latch: bool, now: Instant) {
+        self.latch = latch;
+        if !latch && self.enabled {
+            self.notes = self.held.clone();
+            if self.notes.is_empty() {
+                self.stop(now);
+            }
+        }
+    }
+
+    pub fn note_on(&mut self, note: u8, now: Instant) {
+        // A new chord replaces the latched one
+        if self.latch && self.held.is_empty() {
+            self.notes.clear();
+        }
+
+        if !self.held.contains(&note) {
+            self.held.push(note);
+        }
+
+        if self.enabled && !self.notes.contains(&note) {
+            self.notes.push(note);
+            if self.notes.len() == 1 {
+                self.restart(now);
+            }
+        }
+    }
+
+    pub fn note_off(&mut self, note: u8, now: Instant) {
+        self.held.retain(|held| *held != note);
+
+        if self.enabled && !self.latch {
+            self.notes.retain(|played| *played != note);
+            if self.notes.is_empty() {
+                self.stop(now);
+            }
+        }
+    }
+
+    /// Handles a pulse of the MIDI clock (24 per quarter note).
+    pub fn clock(&mut self, now: Instant) -> Vec<(ArpeggiatorNote, Instant)> {
+        let due = self.clock.pulse();
+        let mut notes = self.run(now);
+
+        if due && !self.notes.is_empty() {
+            let duration = self.clock.step_duration();
+            self.play_step(now, duration, &mut notes);
+        }
+
+        notes
+    }
+
+    /// Handles MIDI Start: the pattern starts from the beginning with the next clock pulse.
+    pub fn clock_start(&mut self) {
+        self.clock.clock_start();
+        self.step = 0;
+    }
+
+    /// Handles MIDI Continue.
+    pub fn clock_continue(&mut self) {
+        self.clock.clock_continue();
+    }
+
+    /// Handles MIDI Stop: the playing note is released.
+    pub fn clock_stop(&mut self, now: Instant) {
+        self.clock.clock_stop();
+        if self.clock.is_external() && self.playing.is_some() {
+            self.note_off_at = Some(now);
+        }
+    }
+
+    /// Forgets the playing note after all notes were released otherwise (e.g. by a program
+    /// change), so that it is not released again.
+    pub fn notes_released(&mut self) {
+        self.playing = None;
+        self.note_off_at = None;
+    }
+
+    /// Returns the time at which `run()` has to be called next, if any.
+    pub fn next_event(&self) -> Option<Instant> {
+        match (self.note_off_at, self.clock.next_step()) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    /// Returns the notes that are due until `now`, with the time they are due.
+    pub fn run(&mut self, now: Instant) -> Vec<(ArpeggiatorNote, Instant)> {
+        let mut notes = vec![];
+
+        loop {
+            // Notes are released before the next one is played
+            if let Some(off) = self.note_off_at {
+                if off <= now && self.clock.next_step().map_or(true, |step| off <= step) {
+                    self.note_off_at = None;
+                    if let Some(note) = self.playing.take() {
+                        notes.push((ArpeggiatorNote::Off(note), off));
+                    }
+                    continue;
+                }
+            }
+
+            match self.clock.due_step(now) {
+                Some(step) => {
+                    let duration = self.clock.step_duration();
+                    self.play_step(step, duration, &mut notes);
+                }
+                None => return notes,
+            }
+        }
+    }
+
+    fn play_step(
+        &mut self,
+        time: Instant,
+        duration: Duration,
+        notes: &mut Vec<(ArpeggiatorNote, Instant)>,
+    ) {
+        if let Some(note) = self.playing.take() {
+            notes.push((ArpeggiatorNote::Off(note), time));
+        }
+
+        let pattern = self.pattern();
+        if pattern.is_empty() {
+            self.note_off_at = None;
+            return;
+        }
+
+        let index = match self.order {
+            Order::Random => self.random() as usize % pattern.len(),
+            _ => self.step % pattern.len(),
+        };
+        self.step = self.step.wrapping_add(1);
+
+        notes.push((ArpeggiatorNote::On(pattern[index]), time));
+        self.playing = Some(pattern[index]);
+        let gate = (duration.as_nanos() as f64 * f64::from(self.gate)).round();
+        self.note_off_at = Some(time + Duration::from_nanos(gate as u64));
+    }
+
+    /// Returns the notes of one cycle of the pattern.
+    fn pattern(&self) -> Vec<u8> {
+        let mut notes = self.notes.clone();
+        if self.order != Order::AsPlayed {
+            notes.sort_unstable();
+        }
+
+        let mut pattern: Vec<u8> = (0..self.octaves)
+            .flat_map(|octave| {
+                notes
+                    .iter()
+                    .filter_map(move |note| note.checked_add(12 * octave))
+            })
+            .filter(|note| *note <= 127)
+            .collect();
+
+        match self.order {
+            Order::Down => pattern.reverse(),
+            Order::UpDown if pattern.len() > 2 => {
+                let down: Vec<u8> = pattern[1..pattern.len() - 1]
+                    .iter()
+                    .rev()
+                    .cloned()
+                    .collect();
+                pattern.extend(down);
+            }
+            _ => {}
+        }
+
+        pattern
+    }
+
+    /// Starts the pattern from the beginning, releasing the playing note.
+    fn restart(&mut self, now: Instant) {
+        self.stop(now);
+        if self.enabled && !self.notes.is_empty() {
+            self.clock.start(now);
+        }
+    }
+
+    fn stop(&mut self, now: Instant) {
+        self.step = 0;
+        self.clock.stop();
+        self.note_off_at = self.playing.map(|_| now);
+    }
+
+    /// Xorshift pseudo random numbers, good enough to pick notes.
+    fn random(&mut self) -> u32 {
+        let mut x = self.random_state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.random_state = x;
+        x
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use self::ArpeggiatorNote::*;
+
+    /// Step duration of 1/16 notes at 120 BPM
+    const STEP: Duration = Duration::from_millis(125);
+
+    fn arpeggiator(order: Order, notes: &[u8], start: Instant) -> Arpeggiator {
+        let mut arpeggiator = Arpeggiator::new();
+        arpeggiator.set_order(order);
+        arpeggiator.set_enabled(true, start);
+        for note in notes {
+            arpeggiator.note_on(*note, start);
+        }
+        arpeggiator
+    }
+
+    /// Returns the notes that are played in the given number of steps.
+    fn played(arpeggiator: &mut Arpeggiator, start: Instant, steps: u32) -> Vec<u8> {
+        (0..steps)
+            .flat_map(|step| arpeggiator.run(start + STEP * step))
+            .filter_map(|(note, _)| match note {
+                On(note) => Some(note),
+                Off(_) => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn orders() {
+        let start = Instant::now();
+        let chord = [64, 60, 67];
+
+        let mut up = arpeggiator(Order::Up, &chord, start);
+        assert_eq!(vec![60, 64, 67, 60], played(&mut up, start, 4));
+
+        let mut down = arpeggiator(Order::Down, &chord, start);
+        assert_eq!(vec![67, 64, 60, 67], played(&mut down, start, 4));
+
+        let mut up_down = arpeggiator(Order::UpDown, &chord, start);
+        assert_eq!(vec![60, 64, 67, 64, 60], played(&mut up_down, start, 5));
+
+        let mut as_played = arpeggiator(Order::AsPlayed, &chord, start);
+        assert_eq!(vec![64, 60, 67, 64], played(&mut as_played, start, 4));
+
+        let mut random = arpeggiator(Order::Random, &chord, start);
+        assert!(played(&mut random, start, 20)
+            .iter()
+            .all(|note| chord.contains(note)));
+    }
+
+    #[test]
+    fn octave_range() {
+        let start = Instant::now();
+        let mut arpeggiator = arpeggiator(Order::Up, &[60, 64], start);
+        arpeggiator.set_octaves(3);
+
+        assert_eq!(
+            vec![60, 64, 72, 76, 84, 88, 60],
+            played(&mut arpeggiator, start, 7)
+        );
+    }
+
+    #[test]
+    fn octaves_above_highest_note_are_skipped() {
+        let start = Instant::now();
+        let mut arpeggiator = arpeggiator(Order::Up, &[110], start);
+        arpeggiator.set_octaves(4);
+
+        assert_eq!(vec![110, 122, 110], played(&mut arpeggiator, start, 3));
+    }
+
+    #[test]
+    fn gate_length() {
+        let start = Instant::now();
+        let mut arpeggiator = arpeggiator(Order::Up, &[60, 62], start);
+        arpeggiator.set_gate(0.25);
+
+        assert_eq!(vec![(On(60), start)], arpeggiator.run(start));
+        assert_eq!(Some(start + STEP / 4), arpeggiator.next_event());
+        assert_eq!(
+            vec![(Off(60), start + STEP / 4), (On(62), start + STEP)],
+            arpeggiator.run(start + STEP)
+        );
+    }
+
+    #[test]
+    fn full_gate_releases_note_before_next_one() {
+        let start = Instant::now();
+        let mut arpeggiator = arpeggiator(Order::Up, &[60, 62], start);
+        arpeggiator.set_gate(1.0);
+
+        arpeggiator.run(start);
+        assert_eq!(
+            vec![(Off(60), start + STEP), (On(62), start + STEP)],
+            arpeggiator.run(start + STEP)
+        );
+    }
+
+    #[test]
+    fn stops_when_keys_are_released() {
+        let start = Instant::now();
+        let mut arpeggiator = arpeggiator(Order::Up, &[60], start);
+
+        arpeggiator.run(start);
+        arpeggiator.note_off(60, start + STEP / 8);
+        assert_eq!(
+            vec![(Off(60), start + STEP / 8)],
+            arpeggiator.run(start + STEP / 8)
+        );
+        assert_eq!(None, arpeggiator.next_event());
+    }
+
+    #[test]
+    fn released_notes_are_not_released_again() {
+        let start = Instant::now();
+        let mut arpeggiator = arpeggiator(Order::Up, &[60, 62], start);
+
+        arpeggiator.run(start);
+        arpeggiator.notes_released();
+        assert_eq!(vec![(On(62), start + STEP)], arpeggiator.run(start + STEP));
+    }
+
+    #[test]
+    fn latch_keeps_pattern_until_new_chord() {
+        let start = Instant::now();
+        let mut arpeggiator = arpeggiator(Order::Up, &[], start);
+        arpeggiator.set_latch(true, start);
+        arpeggiator.note_on(60, start);
+        arpeggiator.note_on(64, start);
+        arpeggiator.note_off(60, start);
+        arpeggiator.note_off(64, start);
+
+        assert_eq!(vec![60, 64, 60], played(&mut arpeggiator, start, 3));
+
+        let later = start + STEP * 3;
+        arpeggiator.note_on(67, later);
+        assert_eq!(vec![67, 67], played(&mut arpeggiator, later, 2));
+
+        arpeggiator.note_off(67, later);
+        arpeggiator.set_latch(false, later);
+        assert_eq!(vec![(Off(67), later)], arpeggiator.run(later + STEP));
+        assert_eq!(None, arpeggiator.next_event());
+    }
+
+    #[test]
+    fn held_keys_are_tracked_while_disabled() {
+        let start = Instant::now();
+        let mut arpeggiator = Arpeggiator::new();
+        arpeggiator.note_on(60, start);
+        arpeggiator.note_on(64, start);
+        assert!(arpeggiator.run(start).is_empty());
+        assert_eq!(&[60, 64], arpeggiator.held_notes());
+
+        arpeggiator.set_enabled(true, start);
+        assert_eq!(vec![60, 64], played(&mut arpeggiator, start, 2));
+    }
+
+    #[test]
+    fn external_clock() {
+        let start = Instant::now();
+        let pulse = Duration::from_millis(20);
+        let mut arpeggiator = Arpeggiator::new();
+        arpeggiator.set_external_clock(true, start);
+        arpeggiator.set_rate(1);
+        arpeggiator.set_tempo(125.0);
+        arpeggiator.set_enabled(true, start);
+        arpeggiator.clock_start();
+        arpeggiator.note_on(60, start);
+        arpeggiator.note_on(64, start);
+
+        assert_eq!(None, arpeggiator.next_event());
+
+        let mut notes = vec![];
+        for n in 1..26 {
+            notes.extend(arpeggiator.clock(start + pulse * n));
+        }
+        assert_eq!(
+            vec![
+                (On(60), start + pulse),
+                (Off(60), start + pulse * 7),
+                (On(64), start + pulse * 13),
+                (Off(64), start + pulse * 19),
+                (On(60), start + pulse * 25),
+            ],
+            notes
+        );
+
+        arpeggiator.clock_stop(start + pulse * 26);
+        assert_eq!(
+            vec![(Off(60), start + pulse * 26)],
+            arpeggiator.run(start + pulse * 26)
+        );
+        assert!(arpeggiator.clock(start + pulse * 27).is_empty());
+    }
+}
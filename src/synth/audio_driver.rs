@@ -1,16 +1,36 @@
 use std::panic::{self, AssertUnwindSafe};
 use std::sync::atomic::Ordering;
+use std::time::Instant;
 
 use portaudio;
-use portaudio::{OutputStreamCallbackArgs, PortAudio, Stream};
+use portaudio::{DeviceIndex, OutputStreamCallbackArgs, PortAudio, Stream};
 
 use synth::synthesizer::Synthesizer;
 
-use errors::Result;
+use errors::ErrorKind::AudioDeviceNotFound;
+use errors::*;
 
-pub const SAMPLE_RATE: f64 = 44_100.0;
+pub const DEFAULT_SAMPLE_RATE: f64 = 44_100.0;
 const CHANNELS: i32 = 2;
-const FRAMES_PER_BUFFER: u32 = 64;
+pub const DEFAULT_FRAMES_PER_BUFFER: u32 = 64;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AudioSettings {
+    pub sample_rate: f64,
+    pub frames_per_buffer: u32,
+    /// Index or name of the output device (the default output device is used if not set)
+    pub device: Option<String>,
+}
+
+impl Default for AudioSettings {
+    fn default() -> Self {
+        Self {
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            frames_per_buffer: DEFAULT_FRAMES_PER_BUFFER,
+            device: None,
+        }
+    }
+}
 
 pub struct AudioDriver {
     portaudio: PortAudio,
@@ -27,39 +47,108 @@ impl AudioDriver {
         })
     }
 
-    pub fn start(&mut self, mut synthesizer: Synthesizer) -> Result<()> {
-        let mut settings = self.portaudio.default_output_stream_settings(
+    /// Prints all devices that support stereo output. The default output device is marked with `*`.
+    pub fn list_devices(&self) -> Result<()> {
+        let default_device = self.portaudio.default_output_device().ok();
+
+        for device in self.portaudio.devices()? {
+            let (index, info) = device?;
+            if info.max_output_channels < CHANNELS {
+                continue;
+            }
+
+            let host_api = self
+                .portaudio
+                .host_api_info(info.host_api)
+                .map_or("unknown host API", |host_api| host_api.name);
+            let marker = if Some(index) == default_device {
+                '*'
+            } else {
+                ' '
+            };
+
+            println!(
+                "{} {:>3}: {} ({}, default sample rate: {} Hz)",
+                marker, index.0, info.name, host_api, info.default_sample_rate
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Looks up an output device by index or by name.
+    fn find_device(&self, device: Option<&str>) -> Result<DeviceIndex> {
+        let device = match device {
+            Some(device) => device,
+            None => return Ok(self.portaudio.default_output_device()?),
+        };
+
+        if let Ok(index) = device.parse() {
+            return match self.portaudio.device_info(DeviceIndex(index)) {
+                Ok(_) => Ok(DeviceIndex(index)),
+                Err(_) => bail!(AudioDeviceNotFound(device.to_string())),
+            };
+        }
+
+        for entry in self.portaudio.devices()? {
+            let (index, info) = entry?;
+            if info.name == device && info.max_output_channels >= CHANNELS {
+                return Ok(index);
+            }
+        }
+
+        bail!(AudioDeviceNotFound(device.to_string()))
+    }
+
+    pub fn start(&mut self, mut synthesizer: Synthesizer, settings: &AudioSettings) -> Result<()> {
+        let device = self.find_device(settings.device.as_deref())?;
+        let info = self.portaudio.device_info(device)?;
+        if info.max_output_channels < CHANNELS {
+            bail!(
+                "Audio device '{}' does not support stereo output",
+                info.name
+            );
+        }
+
+        let params = portaudio::StreamParameters::<f32>::new(
+            device,
             CHANNELS,
-            SAMPLE_RATE,
-            FRAMES_PER_BUFFER,
-        )?;
-        settings.flags = portaudio::stream_flags::CLIP_OFF;
+            true,
+            info.default_low_output_latency,
+        );
+        self.portaudio
+            .is_output_format_supported(params, settings.sample_rate)
+            .chain_err(|| {
+                format!(
+                    "Sample rate of {} Hz not supported by audio device '{}'",
+                    settings.sample_rate, info.name
+                )
+            })?;
+
+        println!(
+            "Audio output: {} ({} Hz, {} frames per buffer)",
+            info.name, settings.sample_rate, settings.frames_per_buffer
+        );
+
+        let mut stream_settings = portaudio::OutputStreamSettings::new(
+            params,
+            settings.sample_rate,
+            settings.frames_per_buffer,
+        );
+        stream_settings.flags = portaudio::stream_flags::CLIP_OFF;
+
+        // Allocate buffer for the mono output of the synthesizer up front, so that the callback
+        // does not need to allocate
+        let mut mono_buffer = vec![0.0; settings.frames_per_buffer as usize];
 
         let mut stream = self.portaudio.open_non_blocking_stream(
-            settings,
+            stream_settings,
             move |OutputStreamCallbackArgs { buffer, frames, .. }| {
-                let mut idx = 0;
-
-                let result = panic::catch_unwind(AssertUnwindSafe(|| {
-                    for _ in 0..frames {
-                        let output_value = synthesizer.next_sample();
-                        buffer[idx] = output_value;
-                        buffer[idx + 1] = output_value;
-                        idx += 2;
-                    }
-                }));
-
-                if result.is_err() {
-                    for _ in (idx / 2)..frames {
-                        buffer[idx] = 0.0;
-                        buffer[idx + 1] = 0.0;
-                        idx += 2;
-                    }
-
+                if render(&mut synthesizer, &mut mono_buffer, buffer, frames) {
+                    portaudio::Continue
+                } else {
                     ::TERMINATION_REQUEST.store(true, Ordering::Release);
                     portaudio::Abort
-                } else {
-                    portaudio::Continue
                 }
             },
         )?;
@@ -71,6 +160,47 @@ impl AudioDriver {
     }
 }
 
+/// Renders one buffer of interleaved stereo samples. Returns false if rendering failed.
+///
+/// This runs in the audio callback, so it must neither block nor allocate.
+fn render(
+    synthesizer: &mut Synthesizer,
+    mono_buffer: &mut [f32],
+    buffer: &mut [f32],
+    frames: usize,
+) -> bool {
+    let mut idx = 0;
+
+    synthesizer.begin_block(Instant::now());
+
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        while idx / 2 < frames {
+            let block_size = (frames - idx / 2).min(mono_buffer.len());
+            let block = &mut mono_buffer[..block_size];
+
+            synthesizer.fill_buffer(block);
+
+            for &output_value in block.iter() {
+                buffer[idx] = output_value;
+                buffer[idx + 1] = output_value;
+                idx += 2;
+            }
+        }
+    }));
+
+    if result.is_err() {
+        for _ in (idx / 2)..frames {
+            buffer[idx] = 0.0;
+            buffer[idx + 1] = 0.0;
+            idx += 2;
+        }
+
+        false
+    } else {
+        true
+    }
+}
+
 impl Drop for AudioDriver {
     fn drop(&mut self) {
         if let Some(ref mut stream) = self.stream {
@@ -79,3 +209,102 @@ impl Drop for AudioDriver {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use synth::dispatcher::{NoteId, SynthControl};
+    use synth::oscillator::Oscillator;
+    use synth::spsc;
+    use synth::synthesizer::{RampTimes, CONTROL_QUEUE_CAPACITY};
+    use testing;
+
+    #[test]
+    fn render_does_not_allocate() {
+        let (ctrl_tx, ctrl_rx) = spsc::channel(CONTROL_QUEUE_CAPACITY);
+        let mut synthesizer = Synthesizer::new(ctrl_rx, DEFAULT_SAMPLE_RATE, &RampTimes::default());
+        let mut mono_buffer = vec![0.0; DEFAULT_FRAMES_PER_BUFFER as usize];
+        let mut buffer = vec![0.0; 2 * DEFAULT_FRAMES_PER_BUFFER as usize];
+
+        let now = Instant::now();
+        ctrl_tx
+            .try_send((SynthControl::Oscillator1Range(0, 441.0), now))
+            .unwrap();
+        ctrl_tx
+            .try_send((SynthControl::Oscillator1Enable(0, true), now))
+            .unwrap();
+        ctrl_tx
+            .try_send((SynthControl::Oscillator1Volume(0, 1.0), now))
+            .unwrap();
+
+        // Fill the queue with a burst of controls (e.g. a knob turned fast)
+        for i in 0..CONTROL_QUEUE_CAPACITY - 3 {
+            let ctrl = match i % 3 {
+                0 => SynthControl::NoteOn(0, i as NoteId, 1.0 + i as f32 / 1000.0),
+                1 => SynthControl::MasterTune(1.0 + i as f32 / 10000.0),
+                _ => SynthControl::NoteOff(0, (i - 2) as NoteId),
+            };
+            ctrl_tx.try_send((ctrl, now)).unwrap();
+        }
+
+        let allocations = testing::allocations();
+        for _ in 0..10 {
+            assert!(render(
+                &mut synthesizer,
+                &mut mono_buffer,
+                &mut buffer,
+                DEFAULT_FRAMES_PER_BUFFER as usize
+            ));
+        }
+        assert_eq!(allocations, testing::allocations());
+    }
+
+    #[test]
+    fn render_duplicates_mono_output_to_both_channels() {
+        let (ctrl_tx, ctrl_rx) = spsc::channel(CONTROL_QUEUE_CAPACITY);
+        let mut synthesizer = Synthesizer::new(
+            ctrl_rx,
+            DEFAULT_SAMPLE_RATE,
+            &RampTimes {
+                volume: Duration::from_millis(0),
+                master_tune: Duration::from_millis(0),
+                pressure: Duration::from_millis(0),
+            },
+        );
+        let mut reference = Oscillator::new(1.0, 0.01);
+
+        // Use mono buffer smaller than the output buffer, so that it has to be rendered in chunks
+        let mut mono_buffer = vec![0.0; 16];
+        let mut buffer = vec![0.0; 2 * DEFAULT_FRAMES_PER_BUFFER as usize];
+
+        let t0 = Instant::now() - Duration::from_secs(1);
+        ctrl_tx
+            .try_send((SynthControl::Oscillator1Range(0, 441.0), t0))
+            .unwrap();
+        ctrl_tx
+            .try_send((SynthControl::Oscillator1Enable(0, true), t0))
+            .unwrap();
+        ctrl_tx
+            .try_send((SynthControl::Oscillator1Volume(0, 1.0), t0))
+            .unwrap();
+        ctrl_tx
+            .try_send((SynthControl::NoteOn(0, 0, 1.0), t0))
+            .unwrap();
+
+        assert!(render(
+            &mut synthesizer,
+            &mut mono_buffer,
+            &mut buffer,
+            DEFAULT_FRAMES_PER_BUFFER as usize
+        ));
+
+        for frame in buffer.chunks(2) {
+            let expected = reference.next().unwrap();
+            assert_float_eq!(expected, frame[0], 1e-6);
+            assert_float_eq!(expected, frame[1], 1e-6);
+        }
+    }
+}
@@ -0,0 +1,277 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use synth::step_clock::CLOCK_PULSES_PER_QUARTER_NOTE;
+use usb_midi::{MidiMessage, Start, Stop};
+
+/// Taps that are further apart start a new measurement
+const TAP_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Number of taps whose intervals are averaged
+const MAX_TAPS: usize = 5;
+
+/// Weight of a new pulse interval in the smoothed interval of an incoming clock
+const PULSE_SMOOTHING: f64 = 0.1;
+
+/// Deviation of a pulse interval from the smoothed one (as a fraction) that is considered a
+/// change of tempo rather than jitter
+const TEMPO_CHANGE: f64 = 0.2;
+
+/// Pulses that are further apart (i.e. slower than 10 BPM) are not measured
+const MAX_PULSE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Tempo of the synthesizer.
+///
+/// As master, the clock runs at the tempo that is set (or tapped) and generates MIDI clock
+/// pulses while the transport is started. As slave, it follows the pulses of an incoming MIDI
+/// clock, smoothing out their jitter.
+pub struct Clock {
+    /// Tempo of the internal clock in BPM
+    tempo: f32,
+    slave: bool,
+    /// Time of the next pulse of the internal clock, while it is started
+    next_pulse: Option<Instant>,
+    taps: VecDeque<Instant>,
+    last_pulse: Option<Instant>,
+    /// Smoothed interval of the incoming clock pulses in seconds
+    pulse_interval: Option<f64>,
+}
+
+impl Clock {
+    pub fn new() -> Self {
+        Self {
+            tempo: 120.0,
+            slave: false,
+            next_pulse: None,
+            taps: VecDeque::with_capacity(MAX_TAPS),
+            last_pulse: None,
+            pulse_interval: None,
+        }
+    }
+
+    /// Tempo in BPM: the internal tempo, or the measured tempo of the incoming clock.
+    pub fn tempo(&self) -> f32 {
+        match self.pulse_interval {
+            Some(interval) if self.slave => {
+                (60.0 / (interval * f64::from(CLOCK_PULSES_PER_QUARTER_NOTE))) as f32
+            }
+            _ => self.tempo,
+        }
+    }
+
+    /// Duration of a quarter note.
+    pub fn beat_duration(&self) -> Duration {
+        self.duration(CLOCK_PULSES_PER_QUARTER_NOTE)
+    }
+
+    /// Duration of a number of clock pulses (24 per quarter note).
+    pub fn duration(&self, pulses: u32) -> Duration {
+        let nanos = 60e9 * f64::from(pulses)
+            / (f64::from(self.tempo()) * f64::from(CLOCK_PULSES_PER_QUARTER_NOTE));
+        Duration::from_nanos(nanos.round() as u64)
+    }
+
+    pub fn set_tempo(&mut self, tempo: f32) {
+        self.tempo = tempo.max(1.0);
+    }
+
+    /// Switches between generating and following a MIDI clock. Returns the message that stops
+    /// the receivers of the internal clock, if it was started.
+    pub fn set_slave(&mut self, slave: bool) -> Option<MidiMessage> {
+        self.slave = slave;
+        self.last_pulse = None;
+        self.pulse_interval = None;
+
+        if slave {
+            self.stop()
+        } else {
+            None
+        }
+    }
+
+    /// Starts generating pulses at `now`. Returns the message that starts the receivers, or
+    /// `None` as slave.
+    pub fn start(&mut self, now: Instant) -> Option<MidiMessage> {
+        if self.slave {
+            return None;
+        }
+
+        self.next_pulse = Some(now);
+        Some(Start::create())
+    }
+
+    /// Stops generating pulses. Returns the message that stops the receivers, if the clock was
+    /// started.
+    pub fn stop(&mut self) -> Option<MidiMessage> {
+        self.next_pulse.take().map(|_| Stop::create())
+    }
+
+    /// Time of the next pulse of the internal clock.
+    pub fn next_event(&self) -> Option<Instant> {
+        self.next_pulse
+    }
+
+    /// Returns the times of the pulses of the internal clock that are due until `now`.
+    pub fn run(&mut self, now: Instant) -> Vec<Instant> {
+        let mut pulses = vec![];
+
+        while let Some(pulse) = self.next_pulse.filter(|pulse| *pulse <= now) {
+            pulses.push(pulse);
+
+            // Skip pulses that are long overdue instead of sending them all at once
+            let next_pulse = pulse + self.duration(1);
+            self.next_pulse = Some(if now - pulse > self.beat_duration() {
+                now + self.duration(1)
+            } else {
+                next_pulse
+            });
+        }
+
+        pulses
+    }
+
+    /// Measures the tempo of an incoming clock pulse. Ignored as master.
+    pub fn pulse(&mut self, now: Instant) {
+        if !self.slave {
+            return;
+        }
+
+        if let Some(last_pulse) = self
+            .last_pulse
+            .filter(|last| now - *last < MAX_PULSE_INTERVAL)
+        {
+            let interval = (now - last_pulse).as_secs_f64();
+            self.pulse_interval = Some(match self.pulse_interval {
+                Some(smoothed) if (interval - smoothed).abs() < TEMPO_CHANGE * smoothed => {
+                    smoothed + PULSE_SMOOTHING * (interval - smoothed)
+                }
+                _ => interval,
+            });
+        }
+        self.last_pulse = Some(now);
+    }
+
+    /// Registers a tap of the tap tempo button. Returns the new tempo once two taps are close
+    /// enough, averaged over the last taps. Ignored as slave.
+    pub fn tap(&mut self, now: Instant) -> Option<f32> {
+        if self.slave {
+            return None;
+        }
+
+        if self
+            .taps
+            .back()
+            .is_some_and(|last| now - *last > TAP_TIMEOUT)
+        {
+            self.taps.clear();
+        }
+        if self.taps.len() == MAX_TAPS {
+            self.taps.pop_front();
+        }
+        self.taps.push_back(now);
+
+        let first = *self.taps.front()?;
+        let intervals = self.taps.len() as u32 - 1;
+        if intervals == 0 {
+            return None;
+        }
+
+        let beat = (now - first) / intervals;
+        self.tempo = (60.0 / beat.as_secs_f64()) as f32;
+        Some(self.tempo)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn master_generates_pulses() {
+        let start = Instant::now();
+        let pulse = Duration::from_millis(20);
+        let mut clock = Clock::new();
+        clock.set_tempo(125.0);
+        assert_eq!(Duration::from_millis(480), clock.beat_duration());
+
+        assert!(clock.run(start + pulse).is_empty());
+        assert_eq!(Some(Start::create()), clock.start(start));
+        assert_eq!(
+            vec![start, start + pulse, start + pulse * 2],
+            clock.run(start + pulse * 2)
+        );
+        assert_eq!(Some(start + pulse * 3), clock.next_event());
+
+        assert_eq!(Some(Stop::create()), clock.stop());
+        assert_eq!(None, clock.stop());
+        assert!(clock.run(start + pulse * 10).is_empty());
+    }
+
+    #[test]
+    fn overdue_pulses_are_skipped() {
+        let start = Instant::now();
+        let mut clock = Clock::new();
+        clock.start(start);
+
+        let late = start + Duration::from_secs(1);
+        assert_eq!(vec![start], clock.run(late));
+        assert_eq!(Some(late + clock.duration(1)), clock.next_event());
+    }
+
+    #[test]
+    fn tap_tempo() {
+        let start = Instant::now();
+        let mut clock = Clock::new();
+
+        assert_eq!(None, clock.tap(start));
+        assert_eq!(Some(150.0), clock.tap(start + Duration::from_millis(400)));
+        assert_eq!(Some(120.0), clock.tap(start + Duration::from_millis(1_000)));
+        assert_eq!(120.0, clock.tempo());
+
+        // A long pause starts a new measurement
+        assert_eq!(None, clock.tap(start + Duration::from_secs(5)));
+        assert_eq!(120.0, clock.tempo());
+    }
+
+    #[test]
+    fn tapped_tempo_sets_pulse_interval() {
+        let start = Instant::now();
+        let mut clock = Clock::new();
+        clock.tap(start);
+        clock.tap(start + Duration::from_millis(400));
+        assert_eq!(Duration::from_millis(400), clock.beat_duration());
+
+        let beat = start + Duration::from_secs(1);
+        clock.start(beat);
+        let pulses = clock.run(beat + Duration::from_millis(399));
+        assert_eq!(24, pulses.len());
+        for pair in pulses.windows(2) {
+            assert_eq!(Duration::from_nanos(16_666_667), pair[1] - pair[0]);
+        }
+    }
+
+    #[test]
+    fn slave_smooths_jitter() {
+        let start = Instant::now();
+        let mut clock = Clock::new();
+        clock.set_slave(true);
+        assert_eq!(None, clock.start(start));
+
+        // Pulses at 100 BPM (25 ms), alternately 2 ms early and late
+        let mut time = start;
+        for n in 0..48 {
+            clock.pulse(time);
+            time += Duration::from_millis(if n % 2 == 0 { 23 } else { 27 });
+        }
+        assert!((clock.tempo() - 100.0).abs() < 1.0, "{}", clock.tempo());
+
+        // Follows a change of tempo immediately
+        time += Duration::from_millis(13);
+        clock.pulse(time);
+        clock.pulse(time + Duration::from_millis(40));
+        assert!((clock.tempo() - 62.5).abs() < 0.1, "{}", clock.tempo());
+
+        clock.set_slave(false);
+        assert_eq!(120.0, clock.tempo());
+    }
+}
@@ -1,10 +1,15 @@
 use std::cell::Cell;
 
 use synth::sample_stream::SampleStream;
+use synth::smoothed_value::SmoothedValue;
 
 pub struct LoudnessContour<T: SampleStream> {
     input: T,
     on: Cell<bool>,
+    /// Gain applied to the input while the contour is on
+    level: Cell<f32>,
+    /// Gain applied by a modulation source (e.g. tremolo)
+    modulation: SmoothedValue,
 }
 
 impl<T: SampleStream> LoudnessContour<T> {
@@ -12,6 +17,8 @@ impl<T: SampleStream> LoudnessContour<T> {
         Self {
             input,
             on: Cell::new(false),
+            level: Cell::new(1.0),
+            modulation: SmoothedValue::new(1.0),
         }
     }
 
@@ -22,16 +29,67 @@ impl<T: SampleStream> LoudnessContour<T> {
     pub fn trigger_off(&self) {
         self.on.set(false);
     }
+
+    /// Gate of the contour as a modulation source (0.0 or 1.0), without level and modulation.
+    pub fn output(&self) -> f32 {
+        if self.on.get() {
+            1.0
+        } else {
+            0.0
+        }
+    }
+
+    pub fn set_level(&self, level: f32) {
+        self.level.set(level);
+    }
+
+    /// Sets the gain of the modulation, which is reached after the ramp length.
+    pub fn set_modulation(&self, gain: f32) {
+        if gain != self.modulation.target() {
+            self.modulation.set_target(gain);
+        }
+    }
+
+    /// Sets the number of samples over which a change of the modulation is spread.
+    pub fn set_modulation_ramp_length(&self, samples: usize) {
+        self.modulation.set_ramp_length(samples);
+    }
 }
 
 impl<T: SampleStream> SampleStream for LoudnessContour<T> {
     fn next_sample(&self) -> f32 {
+        let modulation = self.modulation.next_value();
+
         if self.on.get() {
-            self.input.next_sample()
+            self.input.next_sample() * self.level.get() * modulation
         } else {
             0.0
         }
     }
+
+    fn fill_buffer(&self, buffer: &mut [f32]) {
+        if self.on.get() {
+            self.input.fill_buffer(buffer);
+
+            let level = self.level.get();
+            if self.modulation.is_smoothing() {
+                for sample in buffer.iter_mut() {
+                    *sample *= level * self.modulation.next_value();
+                }
+            } else if level * self.modulation.value() != 1.0 {
+                let gain = level * self.modulation.value();
+                for sample in buffer.iter_mut() {
+                    *sample *= gain;
+                }
+            }
+        } else {
+            self.modulation.skip(buffer.len());
+
+            for sample in buffer.iter_mut() {
+                *sample = 0.0;
+            }
+        }
+    }
 }
 
 #[cfg(test)]
@@ -102,4 +160,60 @@ mod tests {
         assert_float_eq!(0.0, contour.next().unwrap(), 1e-6);
         assert_float_eq!(0.0, contour.next().unwrap(), 1e-6);
     }
+
+    #[test]
+    fn fill_buffer() {
+        let osc = Rc::new(Oscillator::new(1.0, 0.0375));
+        let ref_osc = Oscillator::new(1.0, 0.0375);
+        let contour = LoudnessContour::new(osc);
+
+        let mut buffer = [1.0; 10];
+        contour.fill_buffer(&mut buffer);
+        for sample in buffer.iter() {
+            assert_float_eq!(0.0, *sample, 1e-6);
+        }
+
+        contour.trigger_on();
+        contour.fill_buffer(&mut buffer);
+
+        println!();
+        for (with_contour, reference) in buffer.iter().zip(ref_osc) {
+            println!("Contour output: {}, Reference: {}", with_contour, reference);
+            assert_float_eq!(reference, *with_contour, 1e-6);
+        }
+    }
+
+    #[test]
+    fn level_scales_output() {
+        let osc = Rc::new(Oscillator::new(1.0, 0.0375));
+        let ref_osc = Oscillator::new(1.0, 0.0375);
+        let contour = LoudnessContour::new(osc);
+
+        contour.set_level(2.0);
+        contour.trigger_on();
+
+        let mut buffer = [0.0; 10];
+        contour.fill_buffer(&mut buffer);
+        for (with_contour, reference) in buffer.iter().zip(ref_osc) {
+            assert_float_eq!(2.0 * reference, *with_contour, 1e-6);
+        }
+    }
+
+    #[test]
+    fn modulation_is_ramped() {
+        let osc = Rc::new(Oscillator::new(1.0, 0.0375));
+        let ref_osc = Oscillator::new(1.0, 0.0375);
+        let contour = LoudnessContour::new(osc);
+
+        contour.set_modulation_ramp_length(4);
+        contour.set_modulation(0.5);
+        contour.trigger_on();
+
+        let mut buffer = [0.0; 8];
+        contour.fill_buffer(&mut buffer);
+        for (i, (with_contour, reference)) in buffer.iter().zip(ref_osc).enumerate() {
+            let gain = 1.0 - 0.125 * (i + 1).min(4) as f32;
+            assert_float_eq!(gain * reference, *with_contour, 1e-6);
+        }
+    }
 }
@@ -0,0 +1,712 @@
+use std::cell::Cell;
+use std::sync::mpsc::Sender;
+
+use apc40_protocol::{
+    Apc40Output, Button, ButtonLed, Color, Input, Knob, LedMode, RingStyle, CLIP_COLUMNS, CLIP_ROWS,
+};
+use midi_controller::MidiControllerType;
+use synth::control_surface::{BankState, ControlSurface, StepRows, SurfaceEvent};
+use synth::mapping::Control;
+use synth::parameter::TuneMode;
+use synth::sequencer::{Step, StepField};
+use usb_midi::MidiMessage;
+
+use errors::Result;
+
+const COLOR_UNSELECTED: Color = Color(38);
+const COLOR_SELECTED: Color = Color(124);
+
+const LEARN_BUTTON: Button = Button::DetailView;
+const SAVE_PATCH_BUTTON: Button = Button::Record;
+const LOAD_PATCH_BUTTON: Button = Button::Play;
+const SEQUENCER_VIEW_BUTTON: Button = Button::Session;
+const SEQUENCER_BUTTON: Button = Button::Metronome;
+/// Lit while the master tune is in coarse mode
+const COARSE_TUNE_BUTTON: Button = Button::User;
+/// Shift the keys down or up, lit while they are shifted in that direction
+const OCTAVE_DOWN_BUTTON: Button = Button::DeviceLeft;
+const OCTAVE_UP_BUTTON: Button = Button::DeviceRight;
+const TRANSPOSE_DOWN_BUTTON: Button = Button::BankLeft;
+const TRANSPOSE_UP_BUTTON: Button = Button::BankRight;
+
+/// Banks are selected with the clip grid, except for the first two columns which show the
+/// waveforms. The top row holds the first banks.
+const BANK_GRID_FIRST_COLUMN: u8 = 2;
+const BANK_GRID_COLUMNS: u8 = CLIP_COLUMNS - BANK_GRID_FIRST_COLUMN;
+const COLOR_BANK_EMPTY: Color = Color::BLACK;
+const COLOR_BANK_USED: Color = Color::DARK_GREY;
+const COLOR_BANK_SELECTED: Color = Color::GREEN;
+
+/// In the sequencer view, the rows of the clip grid are either pages of steps (the top row holds
+/// the first steps) or notes (with the columns being the steps of the current page).
+const STEP_ROWS_BUTTON: Button = Button::Bank;
+const PAGE_LEFT_BUTTON: Button = Button::BankSelectLeft;
+const PAGE_RIGHT_BUTTON: Button = Button::BankSelectRight;
+const NOTES_UP_BUTTON: Button = Button::BankSelectUp;
+const NOTES_DOWN_BUTTON: Button = Button::BankSelectDown;
+const COLOR_GATE: Color = Color::GREEN;
+const COLOR_ACCENT: Color = Color::ORANGE;
+const COLOR_PLAYHEAD: Color = Color::WHITE;
+/// Steps without a note in the shown rows
+const COLOR_PLAYHEAD_COLUMN: Color = Color::DARK_GREY;
+
+/// While a step is held, the scene launch buttons toggle its accent and slide and select its
+/// length (from two steps on, the length is reset to one step by selecting it again).
+const ACCENT_SCENE: u8 = 0;
+const SLIDE_SCENE: u8 = 1;
+const FIRST_LENGTH_SCENE: u8 = 2;
+
+/// Akai APC40 MkII, used as control panel.
+pub struct Apc40 {
+    output: Apc40Output,
+    /// Whether the clip grid shows the sequencer instead of the banks
+    sequencer_view: Cell<bool>,
+    step_rows: Cell<StepRows>,
+}
+
+impl Apc40 {
+    pub fn new(controls_tx: Sender<MidiMessage>) -> Self {
+        Self {
+            output: Apc40Output::new(controls_tx),
+            sequencer_view: Cell::new(false),
+            step_rows: Cell::new(StepRows::Pages),
+        }
+    }
+
+    fn show_waveforms(&self) -> Result<()> {
+        // Set LEDs of unselected waveforms to unselected (except first one)
+        for row in 1..CLIP_ROWS {
+            self.output
+                .set_clip(row, 0, COLOR_UNSELECTED, LedMode::Solid)?;
+        }
+        self.output.set_clip(0, 0, COLOR_SELECTED, LedMode::Solid)?;
+        self.output
+            .set_clip(0, 1, COLOR_UNSELECTED, LedMode::Solid)?;
+
+        Ok(())
+    }
+
+    /// Returns true if the LED of a button is used by the sequencer view.
+    fn shows_sequencer(&self, button: Button) -> bool {
+        self.sequencer_view.get() && is_sequencer_button(button)
+    }
+
+    /// Returns the event of a button in the sequencer view.
+    fn sequencer_event(&self, button: Button) -> Option<SurfaceEvent> {
+        let event = match (button, self.step_rows.get()) {
+            (Button::Clip { row, column }, StepRows::Pages) => {
+                SurfaceEvent::EditStep(row * CLIP_COLUMNS + column, StepField::Gate)
+            }
+            (Button::Clip { row, column }, StepRows::Notes { lowest }) => {
+                let note = lowest + (CLIP_ROWS - 1 - row);
+                if note > 127 {
+                    return None;
+                }
+                SurfaceEvent::ToggleStepNote(column, note)
+            }
+            (Button::SceneLaunch(ACCENT_SCENE), _) => SurfaceEvent::EditHeldStep(StepField::Accent),
+            (Button::SceneLaunch(SLIDE_SCENE), _) => SurfaceEvent::EditHeldStep(StepField::Slide),
+            (Button::SceneLaunch(scene), _) => {
+                SurfaceEvent::EditHeldStep(StepField::Length(scene - FIRST_LENGTH_SCENE + 2))
+            }
+            (STEP_ROWS_BUTTON, _) => SurfaceEvent::ToggleStepRows,
+            (PAGE_LEFT_BUTTON, _) => SurfaceEvent::ScrollPage(-1),
+            (PAGE_RIGHT_BUTTON, _) => SurfaceEvent::ScrollPage(1),
+            (NOTES_UP_BUTTON, _) => SurfaceEvent::ScrollNotes(CLIP_ROWS as i8),
+            (NOTES_DOWN_BUTTON, _) => SurfaceEvent::ScrollNotes(-(CLIP_ROWS as i8)),
+            _ => return None,
+        };
+
+        Some(event)
+    }
+
+    /// Returns the event of a button released in the sequencer view.
+    fn sequencer_release_event(&self, button: Button) -> Option<SurfaceEvent> {
+        match (button, self.step_rows.get()) {
+            (Button::Clip { row, column }, StepRows::Pages) => {
+                Some(SurfaceEvent::ReleaseStep(row * CLIP_COLUMNS + column))
+            }
+            (Button::Clip { column, .. }, StepRows::Notes { .. }) => {
+                Some(SurfaceEvent::ReleaseStep(column))
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns the row and the color of the pad of a step in the notes layout.
+    fn note_pad(&self, step: &Step, playhead: bool) -> (Option<u8>, Color) {
+        let row = match self.step_rows.get() {
+            StepRows::Notes { lowest } if step.gate && step.note >= lowest => {
+                (CLIP_ROWS - 1).checked_sub(step.note - lowest)
+            }
+            _ => None,
+        };
+        let color = if playhead {
+            COLOR_PLAYHEAD
+        } else if step.accent {
+            COLOR_ACCENT
+        } else {
+            COLOR_GATE
+        };
+
+        (row, color)
+    }
+}
+
+/// Returns true if a button has a different meaning in the sequencer view.
+fn is_sequencer_button(button: Button) -> bool {
+    match button {
+        Button::Clip { .. } | Button::SceneLaunch(_) => true,
+        _ => [
+            STEP_ROWS_BUTTON,
+            PAGE_LEFT_BUTTON,
+            PAGE_RIGHT_BUTTON,
+            NOTES_UP_BUTTON,
+            NOTES_DOWN_BUTTON,
+        ]
+        .contains(&button),
+    }
+}
+
+/// Returns the knob of a control, if it has an LED ring.
+fn knob(control: Control) -> Option<Knob> {
+    match control {
+        Control::Knob {
+            source: MidiControllerType::ControlPanel,
+            channel,
+            control_number,
+        } => Knob::from_control(channel.unwrap_or(0), control_number),
+        _ => None,
+    }
+}
+
+/// Returns the button of a control, if it has an LED.
+fn button(control: Control) -> Option<Button> {
+    match control {
+        Control::Button {
+            source: MidiControllerType::ControlPanel,
+            channel,
+            note_number,
+        } => Button::from_note(channel, note_number),
+        _ => None,
+    }
+}
+
+impl ControlSurface for Apc40 {
+    fn event(&self, message: &MidiMessage) -> Option<SurfaceEvent> {
+        match Input::parse(message)? {
+            Input::ButtonPressed(LEARN_BUTTON) => return Some(SurfaceEvent::ToggleLearnMode),
+            Input::ButtonPressed(SAVE_PATCH_BUTTON) => return Some(SurfaceEvent::SavePatch),
+            Input::ButtonPressed(LOAD_PATCH_BUTTON) => return Some(SurfaceEvent::LoadPatch),
+            Input::ButtonPressed(SEQUENCER_VIEW_BUTTON) => {
+                return Some(SurfaceEvent::ToggleSequencerView)
+            }
+            Input::ButtonPressed(SEQUENCER_BUTTON) => return Some(SurfaceEvent::ToggleSequencer),
+            Input::ButtonPressed(Button::TapTempo) => return Some(SurfaceEvent::TapTempo),
+            Input::ButtonPressed(COARSE_TUNE_BUTTON) => return Some(SurfaceEvent::ToggleTuneMode),
+            Input::ButtonPressed(OCTAVE_DOWN_BUTTON) => return Some(SurfaceEvent::ShiftOctave(-1)),
+            Input::ButtonPressed(OCTAVE_UP_BUTTON) => return Some(SurfaceEvent::ShiftOctave(1)),
+            Input::ButtonPressed(TRANSPOSE_DOWN_BUTTON) => {
+                return Some(SurfaceEvent::Transpose(-1))
+            }
+            Input::ButtonPressed(TRANSPOSE_UP_BUTTON) => return Some(SurfaceEvent::Transpose(1)),
+            Input::ButtonPressed(button) if self.shows_sequencer(button) => {
+                return self.sequencer_event(button)
+            }
+            Input::ButtonReleased(button) if self.shows_sequencer(button) => {
+                return self.sequencer_release_event(button)
+            }
+            Input::ButtonPressed(Button::Clip { row, column }) => {
+                if let Some(bank) = grid_bank(row, column) {
+                    return Some(SurfaceEvent::SelectBank(bank));
+                }
+            }
+            // Relative encoders cannot be bound to parameters
+            Input::TempoTurned(_) | Input::CueLevelTurned(_) => return None,
+            _ => {}
+        }
+
+        // All other knobs, faders and buttons can be bound to parameters
+        match *message {
+            MidiMessage::ControlChange(ref control_change) => Some(SurfaceEvent::KnobTurned(
+                Control::Knob {
+                    source: MidiControllerType::ControlPanel,
+                    channel: Some(control_change.channel()),
+                    control_number: control_change.control_number(),
+                },
+                control_change.control_value(),
+            )),
+            MidiMessage::NoteOn(ref note_on) => {
+                Some(SurfaceEvent::ButtonPressed(Control::Button {
+                    source: MidiControllerType::ControlPanel,
+                    channel: note_on.channel(),
+                    note_number: note_on.note_number(),
+                }))
+            }
+            _ => None,
+        }
+    }
+
+    fn initialize(&self) -> Result<()> {
+        self.sequencer_view.set(false);
+        self.output.clear_clips()?;
+        self.show_waveforms()?;
+        self.output
+            .set_button(SEQUENCER_VIEW_BUTTON, ButtonLed::Off)?;
+        self.output.set_button(SEQUENCER_BUTTON, ButtonLed::Off)?;
+
+        Ok(())
+    }
+
+    fn show_value(&self, control: Control, value: u8) -> Result<()> {
+        if let Some(knob) = knob(control) {
+            self.output.set_knob(knob, value)?;
+        } else if let Some(button) = button(control).filter(|b| !self.shows_sequencer(*b)) {
+            let led = if value >= 64 {
+                ButtonLed::On
+            } else {
+                ButtonLed::Off
+            };
+            self.output.set_button(button, led)?;
+        }
+
+        Ok(())
+    }
+
+    fn show_takeover(&self, control: Control, in_sync: bool) -> Result<()> {
+        if let Some(knob) = knob(control) {
+            let style = if in_sync {
+                RingStyle::Single
+            } else {
+                RingStyle::Volume
+            };
+            self.output.set_ring_style(knob, style)?;
+        }
+
+        Ok(())
+    }
+
+    fn clear(&self, control: Control) -> Result<()> {
+        if let Some(knob) = knob(control) {
+            self.output.set_ring_style(knob, RingStyle::Off)?;
+        } else if let Some(button) = button(control).filter(|b| !self.shows_sequencer(*b)) {
+            self.output.set_button(button, ButtonLed::Off)?;
+        }
+
+        Ok(())
+    }
+
+    fn show_learn_mode(&self, enabled: bool) -> Result<()> {
+        let led = if enabled {
+            ButtonLed::On
+        } else {
+            ButtonLed::Off
+        };
+        self.output.set_button(LEARN_BUTTON, led)
+    }
+
+    fn show_tune_mode(&self, mode: TuneMode) -> Result<()> {
+        let led = match mode {
+            TuneMode::Coarse => ButtonLed::On,
+            TuneMode::Fine => ButtonLed::Off,
+        };
+        self.output.set_button(COARSE_TUNE_BUTTON, led)
+    }
+
+    fn show_transposition(&self, octaves: i8, semitones: i8) -> Result<()> {
+        let led = |on: bool| if on { ButtonLed::On } else { ButtonLed::Off };
+        self.output
+            .set_button(OCTAVE_DOWN_BUTTON, led(octaves < 0))?;
+        self.output.set_button(OCTAVE_UP_BUTTON, led(octaves > 0))?;
+        self.output
+            .set_button(TRANSPOSE_DOWN_BUTTON, led(semitones < 0))?;
+        self.output
+            .set_button(TRANSPOSE_UP_BUTTON, led(semitones > 0))
+    }
+
+    fn bank_count(&self) -> u16 {
+        u16::from(CLIP_ROWS * BANK_GRID_COLUMNS)
+    }
+
+    fn show_bank(&self, bank: u16, state: BankState) -> Result<()> {
+        let color = match state {
+            BankState::Empty => COLOR_BANK_EMPTY,
+            BankState::Used => COLOR_BANK_USED,
+            BankState::Selected => COLOR_BANK_SELECTED,
+        };
+
+        if let Some((row, column)) = bank_grid_position(bank) {
+            if !self.sequencer_view.get() {
+                self.output.set_clip(row, column, color, LedMode::Solid)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn steps_per_page(&self) -> u8 {
+        match self.step_rows.get() {
+            StepRows::Pages => CLIP_ROWS * CLIP_COLUMNS,
+            StepRows::Notes { .. } => CLIP_COLUMNS,
+        }
+    }
+
+    fn show_step_rows(&self, rows: StepRows) -> Result<()> {
+        self.step_rows.set(rows);
+        if !self.sequencer_view.get() {
+            return Ok(());
+        }
+
+        self.output.clear_clips()?;
+        let led = match rows {
+            StepRows::Pages => ButtonLed::Off,
+            StepRows::Notes { .. } => ButtonLed::On,
+        };
+        self.output.set_button(STEP_ROWS_BUTTON, led)
+    }
+
+    fn show_sequencer_view(&self, shown: bool) -> Result<()> {
+        self.sequencer_view.set(shown);
+        self.output.clear_clips()?;
+        if shown {
+            self.show_step_rows(self.step_rows.get())?;
+        } else {
+            // The buttons are shown again if they are bound to parameters
+            for scene in 0..CLIP_ROWS {
+                self.output
+                    .set_button(Button::SceneLaunch(scene), ButtonLed::Off)?;
+            }
+            for &button in &[
+                STEP_ROWS_BUTTON,
+                PAGE_LEFT_BUTTON,
+                PAGE_RIGHT_BUTTON,
+                NOTES_UP_BUTTON,
+                NOTES_DOWN_BUTTON,
+            ] {
+                self.output.set_button(button, ButtonLed::Off)?;
+            }
+            self.show_waveforms()?;
+        }
+
+        let led = if shown { ButtonLed::On } else { ButtonLed::Off };
+        self.output.set_button(SEQUENCER_VIEW_BUTTON, led)
+    }
+
+    fn show_step(&self, step_number: u8, step: &Step, playhead: bool) -> Result<()> {
+        if !self.sequencer_view.get() || step_number >= self.steps_per_page() {
+            return Ok(());
+        }
+
+        if self.step_rows.get() == StepRows::Pages {
+            let color = if playhead {
+                COLOR_PLAYHEAD
+            } else if !step.gate {
+                Color::BLACK
+            } else if step.accent {
+                COLOR_ACCENT
+            } else {
+                COLOR_GATE
+            };
+            return self.output.set_clip(
+                step_number / CLIP_COLUMNS,
+                step_number % CLIP_COLUMNS,
+                color,
+                LedMode::Solid,
+            );
+        }
+
+        let (note_row, note_color) = self.note_pad(step, playhead);
+        for row in 0..CLIP_ROWS {
+            let color = if Some(row) == note_row {
+                note_color
+            } else if playhead {
+                COLOR_PLAYHEAD_COLUMN
+            } else {
+                Color::BLACK
+            };
+            self.output
+                .set_clip(row, step_number, color, LedMode::Solid)?;
+        }
+
+        Ok(())
+    }
+
+    fn show_page(&self, page: u8, page_count: u8) -> Result<()> {
+        if !self.sequencer_view.get() {
+            return Ok(());
+        }
+
+        let led = |on: bool| if on { ButtonLed::On } else { ButtonLed::Off };
+        self.output.set_button(PAGE_LEFT_BUTTON, led(page > 0))?;
+        self.output
+            .set_button(PAGE_RIGHT_BUTTON, led(page + 1 < page_count))
+    }
+
+    fn show_held_step(&self, step: Option<&Step>) -> Result<()> {
+        if !self.sequencer_view.get() {
+            return Ok(());
+        }
+
+        for scene in 0..CLIP_ROWS {
+            let on = match (scene, step) {
+                (_, None) => false,
+                (ACCENT_SCENE, Some(step)) => step.accent,
+                (SLIDE_SCENE, Some(step)) => step.slide,
+                (scene, Some(step)) => step.length == scene - FIRST_LENGTH_SCENE + 2,
+            };
+            let led = if on { ButtonLed::On } else { ButtonLed::Off };
+            self.output.set_button(Button::SceneLaunch(scene), led)?;
+        }
+
+        Ok(())
+    }
+
+    fn show_sequencer_running(&self, running: bool) -> Result<()> {
+        let led = if running {
+            ButtonLed::On
+        } else {
+            ButtonLed::Off
+        };
+        self.output.set_button(SEQUENCER_BUTTON, led)
+    }
+
+    fn send_clock(&self, message: MidiMessage) -> Result<()> {
+        self.output.send_clock(message)
+    }
+}
+
+/// Returns the bank that is selected by a button of the clip grid.
+fn grid_bank(row: u8, column: u8) -> Option<u16> {
+    if row >= CLIP_ROWS || !(BANK_GRID_FIRST_COLUMN..CLIP_COLUMNS).contains(&column) {
+        return None;
+    }
+
+    Some(u16::from(
+        row * BANK_GRID_COLUMNS + column - BANK_GRID_FIRST_COLUMN,
+    ))
+}
+
+/// Returns the row and column of the button of the clip grid that selects a bank, if any.
+fn bank_grid_position(bank: u16) -> Option<(u8, u8)> {
+    if bank >= u16::from(CLIP_ROWS * BANK_GRID_COLUMNS) {
+        return None;
+    }
+
+    let bank = bank as u8;
+    Some((
+        bank / BANK_GRID_COLUMNS,
+        BANK_GRID_FIRST_COLUMN + bank % BANK_GRID_COLUMNS,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::mpsc;
+
+    use usb_midi::{ControlChange, NoteOff, NoteOn};
+
+    #[test]
+    fn bank_grid_layout() {
+        assert_eq!(Some(0), grid_bank(0, 2));
+        assert_eq!(Some(29), grid_bank(4, 7));
+        assert_eq!(None, grid_bank(0, 0));
+        assert_eq!(None, grid_bank(0, 1));
+        assert_eq!(None, grid_bank(5, 2));
+
+        for bank in 0..30 {
+            let (row, column) = bank_grid_position(bank).unwrap();
+            assert_eq!(Some(bank), grid_bank(row, column));
+        }
+        assert_eq!(None, bank_grid_position(30));
+    }
+
+    #[test]
+    fn events() {
+        let (tx, _rx) = mpsc::channel();
+        let apc40 = Apc40::new(tx);
+
+        assert_eq!(
+            Some(SurfaceEvent::KnobTurned(
+                Control::Knob {
+                    source: MidiControllerType::ControlPanel,
+                    channel: Some(2),
+                    control_number: 0x10,
+                },
+                96
+            )),
+            apc40.event(&ControlChange::create(2, 0x10, 96))
+        );
+        assert_eq!(
+            Some(SurfaceEvent::ButtonPressed(Control::Button {
+                source: MidiControllerType::ControlPanel,
+                channel: 0,
+                note_number: 0x33,
+            })),
+            apc40.event(&NoteOn::create(0, 0x33, 0x7F))
+        );
+        assert_eq!(
+            Some(SurfaceEvent::ToggleLearnMode),
+            apc40.event(&NoteOn::create(0, 0x41, 0x7F))
+        );
+        assert_eq!(
+            Some(SurfaceEvent::ToggleTuneMode),
+            apc40.event(&NoteOn::create(0, 0x59, 0x7F))
+        );
+        assert_eq!(
+            Some(SurfaceEvent::ShiftOctave(-1)),
+            apc40.event(&NoteOn::create(0, 0x3A, 0x7F))
+        );
+        assert_eq!(
+            Some(SurfaceEvent::Transpose(1)),
+            apc40.event(&NoteOn::create(0, 0x3D, 0x7F))
+        );
+        assert_eq!(
+            Some(SurfaceEvent::SelectBank(1)),
+            apc40.event(&NoteOn::create(0, 35, 0x7F))
+        );
+        assert_eq!(None, apc40.event(&NoteOff::create(0, 35, 0x7F)));
+    }
+
+    #[test]
+    fn sequencer_view_events() {
+        let (tx, rx) = mpsc::channel();
+        let apc40 = Apc40::new(tx);
+
+        assert_eq!(
+            Some(SurfaceEvent::ToggleSequencerView),
+            apc40.event(&NoteOn::create(0, 0x66, 0x7F))
+        );
+        assert_eq!(
+            Some(SurfaceEvent::ToggleSequencer),
+            apc40.event(&NoteOn::create(0, 0x5A, 0x7F))
+        );
+
+        apc40.show_sequencer_view(true).unwrap();
+        while rx.try_recv().is_ok() {}
+
+        // Each row is a page, the top row holds the first steps
+        assert_eq!(40, apc40.steps_per_page());
+        assert_eq!(
+            Some(SurfaceEvent::EditStep(3, StepField::Gate)),
+            apc40.event(&NoteOn::create(0, 35, 0x7F))
+        );
+        assert_eq!(
+            Some(SurfaceEvent::ReleaseStep(3)),
+            apc40.event(&NoteOff::create(0, 35, 0x7F))
+        );
+        assert_eq!(
+            Some(SurfaceEvent::EditStep(11, StepField::Gate)),
+            apc40.event(&NoteOn::create(0, 27, 0x7F))
+        );
+
+        // Scene launch buttons edit the held step
+        assert_eq!(
+            Some(SurfaceEvent::EditHeldStep(StepField::Accent)),
+            apc40.event(&NoteOn::create(0, 0x52, 0x7F))
+        );
+        assert_eq!(
+            Some(SurfaceEvent::EditHeldStep(StepField::Length(4))),
+            apc40.event(&NoteOn::create(0, 0x56, 0x7F))
+        );
+        assert_eq!(None, apc40.event(&NoteOff::create(0, 0x56, 0x7F)));
+
+        // Banks are hidden
+        apc40.show_bank(0, BankState::Selected).unwrap();
+        assert!(rx.try_recv().is_err());
+
+        let step = Step {
+            gate: true,
+            accent: true,
+            ..Step::default()
+        };
+        apc40.show_step(9, &step, false).unwrap();
+        assert_eq!(Ok(NoteOn::create(0, 25, COLOR_ACCENT.0)), rx.try_recv());
+
+        // In the notes layout, each row is a note and the columns are the steps of the page
+        assert_eq!(
+            Some(SurfaceEvent::ToggleStepRows),
+            apc40.event(&NoteOn::create(0, 0x67, 0x7F))
+        );
+        apc40
+            .show_step_rows(StepRows::Notes { lowest: 60 })
+            .unwrap();
+        while rx.try_recv().is_ok() {}
+
+        assert_eq!(8, apc40.steps_per_page());
+        assert_eq!(
+            Some(SurfaceEvent::ToggleStepNote(3, 64)),
+            apc40.event(&NoteOn::create(0, 35, 0x7F))
+        );
+        assert_eq!(
+            Some(SurfaceEvent::ToggleStepNote(3, 60)),
+            apc40.event(&NoteOn::create(0, 3, 0x7F))
+        );
+        assert_eq!(
+            Some(SurfaceEvent::ReleaseStep(3)),
+            apc40.event(&NoteOff::create(0, 3, 0x7F))
+        );
+        assert_eq!(
+            Some(SurfaceEvent::ScrollPage(1)),
+            apc40.event(&NoteOn::create(0, 0x60, 0x7F))
+        );
+        assert_eq!(
+            Some(SurfaceEvent::ScrollNotes(-5)),
+            apc40.event(&NoteOn::create(0, 0x5F, 0x7F))
+        );
+
+        let step = Step {
+            gate: true,
+            note: 61,
+            ..Step::default()
+        };
+        apc40.show_step(1, &step, true).unwrap();
+        for (row, color) in [
+            (0, COLOR_PLAYHEAD_COLUMN),
+            (1, COLOR_PLAYHEAD_COLUMN),
+            (2, COLOR_PLAYHEAD_COLUMN),
+            (3, COLOR_PLAYHEAD),
+            (4, COLOR_PLAYHEAD_COLUMN),
+        ] {
+            assert_eq!(
+                Ok(NoteOn::create(0, 8 * (4 - row) + 1, color.0)),
+                rx.try_recv()
+            );
+        }
+    }
+
+    #[test]
+    fn feedback_only_for_controls_with_leds() {
+        let (tx, rx) = mpsc::channel();
+        let apc40 = Apc40::new(tx);
+
+        let fader = Control::Knob {
+            source: MidiControllerType::ControlPanel,
+            channel: Some(0),
+            control_number: 0x07,
+        };
+        let knob = Control::Knob {
+            source: MidiControllerType::ControlPanel,
+            channel: None,
+            control_number: 0x31,
+        };
+
+        apc40.show_value(fader, 100).unwrap();
+        apc40.show_takeover(fader, false).unwrap();
+        assert!(rx.try_recv().is_err());
+
+        apc40.show_value(knob, 100).unwrap();
+        apc40.show_takeover(knob, false).unwrap();
+        apc40.clear(knob).unwrap();
+        assert_eq!(Ok(ControlChange::create(0, 0x31, 100)), rx.try_recv());
+        assert_eq!(
+            Ok(ControlChange::create(0, 0x39, RingStyle::Volume as u8)),
+            rx.try_recv()
+        );
+        assert_eq!(
+            Ok(ControlChange::create(0, 0x39, RingStyle::Off as u8)),
+            rx.try_recv()
+        );
+    }
+}
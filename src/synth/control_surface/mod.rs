@@ -0,0 +1,121 @@
+pub mod apc40;
+
+use synth::mapping::Control;
+use synth::parameter::TuneMode;
+use synth::sequencer::{Step, StepField};
+use usb_midi::MidiMessage;
+
+use errors::Result;
+
+/// Event of a control surface, in terms of what it means to the synthesizer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SurfaceEvent {
+    /// Knob or fader moved to a position (0 to 127)
+    KnobTurned(Control, u8),
+    /// Button that can be bound to a parameter was pressed
+    ButtonPressed(Control),
+    ToggleLearnMode,
+    SavePatch,
+    LoadPatch,
+    SelectBank(u16),
+    /// Switches between showing the patch banks and the steps of the sequencer
+    ToggleSequencerView,
+    /// Starts or stops the sequencer
+    ToggleSequencer,
+    /// Step of the current page pressed in the sequencer view
+    EditStep(u8, StepField),
+    /// Note of a step of the current page pressed in the sequencer view
+    ToggleStepNote(u8, u8),
+    /// Step of the current page released in the sequencer view
+    ReleaseStep(u8),
+    /// Edits the step that is held
+    EditHeldStep(StepField),
+    /// Switches the rows of the sequencer view between pages and notes
+    ToggleStepRows,
+    /// Moves the page of steps by a number of pages
+    ScrollPage(i8),
+    /// Moves the notes shown by the rows by a number of semitones
+    ScrollNotes(i8),
+    TapTempo,
+    /// Switches the control of the master tune between coarse and fine tuning
+    ToggleTuneMode,
+    /// Shifts the keys by a number of octaves
+    ShiftOctave(i8),
+    /// Transposes the keys by a number of semitones
+    Transpose(i8),
+}
+
+/// What the rows of the sequencer view show.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StepRows {
+    /// Each row is a page of steps
+    Pages,
+    /// Each row is a note, the lowest one is shown at the bottom
+    Notes { lowest: u8 },
+}
+
+/// State of a bank as shown on a control surface.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BankState {
+    Empty,
+    /// Bank contains saved patches
+    Used,
+    Selected,
+}
+
+/// Controller with knobs and buttons (and possibly LEDs) that the synthesizer is played with.
+///
+/// Implementations translate the MIDI messages of the controller into events and show the state
+/// of the synthesizer on it. Feedback for controls that the controller cannot show is ignored.
+pub trait ControlSurface {
+    /// Translates a MIDI message of the controller. Returns `None` if the message has no meaning.
+    fn event(&self, message: &MidiMessage) -> Option<SurfaceEvent>;
+
+    /// Sets all LEDs to their initial state.
+    fn initialize(&self) -> Result<()>;
+
+    /// Shows the position of a control (0 to 127).
+    fn show_value(&self, control: Control, value: u8) -> Result<()>;
+
+    /// Shows whether a knob has taken over the value of its parameter.
+    fn show_takeover(&self, control: Control, in_sync: bool) -> Result<()>;
+
+    /// Turns off the LEDs of a control that is no longer bound to a parameter.
+    fn clear(&self, control: Control) -> Result<()>;
+
+    fn show_learn_mode(&self, enabled: bool) -> Result<()>;
+
+    fn show_tune_mode(&self, mode: TuneMode) -> Result<()>;
+
+    /// Shows the octave shift and transposition of the keys.
+    fn show_transposition(&self, octaves: i8, semitones: i8) -> Result<()>;
+
+    /// Number of banks that can be selected on the controller.
+    fn bank_count(&self) -> u16;
+
+    fn show_bank(&self, bank: u16, state: BankState) -> Result<()>;
+
+    /// Number of steps of the sequencer that are shown at once.
+    fn steps_per_page(&self) -> u8;
+
+    /// Selects what the rows of the sequencer view show. Steps and pages are shown by the
+    /// following calls.
+    fn show_step_rows(&self, rows: StepRows) -> Result<()>;
+
+    /// Shows the steps of the sequencer instead of the banks, or the other way round. Steps,
+    /// pages and banks are shown by the following calls.
+    fn show_sequencer_view(&self, shown: bool) -> Result<()>;
+
+    /// Shows a step of the current page, and whether it is played at the moment.
+    fn show_step(&self, step_number: u8, step: &Step, playhead: bool) -> Result<()>;
+
+    fn show_page(&self, page: u8, page_count: u8) -> Result<()>;
+
+    /// Shows the properties of the step that is held, if any.
+    fn show_held_step(&self, step: Option<&Step>) -> Result<()>;
+
+    fn show_sequencer_running(&self, running: bool) -> Result<()>;
+
+    /// Passes on a message of the MIDI clock (e.g. for LEDs that blink in time).
+    fn send_clock(&self, message: MidiMessage) -> Result<()>;
+}
@@ -1,571 +1,2946 @@
-use std::f32;
-use std::sync::mpsc::{Receiver, Sender};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, TrySendError};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use midi_controller::MidiControllerType;
-use synth::audio_driver::SAMPLE_RATE;
-use usb_midi::{ControlChange, MidiMessage, NoteOn};
-
+use synth::arpeggiator::{Arpeggiator, ArpeggiatorNote, Order};
+use synth::clock::Clock;
+use synth::control_surface::{BankState, ControlSurface, StepRows, SurfaceEvent};
+use synth::lfo::LfoShape;
+use synth::mapping::{Binding, Control, Mappings};
+use synth::modulation::{ModulationDestination, ModulationSource, PressureCurve};
+use synth::mpe::{Mpe, MpeZones, TIMBRE};
+use synth::parameter::{ParameterId, ParameterValues, TuneMode, PARAMETERS, PARAMETER_COUNT};
+use synth::patch::{Patch, PatchBank};
+use synth::sequencer::{Pattern, Sequencer, SequencerNote, StepField, SEQUENCER_STEPS};
+use synth::spsc::Producer;
+use synth::synthesizer::VOICE_GROUPS;
+use synth::takeover::{Takeover, TakeoverState};
+use synth::tuning::{Tuning, TuningBank};
+use synth::zone::Zones;
+use usb_midi::{MidiMessage, TimingClock};
+
+use error_chain::ChainedError;
+
+use errors::ErrorKind::SynthControlChannelDisconnected;
 use errors::Result;
 
-const COLOR_UNSELECTED: u8 = 38;
-const COLOR_SELECTED: u8 = 124;
+/// Identifies a note sent to the synthesizer, from its Note On until it is released
+pub type NoteId = u32;
 
 #[derive(Debug, PartialEq)]
 pub enum SynthControl {
     MasterTune(f32),
-    Oscillator1Range(f32),
-    Oscillator1Enable(bool),
-    Oscillator1Volume(f32),
-    NoteOn(f32),
-    NoteOff(f32),
+    /// Gain of the mix of all voice groups
+    MasterVolume(f32),
+    /// Base frequency of oscillator 1 of a voice group in Hz
+    Oscillator1Range(usize, f32),
+    Oscillator1Enable(usize, bool),
+    Oscillator1Volume(usize, f32),
+    /// Note (frequency ratio to middle C) played by a voice group, with the id that its release
+    /// and expression refer to
+    NoteOn(usize, NoteId, f32),
+    NoteOff(usize, NoteId),
+    /// Releases all playing notes
+    AllNotesOff,
+    /// Plays the following notes of a voice group louder
+    Accent(usize, bool),
+    /// Tempo in BPM, for modulation synced to the clock
+    Tempo(f32),
+    LfoShape(LfoShape),
+    /// Rate of the LFO in Hz, unless synced to the tempo
+    LfoRate(f32),
+    /// Length of an LFO cycle in beats if synced to the tempo
+    LfoBeats(Option<f32>),
+    /// Restarts the LFO cycle with each new note
+    LfoKeySync(bool),
+    /// Time in seconds after a new note before the LFO fades in
+    LfoDelay(f32),
+    /// Time in seconds the LFO takes to fade in
+    LfoFadeIn(f32),
+    /// Source of a slot of the modulation matrix (`None` switches the slot off)
+    ModulationSource(usize, Option<ModulationSource>),
+    ModulationDestination(usize, ModulationDestination),
+    /// Bipolar amount of a slot of the modulation matrix (-1.0 to 1.0)
+    ModulationAmount(usize, f32),
+    /// Velocity of the played note (0.0 to 1.0)
+    Velocity(f32),
+    /// Channel pressure (0.0 to 1.0)
+    Aftertouch(f32),
+    /// Polyphonic pressure (0.0 to 1.0) of a note
+    KeyPressure(NoteId, f32),
+    /// Pitch bend of all notes in semitones
+    PitchBend(f32),
+    /// Pitch bend of a note in semitones
+    NotePitchBend(NoteId, f32),
+    /// Timbre (0.0 to 1.0) of a note
+    NoteTimbre(NoteId, f32),
+    PressureCurve(PressureCurve),
+    /// Position of the modulation wheel (0.0 to 1.0)
+    ModWheel(f32),
 }
 
-#[derive(PartialEq)]
-enum OscillatorRange {
-    Low,
-    Range32ft,
-    Range16ft,
-    Range8ft,
-    Range4ft,
-    Range2ft,
-}
+/// Controller of the modulation wheel, unless it is bound to a parameter
+const MOD_WHEEL: u8 = 0x01;
+
+/// Controllers that select the bank for the next Program Change (MSB and LSB)
+const BANK_SELECT_MSB: u8 = 0x00;
+const BANK_SELECT_LSB: u8 = 0x20;
 
-impl<'a> From<&'a OscillatorRange> for f64 {
-    fn from(range: &OscillatorRange) -> f64 {
-        let a = 440.0;
-        // c'' (one octave above c', i.e. middle C) is 300 cents above a'
-        let middle_c = 0.5 * a * 2.0_f64.powf(3.0 / 12.0);
-        match *range {
-            OscillatorRange::Low => 0.0625 * middle_c,
-            OscillatorRange::Range32ft => 0.25 * middle_c,
-            OscillatorRange::Range16ft => 0.5 * middle_c,
-            OscillatorRange::Range8ft => middle_c,
-            OscillatorRange::Range4ft => 2.0 * middle_c,
-            OscillatorRange::Range2ft => 4.0 * middle_c,
+/// Time for which the tuning program has to stay the same before it is loaded, so that turning
+/// its knob does not read the files of every program on the way
+const TUNING_SETTLE_TIME: Duration = Duration::from_millis(250);
+
+/// Note shown by the bottom row when the rows of the sequencer view are switched to notes
+const LOWEST_STEP_NOTE: u8 = 60;
+
+/// Returns whether a message selects the bank or program of the patch.
+fn selects_program(message: &MidiMessage) -> bool {
+    match *message {
+        MidiMessage::ControlChange(ref control_change) => {
+            let control_number = control_change.control_number();
+            control_number == BANK_SELECT_MSB || control_number == BANK_SELECT_LSB
         }
+        MidiMessage::ProgramChange(_) => true,
+        _ => false,
     }
 }
 
-pub struct Dispatcher {
-    controls_rx: Receiver<(MidiMessage, MidiControllerType)>,
-    controls_tx: Sender<MidiMessage>,
-    synth_ctrl_tx: Sender<SynthControl>,
-    master_tune: u8,
-    osc1_range: OscillatorRange,
-    osc1_enable: bool,
-    osc1_volume: u8,
+#[derive(PartialEq)]
+enum LearnState {
+    Off,
+    /// Waiting for a control to be moved, which arms the parameter it is bound to
+    SelectParameter,
+    /// Waiting for a control to be moved, which is then bound to the parameter
+    Armed(ParameterId),
+}
+
+/// Note played by a held key of the keyboard in one of its zones.
+#[derive(Debug, Clone, Copy)]
+struct HeldKey {
+    channel: u8,
+    key: u8,
+    voice_group: usize,
+    note_number: u8,
+}
+
+pub struct Dispatcher<S: ControlSurface> {
+    controls_rx: Receiver<(MidiMessage, MidiControllerType, Instant)>,
+    surface: S,
+    synth_ctrl_tx: Producer<(SynthControl, Instant)>,
+    parameters: ParameterValues,
+    mappings: Mappings,
+    learn_state: LearnState,
+    /// How the control of the master tune sets it
+    tune_mode: TuneMode,
+    takeover: [TakeoverState; PARAMETER_COUNT],
+    clock: Clock,
+    /// Tempo last sent to the synthesizer
+    synth_tempo: f32,
+    arpeggiator: Arpeggiator,
+    sequencer: Sequencer,
+    /// Whether the control surface shows the steps of the sequencer
+    sequencer_view: bool,
+    /// What the rows of the sequencer view show
+    step_rows: StepRows,
+    /// Page of steps shown on the control surface
+    page: u8,
+    /// Step whose gate is held, the next key that is pressed sets its note
+    held_step: Option<usize>,
+    /// Step shown as played on the control surface
+    shown_playhead: Option<usize>,
+    /// Whether the notes of each voice group are played with accent
+    accent: [bool; VOICE_GROUPS],
+    /// Velocity of the last played key, as sent to the synthesizer
+    velocity: u8,
+    zones: Zones,
+    /// Notes played by the held keys of the keyboard, which are released even if the keys were
+    /// transposed in the meantime
+    played: Vec<HeldKey>,
+    mpe: Mpe,
+    tuning: Tuning,
+    tunings: TuningBank,
+    /// Tuning program that was selected last, and when it is loaded if it stays selected
+    pending_tuning: Option<(u8, Instant)>,
+    /// Notes sent to the voice groups of the synthesizer and their ids, which release them even
+    /// if the tuning changed while they were playing
+    sounding: Vec<(usize, u8, NoteId)>,
+    next_note_id: NoteId,
+    patches: PatchBank,
+    bank: u16,
+    /// Bank selected by Bank Select, which takes effect with the next Program Change
+    next_bank: u16,
+    program: u8,
 }
 
-impl Dispatcher {
+impl<S: ControlSurface> Dispatcher<S> {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
-        controls_rx: Receiver<(MidiMessage, MidiControllerType)>,
-        controls_tx: Sender<MidiMessage>,
-        synth_ctrl_tx: Sender<SynthControl>,
-    ) -> Dispatcher {
+        controls_rx: Receiver<(MidiMessage, MidiControllerType, Instant)>,
+        surface: S,
+        synth_ctrl_tx: Producer<(SynthControl, Instant)>,
+        mappings: Mappings,
+        patches: PatchBank,
+        pattern: Pattern,
+        mpe_zones: MpeZones,
+        tunings: TuningBank,
+        zones: Zones,
+    ) -> Self {
         Dispatcher {
             controls_rx,
-            controls_tx,
+            surface,
             synth_ctrl_tx,
-            master_tune: 64,
-            osc1_range: OscillatorRange::Range8ft,
-            osc1_enable: true,
-            osc1_volume: 0,
+            parameters: ParameterValues::default(),
+            mappings,
+            learn_state: LearnState::Off,
+            tune_mode: TuneMode::Coarse,
+            takeover: [TakeoverState::default(); PARAMETER_COUNT],
+            clock: Clock::new(),
+            synth_tempo: 0.0,
+            arpeggiator: Arpeggiator::new(),
+            sequencer: Sequencer::new(pattern),
+            sequencer_view: false,
+            step_rows: StepRows::Pages,
+            page: 0,
+            held_step: None,
+            shown_playhead: None,
+            accent: [false; VOICE_GROUPS],
+            velocity: 0x7F,
+            zones,
+            played: vec![],
+            mpe: Mpe::new(mpe_zones),
+            tuning: Tuning::default(),
+            tunings,
+            pending_tuning: None,
+            sounding: vec![],
+            next_note_id: 0,
+            patches,
+            bank: 0,
+            next_bank: 0,
+            program: 0,
         }
     }
 
     pub fn start(&mut self) -> Result<()> {
         self.initialize()?;
 
-        // Receive MIDI events from controller
-        while let Ok((midi_message, source)) = self.controls_rx.recv() {
-            match (source, midi_message) {
-                (MidiControllerType::ControlPanel, midi_message) => match midi_message {
-                    MidiMessage::ControlChange(control_change) => {
-                        match (control_change.control_number(), control_change.channel()) {
-                            (0x07, 0) => {
-                                self.update_oscillator_volume(control_change.control_value())?
-                            }
-                            (0x30, _) => {
-                                self.update_oscillator_range(control_change.control_value())?
-                            }
-                            (0x31, _) => self.update_master_tune(control_change.control_value())?,
-                            _ => {}
+        // Receive MIDI events from controllers, wake up in between for the clock, the
+        // arpeggiator and the sequencer
+        loop {
+            let next_event = [
+                self.clock.next_event(),
+                self.arpeggiator.next_event(),
+                self.sequencer.next_event(),
+                self.pending_tuning.map(|(_, due)| due),
+            ]
+            .iter()
+            .flatten()
+            .min()
+            .cloned();
+            let received = match next_event {
+                Some(due) => {
+                    let now = Instant::now();
+                    if due > now {
+                        self.controls_rx.recv_timeout(due - now)
+                    } else {
+                        Err(RecvTimeoutError::Timeout)
+                    }
+                }
+                None => self
+                    .controls_rx
+                    .recv()
+                    .map_err(|_| RecvTimeoutError::Disconnected),
+            };
+
+            match received {
+                // Patches are recalled from any source, e.g. also by a DAW on the controls port
+                Ok((midi_message, _, timestamp)) if selects_program(&midi_message) => {
+                    self.select_program(midi_message, timestamp)?
+                }
+                Ok((midi_message, MidiControllerType::ControlPanel, timestamp)) => {
+                    if let Some(event) = self.surface.event(&midi_message) {
+                        self.handle_surface_event(event, timestamp)?;
+                    }
+                }
+                Ok((midi_message, MidiControllerType::Automation, timestamp)) => {
+                    if let Some(event) = self.surface.event(&midi_message) {
+                        self.handle_automation_event(event, timestamp)?;
+                    }
+                }
+                Ok((midi_message, MidiControllerType::Keyboard, timestamp)) => {
+                    self.handle_keyboard_message(midi_message, timestamp)?
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    let now = Instant::now();
+                    self.run_clock(now)?;
+                    self.run_arpeggiator(now)?;
+                    self.run_sequencer(now)?;
+                    self.run_tuning(now);
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    self.save_pattern();
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    fn handle_surface_event(&mut self, event: SurfaceEvent, timestamp: Instant) -> Result<()> {
+        match event {
+            SurfaceEvent::KnobTurned(control, controller_value) => {
+                self.handle_control_change(control, controller_value, timestamp)
+            }
+            // Buttons of the control surface do not play notes, their velocity is not used
+            SurfaceEvent::ButtonPressed(control) => self.handle_note_on(control, 0x7F, timestamp),
+            SurfaceEvent::ToggleLearnMode => self.toggle_learn_mode(),
+            SurfaceEvent::SavePatch => {
+                self.save_patch();
+                self.show_banks()
+            }
+            SurfaceEvent::LoadPatch => self.load_patch(timestamp),
+            SurfaceEvent::SelectBank(bank) => {
+                self.next_bank = bank;
+                self.change_program(bank, self.program, timestamp)
+            }
+            SurfaceEvent::ToggleSequencerView => self.show_sequencer_view(!self.sequencer_view),
+            SurfaceEvent::ToggleSequencer => {
+                // As master, the sequencer also starts and stops the receivers of the clock
+                let message = if self.sequencer.is_running() {
+                    self.sequencer.stop(timestamp);
+                    self.save_pattern();
+                    self.clock.stop()
+                } else {
+                    self.sequencer.start(timestamp);
+                    self.clock.start(timestamp)
+                };
+                self.run_sequencer(timestamp)?;
+                if let Some(message) = message {
+                    self.surface.send_clock(message)?;
+                }
+                self.run_clock(timestamp)?;
+                self.surface
+                    .show_sequencer_running(self.sequencer.is_running())
+            }
+            SurfaceEvent::EditStep(step_number, field) => self.edit_step(step_number, field),
+            SurfaceEvent::ToggleStepNote(step_number, note) => {
+                self.toggle_step_note(step_number, note)
+            }
+            SurfaceEvent::ReleaseStep(step_number) => {
+                if self.held_step == Some(self.step_index(step_number)) {
+                    self.held_step = None;
+                    self.surface.show_held_step(None)?;
+                }
+                Ok(())
+            }
+            SurfaceEvent::EditHeldStep(field) => match self.held_step {
+                Some(index) => {
+                    self.sequencer.edit(index, field);
+                    self.show_step(index)?;
+                    self.show_held_step()
+                }
+                None => Ok(()),
+            },
+            SurfaceEvent::ToggleStepRows => {
+                let rows = match self.step_rows {
+                    StepRows::Pages => StepRows::Notes {
+                        lowest: LOWEST_STEP_NOTE,
+                    },
+                    StepRows::Notes { .. } => StepRows::Pages,
+                };
+                self.show_step_rows(rows)
+            }
+            SurfaceEvent::ScrollPage(pages) => {
+                let page = i32::from(self.page) + i32::from(pages);
+                if page >= 0 && (page as usize) < self.page_count() {
+                    self.page = page as u8;
+                    self.show_pattern()?;
+                }
+                Ok(())
+            }
+            SurfaceEvent::ScrollNotes(semitones) => match self.step_rows {
+                StepRows::Notes { lowest } => {
+                    let lowest = (i32::from(lowest) + i32::from(semitones)).clamp(0, 127) as u8;
+                    self.show_step_rows(StepRows::Notes { lowest })
+                }
+                StepRows::Pages => Ok(()),
+            },
+            SurfaceEvent::TapTempo => match self.clock.tap(timestamp) {
+                Some(tempo) => self.set_parameter(ParameterId::Tempo, tempo, timestamp),
+                None => Ok(()),
+            },
+            SurfaceEvent::ToggleTuneMode => self.toggle_tune_mode(),
+            SurfaceEvent::ShiftOctave(octaves) => {
+                self.step_parameter(ParameterId::OctaveShift, octaves, timestamp)
+            }
+            SurfaceEvent::Transpose(semitones) => {
+                self.step_parameter(ParameterId::Transpose, semitones, timestamp)
+            }
+        }
+    }
+
+    /// Handles an event of the control surface that was sent by another application. Knobs do not
+    /// move along with the values set this way, so they have to take them over again.
+    fn handle_automation_event(&mut self, event: SurfaceEvent, timestamp: Instant) -> Result<()> {
+        match event {
+            SurfaceEvent::KnobTurned(control, controller_value) => {
+                let binding = match self.mappings.find(&control) {
+                    Some(binding) => binding,
+                    None => return Ok(()),
+                };
+
+                match self.controller_to_value(binding.parameter, controller_value) {
+                    Some((value, controller_value))
+                        if value != self.parameters.get(binding.parameter) =>
+                    {
+                        self.update_parameter(binding, value, controller_value, timestamp)?;
+                        self.lose_sync(binding.parameter);
+                        self.surface.show_takeover(binding.control, false)
+                    }
+                    _ => Ok(()),
+                }
+            }
+            event => self.handle_surface_event(event, timestamp),
+        }
+    }
+
+    fn handle_keyboard_message(
+        &mut self,
+        midi_message: MidiMessage,
+        timestamp: Instant,
+    ) -> Result<()> {
+        let source = MidiControllerType::Keyboard;
+
+        match midi_message {
+            // Controllers that are bound to a parameter do not set registered parameters
+            MidiMessage::ControlChange(control_change)
+                if !self.is_bound(control_change.channel(), control_change.control_number())
+                    && self.mpe.control_change(
+                        control_change.channel(),
+                        control_change.control_number(),
+                        control_change.control_value(),
+                    ) => {}
+            MidiMessage::ControlChange(control_change)
+                if control_change.control_number() == TIMBRE
+                    && self.mpe.zones().is_member(control_change.channel()) =>
+            {
+                let timbre = f32::from(control_change.control_value()) / 127.0;
+                if let Some(member) = self.mpe.member(control_change.channel()) {
+                    member.timbre = timbre;
+                    let note_number = member.note;
+                    for id in self.sounding_notes(note_number) {
+                        self.send_synth_ctrl(SynthControl::NoteTimbre(id, timbre), timestamp)?;
+                    }
+                }
+            }
+            MidiMessage::ControlChange(control_change) => match control_change.control_number() {
+                MOD_WHEEL if !self.is_bound(control_change.channel(), MOD_WHEEL) => self
+                    .send_synth_ctrl(
+                        SynthControl::ModWheel(f32::from(control_change.control_value()) / 127.0),
+                        timestamp,
+                    )?,
+                control_number => self.handle_control_change(
+                    Control::Knob {
+                        source,
+                        channel: Some(control_change.channel()),
+                        control_number,
+                    },
+                    control_change.control_value(),
+                    timestamp,
+                )?,
+            },
+            MidiMessage::NoteOn(note_on) => {
+                self.handle_note_on(
+                    Control::Button {
+                        source,
+                        channel: note_on.channel(),
+                        note_number: note_on.note_number(),
+                    },
+                    note_on.key_velocity(),
+                    timestamp,
+                )?;
+                self.start_member_note(note_on.channel(), note_on.note_number(), timestamp)?
+            }
+            MidiMessage::ChannelPressure(channel_pressure) => {
+                let pressure = f32::from(channel_pressure.pressure()) / 127.0;
+                match self.mpe.member(channel_pressure.channel()) {
+                    Some(member) => {
+                        member.pressure = pressure;
+                        let note_number = member.note;
+                        for id in self.sounding_notes(note_number) {
+                            self.send_synth_ctrl(
+                                SynthControl::KeyPressure(id, pressure),
+                                timestamp,
+                            )?;
                         }
                     }
-                    MidiMessage::NoteOn(note_on) => {
-                        match (note_on.note_number(), note_on.channel()) {
-                            (0x33, 0) => self.update_oscillator_enable()?,
-                            _ => continue,
+                    None => self.send_synth_ctrl(SynthControl::Aftertouch(pressure), timestamp)?,
+                }
+            }
+            MidiMessage::PitchBend(pitch_bend) => {
+                let semitones = self
+                    .mpe
+                    .bend(pitch_bend.channel(), pitch_bend.pitch_bend_change());
+                match self.mpe.member(pitch_bend.channel()) {
+                    Some(member) => {
+                        member.bend = semitones;
+                        let note_number = member.note;
+                        for id in self.sounding_notes(note_number) {
+                            self.send_synth_ctrl(
+                                SynthControl::NotePitchBend(id, semitones),
+                                timestamp,
+                            )?;
                         }
                     }
-                    _ => {}
-                },
-                (MidiControllerType::Keyboard, midi_message) => match midi_message {
-                    MidiMessage::NoteOn(note_on) => self.note_on(note_on.note_number())?,
-                    MidiMessage::NoteOff(note_off) => self.note_off(note_off.note_number())?,
-                    _ => {}
-                },
+                    None => self.send_synth_ctrl(SynthControl::PitchBend(semitones), timestamp)?,
+                }
             }
+            MidiMessage::PolyphonicKeyPressure(key_pressure) => {
+                let note_number =
+                    self.played_note(key_pressure.channel(), key_pressure.note_number());
+                let pressure = f32::from(key_pressure.pressure()) / 127.0;
+                for id in self.sounding_notes(note_number) {
+                    self.send_synth_ctrl(SynthControl::KeyPressure(id, pressure), timestamp)?
+                }
+            }
+            MidiMessage::NoteOff(note_off) => {
+                let control = Control::Button {
+                    source,
+                    channel: note_off.channel(),
+                    note_number: note_off.note_number(),
+                };
+
+                let note_number = self.played_note(note_off.channel(), note_off.note_number());
+                if let Some(member) = self.mpe.member(note_off.channel()) {
+                    if note_number.is_some() && member.note == note_number {
+                        member.note = None;
+                    }
+                }
+
+                // Keys that are bound to a parameter do not play notes
+                if self.mappings.find(&control).is_none() {
+                    self.key_released(note_off.channel(), note_off.note_number(), timestamp)?
+                }
+            }
+            MidiMessage::SystemExclusive(sysex) => {
+                // Notes that are playing keep their frequency until released
+                self.tuning.apply_mts(&sysex.id(), sysex.payload());
+            }
+            MidiMessage::TimingClock(_) => {
+                self.clock.pulse(timestamp);
+                self.update_tempo(timestamp)?;
+
+                let notes = self.arpeggiator.clock(timestamp);
+                self.play_arpeggiator_notes(notes)?;
+                let notes = self.sequencer.clock(timestamp);
+                self.play_sequencer_notes(notes)?
+            }
+            MidiMessage::Start(_) => {
+                self.arpeggiator.clock_start();
+                self.sequencer.clock_start(timestamp);
+                self.surface
+                    .show_sequencer_running(self.sequencer.is_running())?
+            }
+            MidiMessage::Continue(_) => {
+                self.arpeggiator.clock_continue();
+                self.sequencer.clock_continue();
+                self.surface
+                    .show_sequencer_running(self.sequencer.is_running())?
+            }
+            MidiMessage::Stop(_) => {
+                self.arpeggiator.clock_stop(timestamp);
+                self.run_arpeggiator(timestamp)?;
+                self.sequencer.clock_stop(timestamp);
+                self.run_sequencer(timestamp)?;
+                if !self.sequencer.is_running() {
+                    self.save_pattern();
+                }
+                self.surface
+                    .show_sequencer_running(self.sequencer.is_running())?
+            }
+            _ => {}
         }
 
         Ok(())
     }
 
     fn initialize(&mut self) -> Result<()> {
-        // Master Tune
-        // Set knob to single style
-        self.controls_tx.send(ControlChange::create(0, 0x39, 1))?;
-
-        // Set knob to position 0
-        self.controls_tx.send(ControlChange::create(0, 0x31, 64))?;
-
-        // Set master tune to 0
-        self.synth_ctrl_tx.send(SynthControl::MasterTune(1.0))?;
-        self.master_tune = 64;
-
-        // Oscillator 1
-        // Set knob to single style
-        self.controls_tx.send(ControlChange::create(0, 0x38, 1))?;
-
-        // Set knob to 8' position
-        self.controls_tx.send(ControlChange::create(0, 0x30, 72))?;
-
-        // Set range of oscillator 1 to 8' (440 Hz)
-        self.osc1_range = OscillatorRange::Range8ft;
-        self.synth_ctrl_tx.send(SynthControl::Oscillator1Range(
-            (f64::from(&self.osc1_range) / SAMPLE_RATE) as f32,
-        ))?;
-
-        // Set LEDs of unselected waveforms to unselected (except first one)
-        self.controls_tx
-            .send(NoteOn::create(0, 0, COLOR_UNSELECTED))?;
-        self.controls_tx
-            .send(NoteOn::create(0, 8, COLOR_UNSELECTED))?;
-        self.controls_tx
-            .send(NoteOn::create(0, 16, COLOR_UNSELECTED))?;
-        self.controls_tx
-            .send(NoteOn::create(0, 24, COLOR_UNSELECTED))?;
-        self.controls_tx
-            .send(NoteOn::create(0, 32, COLOR_SELECTED))?;
-        self.controls_tx
-            .send(NoteOn::create(0, 33, COLOR_UNSELECTED))?;
-
-        // Set oscillator 1 to on
-        self.osc1_enable = true;
-        self.synth_ctrl_tx
-            .send(SynthControl::Oscillator1Enable(self.osc1_enable))?;
-        self.controls_tx.send(NoteOn::create(0, 0x33, 127))?;
-
-        // Set oscillator 1 volume to 0
-        self.osc1_volume = 0;
-        self.synth_ctrl_tx
-            .send(SynthControl::Oscillator1Volume(0.0))?;
+        let now = Instant::now();
+
+        self.surface.initialize()?;
+        self.surface.show_learn_mode(false)?;
+        self.surface.show_tune_mode(self.tune_mode)?;
+        self.show_banks()?;
+
+        // Set all parameters to the values of the current patch, or to their default value
+        self.parameters = ParameterValues::default();
+        for id in &PARAMETERS {
+            self.apply_parameter(*id, self.parameters.get(*id), now)?;
+        }
+        self.show_parameters()?;
+        self.load_patch(now)?;
+
+        // The tuning of the patch is not changed by the player yet
+        if let Some((program, _)) = self.pending_tuning.take() {
+            self.load_tuning(program);
+        }
 
         Ok(())
     }
 
-    fn update_master_tune(&mut self, value: u8) -> Result<()> {
-        if value != self.master_tune {
-            let tune = (f32::from(value) - 64.0) * 5.0 / 128.0;
+    fn save_patch(&self) {
+        let patch = Patch {
+            values: self.parameters.clone(),
+        };
+
+        // Failing to save should not stop the synthesizer
+        match self.patches.save(self.bank, self.program, &patch) {
+            Ok(()) => println!("Saved patch {:03}/{:03}", self.bank, self.program),
+            Err(e) => eprintln!("{}", e.display_chain()),
+        }
+    }
 
-            self.synth_ctrl_tx
-                .send(SynthControl::MasterTune(2.0_f32.powf(tune / 12.0)))?;
+    /// Handles Bank Select and Program Change (see `selects_program()`).
+    fn select_program(&mut self, midi_message: MidiMessage, timestamp: Instant) -> Result<()> {
+        match midi_message {
+            MidiMessage::ControlChange(control_change) => match control_change.control_number() {
+                BANK_SELECT_MSB => {
+                    self.next_bank =
+                        u16::from(control_change.control_value()) << 7 | (self.next_bank & 0x7F);
+                }
+                BANK_SELECT_LSB => {
+                    self.next_bank =
+                        (self.next_bank & !0x7F) | u16::from(control_change.control_value());
+                }
+                _ => {}
+            },
+            MidiMessage::ProgramChange(program_change) => {
+                self.change_program(self.next_bank, program_change.program_number(), timestamp)?
+            }
+            _ => {}
+        }
 
-            self.controls_tx
-                .send(ControlChange::create(0, 0x31, value))?;
+        Ok(())
+    }
 
-            self.master_tune = value;
+    /// Switches to the patch of another program. Playing notes are released if the patch is
+    /// loaded, the continuous parameters of the new patch are smoothed in by the synthesizer.
+    fn change_program(&mut self, bank: u16, program: u8, timestamp: Instant) -> Result<()> {
+        self.bank = bank;
+        self.program = program;
+
+        if let Some(patch) = self.read_patch() {
+            self.send_synth_ctrl(SynthControl::AllNotesOff, timestamp)?;
+            self.sounding.clear();
+            self.arpeggiator.notes_released();
+            self.sequencer.notes_released();
+            self.apply_patch(patch, timestamp)?;
         }
+        self.show_banks()?;
 
         Ok(())
     }
 
-    fn update_oscillator_range(&mut self, value: u8) -> Result<()> {
-        let (value, range) = match value {
-            0...21 => (21, OscillatorRange::Low),
-            val @ 35...38 => (val, OscillatorRange::Range32ft),
-            val @ 53...56 => (val, OscillatorRange::Range16ft),
-            val @ 70...73 => (val, OscillatorRange::Range8ft),
-            val @ 88...91 => (val, OscillatorRange::Range4ft),
-            105...127 => (105, OscillatorRange::Range2ft),
-            _ => return Ok(()),
-        };
+    /// Loads the patch of the current program and sends its values to the synthesizer and the
+    /// controller. Keeps the current values if the patch cannot be loaded.
+    fn load_patch(&mut self, timestamp: Instant) -> Result<()> {
+        match self.read_patch() {
+            Some(patch) => self.apply_patch(patch, timestamp),
+            None => Ok(()),
+        }
+    }
 
-        if range != self.osc1_range {
-            self.synth_ctrl_tx.send(SynthControl::Oscillator1Range(
-                (f64::from(&range) / SAMPLE_RATE) as f32,
-            ))?;
+    /// Reads the patch of the current program, or returns `None` if it is empty or cannot be read.
+    fn read_patch(&self) -> Option<Patch> {
+        match self.patches.load(self.bank, self.program) {
+            Ok(Some(patch)) => Some(patch),
+            Ok(None) => {
+                println!("Patch {:03}/{:03} is empty", self.bank, self.program);
+                None
+            }
+            Err(e) => {
+                eprintln!("{}", e.display_chain());
+                None
+            }
+        }
+    }
 
-            self.controls_tx
-                .send(ControlChange::create(0, 0x30, value))?;
+    /// Sends the values of a patch to the synthesizer and the controller.
+    fn apply_patch(&mut self, patch: Patch, timestamp: Instant) -> Result<()> {
+        // Global parameters keep their values
+        let mut values = patch.values;
+        for id in &PARAMETERS {
+            if id.is_global() {
+                values.set(*id, self.parameters.get(*id));
+                continue;
+            }
 
-            self.osc1_range = range;
+            let value = values.get(*id);
+            if value != self.parameters.get(*id) {
+                self.apply_parameter(*id, value, timestamp)?;
+                self.lose_sync(*id);
+            }
         }
+        self.parameters = values;
+        self.show_parameters()?;
+
+        println!("Loaded patch {:03}/{:03}", self.bank, self.program);
 
         Ok(())
     }
 
-    fn update_oscillator_enable(&mut self) -> Result<()> {
-        self.osc1_enable = !self.osc1_enable;
-        let value = if self.osc1_enable { 0x7F } else { 0x00 };
-
-        self.synth_ctrl_tx
-            .send(SynthControl::Oscillator1Enable(self.osc1_enable))?;
+    fn handle_control_change(
+        &mut self,
+        control: Control,
+        controller_value: u8,
+        timestamp: Instant,
+    ) -> Result<()> {
+        if self.learn(control)? {
+            return Ok(());
+        }
 
-        self.controls_tx.send(NoteOn::create(0, 0x33, value))?;
+        if let Some(binding) = self.mappings.find(&control) {
+            let definition = binding.parameter.definition();
+            let current =
+                self.value_to_controller(binding.parameter, self.parameters.get(binding.parameter));
+            let state = &mut self.takeover[binding.parameter as usize];
+            let was_in_sync = state.is_in_sync();
+            let taken_over = state.take_over(definition.takeover, current, controller_value);
+            let is_in_sync = state.is_in_sync();
+
+            if let Some((value, controller_value)) = taken_over
+                .and_then(|position| self.controller_to_value(binding.parameter, position))
+            {
+                self.update_parameter(binding, value, controller_value, timestamp)?;
+            }
+            if is_in_sync != was_in_sync {
+                self.surface.show_takeover(binding.control, is_in_sync)?;
+            }
+        }
 
         Ok(())
     }
 
-    fn update_oscillator_volume(&mut self, value: u8) -> Result<()> {
-        const DB_RANGE: f32 = 50.0; // 50 dB
-
-        if value != self.osc1_volume {
-            let volume = 10.0_f32.powf(0.05 * DB_RANGE / 127.0 * (f32::from(value) - 127.0));
+    fn handle_note_on(&mut self, control: Control, velocity: u8, timestamp: Instant) -> Result<()> {
+        if self.learn(control)? {
+            return Ok(());
+        }
 
-            self.synth_ctrl_tx
-                .send(SynthControl::Oscillator1Volume(volume))?;
+        match (self.mappings.find(&control), control) {
+            (Some(binding), _) => {
+                let definition = binding.parameter.definition();
+                let value = if self.parameters.get(binding.parameter)
+                    >= 0.5 * (definition.min + definition.max)
+                {
+                    definition.min
+                } else {
+                    definition.max
+                };
+                let controller_value = self.value_to_controller(binding.parameter, value);
 
-            self.osc1_volume = value;
+                self.update_parameter(binding, value, controller_value, timestamp)?;
+            }
+            (
+                None,
+                Control::Button {
+                    source: MidiControllerType::Keyboard,
+                    channel,
+                    note_number,
+                },
+            ) => self.key_pressed(channel, note_number, velocity, timestamp)?,
+            _ => {}
         }
 
         Ok(())
     }
 
-    fn calculate_note(&self, note_number: u8) -> f32 {
-        let half_steps = f32::from(note_number) - 60.0;
-        2.0_f32.powf(half_steps / 12.0)
+    fn toggle_learn_mode(&mut self) -> Result<()> {
+        self.learn_state = match self.learn_state {
+            LearnState::Off => {
+                println!("MIDI learn: move the control of the parameter to map");
+                LearnState::SelectParameter
+            }
+            _ => {
+                println!("MIDI learn: cancelled");
+                LearnState::Off
+            }
+        };
+
+        self.show_learn_state()
     }
 
-    fn note_on(&mut self, note_number: u8) -> Result<()> {
-        let freq = self.calculate_note(note_number);
+    /// Handles a control event in MIDI learn mode. Returns true if the event has been consumed.
+    fn learn(&mut self, control: Control) -> Result<bool> {
+        match self.learn_state {
+            LearnState::Off => return Ok(false),
+            LearnState::SelectParameter => {
+                if let Some(binding) = self.mappings.find(&control) {
+                    println!(
+                        "MIDI learn: {} armed, move a knob or press a button to map it to",
+                        binding.parameter.definition().name
+                    );
+                    self.learn_state = LearnState::Armed(binding.parameter);
+                }
+            }
+            LearnState::Armed(parameter) => {
+                // Ignore further events of the control that armed the parameter
+                if self
+                    .mappings
+                    .find(&control)
+                    .map(|binding| binding.parameter)
+                    == Some(parameter)
+                {
+                    return Ok(true);
+                }
+
+                if let Some(unbound) = self.mappings.learn(parameter, control) {
+                    self.surface.clear(unbound)?;
+                }
+                // Position of the new control is not known yet
+                self.takeover = [TakeoverState::default(); PARAMETER_COUNT];
+                println!(
+                    "MIDI learn: {} mapped to {}",
+                    parameter.definition().name,
+                    control
+                );
 
-        self.synth_ctrl_tx.send(SynthControl::NoteOn(freq))?;
+                // Failing to save should not stop the synthesizer
+                if let Err(e) = self.mappings.save() {
+                    eprintln!("{}", e.display_chain());
+                }
 
-        Ok(())
+                self.learn_state = LearnState::Off;
+                self.show_learn_state()?;
+                self.show_parameters()?;
+            }
+        }
+
+        Ok(true)
     }
 
-    fn note_off(&mut self, note_number: u8) -> Result<()> {
-        let freq = self.calculate_note(note_number);
+    /// Sets a parameter to a new value, passes it on to the synthesizer and echoes the new
+    /// position to the controller. Does nothing if the value did not change.
+    fn update_parameter(
+        &mut self,
+        binding: Binding,
+        value: f32,
+        controller_value: u8,
+        timestamp: Instant,
+    ) -> Result<()> {
+        let id = binding.parameter;
+        if value == self.parameters.get(id) {
+            return Ok(());
+        }
 
-        self.synth_ctrl_tx.send(SynthControl::NoteOff(freq))?;
+        self.parameters.set(id, value);
+        self.apply_parameter(id, self.parameters.get(id), timestamp)?;
+        self.surface.show_value(binding.control, controller_value)?;
 
         Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::sync::mpsc;
-    use std::thread;
-    use std::time::Duration;
+    /// Marks the control of a parameter as out of sync after its value was changed by something
+    /// else than the control, which still is at the position of the previous value.
+    fn lose_sync(&mut self, id: ParameterId) {
+        if id.definition().takeover != Takeover::Jump {
+            self.takeover[id as usize].lose_sync();
+        }
+    }
 
-    use usb_midi::NoteOff;
+    /// Passes a new value of a parameter on to the synthesizer, or to the arpeggiator.
+    fn apply_parameter(&mut self, id: ParameterId, value: f32, timestamp: Instant) -> Result<()> {
+        if let Some(ctrl) = id.synth_control(value) {
+            return self.send_synth_ctrl(ctrl, timestamp);
+        }
 
-    macro_rules! setup_dispatcher {
-        () => {{
-            let (midi_cmd_tx, midi_cmd_rx) = mpsc::channel();
-            let (midi_resp_tx, midi_resp_rx) = mpsc::channel();
-            let (synth_ctrl_tx, synth_ctrl_rx) = mpsc::channel();
+        match id {
+            ParameterId::ArpeggiatorEnable => {
+                return self.enable_arpeggiator(value >= 0.5, timestamp)
+            }
+            ParameterId::ArpeggiatorOrder => self.arpeggiator.set_order(Order::from_value(value)),
+            ParameterId::ArpeggiatorOctaves => self.arpeggiator.set_octaves(value.round() as u8),
+            ParameterId::ArpeggiatorGate => self.arpeggiator.set_gate(value),
+            ParameterId::ArpeggiatorRate => self.arpeggiator.set_rate(value.round() as usize),
+            ParameterId::Tempo => {
+                self.clock.set_tempo(value);
+                self.update_tempo(timestamp)?;
+            }
+            ParameterId::ClockSync => {
+                if let Some(message) = self.clock.set_slave(value >= 0.5) {
+                    self.surface.send_clock(message)?;
+                }
+                self.update_tempo(timestamp)?;
+                self.arpeggiator.set_external_clock(value >= 0.5, timestamp);
+                self.sequencer.set_external_clock(value >= 0.5, timestamp);
+            }
+            ParameterId::ArpeggiatorLatch => self.arpeggiator.set_latch(value >= 0.5, timestamp),
+            ParameterId::Tuning => {
+                self.pending_tuning = Some((value.round() as u8, timestamp + TUNING_SETTLE_TIME))
+            }
+            ParameterId::Transpose | ParameterId::OctaveShift => self.surface.show_transposition(
+                self.parameters.get(ParameterId::OctaveShift).round() as i8,
+                self.parameters.get(ParameterId::Transpose).round() as i8,
+            )?,
+            _ => {}
+        }
 
-            let mut dispatcher = Dispatcher::new(midi_cmd_rx, midi_resp_tx, synth_ctrl_tx);
-            let _dispatcher_thread = thread::spawn(move || dispatcher.start());
+        self.run_arpeggiator(timestamp)?;
+        self.run_sequencer(timestamp)
+    }
 
-            // Clear initialization messages
-            while let Ok(_) = midi_resp_rx.recv_timeout(Duration::from_millis(100)) {}
-            while let Ok(_) = synth_ctrl_rx.recv_timeout(Duration::from_millis(100)) {}
+    /// Sets a parameter to a value that does not come from its control (e.g. a tapped tempo),
+    /// passes it on and shows it on the controls it is bound to.
+    fn set_parameter(&mut self, id: ParameterId, value: f32, timestamp: Instant) -> Result<()> {
+        let definition = id.definition();
+        self.parameters.set(id, value);
+        self.apply_parameter(id, self.parameters.get(id), timestamp)?;
 
-            (midi_cmd_tx, midi_resp_rx, synth_ctrl_rx)
-        }};
-    }
+        // The control still is at the position of the previous value
+        if definition.takeover != Takeover::Jump {
+            self.takeover[id as usize].lose_sync();
+        }
+        let in_sync = self.takeover[id as usize].is_in_sync();
+        let controller_value = self.value_to_controller(id, self.parameters.get(id));
+        for binding in self.mappings.bindings() {
+            if binding.parameter == id {
+                self.surface.show_takeover(binding.control, in_sync)?;
+                self.surface.show_value(binding.control, controller_value)?;
+            }
+        }
 
-    macro_rules! send_cmd {
-        ($tx:ident, $cmd:expr, $src:expr) => {
-            $tx.send(($cmd, $src)).unwrap();
-        };
+        Ok(())
     }
 
-    macro_rules! expect_resp {
-        ($rx:ident, $resp:expr) => {
-            assert_eq!($rx.recv_timeout(Duration::from_millis(100)).unwrap(), $resp);
-        };
+    /// Converts a controller value to the value of a parameter, see
+    /// `Parameter::controller_to_value()`. The master tune depends on the tune mode.
+    fn controller_to_value(&self, id: ParameterId, controller_value: u8) -> Option<(f32, u8)> {
+        match id {
+            ParameterId::MasterTune => Some(
+                self.tune_mode
+                    .controller_to_value(self.parameters.get(id), controller_value),
+            ),
+            _ => id.definition().controller_to_value(controller_value),
+        }
     }
 
-    macro_rules! get_resp {
-        ($rx:ident) => {{
-            $rx.recv_timeout(Duration::from_millis(100)).unwrap()
-        }};
+    /// Converts the value of a parameter to the position of its control.
+    fn value_to_controller(&self, id: ParameterId, value: f32) -> u8 {
+        match id {
+            ParameterId::MasterTune => self.tune_mode.value_to_controller(value),
+            _ => id.definition().value_to_controller(value),
+        }
     }
 
-    macro_rules! expect_no_resp {
-        ($rx:ident) => {
-            assert!($rx.recv_timeout(Duration::from_millis(100)).is_err());
+    /// Switches the control of the master tune between coarse and fine tuning. Its position no
+    /// longer matches the value, so it has to take over again.
+    fn toggle_tune_mode(&mut self) -> Result<()> {
+        self.tune_mode = match self.tune_mode {
+            TuneMode::Coarse => TuneMode::Fine,
+            TuneMode::Fine => TuneMode::Coarse,
         };
+        println!("Master tune: {:?}", self.tune_mode);
+
+        self.takeover[ParameterId::MasterTune as usize].lose_sync();
+        self.surface.show_tune_mode(self.tune_mode)?;
+        self.show_parameters()
     }
 
-    #[test]
-    fn master_tune() {
-        macro_rules! send_and_check {
-            ($tx:ident, $rx_midi:ident, $val:expr, $rx_synth:ident, $tune:expr, $eps:expr) => {
-                send_cmd!(
-                    $tx,
-                    ControlChange::create(0, 0x31, $val),
-                    MidiControllerType::ControlPanel
-                );
-                expect_resp!($rx_midi, ControlChange::create(0, 0x31, $val));
+    /// Changes a parameter with whole steps (e.g. the transposition) by a number of steps.
+    fn step_parameter(&mut self, id: ParameterId, steps: i8, timestamp: Instant) -> Result<()> {
+        let value = self.parameters.get(id).round() + f32::from(steps);
+        self.set_parameter(id, value, timestamp)
+    }
 
-                let tune = get_resp!($rx_synth);
-                let tune = match tune {
-                    SynthControl::MasterTune(tune) => tune,
-                    _ => panic!("wrong variant!"),
-                };
-                assert_float_eq!($tune, tune, $eps);
+    /// Shows the values of all parameters on the controls they are bound to.
+    fn show_parameters(&self) -> Result<()> {
+        for binding in self.mappings.bindings() {
+            let value = self.parameters.get(binding.parameter);
+            let in_sync = self.takeover[binding.parameter as usize].is_in_sync();
+            self.surface.show_takeover(binding.control, in_sync)?;
+            self.surface.show_value(
+                binding.control,
+                self.value_to_controller(binding.parameter, value),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Shows the current bank, banks with saved patches and empty banks.
+    fn show_banks(&self) -> Result<()> {
+        for bank in 0..self.surface.bank_count() {
+            let state = if bank == self.bank {
+                BankState::Selected
+            } else if self.patches.contains_bank(bank) {
+                BankState::Used
+            } else {
+                BankState::Empty
             };
+            self.surface.show_bank(bank, state)?;
         }
 
-        let (midi_cmd_tx, midi_rsp_rx, synth_ctrl_rx) = setup_dispatcher!();
+        Ok(())
+    }
 
-        send_and_check!(midi_cmd_tx, midi_rsp_rx, 0, synth_ctrl_rx, 0.865537, 1e-6);
-        send_and_check!(midi_cmd_tx, midi_rsp_rx, 32, synth_ctrl_rx, 0.930342, 1e-6);
-        send_and_check!(midi_cmd_tx, midi_rsp_rx, 64, synth_ctrl_rx, 1.0, 1e-6);
-        send_and_check!(midi_cmd_tx, midi_rsp_rx, 96, synth_ctrl_rx, 1.074873, 1e-6);
-        send_and_check!(midi_cmd_tx, midi_rsp_rx, 127, synth_ctrl_rx, 1.152749, 1e-6);
+    /// Shows the steps of the current page of the sequencer, or the banks.
+    fn show_sequencer_view(&mut self, shown: bool) -> Result<()> {
+        self.sequencer_view = shown;
+        self.held_step = None;
+        self.surface.show_sequencer_view(shown)?;
+
+        if shown {
+            self.show_pattern()
+        } else {
+            self.save_pattern();
+            self.show_banks()?;
+            self.show_parameters()
+        }
     }
 
-    #[test]
-    fn master_tune_update_only_when_different() {
-        let (midi_cmd_tx, midi_resp_rx, synth_ctrl_rx) = setup_dispatcher!();
+    /// Changes what the rows of the sequencer view show, starting again at the first page.
+    fn show_step_rows(&mut self, rows: StepRows) -> Result<()> {
+        self.step_rows = rows;
+        self.page = 0;
+        self.held_step = None;
+        self.surface.show_step_rows(rows)?;
+        self.surface.show_held_step(None)?;
 
-        send_cmd!(
-            midi_cmd_tx,
-            ControlChange::create(0, 0x31, 32),
-            MidiControllerType::ControlPanel
-        );
+        if self.sequencer_view {
+            self.show_pattern()?;
+        }
 
-        expect_resp!(midi_resp_rx, ControlChange::create(0, 0x31, 32));
+        Ok(())
+    }
 
-        let tune = get_resp!(synth_ctrl_rx);
-        let tune = match tune {
-            SynthControl::MasterTune(tune) => tune,
-            _ => panic!("wrong variant!"),
-        };
-        assert_float_eq!(tune, 0.930342, 1e-6);
+    /// Saves the pattern of the sequencer if it was edited.
+    fn save_pattern(&mut self) {
+        // Failing to save should not stop the synthesizer
+        if let Err(e) = self.sequencer.save() {
+            eprintln!("{}", e.display_chain());
+        }
+    }
 
-        send_cmd!(
-            midi_cmd_tx,
-            ControlChange::create(0, 0x31, 32),
-            MidiControllerType::ControlPanel
-        );
+    fn show_pattern(&mut self) -> Result<()> {
+        self.surface.show_page(self.page, self.page_count() as u8)?;
+        self.shown_playhead = self.sequencer.playhead();
 
-        expect_no_resp!(midi_resp_rx);
-        expect_no_resp!(synth_ctrl_rx);
+        let steps_per_page = usize::from(self.surface.steps_per_page());
+        let first = usize::from(self.page) * steps_per_page;
+        for index in first..(first + steps_per_page).min(SEQUENCER_STEPS) {
+            self.show_step(index)?;
+        }
+
+        Ok(())
     }
 
-    #[test]
-    fn oscillator1_range_valid_positions() {
-        macro_rules! send_and_check {
-            (
-                $tx:ident, $tx_val:expr, $rx_midi:ident, $rx_val:expr, $rx_synth:ident, $range:expr
-            ) => {
-                send_cmd!(
-                    $tx,
-                    ControlChange::create(0, 0x30, $tx_val),
-                    MidiControllerType::ControlPanel
-                );
-                expect_resp!($rx_midi, ControlChange::create(0, 0x30, $rx_val));
-                expect_resp!(
-                    $rx_synth,
-                    SynthControl::Oscillator1Range((f64::from($range) / SAMPLE_RATE) as f32)
-                );
-            };
+    /// Shows a step if it is on the current page.
+    fn show_step(&self, index: usize) -> Result<()> {
+        let steps_per_page = usize::from(self.surface.steps_per_page());
+        if !self.sequencer_view || index / steps_per_page != usize::from(self.page) {
+            return Ok(());
         }
 
-        let (cmd, rsp, synth_rx) = setup_dispatcher!();
+        self.surface.show_step(
+            (index % steps_per_page) as u8,
+            &self.sequencer.pattern().steps[index],
+            self.shown_playhead == Some(index),
+        )
+    }
 
-        send_and_check!(cmd, 20, rsp, 21, synth_rx, &OscillatorRange::Low);
-        send_and_check!(cmd, 54, rsp, 54, synth_rx, &OscillatorRange::Range16ft);
-        send_and_check!(cmd, 72, rsp, 72, synth_rx, &OscillatorRange::Range8ft);
-        send_and_check!(cmd, 90, rsp, 90, synth_rx, &OscillatorRange::Range4ft);
-        send_and_check!(cmd, 110, rsp, 105, synth_rx, &OscillatorRange::Range2ft);
+    fn page_count(&self) -> usize {
+        let steps_per_page = usize::from(self.surface.steps_per_page()).max(1);
+        SEQUENCER_STEPS.div_ceil(steps_per_page)
     }
 
-    #[test]
-    fn oscillator1_range_invalid_positions() {
-        macro_rules! send_and_check_no_resp {
-            ($tx:ident, $val:expr, $rx_midi:ident, $rx_synth:ident) => {
-                send_cmd!(
-                    $tx,
-                    ControlChange::create(0, 0x30, $val),
-                    MidiControllerType::ControlPanel
-                );
-                expect_no_resp!($rx_midi);
-                expect_no_resp!($rx_synth);
-            };
+    /// Returns the index of a step of the current page.
+    fn step_index(&self, step_number: u8) -> usize {
+        usize::from(self.page) * usize::from(self.surface.steps_per_page())
+            + usize::from(step_number)
+    }
+
+    fn edit_step(&mut self, step_number: u8, field: StepField) -> Result<()> {
+        let index = self.step_index(step_number);
+        if index >= SEQUENCER_STEPS {
+            return Ok(());
         }
 
-        let (midi_cmd_tx, midi_resp_rx, synth_ctrl_rx) = setup_dispatcher!();
+        self.sequencer.edit(index, field);
+        if field == StepField::Gate {
+            self.held_step = Some(index);
+        }
 
-        send_and_check_no_resp!(midi_cmd_tx, 30, midi_resp_rx, synth_ctrl_rx);
-        send_and_check_no_resp!(midi_cmd_tx, 40, midi_resp_rx, synth_ctrl_rx);
-        send_and_check_no_resp!(midi_cmd_tx, 60, midi_resp_rx, synth_ctrl_rx);
-        send_and_check_no_resp!(midi_cmd_tx, 80, midi_resp_rx, synth_ctrl_rx);
-        send_and_check_no_resp!(midi_cmd_tx, 100, midi_resp_rx, synth_ctrl_rx);
+        self.show_step(index)?;
+        self.show_held_step()
     }
 
-    #[test]
-    fn oscillator1_range_update_only_if_different() {
-        let (midi_cmd_tx, midi_resp_rx, synth_ctrl_rx) = setup_dispatcher!();
+    fn toggle_step_note(&mut self, step_number: u8, note: u8) -> Result<()> {
+        let index = self.step_index(step_number);
+        if index >= SEQUENCER_STEPS {
+            return Ok(());
+        }
 
-        send_cmd!(
-            midi_cmd_tx,
-            ControlChange::create(0, 0x30, 54),
-            MidiControllerType::ControlPanel
-        );
-        expect_resp!(midi_resp_rx, ControlChange::create(0, 0x30, 54));
-        expect_resp!(
-            synth_ctrl_rx,
-            SynthControl::Oscillator1Range(
-                (f64::from(&OscillatorRange::Range16ft) / SAMPLE_RATE) as f32
-            )
-        );
+        self.sequencer.toggle_note(index, note);
+        self.held_step = Some(index);
 
-        send_cmd!(
-            midi_cmd_tx,
-            ControlChange::create(0, 0x30, 55),
+        self.show_step(index)?;
+        self.show_held_step()
+    }
+
+    fn show_held_step(&self) -> Result<()> {
+        let step = self
+            .held_step
+            .map(|index| &self.sequencer.pattern().steps[index]);
+        self.surface.show_held_step(step)
+    }
+
+    fn show_learn_state(&self) -> Result<()> {
+        self.surface
+            .show_learn_mode(self.learn_state != LearnState::Off)
+    }
+
+    fn send_synth_ctrl(&self, ctrl: SynthControl, timestamp: Instant) -> Result<()> {
+        let mut msg = (ctrl, timestamp);
+
+        // The synthesizer drains the queue once per audio buffer, so wait if it is full
+        loop {
+            match self.synth_ctrl_tx.try_send(msg) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Full(m)) => msg = m,
+                Err(TrySendError::Disconnected(_)) => {
+                    return Err(SynthControlChannelDisconnected.into())
+                }
+            }
+
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    /// Returns the frequency of a note as a ratio to middle C, or `None` if the tuning does not
+    /// play it.
+    fn calculate_note(&self, note_number: u8) -> Option<f32> {
+        self.tuning.note(note_number)
+    }
+
+    /// Returns the ids with which a playing note was sent to the voice groups of the
+    /// synthesizer.
+    fn sounding_notes(&self, note_number: Option<u8>) -> Vec<NoteId> {
+        self.sounding
+            .iter()
+            .filter(|(_, note, _)| Some(*note) == note_number)
+            .map(|(_, _, id)| *id)
+            .collect()
+    }
+
+    /// Loads the selected tuning program once it settled.
+    fn run_tuning(&mut self, now: Instant) {
+        if let Some((program, due)) = self.pending_tuning {
+            if due <= now {
+                self.pending_tuning = None;
+                self.load_tuning(program);
+            }
+        }
+    }
+
+    /// Switches to the tuning of a program of the tuning bank, or to equal temperament if there
+    /// is none. Playing notes keep their frequency until released.
+    fn load_tuning(&mut self, program: u8) {
+        self.tuning = match self.tunings.load(program) {
+            Ok(Some(tuning)) => {
+                println!("Loaded tuning {:03}", program);
+                tuning
+            }
+            Ok(None) => {
+                println!("No tuning {:03}, using equal temperament", program);
+                Tuning::default()
+            }
+            Err(e) => {
+                eprintln!("{}", e.display_chain());
+                Tuning::default()
+            }
+        };
+    }
+
+    /// Switches between playing the held keys and arpeggiating them.
+    fn enable_arpeggiator(&mut self, enabled: bool, timestamp: Instant) -> Result<()> {
+        if enabled == self.arpeggiator.is_enabled() {
+            return Ok(());
+        }
+
+        let held = self.arpeggiator.held_notes().to_vec();
+        if enabled {
+            for note_number in &held {
+                self.note_off(0, *note_number, timestamp)?;
+            }
+            self.arpeggiator.set_enabled(true, timestamp);
+            self.run_arpeggiator(timestamp)?;
+        } else {
+            self.arpeggiator.set_enabled(false, timestamp);
+            self.run_arpeggiator(timestamp)?;
+            for note_number in &held {
+                self.note_on(0, *note_number, false, timestamp)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Plays the notes of all zones that contain the key. The arpeggiator plays the notes of the
+    /// first voice group, the other voice groups play their notes directly.
+    fn key_pressed(
+        &mut self,
+        channel: u8,
+        key: u8,
+        velocity: u8,
+        timestamp: Instant,
+    ) -> Result<()> {
+        let mut held = vec![];
+        for zone in self.zones.find(channel, key, velocity) {
+            if let Some(note_number) = self.transpose(key, zone.transpose) {
+                held.push(HeldKey {
+                    channel,
+                    key,
+                    voice_group: zone.voice_group,
+                    note_number,
+                });
+            }
+        }
+        if held.is_empty() {
+            return Ok(());
+        }
+        self.played.extend_from_slice(&held);
+
+        if velocity != self.velocity {
+            self.velocity = velocity;
+            self.send_synth_ctrl(
+                SynthControl::Velocity(f32::from(velocity) / 127.0),
+                timestamp,
+            )?;
+        }
+
+        if let Some(index) = self.held_step {
+            self.sequencer.set_note(index, held[0].note_number);
+            self.show_step(index)?;
+        }
+
+        for held_key in &held {
+            if held_key.voice_group == 0 {
+                self.arpeggiator.note_on(held_key.note_number, timestamp);
+                if !self.arpeggiator.is_enabled() {
+                    self.note_on(0, held_key.note_number, false, timestamp)?;
+                }
+            } else {
+                self.note_on(held_key.voice_group, held_key.note_number, false, timestamp)?;
+            }
+        }
+
+        if self.arpeggiator.is_enabled() {
+            self.run_arpeggiator(timestamp)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the note that a key of the keyboard plays with the current octave shift and
+    /// transposition plus the transposition of its zone, or `None` if it is out of range.
+    fn transpose(&self, key: u8, zone_transpose: i8) -> Option<u8> {
+        let octaves = self.parameters.get(ParameterId::OctaveShift).round() as i32;
+        let semitones = self.parameters.get(ParameterId::Transpose).round() as i32;
+        let note = i32::from(key) + 12 * octaves + semitones + i32::from(zone_transpose);
+        if (0..128).contains(&note) {
+            Some(note as u8)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the note played by a held key (in its first zone).
+    fn played_note(&self, channel: u8, key: u8) -> Option<u8> {
+        self.played
+            .iter()
+            .find(|held| held.channel == channel && held.key == key)
+            .map(|held| held.note_number)
+    }
+
+    /// Assigns the note of a key to its MPE member channel and applies the expression the
+    /// channel already has (e.g. a pitch bend sent before the note).
+    fn start_member_note(&mut self, channel: u8, key: u8, timestamp: Instant) -> Result<()> {
+        let note_number = match self.played_note(channel, key) {
+            Some(note_number) => note_number,
+            None => return Ok(()),
+        };
+        let member = match self.mpe.member(channel) {
+            Some(member) => {
+                member.note = Some(note_number);
+                *member
+            }
+            None => return Ok(()),
+        };
+
+        for id in self.sounding_notes(Some(note_number)) {
+            if member.bend != 0.0 {
+                self.send_synth_ctrl(SynthControl::NotePitchBend(id, member.bend), timestamp)?;
+            }
+            if member.timbre != 0.0 {
+                self.send_synth_ctrl(SynthControl::NoteTimbre(id, member.timbre), timestamp)?;
+            }
+            if member.pressure != 0.0 {
+                self.send_synth_ctrl(SynthControl::KeyPressure(id, member.pressure), timestamp)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns true if a controller of the keyboard is bound to a parameter or being learned, so
+    /// that it does not have its usual meaning (e.g. the modulation wheel).
+    fn is_bound(&self, channel: u8, control_number: u8) -> bool {
+        let control = Control::Knob {
+            source: MidiControllerType::Keyboard,
+            channel: Some(channel),
+            control_number,
+        };
+        self.learn_state != LearnState::Off || self.mappings.find(&control).is_some()
+    }
+
+    fn key_released(&mut self, channel: u8, key: u8, timestamp: Instant) -> Result<()> {
+        let mut released = vec![];
+        self.played.retain(|held| {
+            let is_released = held.channel == channel && held.key == key;
+            if is_released {
+                released.push(*held);
+            }
+            !is_released
+        });
+
+        for held_key in &released {
+            if held_key.voice_group == 0 {
+                self.arpeggiator.note_off(held_key.note_number, timestamp);
+                if !self.arpeggiator.is_enabled() {
+                    self.note_off(0, held_key.note_number, timestamp)?;
+                }
+            } else {
+                self.note_off(held_key.voice_group, held_key.note_number, timestamp)?;
+            }
+        }
+
+        if self.arpeggiator.is_enabled() {
+            self.run_arpeggiator(timestamp)?;
+        }
+
+        Ok(())
+    }
+
+    /// Passes the tempo of the clock on to the arpeggiator, the sequencer and the synthesizer.
+    fn update_tempo(&mut self, timestamp: Instant) -> Result<()> {
+        let tempo = self.clock.tempo();
+        self.arpeggiator.set_tempo(tempo);
+        self.sequencer.set_tempo(tempo);
+
+        if tempo != self.synth_tempo {
+            self.synth_tempo = tempo;
+            self.send_synth_ctrl(SynthControl::Tempo(tempo), timestamp)?;
+        }
+
+        Ok(())
+    }
+
+    /// Sends the pulses of the internal clock that are due.
+    fn run_clock(&mut self, now: Instant) -> Result<()> {
+        for _ in self.clock.run(now) {
+            self.surface.send_clock(TimingClock::create())?;
+        }
+
+        Ok(())
+    }
+
+    /// Plays the notes of the arpeggiator that are due.
+    fn run_arpeggiator(&mut self, now: Instant) -> Result<()> {
+        let notes = self.arpeggiator.run(now);
+        self.play_arpeggiator_notes(notes)
+    }
+
+    fn play_arpeggiator_notes(&mut self, notes: Vec<(ArpeggiatorNote, Instant)>) -> Result<()> {
+        for (note, timestamp) in notes {
+            match note {
+                ArpeggiatorNote::On(note_number) => {
+                    self.note_on(0, note_number, false, timestamp)?
+                }
+                ArpeggiatorNote::Off(note_number) => self.note_off(0, note_number, timestamp)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Plays the steps of the sequencer that are due and moves the playhead.
+    fn run_sequencer(&mut self, now: Instant) -> Result<()> {
+        let notes = self.sequencer.run(now);
+        self.play_sequencer_notes(notes)
+    }
+
+    fn play_sequencer_notes(&mut self, notes: Vec<(SequencerNote, Instant)>) -> Result<()> {
+        for (note, timestamp) in notes {
+            match note {
+                SequencerNote::On { note, accent } => self.note_on(0, note, accent, timestamp)?,
+                SequencerNote::Off(note_number) => self.note_off(0, note_number, timestamp)?,
+            }
+        }
+
+        let playhead = self.sequencer.playhead();
+        if playhead != self.shown_playhead {
+            let previous = self.shown_playhead;
+            self.shown_playhead = playhead;
+            for index in previous.iter().chain(playhead.iter()) {
+                self.show_step(*index)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn note_on(
+        &mut self,
+        voice_group: usize,
+        note_number: u8,
+        accent: bool,
+        timestamp: Instant,
+    ) -> Result<()> {
+        let freq = match self.calculate_note(note_number) {
+            Some(freq) => freq,
+            None => return Ok(()),
+        };
+
+        if self.accent.get(voice_group) == Some(&!accent) {
+            self.accent[voice_group] = accent;
+            self.send_synth_ctrl(SynthControl::Accent(voice_group, accent), timestamp)?;
+        }
+        let id = self.next_note_id;
+        self.next_note_id = id.wrapping_add(1);
+        self.send_synth_ctrl(SynthControl::NoteOn(voice_group, id, freq), timestamp)?;
+        self.sounding.push((voice_group, note_number, id));
+
+        Ok(())
+    }
+
+    /// Releases a note by the id it was played with. Notes that are not sounding (e.g. because
+    /// all notes were released by a program change) are ignored.
+    fn note_off(&mut self, voice_group: usize, note_number: u8, timestamp: Instant) -> Result<()> {
+        let index = self
+            .sounding
+            .iter()
+            .position(|(group, note, _)| *group == voice_group && *note == note_number);
+        let id = match index {
+            Some(index) => self.sounding.remove(index).2,
+            None => return Ok(()),
+        };
+
+        self.send_synth_ctrl(SynthControl::NoteOff(voice_group, id), timestamp)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::path::Path;
+    use std::process;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use apc40_protocol::{Color, RingStyle};
+    use synth::control_surface::apc40::Apc40;
+    use synth::spsc::{self, Consumer};
+    use synth::synthesizer::CONTROL_QUEUE_CAPACITY;
+    use usb_midi::{
+        ChannelPressure, ControlChange, NoteOff, NoteOn, PitchBend, PolyphonicKeyPressure,
+        ProgramChange, Start, SystemExclusive, SystemExlusiveId,
+    };
+
+    const MIDDLE_C: f32 = 261.625_58;
+
+    // Buttons of the control panel ("Detail View", "Record", "Play" and "User")
+    const LEARN_BUTTON: u8 = 0x41;
+    const SAVE_PATCH_BUTTON: u8 = 0x5D;
+    const LOAD_PATCH_BUTTON: u8 = 0x5B;
+    const COARSE_TUNE_BUTTON: u8 = 0x59;
+    // "Device →" and "Bank →" buttons
+    const OCTAVE_UP_BUTTON: u8 = 0x3B;
+    const TRANSPOSE_UP_BUTTON: u8 = 0x3D;
+
+    fn recv_ctrl(rx: &Consumer<(SynthControl, Instant)>) -> Option<(SynthControl, Instant)> {
+        let deadline = Instant::now() + Duration::from_millis(100);
+        while Instant::now() < deadline {
+            if let Ok(ctrl) = rx.try_recv() {
+                return Some(ctrl);
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        None
+    }
+
+    macro_rules! setup_dispatcher {
+        () => {
+            setup_dispatcher!(PatchBank::new(None))
+        };
+        ($patches:expr) => {
+            setup_dispatcher!($patches, Zones::default())
+        };
+        ($patches:expr, $zones:expr) => {
+            setup_dispatcher!($patches, $zones, TuningBank::new(None))
+        };
+        ($patches:expr, $zones:expr, $tunings:expr) => {{
+            let (midi_cmd_tx, midi_cmd_rx) = mpsc::channel();
+            let (midi_resp_tx, midi_resp_rx) = mpsc::channel();
+            let (synth_ctrl_tx, synth_ctrl_rx) = spsc::channel(CONTROL_QUEUE_CAPACITY);
+
+            let mut dispatcher = Dispatcher::new(
+                midi_cmd_rx,
+                Apc40::new(midi_resp_tx),
+                synth_ctrl_tx,
+                Mappings::default(),
+                $patches,
+                Pattern::default(),
+                MpeZones::default(),
+                $tunings,
+                $zones,
+            );
+            let _dispatcher_thread = thread::spawn(move || dispatcher.start());
+
+            // Clear initialization messages
+            while midi_resp_rx
+                .recv_timeout(Duration::from_millis(100))
+                .is_ok()
+            {}
+            while let Some(_) = recv_ctrl(&synth_ctrl_rx) {}
+
+            (midi_cmd_tx, midi_resp_rx, synth_ctrl_rx)
+        }};
+    }
+
+    macro_rules! send_cmd {
+        ($tx:ident, $cmd:expr, $src:expr) => {
+            $tx.send(($cmd, $src, Instant::now())).unwrap();
+        };
+    }
+
+    macro_rules! expect_resp {
+        ($rx:ident, $resp:expr) => {
+            assert_eq!($rx.recv_timeout(Duration::from_millis(100)).unwrap(), $resp);
+        };
+    }
+
+    macro_rules! expect_ctrl {
+        ($rx:ident, $ctrl:expr) => {
+            assert_eq!(get_ctrl!($rx), $ctrl);
+        };
+    }
+
+    macro_rules! get_ctrl {
+        ($rx:ident) => {{
+            recv_ctrl(&$rx).unwrap().0
+        }};
+    }
+
+    macro_rules! expect_no_ctrl {
+        ($rx:ident) => {
+            assert!(recv_ctrl(&$rx).is_none());
+        };
+    }
+
+    macro_rules! expect_no_resp {
+        ($rx:ident) => {
+            assert!($rx.recv_timeout(Duration::from_millis(100)).is_err());
+        };
+    }
+
+    #[test]
+    fn master_tune() {
+        macro_rules! send_and_check {
+            ($tx:ident, $rx_midi:ident, $val:expr, $rx_synth:ident, $tune:expr, $eps:expr) => {
+                send_cmd!(
+                    $tx,
+                    ControlChange::create(0, 0x31, $val),
+                    MidiControllerType::ControlPanel
+                );
+                expect_resp!($rx_midi, ControlChange::create(0, 0x31, $val));
+
+                let tune = get_ctrl!($rx_synth);
+                let tune = match tune {
+                    SynthControl::MasterTune(tune) => tune,
+                    _ => panic!("wrong variant!"),
+                };
+                assert_float_eq!($tune, tune, $eps);
+            };
+        }
+
+        let (midi_cmd_tx, midi_rsp_rx, synth_ctrl_rx) = setup_dispatcher!();
+
+        send_and_check!(midi_cmd_tx, midi_rsp_rx, 0, synth_ctrl_rx, 0.865537, 1e-6);
+        send_and_check!(midi_cmd_tx, midi_rsp_rx, 32, synth_ctrl_rx, 0.930342, 1e-6);
+        send_and_check!(midi_cmd_tx, midi_rsp_rx, 64, synth_ctrl_rx, 1.0, 1e-6);
+        send_and_check!(midi_cmd_tx, midi_rsp_rx, 96, synth_ctrl_rx, 1.074873, 1e-6);
+        send_and_check!(midi_cmd_tx, midi_rsp_rx, 127, synth_ctrl_rx, 1.152749, 1e-6);
+    }
+
+    #[test]
+    fn fine_tune_changes_cents_within_semitone() {
+        let (midi_cmd_tx, midi_resp_rx, synth_ctrl_rx) = setup_dispatcher!();
+
+        // +62.5 cents
+        send_cmd!(
+            midi_cmd_tx,
+            ControlChange::create(0, 0x31, 80),
+            MidiControllerType::ControlPanel
+        );
+        expect_resp!(midi_resp_rx, ControlChange::create(0, 0x31, 80));
+        get_ctrl!(synth_ctrl_rx);
+
+        // The ring shows the deviation from the nearest semitone, the knob has to pick it up
+        send_cmd!(
+            midi_cmd_tx,
+            NoteOn::create(0, COARSE_TUNE_BUTTON, 0x7F),
+            MidiControllerType::ControlPanel
+        );
+        let mut responses = vec![];
+        while let Ok(resp) = midi_resp_rx.recv_timeout(Duration::from_millis(100)) {
+            responses.push(resp);
+        }
+        assert!(responses.contains(&NoteOn::create(0, COARSE_TUNE_BUTTON, 0x00)));
+        assert!(responses.contains(&ControlChange::create(0, 0x31, 16)));
+        assert!(responses.contains(&ControlChange::create(0, 0x39, RingStyle::Volume as u8)));
+        expect_no_ctrl!(synth_ctrl_rx);
+
+        send_cmd!(
+            midi_cmd_tx,
+            ControlChange::create(0, 0x31, 16),
+            MidiControllerType::ControlPanel
+        );
+        expect_resp!(
+            midi_resp_rx,
+            ControlChange::create(0, 0x39, RingStyle::Single as u8)
+        );
+        expect_no_ctrl!(synth_ctrl_rx);
+
+        // +75 cents
+        send_cmd!(
+            midi_cmd_tx,
+            ControlChange::create(0, 0x31, 32),
+            MidiControllerType::ControlPanel
+        );
+        expect_resp!(midi_resp_rx, ControlChange::create(0, 0x31, 32));
+        match get_ctrl!(synth_ctrl_rx) {
+            SynthControl::MasterTune(tune) => {
+                assert_float_eq!(2.0_f32.powf(75.0 / 1200.0), tune, 1e-6)
+            }
+            _ => panic!("wrong variant!"),
+        }
+
+        // Coarse tuning shows the deviation over the full range again
+        send_cmd!(
+            midi_cmd_tx,
+            NoteOn::create(0, COARSE_TUNE_BUTTON, 0x7F),
+            MidiControllerType::ControlPanel
+        );
+        let mut responses = vec![];
+        while let Ok(resp) = midi_resp_rx.recv_timeout(Duration::from_millis(100)) {
+            responses.push(resp);
+        }
+        assert!(responses.contains(&NoteOn::create(0, COARSE_TUNE_BUTTON, 0x7F)));
+        assert!(responses.contains(&ControlChange::create(0, 0x31, 83)));
+        expect_no_ctrl!(synth_ctrl_rx);
+    }
+
+    #[test]
+    fn master_tune_update_only_when_different() {
+        let (midi_cmd_tx, midi_resp_rx, synth_ctrl_rx) = setup_dispatcher!();
+
+        send_cmd!(
+            midi_cmd_tx,
+            ControlChange::create(0, 0x31, 32),
+            MidiControllerType::ControlPanel
+        );
+
+        expect_resp!(midi_resp_rx, ControlChange::create(0, 0x31, 32));
+
+        let tune = get_ctrl!(synth_ctrl_rx);
+        let tune = match tune {
+            SynthControl::MasterTune(tune) => tune,
+            _ => panic!("wrong variant!"),
+        };
+        assert_float_eq!(tune, 0.930342, 1e-6);
+
+        send_cmd!(
+            midi_cmd_tx,
+            ControlChange::create(0, 0x31, 32),
+            MidiControllerType::ControlPanel
+        );
+
+        expect_no_resp!(midi_resp_rx);
+        expect_no_ctrl!(synth_ctrl_rx);
+    }
+
+    #[test]
+    fn oscillator1_range_valid_positions() {
+        macro_rules! send_and_check {
+            (
+                $tx:ident, $tx_val:expr, $rx_midi:ident, $rx_val:expr, $rx_synth:ident, $range:expr
+            ) => {
+                send_cmd!(
+                    $tx,
+                    ControlChange::create(0, 0x30, $tx_val),
+                    MidiControllerType::ControlPanel
+                );
+                expect_resp!($rx_midi, ControlChange::create(0, 0x30, $rx_val));
+                expect_ctrl!($rx_synth, SynthControl::Oscillator1Range(0, $range));
+            };
+        }
+
+        let (cmd, rsp, synth_rx) = setup_dispatcher!();
+
+        send_and_check!(cmd, 20, rsp, 21, synth_rx, 0.0625 * MIDDLE_C);
+        send_and_check!(cmd, 54, rsp, 54, synth_rx, 0.5 * MIDDLE_C);
+        send_and_check!(cmd, 72, rsp, 72, synth_rx, MIDDLE_C);
+        send_and_check!(cmd, 90, rsp, 90, synth_rx, 2.0 * MIDDLE_C);
+        send_and_check!(cmd, 110, rsp, 105, synth_rx, 4.0 * MIDDLE_C);
+    }
+
+    #[test]
+    fn oscillator1_range_invalid_positions() {
+        macro_rules! send_and_check_no_resp {
+            ($tx:ident, $val:expr, $rx_midi:ident, $rx_synth:ident) => {
+                send_cmd!(
+                    $tx,
+                    ControlChange::create(0, 0x30, $val),
+                    MidiControllerType::ControlPanel
+                );
+                expect_no_resp!($rx_midi);
+                expect_no_ctrl!($rx_synth);
+            };
+        }
+
+        let (midi_cmd_tx, midi_resp_rx, synth_ctrl_rx) = setup_dispatcher!();
+
+        send_and_check_no_resp!(midi_cmd_tx, 30, midi_resp_rx, synth_ctrl_rx);
+        send_and_check_no_resp!(midi_cmd_tx, 40, midi_resp_rx, synth_ctrl_rx);
+        send_and_check_no_resp!(midi_cmd_tx, 60, midi_resp_rx, synth_ctrl_rx);
+        send_and_check_no_resp!(midi_cmd_tx, 80, midi_resp_rx, synth_ctrl_rx);
+        send_and_check_no_resp!(midi_cmd_tx, 100, midi_resp_rx, synth_ctrl_rx);
+    }
+
+    #[test]
+    fn oscillator1_range_update_only_if_different() {
+        let (midi_cmd_tx, midi_resp_rx, synth_ctrl_rx) = setup_dispatcher!();
+
+        send_cmd!(
+            midi_cmd_tx,
+            ControlChange::create(0, 0x30, 54),
+            MidiControllerType::ControlPanel
+        );
+        expect_resp!(midi_resp_rx, ControlChange::create(0, 0x30, 54));
+        expect_ctrl!(
+            synth_ctrl_rx,
+            SynthControl::Oscillator1Range(0, 0.5 * MIDDLE_C)
+        );
+
+        send_cmd!(
+            midi_cmd_tx,
+            ControlChange::create(0, 0x30, 55),
+            MidiControllerType::ControlPanel
+        );
+        expect_no_resp!(midi_resp_rx);
+        expect_no_ctrl!(synth_ctrl_rx);
+    }
+
+    #[test]
+    fn oscillator1_enable_disable() {
+        let (midi_cmd_tx, midi_resp_rx, synth_ctrl_rx) = setup_dispatcher!();
+
+        send_cmd!(
+            midi_cmd_tx,
+            NoteOn::create(0, 0x33, 0x7F),
+            MidiControllerType::ControlPanel
+        );
+        expect_resp!(midi_resp_rx, NoteOn::create(0, 0x33, 0x00));
+        expect_ctrl!(synth_ctrl_rx, SynthControl::Oscillator1Enable(0, false));
+
+        send_cmd!(
+            midi_cmd_tx,
+            NoteOn::create(0, 0x33, 0x7F),
+            MidiControllerType::ControlPanel
+        );
+        expect_resp!(midi_resp_rx, NoteOn::create(0, 0x33, 0x7F));
+        expect_ctrl!(synth_ctrl_rx, SynthControl::Oscillator1Enable(0, true));
+    }
+
+    #[test]
+    fn oscillator1_enable_only_reacts_to_track_select_1() {
+        let (midi_cmd_tx, midi_resp_rx, synth_ctrl_rx) = setup_dispatcher!();
+
+        send_cmd!(
+            midi_cmd_tx,
+            NoteOn::create(1, 0x33, 0x7F),
+            MidiControllerType::ControlPanel
+        );
+        expect_no_resp!(midi_resp_rx);
+        expect_no_ctrl!(synth_ctrl_rx);
+    }
+
+    #[test]
+    fn oscillator1_volume() {
+        let (midi_cmd_tx, midi_resp_rx, synth_ctrl_rx) = setup_dispatcher!();
+
+        send_cmd!(
+            midi_cmd_tx,
+            ControlChange::create(0, 0x07, 0x7F),
+            MidiControllerType::ControlPanel
+        );
+        expect_no_resp!(midi_resp_rx);
+        expect_ctrl!(synth_ctrl_rx, SynthControl::Oscillator1Volume(0, 1.0));
+
+        send_cmd!(
+            midi_cmd_tx,
+            ControlChange::create(0, 0x07, 0x3F),
+            MidiControllerType::ControlPanel
+        );
+        expect_no_resp!(midi_resp_rx);
+        expect_ctrl!(
+            synth_ctrl_rx,
+            SynthControl::Oscillator1Volume(0, 0.05497402)
+        );
+
+        send_cmd!(
+            midi_cmd_tx,
+            ControlChange::create(0, 0x07, 0x00),
+            MidiControllerType::ControlPanel
+        );
+        expect_no_resp!(midi_resp_rx);
+        expect_ctrl!(
+            synth_ctrl_rx,
+            SynthControl::Oscillator1Volume(0, 0.0031622776)
+        );
+    }
+
+    #[test]
+    fn second_voice_group_on_third_track() {
+        let (midi_cmd_tx, midi_resp_rx, synth_ctrl_rx) = setup_dispatcher!();
+
+        send_cmd!(
+            midi_cmd_tx,
+            ControlChange::create(0, 0x32, 54),
+            MidiControllerType::ControlPanel
+        );
+        expect_resp!(midi_resp_rx, ControlChange::create(0, 0x32, 54));
+        expect_ctrl!(
+            synth_ctrl_rx,
+            SynthControl::Oscillator1Range(1, 0.5 * MIDDLE_C)
+        );
+
+        send_cmd!(
+            midi_cmd_tx,
+            NoteOn::create(2, 0x33, 0x7F),
+            MidiControllerType::ControlPanel
+        );
+        expect_resp!(midi_resp_rx, NoteOn::create(2, 0x33, 0x00));
+        expect_ctrl!(synth_ctrl_rx, SynthControl::Oscillator1Enable(1, false));
+
+        send_cmd!(
+            midi_cmd_tx,
+            ControlChange::create(2, 0x07, 0x7F),
+            MidiControllerType::ControlPanel
+        );
+        expect_no_resp!(midi_resp_rx);
+        expect_ctrl!(synth_ctrl_rx, SynthControl::Oscillator1Volume(1, 1.0));
+    }
+
+    #[test]
+    fn master_volume() {
+        let (midi_cmd_tx, midi_resp_rx, synth_ctrl_rx) = setup_dispatcher!();
+
+        send_cmd!(
+            midi_cmd_tx,
+            ControlChange::create(0, 0x0E, 0x00),
+            MidiControllerType::ControlPanel
+        );
+        expect_no_resp!(midi_resp_rx);
+        expect_ctrl!(synth_ctrl_rx, SynthControl::MasterVolume(0.0031622776));
+
+        send_cmd!(
+            midi_cmd_tx,
+            ControlChange::create(0, 0x0E, 0x7F),
+            MidiControllerType::ControlPanel
+        );
+        expect_no_resp!(midi_resp_rx);
+        expect_ctrl!(synth_ctrl_rx, SynthControl::MasterVolume(1.0));
+    }
+
+    #[test]
+    fn oscillator1_volume_only_reacts_to_track_fader_1() {
+        let (midi_cmd_tx, midi_resp_rx, synth_ctrl_rx) = setup_dispatcher!();
+
+        send_cmd!(
+            midi_cmd_tx,
+            ControlChange::create(1, 0x07, 0x7F),
+            MidiControllerType::ControlPanel
+        );
+        expect_no_resp!(midi_resp_rx);
+        expect_no_ctrl!(synth_ctrl_rx);
+    }
+
+    #[test]
+    fn keyboard_play_notes() {
+        macro_rules! send_and_check {
+            ($tx:ident, $note:expr, $midi_rx:ident, $synth_rx:ident, $expected:expr, $eps:expr) => {
+                send_cmd!(
+                    $tx,
+                    NoteOn::create(0, $note, 127),
+                    MidiControllerType::Keyboard
+                );
+                expect_no_resp!($midi_rx);
+                let note = match get_ctrl!($synth_rx) {
+                    SynthControl::NoteOn(0, _, note) => note,
+                    _ => panic!("wrong variant!"),
+                };
+                assert_float_eq!($expected, note, $eps);
+            };
+        }
+
+        let (midi_cmd_tx, midi_resp_rx, synth_ctrl_rx) = setup_dispatcher!();
+
+        send_and_check!(midi_cmd_tx, 60, midi_resp_rx, synth_ctrl_rx, 1.0, 1e-6);
+        send_and_check!(midi_cmd_tx, 72, midi_resp_rx, synth_ctrl_rx, 2.0, 1e-6);
+        send_and_check!(midi_cmd_tx, 48, midi_resp_rx, synth_ctrl_rx, 0.5, 1e-6);
+        send_and_check!(midi_cmd_tx, 84, midi_resp_rx, synth_ctrl_rx, 4.0, 1e-6);
+        send_and_check!(midi_cmd_tx, 36, midi_resp_rx, synth_ctrl_rx, 0.25, 1e-6);
+    }
+
+    #[test]
+    fn keyboard_release_notes() {
+        macro_rules! send_and_check {
+            ($tx:ident, $note:expr, $midi_rx:ident, $synth_rx:ident, $expected:expr, $eps:expr) => {
+                send_cmd!(
+                    $tx,
+                    NoteOn::create(0, $note, 127),
+                    MidiControllerType::Keyboard
+                );
+                let id = match get_ctrl!($synth_rx) {
+                    SynthControl::NoteOn(0, id, note) => {
+                        assert_float_eq!($expected, note, $eps);
+                        id
+                    }
+                    _ => panic!("wrong variant!"),
+                };
+                send_cmd!(
+                    $tx,
+                    NoteOff::create(0, $note, 127),
+                    MidiControllerType::Keyboard
+                );
+                expect_no_resp!($midi_rx);
+                assert_eq!(SynthControl::NoteOff(0, id), get_ctrl!($synth_rx));
+            };
+        }
+
+        let (midi_cmd_tx, midi_resp_rx, synth_ctrl_rx) = setup_dispatcher!();
+
+        send_and_check!(midi_cmd_tx, 60, midi_resp_rx, synth_ctrl_rx, 1.0, 1e-6);
+        send_and_check!(midi_cmd_tx, 72, midi_resp_rx, synth_ctrl_rx, 2.0, 1e-6);
+        send_and_check!(midi_cmd_tx, 48, midi_resp_rx, synth_ctrl_rx, 0.5, 1e-6);
+        send_and_check!(midi_cmd_tx, 84, midi_resp_rx, synth_ctrl_rx, 4.0, 1e-6);
+        send_and_check!(midi_cmd_tx, 36, midi_resp_rx, synth_ctrl_rx, 0.25, 1e-6);
+
+        // Keys that were not pressed (e.g. before a restart) release nothing
+        send_cmd!(
+            midi_cmd_tx,
+            NoteOff::create(0, 60, 127),
+            MidiControllerType::Keyboard
+        );
+        expect_no_ctrl!(synth_ctrl_rx);
+    }
+
+    #[test]
+    fn learn_knob() {
+        let (midi_cmd_tx, midi_resp_rx, synth_ctrl_rx) = setup_dispatcher!();
+
+        send_cmd!(
+            midi_cmd_tx,
+            NoteOn::create(0, LEARN_BUTTON, 0x7F),
+            MidiControllerType::ControlPanel
+        );
+        expect_resp!(midi_resp_rx, NoteOn::create(0, LEARN_BUTTON, 0x7F));
+
+        // Arm master tune, then map it to the first device knob
+        send_cmd!(
+            midi_cmd_tx,
+            ControlChange::create(0, 0x31, 80),
+            MidiControllerType::ControlPanel
+        );
+        send_cmd!(
+            midi_cmd_tx,
+            ControlChange::create(0, 0x31, 81),
+            MidiControllerType::ControlPanel
+        );
+        send_cmd!(
+            midi_cmd_tx,
+            ControlChange::create(2, 0x10, 96),
+            MidiControllerType::ControlPanel
+        );
+        expect_no_ctrl!(synth_ctrl_rx);
+        expect_resp!(midi_resp_rx, ControlChange::create(0, 0x39, 0));
+        expect_resp!(midi_resp_rx, NoteOn::create(0, LEARN_BUTTON, 0x00));
+        while midi_resp_rx
+            .recv_timeout(Duration::from_millis(100))
+            .is_ok()
+        {}
+
+        send_cmd!(
+            midi_cmd_tx,
+            ControlChange::create(2, 0x10, 96),
+            MidiControllerType::ControlPanel
+        );
+        expect_resp!(midi_resp_rx, ControlChange::create(2, 0x10, 96));
+        match get_ctrl!(synth_ctrl_rx) {
+            SynthControl::MasterTune(tune) => assert_float_eq!(1.074873, tune, 1e-6),
+            _ => panic!("wrong variant!"),
+        }
+
+        // Previous knob is no longer mapped
+        send_cmd!(
+            midi_cmd_tx,
+            ControlChange::create(0, 0x31, 32),
+            MidiControllerType::ControlPanel
+        );
+        expect_no_resp!(midi_resp_rx);
+        expect_no_ctrl!(synth_ctrl_rx);
+    }
+
+    #[test]
+    fn learn_keyboard_key() {
+        let (midi_cmd_tx, midi_resp_rx, synth_ctrl_rx) = setup_dispatcher!();
+
+        send_cmd!(
+            midi_cmd_tx,
+            NoteOn::create(0, LEARN_BUTTON, 0x7F),
+            MidiControllerType::ControlPanel
+        );
+        send_cmd!(
+            midi_cmd_tx,
+            NoteOn::create(0, 0x33, 0x7F),
+            MidiControllerType::ControlPanel
+        );
+        send_cmd!(
+            midi_cmd_tx,
+            NoteOn::create(0, 36, 0x7F),
+            MidiControllerType::Keyboard
+        );
+        expect_no_ctrl!(synth_ctrl_rx);
+        while midi_resp_rx
+            .recv_timeout(Duration::from_millis(100))
+            .is_ok()
+        {}
+
+        // Key toggles oscillator 1 instead of playing a note
+        send_cmd!(
+            midi_cmd_tx,
+            NoteOn::create(0, 36, 0x7F),
+            MidiControllerType::Keyboard
+        );
+        expect_ctrl!(synth_ctrl_rx, SynthControl::Oscillator1Enable(0, false));
+        send_cmd!(
+            midi_cmd_tx,
+            NoteOff::create(0, 36, 0x7F),
+            MidiControllerType::Keyboard
+        );
+        expect_no_ctrl!(synth_ctrl_rx);
+
+        // Other keys still play notes
+        send_cmd!(
+            midi_cmd_tx,
+            NoteOn::create(0, 60, 0x7F),
+            MidiControllerType::Keyboard
+        );
+        expect_ctrl!(synth_ctrl_rx, SynthControl::NoteOn(0, 0, 1.0));
+    }
+
+    #[test]
+    fn learn_mode_can_be_cancelled() {
+        let (midi_cmd_tx, midi_resp_rx, synth_ctrl_rx) = setup_dispatcher!();
+
+        send_cmd!(
+            midi_cmd_tx,
+            NoteOn::create(0, LEARN_BUTTON, 0x7F),
+            MidiControllerType::ControlPanel
+        );
+        expect_resp!(midi_resp_rx, NoteOn::create(0, LEARN_BUTTON, 0x7F));
+        send_cmd!(
+            midi_cmd_tx,
+            NoteOn::create(0, LEARN_BUTTON, 0x7F),
+            MidiControllerType::ControlPanel
+        );
+        expect_resp!(midi_resp_rx, NoteOn::create(0, LEARN_BUTTON, 0x00));
+
+        send_cmd!(
+            midi_cmd_tx,
+            ControlChange::create(0, 0x07, 0x7F),
+            MidiControllerType::ControlPanel
+        );
+        expect_ctrl!(synth_ctrl_rx, SynthControl::Oscillator1Volume(0, 1.0));
+    }
+
+    #[test]
+    fn save_and_load_patch() {
+        let dir = env::temp_dir().join(format!("midi-synth-dispatcher-test-{}", process::id()));
+        let (midi_cmd_tx, midi_resp_rx, synth_ctrl_rx) =
+            setup_dispatcher!(PatchBank::new(Some(dir.clone())));
+
+        send_cmd!(
+            midi_cmd_tx,
+            ControlChange::create(0, 0x07, 0x7F),
+            MidiControllerType::ControlPanel
+        );
+        expect_ctrl!(synth_ctrl_rx, SynthControl::Oscillator1Volume(0, 1.0));
+        send_cmd!(
+            midi_cmd_tx,
+            NoteOn::create(0, SAVE_PATCH_BUTTON, 0x7F),
+            MidiControllerType::ControlPanel
+        );
+        while midi_resp_rx
+            .recv_timeout(Duration::from_millis(100))
+            .is_ok()
+        {}
+        send_cmd!(
+            midi_cmd_tx,
+            ControlChange::create(0, 0x07, 0x40),
+            MidiControllerType::ControlPanel
+        );
+        get_ctrl!(synth_ctrl_rx);
+        send_cmd!(
+            midi_cmd_tx,
+            ControlChange::create(0, 0x31, 96),
+            MidiControllerType::ControlPanel
+        );
+        expect_resp!(midi_resp_rx, ControlChange::create(0, 0x31, 96));
+        get_ctrl!(synth_ctrl_rx);
+        assert!(dir.join("000").join("000.patch").exists());
+
+        // Loading sends the changed values to the synth and the knob positions to the controller
+        send_cmd!(
+            midi_cmd_tx,
+            NoteOn::create(0, LOAD_PATCH_BUTTON, 0x7F),
+            MidiControllerType::ControlPanel
+        );
+        expect_ctrl!(synth_ctrl_rx, SynthControl::MasterTune(1.0));
+        expect_ctrl!(synth_ctrl_rx, SynthControl::Oscillator1Volume(0, 1.0));
+        expect_no_ctrl!(synth_ctrl_rx);
+
+        let tune_position = TuneMode::Coarse.value_to_controller(1.0);
+        let mut responses = vec![];
+        while let Ok(resp) = midi_resp_rx.recv_timeout(Duration::from_millis(100)) {
+            responses.push(resp);
+        }
+        assert!(responses.contains(&ControlChange::create(0, 0x31, tune_position)));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn save_test_patch(dir: &Path, bank: u16, program: u8, volume: f32) {
+        let mut values = ParameterValues::default();
+        values.set(ParameterId::Oscillator1Volume, volume);
+        PatchBank::new(Some(dir.to_path_buf()))
+            .save(bank, program, &Patch { values })
+            .unwrap();
+    }
+
+    #[test]
+    fn program_change_with_bank_select() {
+        let dir = env::temp_dir().join(format!("midi-synth-program-test-{}", process::id()));
+        save_test_patch(&dir, 0, 5, 0.25);
+        save_test_patch(&dir, 130, 5, 0.5);
+        let (midi_cmd_tx, _midi_resp_rx, synth_ctrl_rx) =
+            setup_dispatcher!(PatchBank::new(Some(dir.clone())));
+
+        send_cmd!(
+            midi_cmd_tx,
+            ProgramChange::create(0, 5),
+            MidiControllerType::Keyboard
+        );
+        expect_ctrl!(synth_ctrl_rx, SynthControl::AllNotesOff);
+        expect_ctrl!(synth_ctrl_rx, SynthControl::Oscillator1Volume(0, 0.25));
+
+        // Bank select only takes effect with the next program change
+        send_cmd!(
+            midi_cmd_tx,
+            ControlChange::create(0, BANK_SELECT_MSB, 1),
+            MidiControllerType::Keyboard
+        );
+        send_cmd!(
+            midi_cmd_tx,
+            ControlChange::create(0, BANK_SELECT_LSB, 2),
+            MidiControllerType::Keyboard
+        );
+        expect_no_ctrl!(synth_ctrl_rx);
+        send_cmd!(
+            midi_cmd_tx,
+            ProgramChange::create(0, 5),
+            MidiControllerType::Keyboard
+        );
+        expect_ctrl!(synth_ctrl_rx, SynthControl::AllNotesOff);
+        expect_ctrl!(synth_ctrl_rx, SynthControl::Oscillator1Volume(0, 0.5));
+
+        // Empty programs keep the current sound and the playing notes
+        send_cmd!(
+            midi_cmd_tx,
+            ProgramChange::create(0, 6),
+            MidiControllerType::Keyboard
+        );
+        expect_no_ctrl!(synth_ctrl_rx);
+
+        // Other applications may select patches on the port of the control panel
+        send_cmd!(
+            midi_cmd_tx,
+            ControlChange::create(0, BANK_SELECT_LSB, 0),
+            MidiControllerType::ControlPanel
+        );
+        send_cmd!(
+            midi_cmd_tx,
+            ControlChange::create(0, BANK_SELECT_MSB, 0),
+            MidiControllerType::ControlPanel
+        );
+        send_cmd!(
+            midi_cmd_tx,
+            ProgramChange::create(0, 5),
+            MidiControllerType::ControlPanel
+        );
+        expect_ctrl!(synth_ctrl_rx, SynthControl::AllNotesOff);
+        expect_ctrl!(synth_ctrl_rx, SynthControl::Oscillator1Volume(0, 0.25));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn select_bank_on_clip_grid() {
+        let dir = env::temp_dir().join(format!("midi-synth-grid-test-{}", process::id()));
+        save_test_patch(&dir, 1, 0, 0.5);
+        let (midi_cmd_tx, midi_resp_rx, synth_ctrl_rx) =
+            setup_dispatcher!(PatchBank::new(Some(dir.clone())));
+
+        // Second button of the top row of banks
+        send_cmd!(
+            midi_cmd_tx,
+            NoteOn::create(0, 35, 0x7F),
+            MidiControllerType::ControlPanel
+        );
+        expect_ctrl!(synth_ctrl_rx, SynthControl::AllNotesOff);
+        expect_ctrl!(synth_ctrl_rx, SynthControl::Oscillator1Volume(0, 0.5));
+
+        let mut responses = vec![];
+        while let Ok(resp) = midi_resp_rx.recv_timeout(Duration::from_millis(100)) {
+            responses.push(resp);
+        }
+        assert!(responses.contains(&NoteOn::create(0, 35, Color::GREEN.0)));
+        assert!(responses.contains(&NoteOn::create(0, 34, Color::BLACK.0)));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn knob_picks_up_loaded_value() {
+        let dir = env::temp_dir().join(format!("midi-synth-pickup-test-{}", process::id()));
+        save_test_patch(&dir, 0, 0, 0.5);
+        let (midi_cmd_tx, midi_resp_rx, synth_ctrl_rx) =
+            setup_dispatcher!(PatchBank::new(Some(dir.clone())));
+
+        send_cmd!(
+            midi_cmd_tx,
+            ControlChange::create(0, 0x31, 100),
+            MidiControllerType::ControlPanel
+        );
+        expect_resp!(midi_resp_rx, ControlChange::create(0, 0x31, 100));
+        get_ctrl!(synth_ctrl_rx);
+
+        // Loading resets master tune, the knob is still at 100
+        send_cmd!(
+            midi_cmd_tx,
+            NoteOn::create(0, LOAD_PATCH_BUTTON, 0x7F),
+            MidiControllerType::ControlPanel
+        );
+        expect_ctrl!(synth_ctrl_rx, SynthControl::MasterTune(1.0));
+        let mut responses = vec![];
+        while let Ok(resp) = midi_resp_rx.recv_timeout(Duration::from_millis(100)) {
+            responses.push(resp);
+        }
+        assert!(responses.contains(&ControlChange::create(0, 0x39, RingStyle::Volume as u8)));
+
+        send_cmd!(
+            midi_cmd_tx,
+            ControlChange::create(0, 0x31, 110),
+            MidiControllerType::ControlPanel
+        );
+        send_cmd!(
+            midi_cmd_tx,
+            ControlChange::create(0, 0x31, 80),
+            MidiControllerType::ControlPanel
+        );
+        expect_no_ctrl!(synth_ctrl_rx);
+        expect_no_resp!(midi_resp_rx);
+
+        // Knob passes the loaded value
+        let tune_position = TuneMode::Coarse.value_to_controller(1.0);
+        send_cmd!(
+            midi_cmd_tx,
+            ControlChange::create(0, 0x31, tune_position - 4),
+            MidiControllerType::ControlPanel
+        );
+        expect_resp!(
+            midi_resp_rx,
+            ControlChange::create(0, 0x31, tune_position - 4)
+        );
+        expect_resp!(
+            midi_resp_rx,
+            ControlChange::create(0, 0x39, RingStyle::Single as u8)
+        );
+        get_ctrl!(synth_ctrl_rx);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn knob_picks_up_automated_value() {
+        let (midi_cmd_tx, midi_resp_rx, synth_ctrl_rx) = setup_dispatcher!();
+
+        send_cmd!(
+            midi_cmd_tx,
+            ControlChange::create(0, 0x31, 100),
+            MidiControllerType::Automation
+        );
+        get_ctrl!(synth_ctrl_rx);
+        let mut responses = vec![];
+        while let Ok(resp) = midi_resp_rx.recv_timeout(Duration::from_millis(100)) {
+            responses.push(resp);
+        }
+        assert!(responses.contains(&ControlChange::create(0, 0x31, 100)));
+        assert!(responses.contains(&ControlChange::create(0, 0x39, RingStyle::Volume as u8)));
+
+        // The knob did not move along
+        send_cmd!(
+            midi_cmd_tx,
+            ControlChange::create(0, 0x31, 20),
+            MidiControllerType::ControlPanel
+        );
+        expect_no_ctrl!(synth_ctrl_rx);
+        expect_no_resp!(midi_resp_rx);
+    }
+
+    #[test]
+    fn timestamp_is_passed_on_to_synth() {
+        let (midi_cmd_tx, midi_resp_rx, synth_ctrl_rx) = setup_dispatcher!();
+
+        let timestamp = Instant::now();
+        midi_cmd_tx
+            .send((
+                ControlChange::create(0, 0x31, 96),
+                MidiControllerType::ControlPanel,
+                timestamp,
+            ))
+            .unwrap();
+        expect_resp!(midi_resp_rx, ControlChange::create(0, 0x31, 96));
+
+        let (_, synth_timestamp) = recv_ctrl(&synth_ctrl_rx).unwrap();
+        assert_eq!(timestamp, synth_timestamp);
+
+        let timestamp = Instant::now();
+        midi_cmd_tx
+            .send((
+                NoteOn::create(0, 60, 127),
+                MidiControllerType::Keyboard,
+                timestamp,
+            ))
+            .unwrap();
+        assert_eq!(
+            Some((SynthControl::NoteOn(0, 0, 1.0), timestamp)),
+            recv_ctrl(&synth_ctrl_rx)
+        );
+    }
+
+    #[test]
+    fn arpeggiator_plays_held_keys() {
+        let (midi_cmd_tx, midi_resp_rx, synth_ctrl_rx) = setup_dispatcher!();
+
+        // "Device On/Off" button
+        send_cmd!(
+            midi_cmd_tx,
+            NoteOn::create(0, 0x3E, 0x7F),
+            MidiControllerType::ControlPanel
+        );
+        expect_resp!(midi_resp_rx, NoteOn::create(0, 0x3E, 0x7F));
+
+        for note_number in &[60, 72] {
+            send_cmd!(
+                midi_cmd_tx,
+                NoteOn::create(0, *note_number, 0x7F),
+                MidiControllerType::Keyboard
+            );
+        }
+
+        // 1/16 notes at 120 BPM, half a step long
+        let (first, first_timestamp) = recv_ctrl(&synth_ctrl_rx).unwrap();
+        assert_eq!(SynthControl::NoteOn(0, 0, 1.0), first);
+        let (second, second_timestamp) = recv_ctrl(&synth_ctrl_rx).unwrap();
+        assert_eq!(SynthControl::NoteOff(0, 0), second);
+        assert_eq!(
+            Duration::from_micros(62_500),
+            second_timestamp - first_timestamp
+        );
+        let (third, third_timestamp) = recv_ctrl(&synth_ctrl_rx).unwrap();
+        assert_eq!(SynthControl::NoteOn(0, 1, 2.0), third);
+        assert_eq!(
+            Duration::from_millis(125),
+            third_timestamp - first_timestamp
+        );
+
+        for note_number in &[60, 72] {
+            send_cmd!(
+                midi_cmd_tx,
+                NoteOff::create(0, *note_number, 0x7F),
+                MidiControllerType::Keyboard
+            );
+        }
+        expect_ctrl!(synth_ctrl_rx, SynthControl::NoteOff(0, 1));
+        expect_no_ctrl!(synth_ctrl_rx);
+    }
+
+    #[test]
+    fn sequencer_plays_programmed_steps() {
+        let (midi_cmd_tx, midi_resp_rx, synth_ctrl_rx) = setup_dispatcher!();
+
+        // "Session" button shows the sequencer on the clip grid
+        send_cmd!(
+            midi_cmd_tx,
+            NoteOn::create(0, 0x66, 0x7F),
+            MidiControllerType::ControlPanel
+        );
+        while midi_resp_rx
+            .recv_timeout(Duration::from_millis(100))
+            .is_ok()
+        {}
+
+        // Hold the gate of the first step and set its note with a key
+        send_cmd!(
+            midi_cmd_tx,
+            NoteOn::create(0, 32, 0x7F),
             MidiControllerType::ControlPanel
         );
-        expect_no_resp!(midi_resp_rx);
-        expect_no_resp!(synth_ctrl_rx);
+        expect_resp!(midi_resp_rx, NoteOn::create(0, 32, Color::GREEN.0));
+        send_cmd!(
+            midi_cmd_tx,
+            NoteOn::create(0, 72, 0x7F),
+            MidiControllerType::Keyboard
+        );
+        expect_ctrl!(synth_ctrl_rx, SynthControl::NoteOn(0, 0, 2.0));
+        send_cmd!(
+            midi_cmd_tx,
+            NoteOff::create(0, 72, 0x7F),
+            MidiControllerType::Keyboard
+        );
+        expect_ctrl!(synth_ctrl_rx, SynthControl::NoteOff(0, 0));
+        send_cmd!(
+            midi_cmd_tx,
+            NoteOff::create(0, 32, 0x7F),
+            MidiControllerType::ControlPanel
+        );
+        while midi_resp_rx
+            .recv_timeout(Duration::from_millis(100))
+            .is_ok()
+        {}
+
+        // "Metronome" button starts the sequencer, the playhead is shown on the gate
+        send_cmd!(
+            midi_cmd_tx,
+            NoteOn::create(0, 0x5A, 0x7F),
+            MidiControllerType::ControlPanel
+        );
+        let (on, on_timestamp) = recv_ctrl(&synth_ctrl_rx).unwrap();
+        assert_eq!(SynthControl::NoteOn(0, 1, 2.0), on);
+        let (off, off_timestamp) = recv_ctrl(&synth_ctrl_rx).unwrap();
+        assert_eq!(SynthControl::NoteOff(0, 1), off);
+        assert_eq!(Duration::from_micros(62_500), off_timestamp - on_timestamp);
+        expect_resp!(midi_resp_rx, NoteOn::create(0, 32, Color::WHITE.0));
+
+        send_cmd!(
+            midi_cmd_tx,
+            NoteOn::create(0, 0x5A, 0x7F),
+            MidiControllerType::ControlPanel
+        );
+        expect_no_ctrl!(synth_ctrl_rx);
     }
 
     #[test]
-    fn oscillator1_enable_disable() {
-        let (midi_cmd_tx, midi_resp_rx, synth_ctrl_rx) = setup_dispatcher!();
+    fn keyboard_controls_modulation_sources() {
+        let (midi_cmd_tx, _midi_resp_rx, synth_ctrl_rx) = setup_dispatcher!();
 
+        // Velocity is only sent when it changes
         send_cmd!(
             midi_cmd_tx,
-            NoteOn::create(0, 0x33, 0x7F),
-            MidiControllerType::ControlPanel
+            NoteOn::create(0, 60, 0x7F),
+            MidiControllerType::Keyboard
         );
-        expect_resp!(midi_resp_rx, NoteOn::create(0, 0x33, 0x00));
-        expect_resp!(synth_ctrl_rx, SynthControl::Oscillator1Enable(false));
+        expect_ctrl!(synth_ctrl_rx, SynthControl::NoteOn(0, 0, 1.0));
+        send_cmd!(
+            midi_cmd_tx,
+            NoteOn::create(0, 72, 64),
+            MidiControllerType::Keyboard
+        );
+        expect_ctrl!(synth_ctrl_rx, SynthControl::Velocity(64.0 / 127.0));
+        expect_ctrl!(synth_ctrl_rx, SynthControl::NoteOn(0, 1, 2.0));
 
         send_cmd!(
             midi_cmd_tx,
-            NoteOn::create(0, 0x33, 0x7F),
-            MidiControllerType::ControlPanel
+            ControlChange::create(0, MOD_WHEEL, 127),
+            MidiControllerType::Keyboard
         );
-        expect_resp!(midi_resp_rx, NoteOn::create(0, 0x33, 0x7F));
-        expect_resp!(synth_ctrl_rx, SynthControl::Oscillator1Enable(true));
+        expect_ctrl!(synth_ctrl_rx, SynthControl::ModWheel(1.0));
+
+        send_cmd!(
+            midi_cmd_tx,
+            ChannelPressure::create(0, 0),
+            MidiControllerType::Keyboard
+        );
+        expect_ctrl!(synth_ctrl_rx, SynthControl::Aftertouch(0.0));
+        send_cmd!(
+            midi_cmd_tx,
+            PolyphonicKeyPressure::create(0, 72, 127),
+            MidiControllerType::Keyboard
+        );
+        expect_ctrl!(synth_ctrl_rx, SynthControl::KeyPressure(1, 1.0));
+        expect_no_ctrl!(synth_ctrl_rx);
     }
 
     #[test]
-    fn oscillator1_enable_only_reacts_to_track_select_1() {
+    fn rpn_controllers_can_be_bound_to_parameters() {
         let (midi_cmd_tx, midi_resp_rx, synth_ctrl_rx) = setup_dispatcher!();
 
+        // Map master tune to the controller that selects the RPN (LSB)
         send_cmd!(
             midi_cmd_tx,
-            NoteOn::create(1, 0x33, 0x7F),
+            NoteOn::create(0, LEARN_BUTTON, 0x7F),
             MidiControllerType::ControlPanel
         );
-        expect_no_resp!(midi_resp_rx);
-        expect_no_resp!(synth_ctrl_rx);
+        send_cmd!(
+            midi_cmd_tx,
+            ControlChange::create(0, 0x31, 80),
+            MidiControllerType::ControlPanel
+        );
+        send_cmd!(
+            midi_cmd_tx,
+            ControlChange::create(0, 0x31, 81),
+            MidiControllerType::ControlPanel
+        );
+        send_cmd!(
+            midi_cmd_tx,
+            ControlChange::create(0, 0x64, 96),
+            MidiControllerType::Keyboard
+        );
+        expect_no_ctrl!(synth_ctrl_rx);
+        while midi_resp_rx
+            .recv_timeout(Duration::from_millis(100))
+            .is_ok()
+        {}
+
+        send_cmd!(
+            midi_cmd_tx,
+            ControlChange::create(0, 0x64, 96),
+            MidiControllerType::Keyboard
+        );
+        match get_ctrl!(synth_ctrl_rx) {
+            SynthControl::MasterTune(tune) => assert_float_eq!(1.074873, tune, 1e-6),
+            _ => panic!("wrong variant!"),
+        }
     }
 
     #[test]
-    fn oscillator1_volume() {
-        let (midi_cmd_tx, midi_resp_rx, synth_ctrl_rx) = setup_dispatcher!();
+    fn mpe_expression_applies_to_note_of_member_channel() {
+        let (midi_cmd_tx, _midi_resp_rx, synth_ctrl_rx) = setup_dispatcher!();
+
+        // Lower zone with two member channels
+        for (control_number, value) in [(0x65, 0), (0x64, 6), (0x06, 2)] {
+            send_cmd!(
+                midi_cmd_tx,
+                ControlChange::create(0, control_number, value),
+                MidiControllerType::Keyboard
+            );
+        }
 
         send_cmd!(
             midi_cmd_tx,
-            ControlChange::create(0, 0x07, 0x7F),
-            MidiControllerType::ControlPanel
+            NoteOn::create(1, 60, 0x7F),
+            MidiControllerType::Keyboard
+        );
+        expect_ctrl!(synth_ctrl_rx, SynthControl::NoteOn(0, 0, 1.0));
+        send_cmd!(
+            midi_cmd_tx,
+            PitchBend::create(1, 0x3000),
+            MidiControllerType::Keyboard
+        );
+        expect_ctrl!(synth_ctrl_rx, SynthControl::NotePitchBend(0, 24.0));
+        send_cmd!(
+            midi_cmd_tx,
+            ControlChange::create(1, TIMBRE, 127),
+            MidiControllerType::Keyboard
+        );
+        expect_ctrl!(synth_ctrl_rx, SynthControl::NoteTimbre(0, 1.0));
+        send_cmd!(
+            midi_cmd_tx,
+            ChannelPressure::create(1, 127),
+            MidiControllerType::Keyboard
+        );
+        expect_ctrl!(synth_ctrl_rx, SynthControl::KeyPressure(0, 1.0));
+
+        // The manager channel is global
+        send_cmd!(
+            midi_cmd_tx,
+            ChannelPressure::create(0, 0),
+            MidiControllerType::Keyboard
+        );
+        expect_ctrl!(synth_ctrl_rx, SynthControl::Aftertouch(0.0));
+        send_cmd!(
+            midi_cmd_tx,
+            PitchBend::create(0, 0),
+            MidiControllerType::Keyboard
+        );
+        expect_ctrl!(synth_ctrl_rx, SynthControl::PitchBend(-2.0));
+
+        // Expression sent before the note applies to it
+        send_cmd!(
+            midi_cmd_tx,
+            PitchBend::create(2, 0),
+            MidiControllerType::Keyboard
+        );
+        send_cmd!(
+            midi_cmd_tx,
+            NoteOn::create(2, 72, 0x7F),
+            MidiControllerType::Keyboard
+        );
+        expect_ctrl!(synth_ctrl_rx, SynthControl::NoteOn(0, 1, 2.0));
+        expect_ctrl!(synth_ctrl_rx, SynthControl::NotePitchBend(1, -48.0));
+
+        send_cmd!(
+            midi_cmd_tx,
+            NoteOff::create(1, 60, 0),
+            MidiControllerType::Keyboard
+        );
+        expect_ctrl!(synth_ctrl_rx, SynthControl::NoteOff(0, 0));
+        send_cmd!(
+            midi_cmd_tx,
+            PitchBend::create(1, 0x2000),
+            MidiControllerType::Keyboard
+        );
+        expect_no_ctrl!(synth_ctrl_rx);
+    }
+
+    #[test]
+    fn tap_tempo_sets_tempo_of_master_clock() {
+        let (midi_cmd_tx, midi_resp_rx, synth_ctrl_rx) = setup_dispatcher!();
+
+        // "Tap Tempo" button, tapped at 150 BPM
+        let start = Instant::now();
+        for n in 0..2 {
+            midi_cmd_tx
+                .send((
+                    NoteOn::create(0, 0x63, 0x7F),
+                    MidiControllerType::ControlPanel,
+                    start + Duration::from_millis(400) * n,
+                ))
+                .unwrap();
+        }
+
+        // Tempo knob has to pick up the new value
+        expect_resp!(
+            midi_resp_rx,
+            ControlChange::create(0, 0x1C, RingStyle::Volume as u8)
         );
+        expect_resp!(midi_resp_rx, ControlChange::create(0, 0x14, 70));
         expect_no_resp!(midi_resp_rx);
-        expect_resp!(synth_ctrl_rx, SynthControl::Oscillator1Volume(1.0));
 
+        // "Metronome" button starts the sequencer and the clock, with 24 pulses per beat
         send_cmd!(
             midi_cmd_tx,
-            ControlChange::create(0, 0x07, 0x3F),
+            NoteOn::create(0, 0x5A, 0x7F),
             MidiControllerType::ControlPanel
         );
-        expect_no_resp!(midi_resp_rx);
-        expect_resp!(synth_ctrl_rx, SynthControl::Oscillator1Volume(0.05497402));
+        expect_resp!(midi_resp_rx, Start::create());
+        expect_resp!(midi_resp_rx, TimingClock::create());
+        expect_resp!(midi_resp_rx, NoteOn::create(0, 0x5A, 0x7F));
+        for _ in 0..24 {
+            expect_resp!(midi_resp_rx, TimingClock::create());
+        }
+
+        // Tempo-synced modulation follows the clock (the interval of the pulses is tested with
+        // the clock)
+        expect_ctrl!(synth_ctrl_rx, SynthControl::Tempo(150.0));
+        expect_no_ctrl!(synth_ctrl_rx);
+    }
 
+    #[test]
+    fn tuning_is_loaded_when_its_program_settles() {
+        let dir = env::temp_dir().join(format!("midi-synth-dispatcher-tunings-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("001.scl"),
+            "C# a quarter tone sharp\n12\n150.0\n200.0\n300.0\n400.0\n500.0\n600.0\n700.0\n\
+             800.0\n900.0\n1000.0\n1100.0\n2/1\n",
+        )
+        .unwrap();
+        let (midi_cmd_tx, _midi_resp_rx, synth_ctrl_rx) = setup_dispatcher!(
+            PatchBank::new(None),
+            Zones::default(),
+            TuningBank::new(Some(dir.clone()))
+        );
+
+        // "Tuning" knob selects program 1, which is not loaded while the knob is turned
         send_cmd!(
             midi_cmd_tx,
-            ControlChange::create(0, 0x07, 0x00),
+            ControlChange::create(7, 0x15, 1),
             MidiControllerType::ControlPanel
         );
-        expect_no_resp!(midi_resp_rx);
-        expect_resp!(synth_ctrl_rx, SynthControl::Oscillator1Volume(0.0031622776));
+        send_cmd!(
+            midi_cmd_tx,
+            NoteOn::create(0, 61, 0x7F),
+            MidiControllerType::Keyboard
+        );
+        let note = match get_ctrl!(synth_ctrl_rx) {
+            SynthControl::NoteOn(0, _, note) => note,
+            _ => panic!("wrong variant!"),
+        };
+        assert_float_eq!(2.0_f32.powf(1.0 / 12.0), note, 1e-4);
+        send_cmd!(
+            midi_cmd_tx,
+            NoteOff::create(0, 61, 0x7F),
+            MidiControllerType::Keyboard
+        );
+        get_ctrl!(synth_ctrl_rx);
+
+        thread::sleep(TUNING_SETTLE_TIME + Duration::from_millis(50));
+        send_cmd!(
+            midi_cmd_tx,
+            NoteOn::create(0, 61, 0x7F),
+            MidiControllerType::Keyboard
+        );
+        let note = match get_ctrl!(synth_ctrl_rx) {
+            SynthControl::NoteOn(0, _, note) => note,
+            _ => panic!("wrong variant!"),
+        };
+        assert_float_eq!(2.0_f32.powf(1.5 / 12.0), note, 1e-4);
+
+        fs::remove_dir_all(dir).unwrap();
     }
 
     #[test]
-    fn oscillator1_volume_only_reacts_to_track_fader_1() {
-        let (midi_cmd_tx, midi_resp_rx, synth_ctrl_rx) = setup_dispatcher!();
+    fn notes_are_released_with_their_tuning() {
+        let (midi_cmd_tx, _midi_resp_rx, synth_ctrl_rx) = setup_dispatcher!();
 
         send_cmd!(
             midi_cmd_tx,
-            ControlChange::create(1, 0x07, 0x7F),
+            NoteOn::create(0, 60, 0x7F),
+            MidiControllerType::Keyboard
+        );
+        expect_ctrl!(synth_ctrl_rx, SynthControl::NoteOn(0, 0, 1.0));
+
+        // MTS single note tuning change: note 60 one semitone higher
+        send_cmd!(
+            midi_cmd_tx,
+            SystemExclusive::create(
+                SystemExlusiveId::OneByte(0x7F),
+                vec![0x7F, 0x08, 0x02, 0x00, 0x01, 60, 61, 0x00, 0x00]
+            ),
+            MidiControllerType::Keyboard
+        );
+        expect_no_ctrl!(synth_ctrl_rx);
+
+        // The playing note is released, the next one is retuned
+        send_cmd!(
+            midi_cmd_tx,
+            NoteOff::create(0, 60, 0x7F),
+            MidiControllerType::Keyboard
+        );
+        expect_ctrl!(synth_ctrl_rx, SynthControl::NoteOff(0, 0));
+        send_cmd!(
+            midi_cmd_tx,
+            NoteOn::create(0, 60, 0x7F),
+            MidiControllerType::Keyboard
+        );
+        let note = match get_ctrl!(synth_ctrl_rx) {
+            SynthControl::NoteOn(0, _, note) => note,
+            _ => panic!("wrong variant!"),
+        };
+        assert_float_eq!(2.0_f32.powf(1.0 / 12.0), note, 1e-6);
+    }
+
+    #[test]
+    fn program_change_keeps_octave_shift() {
+        let dir = env::temp_dir().join(format!("midi-synth-shift-test-{}", process::id()));
+        save_test_patch(&dir, 0, 5, 0.25);
+        let (midi_cmd_tx, _midi_resp_rx, synth_ctrl_rx) =
+            setup_dispatcher!(PatchBank::new(Some(dir.clone())));
+
+        send_cmd!(
+            midi_cmd_tx,
+            NoteOn::create(0, OCTAVE_UP_BUTTON, 0x7F),
             MidiControllerType::ControlPanel
         );
-        expect_no_resp!(midi_resp_rx);
-        expect_no_resp!(synth_ctrl_rx);
+        send_cmd!(
+            midi_cmd_tx,
+            ProgramChange::create(0, 5),
+            MidiControllerType::Keyboard
+        );
+        expect_ctrl!(synth_ctrl_rx, SynthControl::AllNotesOff);
+        expect_ctrl!(synth_ctrl_rx, SynthControl::Oscillator1Volume(0, 0.25));
+        expect_no_ctrl!(synth_ctrl_rx);
+
+        send_cmd!(
+            midi_cmd_tx,
+            NoteOn::create(0, 60, 0x7F),
+            MidiControllerType::Keyboard
+        );
+        expect_ctrl!(synth_ctrl_rx, SynthControl::NoteOn(0, 0, 2.0));
+
+        fs::remove_dir_all(dir).unwrap();
     }
 
     #[test]
-    fn keyboard_play_notes() {
-        macro_rules! send_and_check {
-            ($tx:ident, $note:expr, $midi_rx:ident, $synth_rx:ident, $expected:expr, $eps:expr) => {
-                send_cmd!(
-                    $tx,
-                    NoteOn::create(0, $note, 127),
-                    MidiControllerType::Keyboard
-                );
-                expect_no_resp!($midi_rx);
-                let note = match get_resp!($synth_rx) {
-                    SynthControl::NoteOn(note) => note,
-                    _ => panic!("wrong variant!"),
-                };
-                assert_float_eq!($expected, note, $eps);
-            };
+    fn held_key_releases_note_after_transposition() {
+        let (midi_cmd_tx, midi_resp_rx, synth_ctrl_rx) = setup_dispatcher!();
+
+        send_cmd!(
+            midi_cmd_tx,
+            NoteOn::create(0, 60, 0x7F),
+            MidiControllerType::Keyboard
+        );
+        expect_ctrl!(synth_ctrl_rx, SynthControl::NoteOn(0, 0, 1.0));
+
+        send_cmd!(
+            midi_cmd_tx,
+            NoteOn::create(0, OCTAVE_UP_BUTTON, 0x7F),
+            MidiControllerType::ControlPanel
+        );
+        let mut responses = vec![];
+        while let Ok(resp) = midi_resp_rx.recv_timeout(Duration::from_millis(100)) {
+            responses.push(resp);
         }
+        assert!(responses.contains(&NoteOn::create(0, OCTAVE_UP_BUTTON, 0x7F)));
+        expect_no_ctrl!(synth_ctrl_rx);
 
-        let (midi_cmd_tx, midi_resp_rx, synth_ctrl_rx) = setup_dispatcher!();
+        // The key releases the note it played
+        send_cmd!(
+            midi_cmd_tx,
+            NoteOff::create(0, 60, 0x7F),
+            MidiControllerType::Keyboard
+        );
+        expect_ctrl!(synth_ctrl_rx, SynthControl::NoteOff(0, 0));
+        send_cmd!(
+            midi_cmd_tx,
+            NoteOn::create(0, 60, 0x7F),
+            MidiControllerType::Keyboard
+        );
+        expect_ctrl!(synth_ctrl_rx, SynthControl::NoteOn(0, 1, 2.0));
+        send_cmd!(
+            midi_cmd_tx,
+            NoteOff::create(0, 60, 0x7F),
+            MidiControllerType::Keyboard
+        );
+        expect_ctrl!(synth_ctrl_rx, SynthControl::NoteOff(0, 1));
 
-        send_and_check!(midi_cmd_tx, 60, midi_resp_rx, synth_ctrl_rx, 1.0, 1e-6);
-        send_and_check!(midi_cmd_tx, 72, midi_resp_rx, synth_ctrl_rx, 2.0, 1e-6);
-        send_and_check!(midi_cmd_tx, 48, midi_resp_rx, synth_ctrl_rx, 0.5, 1e-6);
-        send_and_check!(midi_cmd_tx, 84, midi_resp_rx, synth_ctrl_rx, 4.0, 1e-6);
-        send_and_check!(midi_cmd_tx, 36, midi_resp_rx, synth_ctrl_rx, 0.25, 1e-6);
+        // Transposition by a knob adds to the octave shift
+        send_cmd!(
+            midi_cmd_tx,
+            ControlChange::create(7, 0x16, 127),
+            MidiControllerType::ControlPanel
+        );
+        let mut responses = vec![];
+        while let Ok(resp) = midi_resp_rx.recv_timeout(Duration::from_millis(100)) {
+            responses.push(resp);
+        }
+        assert!(responses.contains(&ControlChange::create(7, 0x16, 127)));
+        assert!(responses.contains(&NoteOn::create(0, TRANSPOSE_UP_BUTTON, 0x7F)));
+        send_cmd!(
+            midi_cmd_tx,
+            NoteOn::create(0, 48, 0x7F),
+            MidiControllerType::Keyboard
+        );
+        expect_ctrl!(synth_ctrl_rx, SynthControl::NoteOn(0, 2, 2.0));
+
+        // Keys shifted out of range do not play
+        send_cmd!(
+            midi_cmd_tx,
+            NoteOn::create(0, 120, 0x7F),
+            MidiControllerType::Keyboard
+        );
+        expect_no_ctrl!(synth_ctrl_rx);
     }
 
     #[test]
-    fn keyboard_release_notes() {
-        macro_rules! send_and_check {
-            ($tx:ident, $note:expr, $midi_rx:ident, $synth_rx:ident, $expected:expr, $eps:expr) => {
-                send_cmd!(
-                    $tx,
-                    NoteOff::create(0, $note, 127),
-                    MidiControllerType::Keyboard
-                );
-                expect_no_resp!($midi_rx);
-                let note = match get_resp!($synth_rx) {
-                    SynthControl::NoteOff(note) => note,
-                    _ => panic!("wrong variant!"),
-                };
-                assert_float_eq!($expected, note, $eps);
-            };
-        }
+    fn zones_split_and_layer_keyboard() {
+        let zones = Zones::read_from(
+            "zone = 0-59 1 transpose 12\nzone = 60-127 0\nzone = 60-127 1 velocity 100-127\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        let (midi_cmd_tx, _midi_resp_rx, synth_ctrl_rx) =
+            setup_dispatcher!(PatchBank::new(None), zones);
+
+        // Keys of the lower zone play the second voice group an octave higher
+        send_cmd!(
+            midi_cmd_tx,
+            NoteOn::create(0, 48, 64),
+            MidiControllerType::Keyboard
+        );
+        expect_ctrl!(synth_ctrl_rx, SynthControl::Velocity(64.0 / 127.0));
+        expect_ctrl!(synth_ctrl_rx, SynthControl::NoteOn(1, 0, 1.0));
 
-        let (midi_cmd_tx, midi_resp_rx, synth_ctrl_rx) = setup_dispatcher!();
+        // Soft keys of the upper zone play only the first voice group
+        send_cmd!(
+            midi_cmd_tx,
+            NoteOn::create(0, 72, 64),
+            MidiControllerType::Keyboard
+        );
+        expect_ctrl!(synth_ctrl_rx, SynthControl::NoteOn(0, 1, 2.0));
+        send_cmd!(
+            midi_cmd_tx,
+            NoteOff::create(0, 72, 64),
+            MidiControllerType::Keyboard
+        );
+        expect_ctrl!(synth_ctrl_rx, SynthControl::NoteOff(0, 1));
 
-        send_and_check!(midi_cmd_tx, 60, midi_resp_rx, synth_ctrl_rx, 1.0, 1e-6);
-        send_and_check!(midi_cmd_tx, 72, midi_resp_rx, synth_ctrl_rx, 2.0, 1e-6);
-        send_and_check!(midi_cmd_tx, 48, midi_resp_rx, synth_ctrl_rx, 0.5, 1e-6);
-        send_and_check!(midi_cmd_tx, 84, midi_resp_rx, synth_ctrl_rx, 4.0, 1e-6);
-        send_and_check!(midi_cmd_tx, 36, midi_resp_rx, synth_ctrl_rx, 0.25, 1e-6);
+        // Hard keys are layered
+        send_cmd!(
+            midi_cmd_tx,
+            NoteOn::create(0, 72, 0x7F),
+            MidiControllerType::Keyboard
+        );
+        expect_ctrl!(synth_ctrl_rx, SynthControl::Velocity(1.0));
+        expect_ctrl!(synth_ctrl_rx, SynthControl::NoteOn(0, 2, 2.0));
+        expect_ctrl!(synth_ctrl_rx, SynthControl::NoteOn(1, 3, 2.0));
+        send_cmd!(
+            midi_cmd_tx,
+            PolyphonicKeyPressure::create(0, 72, 127),
+            MidiControllerType::Keyboard
+        );
+        expect_ctrl!(synth_ctrl_rx, SynthControl::KeyPressure(2, 1.0));
+        expect_ctrl!(synth_ctrl_rx, SynthControl::KeyPressure(3, 1.0));
+        send_cmd!(
+            midi_cmd_tx,
+            NoteOff::create(0, 72, 0x00),
+            MidiControllerType::Keyboard
+        );
+        expect_ctrl!(synth_ctrl_rx, SynthControl::NoteOff(0, 2));
+        expect_ctrl!(synth_ctrl_rx, SynthControl::NoteOff(1, 3));
+
+        send_cmd!(
+            midi_cmd_tx,
+            NoteOff::create(0, 48, 0x00),
+            MidiControllerType::Keyboard
+        );
+        expect_ctrl!(synth_ctrl_rx, SynthControl::NoteOff(1, 0));
+        expect_no_ctrl!(synth_ctrl_rx);
     }
 }
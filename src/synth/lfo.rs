@@ -0,0 +1,290 @@
+use std::cell::Cell;
+
+use synth::sample_stream::SampleStream;
+
+/// Length of an LFO cycle in beats for each tempo-synced value of `ParameterId::LfoDivision`
+/// (1 bar, 1/2, 1/4, 1/8, 1/8 triplet, 1/16)
+pub const DIVISION_BEATS: [f32; 6] = [4.0, 2.0, 1.0, 0.5, 1.0 / 3.0, 0.25];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LfoShape {
+    Triangle,
+    /// Rising ramp
+    Saw,
+    Square,
+    /// New random value at the start of each cycle
+    SampleAndHold,
+    /// Glides from one random value to the next within a cycle
+    SmoothRandom,
+}
+
+impl LfoShape {
+    /// Returns the shape for a value of `ParameterId::LfoShape`.
+    pub fn from_value(value: f32) -> Self {
+        match value.round() as i32 {
+            1 => LfoShape::Saw,
+            2 => LfoShape::Square,
+            3 => LfoShape::SampleAndHold,
+            4 => LfoShape::SmoothRandom,
+            _ => LfoShape::Triangle,
+        }
+    }
+}
+
+/// Low frequency oscillator with an output between -1.0 and 1.0.
+///
+/// The rate is either given in Hz or as a number of beats per cycle, which follows the tempo.
+/// With key sync, each `trigger()` restarts the cycle. After a trigger, the output is silent for
+/// the delay and then fades in.
+pub struct Lfo {
+    sample_rate: f32,
+    shape: Cell<LfoShape>,
+    /// Rate in Hz, unless synced to the tempo
+    rate: Cell<f32>,
+    /// Length of a cycle in beats, if synced to the tempo
+    beats: Cell<Option<f32>>,
+    /// Tempo in BPM
+    tempo: Cell<f32>,
+    key_sync: Cell<bool>,
+    delay_samples: Cell<u64>,
+    fade_in_samples: Cell<u64>,
+    /// Samples since the last trigger
+    elapsed: Cell<u64>,
+    /// Position within the cycle (0.0 to 1.0)
+    phase: Cell<f32>,
+    /// Random value of the current cycle, and the one of the next cycle
+    random_values: Cell<(f32, f32)>,
+    random_state: Cell<u32>,
+}
+
+impl Lfo {
+    pub fn new(sample_rate: f32) -> Self {
+        let lfo = Self {
+            sample_rate,
+            shape: Cell::new(LfoShape::Triangle),
+            rate: Cell::new(5.0),
+            beats: Cell::new(None),
+            tempo: Cell::new(120.0),
+            key_sync: Cell::new(false),
+            delay_samples: Cell::new(0),
+            fade_in_samples: Cell::new(0),
+            elapsed: Cell::new(u64::MAX),
+            phase: Cell::new(0.0),
+            random_values: Cell::new((0.0, 0.0)),
+            random_state: Cell::new(0x2545_f491),
+        };
+        lfo.random_values.set((lfo.random(), lfo.random()));
+        lfo
+    }
+
+    pub fn set_shape(&self, shape: LfoShape) {
+        self.shape.set(shape);
+    }
+
+    pub fn set_rate(&self, rate: f32) {
+        self.rate.set(rate.max(0.0));
+    }
+
+    /// Syncs the rate to the tempo with a cycle of `beats` beats, or frees it (`None`).
+    pub fn set_beats(&self, beats: Option<f32>) {
+        self.beats.set(beats);
+    }
+
+    pub fn set_tempo(&self, tempo: f32) {
+        self.tempo.set(tempo.max(1.0));
+    }
+
+    pub fn set_key_sync(&self, key_sync: bool) {
+        self.key_sync.set(key_sync);
+    }
+
+    /// Sets the time in seconds after a trigger before the output starts to fade in.
+    pub fn set_delay(&self, delay: f32) {
+        self.delay_samples
+            .set((delay.max(0.0) * self.sample_rate) as u64);
+    }
+
+    /// Sets the time in seconds it takes the output to reach its full level after the delay.
+    pub fn set_fade_in(&self, fade_in: f32) {
+        self.fade_in_samples
+            .set((fade_in.max(0.0) * self.sample_rate) as u64);
+    }
+
+    /// Starts the delay and fade in, and restarts the cycle with key sync.
+    pub fn trigger(&self) {
+        self.elapsed.set(0);
+        if self.key_sync.get() {
+            self.phase.set(0.0);
+        }
+    }
+
+    /// Frequency in cycles per sample.
+    fn increment(&self) -> f32 {
+        let frequency = match self.beats.get() {
+            Some(beats) => self.tempo.get() / (60.0 * beats),
+            None => self.rate.get(),
+        };
+        frequency / self.sample_rate
+    }
+
+    /// Level of the output according to delay and fade in.
+    fn level(&self, elapsed: u64) -> f32 {
+        let delay = self.delay_samples.get();
+        let fade_in = self.fade_in_samples.get();
+
+        if elapsed < delay {
+            0.0
+        } else if elapsed - delay < fade_in {
+            (elapsed - delay) as f32 / fade_in as f32
+        } else {
+            1.0
+        }
+    }
+
+    /// Returns a random value between -1.0 and 1.0 (xorshift).
+    fn random(&self) -> f32 {
+        let mut x = self.random_state.get();
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.random_state.set(x);
+
+        (f64::from(x) / f64::from(u32::MAX) * 2.0 - 1.0) as f32
+    }
+}
+
+impl SampleStream for Lfo {
+    fn next_sample(&self) -> f32 {
+        let phase = self.phase.get();
+        let (current, next) = self.random_values.get();
+
+        let value = match self.shape.get() {
+            LfoShape::Triangle if phase < 0.25 => 4.0 * phase,
+            LfoShape::Triangle if phase < 0.75 => 2.0 - 4.0 * phase,
+            LfoShape::Triangle => 4.0 * phase - 4.0,
+            LfoShape::Saw => 2.0 * phase - 1.0,
+            LfoShape::Square if phase < 0.5 => 1.0,
+            LfoShape::Square => -1.0,
+            LfoShape::SampleAndHold => current,
+            LfoShape::SmoothRandom => {
+                current + (next - current) * phase * phase * (3.0 - 2.0 * phase)
+            }
+        };
+
+        let elapsed = self.elapsed.get();
+        let level = self.level(elapsed);
+        self.elapsed.set(elapsed.saturating_add(1));
+
+        let mut phase = phase + self.increment();
+        if phase >= 1.0 {
+            phase = phase.fract();
+            self.random_values.set((next, self.random()));
+        }
+        self.phase.set(phase);
+
+        value * level
+    }
+
+    fn fill_buffer(&self, buffer: &mut [f32]) {
+        for sample in buffer.iter_mut() {
+            *sample = self.next_sample();
+        }
+    }
+}
+
+iterator!(Lfo);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shapes() {
+        let lfo = Lfo::new(8.0);
+        lfo.set_rate(1.0);
+
+        let mut buffer = [0.0; 8];
+        lfo.fill_buffer(&mut buffer);
+        assert_eq!([0.0, 0.5, 1.0, 0.5, 0.0, -0.5, -1.0, -0.5], buffer);
+
+        lfo.set_shape(LfoShape::Saw);
+        lfo.fill_buffer(&mut buffer);
+        assert_eq!([-1.0, -0.75, -0.5, -0.25, 0.0, 0.25, 0.5, 0.75], buffer);
+
+        lfo.set_shape(LfoShape::Square);
+        lfo.fill_buffer(&mut buffer);
+        assert_eq!([1.0, 1.0, 1.0, 1.0, -1.0, -1.0, -1.0, -1.0], buffer);
+    }
+
+    #[test]
+    fn random_shapes() {
+        let lfo = Lfo::new(4.0);
+        lfo.set_rate(1.0);
+        lfo.set_shape(LfoShape::SampleAndHold);
+
+        let mut buffer = [0.0; 8];
+        lfo.fill_buffer(&mut buffer);
+        assert!(buffer[..4].iter().all(|sample| *sample == buffer[0]));
+        assert!(buffer[4..].iter().all(|sample| *sample == buffer[4]));
+        assert_ne!(buffer[0], buffer[4]);
+        assert!(buffer.iter().all(|sample| sample.abs() <= 1.0));
+
+        // Glides from the held value to the next one
+        let lfo = Lfo::new(4.0);
+        lfo.set_rate(1.0);
+        lfo.set_shape(LfoShape::SmoothRandom);
+        let mut smooth = [0.0; 5];
+        lfo.fill_buffer(&mut smooth);
+        assert_eq!(buffer[0], smooth[0]);
+        assert_eq!(buffer[4], smooth[4]);
+        assert!(smooth[1..4]
+            .iter()
+            .all(|sample| sample.abs() <= buffer[0].abs().max(buffer[4].abs())));
+    }
+
+    #[test]
+    fn tempo_sync() {
+        let lfo = Lfo::new(100.0);
+        lfo.set_rate(1.0);
+        lfo.set_shape(LfoShape::Saw);
+
+        // 1/4 at 150 BPM: 2.5 Hz, i.e. 40 samples per cycle
+        lfo.set_beats(Some(1.0));
+        lfo.set_tempo(150.0);
+        let samples: Vec<f32> = lfo.take(31).collect();
+        assert_float_eq!(-1.0, samples[0], 1e-6);
+        assert_float_eq!(-0.5, samples[10], 1e-5);
+        assert_float_eq!(0.5, samples[30], 1e-5);
+    }
+
+    #[test]
+    fn key_sync_restarts_cycle() {
+        let mut lfo = Lfo::new(8.0);
+        lfo.set_rate(1.0);
+        lfo.set_shape(LfoShape::Saw);
+
+        lfo.by_ref().take(3).count();
+        lfo.trigger();
+        assert_eq!(-0.25, lfo.next_sample());
+
+        lfo.set_key_sync(true);
+        lfo.trigger();
+        assert_eq!(-1.0, lfo.next_sample());
+    }
+
+    #[test]
+    fn delay_and_fade_in() {
+        let lfo = Lfo::new(4.0);
+        lfo.set_rate(0.0);
+        lfo.set_shape(LfoShape::Square);
+        lfo.set_delay(1.0);
+        lfo.set_fade_in(0.5);
+
+        // Full level until triggered
+        assert_eq!(1.0, lfo.next_sample());
+
+        lfo.trigger();
+        let samples: Vec<f32> = lfo.take(7).collect();
+        assert_eq!(vec![0.0, 0.0, 0.0, 0.0, 0.0, 0.5, 1.0], samples);
+    }
+}
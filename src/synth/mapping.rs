@@ -0,0 +1,746 @@
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, ErrorKind as IoErrorKind, Write};
+use std::path::PathBuf;
+
+use midi_controller::MidiControllerType;
+use synth::parameter::ParameterId;
+
+use errors::*;
+
+/// Knob, fader or button of a MIDI controller.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Control {
+    /// Knob or fader sending control changes (bound on any channel if `channel` is `None`)
+    Knob {
+        source: MidiControllerType,
+        channel: Option<u8>,
+        control_number: u8,
+    },
+    /// Button sending note on messages
+    Button {
+        source: MidiControllerType,
+        channel: u8,
+        note_number: u8,
+    },
+}
+
+impl Control {
+    /// Returns true if an event of the `other` control is handled by this control.
+    fn matches(&self, other: &Control) -> bool {
+        match (*self, *other) {
+            (
+                Control::Knob {
+                    source,
+                    channel,
+                    control_number,
+                },
+                Control::Knob {
+                    source: other_source,
+                    channel: other_channel,
+                    control_number: other_control_number,
+                },
+            ) => {
+                source == other_source
+                    && control_number == other_control_number
+                    && (channel.is_none() || channel == other_channel)
+            }
+            (Control::Button { .. }, Control::Button { .. }) => self == other,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Control {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let source = |source| match source {
+            MidiControllerType::Keyboard => "keyboard",
+            MidiControllerType::ControlPanel => "control_panel",
+            MidiControllerType::Automation => "automation",
+        };
+
+        match *self {
+            Control::Knob {
+                source: s,
+                channel: Some(channel),
+                control_number,
+            } => write!(f, "{} cc {} {}", source(s), channel, control_number),
+            Control::Knob {
+                source: s,
+                channel: None,
+                control_number,
+            } => write!(f, "{} cc * {}", source(s), control_number),
+            Control::Button {
+                source: s,
+                channel,
+                note_number,
+            } => write!(f, "{} note {} {}", source(s), channel, note_number),
+        }
+    }
+}
+
+/// Parses a control in the format written by its `Display` implementation.
+fn parse_control(text: &str) -> Option<Control> {
+    let parts = text.split_whitespace().collect::<Vec<_>>();
+    if parts.len() != 4 {
+        return None;
+    }
+
+    let source = match parts[0] {
+        "keyboard" => MidiControllerType::Keyboard,
+        "control_panel" => MidiControllerType::ControlPanel,
+        "automation" => MidiControllerType::Automation,
+        _ => return None,
+    };
+    let channel = match parts[2] {
+        "*" => None,
+        channel => Some(channel.parse().ok().filter(|&channel| channel < 16)?),
+    };
+    let number = parts[3].parse().ok().filter(|&number| number < 128)?;
+
+    match (parts[1], channel) {
+        ("cc", channel) => Some(Control::Knob {
+            source,
+            channel,
+            control_number: number,
+        }),
+        ("note", Some(channel)) => Some(Control::Button {
+            source,
+            channel,
+            note_number: number,
+        }),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Binding {
+    pub parameter: ParameterId,
+    pub control: Control,
+}
+
+/// Bindings of controls to parameters. Each parameter is bound to one control.
+#[derive(Debug, PartialEq)]
+pub struct Mappings {
+    bindings: Vec<Binding>,
+    /// File the mappings are saved to
+    path: Option<PathBuf>,
+}
+
+impl Default for Mappings {
+    fn default() -> Self {
+        let control_panel = MidiControllerType::ControlPanel;
+
+        Mappings {
+            bindings: vec![
+                Binding {
+                    parameter: ParameterId::MasterTune,
+                    control: Control::Knob {
+                        source: control_panel,
+                        channel: None,
+                        control_number: 0x31,
+                    },
+                },
+                Binding {
+                    parameter: ParameterId::MasterVolume,
+                    control: Control::Knob {
+                        source: control_panel,
+                        channel: None,
+                        control_number: 0x0E,
+                    },
+                },
+                Binding {
+                    parameter: ParameterId::Oscillator1Range,
+                    control: Control::Knob {
+                        source: control_panel,
+                        channel: None,
+                        control_number: 0x30,
+                    },
+                },
+                Binding {
+                    parameter: ParameterId::Oscillator1Enable,
+                    control: Control::Button {
+                        source: control_panel,
+                        channel: 0,
+                        note_number: 0x33,
+                    },
+                },
+                Binding {
+                    parameter: ParameterId::Oscillator1Volume,
+                    control: Control::Knob {
+                        source: control_panel,
+                        channel: Some(0),
+                        control_number: 0x07,
+                    },
+                },
+                // Oscillator of the second voice group on the third track control knob, and the track
+                // select button and fader of the third track
+                Binding {
+                    parameter: ParameterId::Group1Oscillator1Range,
+                    control: Control::Knob {
+                        source: control_panel,
+                        channel: None,
+                        control_number: 0x32,
+                    },
+                },
+                Binding {
+                    parameter: ParameterId::Group1Oscillator1Enable,
+                    control: Control::Button {
+                        source: control_panel,
+                        channel: 2,
+                        note_number: 0x33,
+                    },
+                },
+                Binding {
+                    parameter: ParameterId::Group1Oscillator1Volume,
+                    control: Control::Knob {
+                        source: control_panel,
+                        channel: Some(2),
+                        control_number: 0x07,
+                    },
+                },
+                // Arpeggiator on the device knobs and buttons of the first track
+                Binding {
+                    parameter: ParameterId::ArpeggiatorEnable,
+                    control: Control::Button {
+                        source: control_panel,
+                        channel: 0,
+                        note_number: 0x3E,
+                    },
+                },
+                Binding {
+                    parameter: ParameterId::ArpeggiatorLatch,
+                    control: Control::Button {
+                        source: control_panel,
+                        channel: 0,
+                        note_number: 0x3F,
+                    },
+                },
+                Binding {
+                    parameter: ParameterId::ClockSync,
+                    control: Control::Button {
+                        source: control_panel,
+                        channel: 0,
+                        note_number: 0x40,
+                    },
+                },
+                Binding {
+                    parameter: ParameterId::ArpeggiatorOrder,
+                    control: Control::Knob {
+                        source: control_panel,
+                        channel: Some(0),
+                        control_number: 0x10,
+                    },
+                },
+                Binding {
+                    parameter: ParameterId::ArpeggiatorOctaves,
+                    control: Control::Knob {
+                        source: control_panel,
+                        channel: Some(0),
+                        control_number: 0x11,
+                    },
+                },
+                Binding {
+                    parameter: ParameterId::ArpeggiatorGate,
+                    control: Control::Knob {
+                        source: control_panel,
+                        channel: Some(0),
+                        control_number: 0x12,
+                    },
+                },
+                Binding {
+                    parameter: ParameterId::ArpeggiatorRate,
+                    control: Control::Knob {
+                        source: control_panel,
+                        channel: Some(0),
+                        control_number: 0x13,
+                    },
+                },
+                Binding {
+                    parameter: ParameterId::Tempo,
+                    control: Control::Knob {
+                        source: control_panel,
+                        channel: Some(0),
+                        control_number: 0x14,
+                    },
+                },
+                // LFO on the device knobs of the second track
+                Binding {
+                    parameter: ParameterId::LfoShape,
+                    control: Control::Knob {
+                        source: control_panel,
+                        channel: Some(1),
+                        control_number: 0x10,
+                    },
+                },
+                Binding {
+                    parameter: ParameterId::LfoRate,
+                    control: Control::Knob {
+                        source: control_panel,
+                        channel: Some(1),
+                        control_number: 0x11,
+                    },
+                },
+                Binding {
+                    parameter: ParameterId::LfoDivision,
+                    control: Control::Knob {
+                        source: control_panel,
+                        channel: Some(1),
+                        control_number: 0x12,
+                    },
+                },
+                Binding {
+                    parameter: ParameterId::LfoKeySync,
+                    control: Control::Knob {
+                        source: control_panel,
+                        channel: Some(1),
+                        control_number: 0x13,
+                    },
+                },
+                Binding {
+                    parameter: ParameterId::LfoDelay,
+                    control: Control::Knob {
+                        source: control_panel,
+                        channel: Some(1),
+                        control_number: 0x14,
+                    },
+                },
+                Binding {
+                    parameter: ParameterId::LfoFadeIn,
+                    control: Control::Knob {
+                        source: control_panel,
+                        channel: Some(1),
+                        control_number: 0x15,
+                    },
+                },
+                // Modulation matrix on the device knobs of the seventh (sources and amounts) and
+                // eighth track (destinations and pressure curve)
+                Binding {
+                    parameter: ParameterId::Modulation1Source,
+                    control: Control::Knob {
+                        source: control_panel,
+                        channel: Some(6),
+                        control_number: 0x10,
+                    },
+                },
+                Binding {
+                    parameter: ParameterId::Modulation2Source,
+                    control: Control::Knob {
+                        source: control_panel,
+                        channel: Some(6),
+                        control_number: 0x11,
+                    },
+                },
+                Binding {
+                    parameter: ParameterId::Modulation3Source,
+                    control: Control::Knob {
+                        source: control_panel,
+                        channel: Some(6),
+                        control_number: 0x12,
+                    },
+                },
+                Binding {
+                    parameter: ParameterId::Modulation4Source,
+                    control: Control::Knob {
+                        source: control_panel,
+                        channel: Some(6),
+                        control_number: 0x13,
+                    },
+                },
+                Binding {
+                    parameter: ParameterId::Modulation1Amount,
+                    control: Control::Knob {
+                        source: control_panel,
+                        channel: Some(6),
+                        control_number: 0x14,
+                    },
+                },
+                Binding {
+                    parameter: ParameterId::Modulation2Amount,
+                    control: Control::Knob {
+                        source: control_panel,
+                        channel: Some(6),
+                        control_number: 0x15,
+                    },
+                },
+                Binding {
+                    parameter: ParameterId::Modulation3Amount,
+                    control: Control::Knob {
+                        source: control_panel,
+                        channel: Some(6),
+                        control_number: 0x16,
+                    },
+                },
+                Binding {
+                    parameter: ParameterId::Modulation4Amount,
+                    control: Control::Knob {
+                        source: control_panel,
+                        channel: Some(6),
+                        control_number: 0x17,
+                    },
+                },
+                Binding {
+                    parameter: ParameterId::Modulation1Destination,
+                    control: Control::Knob {
+                        source: control_panel,
+                        channel: Some(7),
+                        control_number: 0x10,
+                    },
+                },
+                Binding {
+                    parameter: ParameterId::Modulation2Destination,
+                    control: Control::Knob {
+                        source: control_panel,
+                        channel: Some(7),
+                        control_number: 0x11,
+                    },
+                },
+                Binding {
+                    parameter: ParameterId::Modulation3Destination,
+                    control: Control::Knob {
+                        source: control_panel,
+                        channel: Some(7),
+                        control_number: 0x12,
+                    },
+                },
+                Binding {
+                    parameter: ParameterId::Modulation4Destination,
+                    control: Control::Knob {
+                        source: control_panel,
+                        channel: Some(7),
+                        control_number: 0x13,
+                    },
+                },
+                Binding {
+                    parameter: ParameterId::PressureCurve,
+                    control: Control::Knob {
+                        source: control_panel,
+                        channel: Some(7),
+                        control_number: 0x14,
+                    },
+                },
+                Binding {
+                    parameter: ParameterId::Tuning,
+                    control: Control::Knob {
+                        source: control_panel,
+                        channel: Some(7),
+                        control_number: 0x15,
+                    },
+                },
+                Binding {
+                    parameter: ParameterId::Transpose,
+                    control: Control::Knob {
+                        source: control_panel,
+                        channel: Some(7),
+                        control_number: 0x16,
+                    },
+                },
+                Binding {
+                    parameter: ParameterId::OctaveShift,
+                    control: Control::Knob {
+                        source: control_panel,
+                        channel: Some(7),
+                        control_number: 0x17,
+                    },
+                },
+            ],
+            path: None,
+        }
+    }
+}
+
+impl Mappings {
+    /// Loads the mappings from a file. Returns the default mappings if the file does not exist.
+    ///
+    /// Learned mappings are saved to the same file.
+    pub fn load(path: PathBuf) -> Result<Self> {
+        let mut mappings = Mappings::default();
+
+        match File::open(&path) {
+            Ok(file) => mappings
+                .read_from(BufReader::new(file))
+                .chain_err(|| format!("Failed to load mappings from {}", path.display()))?,
+            Err(ref e) if e.kind() == IoErrorKind::NotFound => {}
+            Err(e) => {
+                return Err(e)
+                    .chain_err(|| format!("Failed to open mappings file {}", path.display()))
+            }
+        }
+
+        mappings.path = Some(path);
+        Ok(mappings)
+    }
+
+    fn read_from<R: BufRead>(&mut self, reader: R) -> Result<()> {
+        for (line_number, line) in reader.lines().enumerate() {
+            let line = line?;
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let mut parts = line.splitn(2, '=');
+            let key = parts.next().unwrap_or("").trim();
+            let control = parts.next().unwrap_or("").trim();
+
+            // Mappings written by another version may contain parameters this one does not know
+            let parameter = match ParameterId::from_key(key) {
+                Some(parameter) => parameter,
+                None => {
+                    println!("Ignoring mapping of unknown parameter: {}", key);
+                    continue;
+                }
+            };
+            let control = parse_control(control).ok_or_else(|| {
+                format!("Invalid control in line {}: {}", line_number + 1, control)
+            })?;
+
+            self.learn(parameter, control);
+        }
+
+        Ok(())
+    }
+
+    /// Saves the mappings to the file they were loaded from.
+    pub fn save(&self) -> Result<()> {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .chain_err(|| format!("Failed to create directory {}", dir.display()))?;
+        }
+
+        let file = File::create(path)
+            .chain_err(|| format!("Failed to create mappings file {}", path.display()))?;
+        self.write_to(&mut BufWriter::new(file))
+            .chain_err(|| format!("Failed to save mappings to {}", path.display()))
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writeln!(
+            writer,
+            "# <parameter> = <controller> cc|note <channel (0-15) or *> <number>"
+        )?;
+        for binding in &self.bindings {
+            writeln!(
+                writer,
+                "{} = {}",
+                binding.parameter.definition().key,
+                binding.control
+            )?;
+        }
+
+        Ok(())
+    }
+
+    pub fn bindings(&self) -> &[Binding] {
+        &self.bindings
+    }
+
+    /// Returns the binding that handles events of the given control.
+    pub fn find(&self, control: &Control) -> Option<Binding> {
+        self.bindings
+            .iter()
+            .find(|binding| binding.control.matches(control))
+            .cloned()
+    }
+
+    pub fn control(&self, parameter: ParameterId) -> Option<Control> {
+        self.bindings
+            .iter()
+            .find(|binding| binding.parameter == parameter)
+            .map(|binding| binding.control)
+    }
+
+    /// Binds a control to a parameter. If the control was bound to another parameter before, the
+    /// controls of the two parameters are swapped.
+    ///
+    /// Returns the control that the parameter was bound to before, if it is no longer bound.
+    pub fn learn(&mut self, parameter: ParameterId, control: Control) -> Option<Control> {
+        let previous = self.control(parameter);
+
+        match self
+            .bindings
+            .iter()
+            .position(|binding| binding.control.matches(&control))
+        {
+            Some(index) if self.bindings[index].parameter == parameter => return None,
+            Some(index) => match previous {
+                Some(previous) => self.bindings[index].control = previous,
+                None => {
+                    self.bindings.remove(index);
+                }
+            },
+            None => {}
+        }
+
+        self.bindings
+            .retain(|binding| binding.parameter != parameter);
+        self.bindings.push(Binding { parameter, control });
+
+        match previous {
+            Some(previous) if self.find(&previous).is_none() => Some(previous),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::process;
+
+    fn knob(channel: u8, control_number: u8) -> Control {
+        Control::Knob {
+            source: MidiControllerType::ControlPanel,
+            channel: Some(channel),
+            control_number,
+        }
+    }
+
+    #[test]
+    fn default_mappings() {
+        let mappings = Mappings::default();
+
+        assert_eq!(
+            Some(ParameterId::MasterTune),
+            mappings.find(&knob(3, 0x31)).map(|b| b.parameter)
+        );
+        assert_eq!(
+            Some(ParameterId::Oscillator1Volume),
+            mappings.find(&knob(0, 0x07)).map(|b| b.parameter)
+        );
+        assert_eq!(None, mappings.find(&knob(1, 0x07)));
+    }
+
+    #[test]
+    fn learn_unbound_control() {
+        let mut mappings = Mappings::default();
+
+        let previous = mappings.learn(ParameterId::MasterTune, knob(2, 0x10));
+
+        assert_eq!(
+            Some(Control::Knob {
+                source: MidiControllerType::ControlPanel,
+                channel: None,
+                control_number: 0x31,
+            }),
+            previous
+        );
+        assert_eq!(
+            Some(ParameterId::MasterTune),
+            mappings.find(&knob(2, 0x10)).map(|b| b.parameter)
+        );
+        assert_eq!(None, mappings.find(&knob(2, 0x31)));
+        assert_eq!(None, mappings.find(&knob(3, 0x10)));
+    }
+
+    #[test]
+    fn learn_bound_control_swaps_controls() {
+        let mut mappings = Mappings::default();
+
+        let button = Control::Button {
+            source: MidiControllerType::Keyboard,
+            channel: 0,
+            note_number: 36,
+        };
+        assert_eq!(
+            None,
+            mappings.learn(ParameterId::Oscillator1Volume, knob(0, 0x31))
+        );
+        mappings.learn(ParameterId::Oscillator1Enable, button);
+
+        assert_eq!(
+            Some(ParameterId::Oscillator1Volume),
+            mappings.find(&knob(0, 0x31)).map(|b| b.parameter)
+        );
+        assert_eq!(
+            Some(ParameterId::MasterTune),
+            mappings.find(&knob(0, 0x07)).map(|b| b.parameter)
+        );
+        assert_eq!(
+            Some(ParameterId::Oscillator1Enable),
+            mappings.find(&button).map(|b| b.parameter)
+        );
+        assert_eq!(
+            Mappings::default().bindings().len(),
+            mappings.bindings().len()
+        );
+    }
+
+    #[test]
+    fn controls_round_trip() {
+        for control in &[
+            knob(15, 127),
+            Control::Knob {
+                source: MidiControllerType::Keyboard,
+                channel: None,
+                control_number: 1,
+            },
+            Control::Button {
+                source: MidiControllerType::ControlPanel,
+                channel: 0,
+                note_number: 0x33,
+            },
+        ] {
+            assert_eq!(Some(*control), parse_control(&control.to_string()));
+        }
+
+        assert_eq!(None, parse_control("control_panel cc 16 7"));
+        assert_eq!(None, parse_control("control_panel note * 7"));
+        assert_eq!(None, parse_control("mixer cc 0 7"));
+        assert_eq!(None, parse_control("control_panel cc 0"));
+    }
+
+    #[test]
+    fn save_and_load() {
+        let path = env::temp_dir().join(format!("midi-synth-test-{}", process::id()));
+        let file = path.join("mappings.txt");
+
+        let mut mappings = Mappings::load(file.clone()).unwrap();
+        assert_eq!(Mappings::default().bindings, mappings.bindings);
+
+        mappings.learn(ParameterId::Oscillator1Range, knob(4, 0x14));
+        mappings.save().unwrap();
+
+        let loaded = Mappings::load(file).unwrap();
+        assert_eq!(mappings, loaded);
+
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn load_rejects_invalid_lines() {
+        let mut mappings = Mappings::default();
+        assert!(mappings
+            .read_from("osc1_volume = control_panel cc 0 128".as_bytes())
+            .is_err());
+        assert!(mappings
+            .read_from("osc1_volume = control_panel 0 7".as_bytes())
+            .is_err());
+    }
+
+    #[test]
+    fn unknown_parameters_are_ignored() {
+        let mut mappings = Mappings::default();
+        mappings
+            .read_from(
+                "volume = control_panel cc 0 7\nmaster_tune = control_panel cc 2 9\n".as_bytes(),
+            )
+            .unwrap();
+
+        assert_eq!(
+            Some(ParameterId::Oscillator1Volume),
+            mappings.find(&knob(0, 0x07)).map(|b| b.parameter)
+        );
+        assert_eq!(
+            Some(ParameterId::MasterTune),
+            mappings.find(&knob(2, 9)).map(|b| b.parameter)
+        );
+    }
+}
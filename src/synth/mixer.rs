@@ -3,11 +3,14 @@ use std::rc::Rc;
 
 use synth::oscillator::Oscillator;
 use synth::sample_stream::SampleStream;
+use synth::smoothed_value::SmoothedValue;
 
 pub struct Mixer {
     osc1: Rc<Oscillator>,
     osc1_enabled: Cell<bool>,
-    osc1_volume: Cell<f32>,
+    osc1_volume: SmoothedValue,
+    /// Gain of oscillator 1 applied by the modulation matrix
+    osc1_modulation: SmoothedValue,
 }
 
 impl Mixer {
@@ -15,7 +18,8 @@ impl Mixer {
         Self {
             osc1,
             osc1_enabled: Cell::new(false),
-            osc1_volume: Cell::new(0.0),
+            osc1_volume: SmoothedValue::new(0.0),
+            osc1_modulation: SmoothedValue::new(1.0),
         }
     }
 
@@ -26,18 +30,61 @@ impl Mixer {
 
     // TODO: take additional enum specifying which input and reuse set_volume() for all inputs
     pub fn set_volume(&self, volume: f32) {
-        self.osc1_volume.set(volume);
+        self.osc1_volume.set_target(volume);
+    }
+
+    /// Sets the number of samples over which a change of the volume is spread.
+    pub fn set_volume_ramp_length(&self, samples: usize) {
+        self.osc1_volume.set_ramp_length(samples);
+    }
+
+    /// Sets the gain of the modulation of oscillator 1, which is reached after the ramp length.
+    pub fn set_modulation(&self, gain: f32) {
+        if gain != self.osc1_modulation.target() {
+            self.osc1_modulation.set_target(gain);
+        }
+    }
+
+    /// Sets the number of samples over which a change of the modulation is spread.
+    pub fn set_modulation_ramp_length(&self, samples: usize) {
+        self.osc1_modulation.set_ramp_length(samples);
     }
 }
 
 impl SampleStream for Mixer {
     fn next_sample(&self) -> f32 {
+        let volume = self.osc1_volume.next_value() * self.osc1_modulation.next_value();
+
         if self.osc1_enabled.get() {
-            self.osc1.next_sample() * self.osc1_volume.get()
+            self.osc1.next_sample() * volume
         } else {
             0.0
         }
     }
+
+    fn fill_buffer(&self, buffer: &mut [f32]) {
+        if self.osc1_enabled.get() {
+            self.osc1.fill_buffer(buffer);
+
+            if self.osc1_volume.is_smoothing() || self.osc1_modulation.is_smoothing() {
+                for sample in buffer.iter_mut() {
+                    *sample *= self.osc1_volume.next_value() * self.osc1_modulation.next_value();
+                }
+            } else {
+                let volume = self.osc1_volume.value() * self.osc1_modulation.value();
+                for sample in buffer.iter_mut() {
+                    *sample *= volume;
+                }
+            }
+        } else {
+            self.osc1_volume.skip(buffer.len());
+            self.osc1_modulation.skip(buffer.len());
+
+            for sample in buffer.iter_mut() {
+                *sample = 0.0;
+            }
+        }
+    }
 }
 
 iterator!(Mixer);
@@ -87,4 +134,67 @@ mod tests {
         }
         assert_eq!(i, 10);
     }
+
+    #[test]
+    fn volume_changes_are_smoothed() {
+        let osc1 = Rc::new(Oscillator::new(1.0, 0.0375));
+        let ref_osc1 = Oscillator::new(1.0, 0.0375);
+        let mixer = Mixer::new(osc1);
+        mixer.set_enabled(true);
+        mixer.set_volume_ramp_length(100);
+
+        // Full fader movement in a single step
+        mixer.set_volume(1.0);
+
+        let mut buffer = [0.0; 150];
+        mixer.fill_buffer(&mut buffer[..50]);
+        mixer.fill_buffer(&mut buffer[50..]);
+
+        for (i, (mixed, reference)) in buffer.iter().zip(ref_osc1).enumerate() {
+            let volume = ((i + 1) as f32 / 100.0).min(1.0);
+            assert_float_eq!(reference * volume, *mixed, 1e-5);
+        }
+    }
+
+    #[test]
+    fn modulation_scales_volume() {
+        let osc1 = Rc::new(Oscillator::new(1.0, 0.0375));
+        let ref_osc1 = Oscillator::new(1.0, 0.0375);
+        let mixer = Mixer::new(osc1);
+        mixer.set_enabled(true);
+        mixer.set_volume(0.5);
+        mixer.set_modulation_ramp_length(4);
+        mixer.set_modulation(2.0);
+
+        let mut buffer = [0.0; 8];
+        mixer.fill_buffer(&mut buffer);
+
+        for (i, (mixed, reference)) in buffer.iter().zip(ref_osc1).enumerate() {
+            let gain = 1.0 + ((i + 1) as f32 / 4.0).min(1.0);
+            assert_float_eq!(reference * 0.5 * gain, *mixed, 1e-6);
+        }
+    }
+
+    #[test]
+    fn fill_buffer() {
+        let osc1 = Rc::new(Oscillator::new(1.0, 0.0375));
+        let ref_osc1 = Oscillator::new(1.0, 0.0375);
+        let mixer = Mixer::new(osc1);
+        mixer.set_volume(0.5);
+
+        let mut buffer = [1.0; 10];
+        mixer.fill_buffer(&mut buffer);
+        for sample in buffer.iter() {
+            assert_float_eq!(0.0, *sample, 1e-6);
+        }
+
+        mixer.set_enabled(true);
+        mixer.fill_buffer(&mut buffer);
+
+        println!();
+        for (mixed, reference) in buffer.iter().zip(ref_osc1) {
+            println!("Mixer output: {}, Reference: {}", mixed, reference);
+            assert_float_eq!(reference * 0.5, *mixed, 1e-6);
+        }
+    }
 }
@@ -1,9 +1,26 @@
 #[macro_use]
 pub mod sample_stream;
 
+pub mod arpeggiator;
 pub mod audio_driver;
+pub mod clock;
 pub mod contour;
+pub mod control_surface;
 pub mod dispatcher;
+pub mod lfo;
+pub mod mapping;
 pub mod mixer;
+pub mod modulation;
+pub mod mpe;
 pub mod oscillator;
+pub mod parameter;
+pub mod patch;
+pub mod sequencer;
+pub mod settings;
+pub mod smoothed_value;
+pub mod spsc;
+pub mod step_clock;
 pub mod synthesizer;
+pub mod takeover;
+pub mod tuning;
+pub mod zone;
@@ -0,0 +1,230 @@
+/// Number of slots of the modulation matrix
+pub const MODULATION_SLOTS: usize = 4;
+
+const SOURCE_COUNT: usize = 7;
+
+/// Signal that modulates a destination. All sources are normalized to -1.0 to 1.0 (or 0.0 to
+/// 1.0 for unipolar sources).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ModulationSource {
+    /// Velocity of the last played note
+    Velocity,
+    /// Channel pressure of the keyboard, or the polyphonic pressure of the playing note
+    Aftertouch,
+    ModWheel,
+    /// Distance of the playing note from middle C (-1.0 to 1.0 over `KEY_POSITION_OCTAVES`)
+    KeyPosition,
+    /// Whether the voice group plays a note (0.0 or 1.0)
+    Gate,
+    Lfo,
+    /// Timbre (MPE CC74) of the playing note
+    Timbre,
+}
+
+impl ModulationSource {
+    /// Returns the source for a value of a `ParameterId::Modulation*Source`, or `None` if the
+    /// slot is off.
+    pub fn from_value(value: f32) -> Option<Self> {
+        match value.round() as i32 {
+            1 => Some(ModulationSource::Velocity),
+            2 => Some(ModulationSource::Aftertouch),
+            3 => Some(ModulationSource::ModWheel),
+            4 => Some(ModulationSource::KeyPosition),
+            5 => Some(ModulationSource::Gate),
+            6 => Some(ModulationSource::Lfo),
+            7 => Some(ModulationSource::Timbre),
+            _ => None,
+        }
+    }
+}
+
+/// Response of the aftertouch source to the pressure.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PressureCurve {
+    /// Responds strongly to light pressure
+    Soft,
+    Linear,
+    /// Responds mostly to strong pressure
+    Hard,
+}
+
+impl PressureCurve {
+    /// Returns the curve for a value of `ParameterId::PressureCurve`.
+    pub fn from_value(value: f32) -> Self {
+        match value.round() as i32 {
+            0 => PressureCurve::Soft,
+            2 => PressureCurve::Hard,
+            _ => PressureCurve::Linear,
+        }
+    }
+
+    /// Applies the curve to a pressure (0.0 to 1.0).
+    pub fn apply(self, pressure: f32) -> f32 {
+        match self {
+            PressureCurve::Soft => pressure.sqrt(),
+            PressureCurve::Linear => pressure,
+            PressureCurve::Hard => pressure * pressure,
+        }
+    }
+}
+
+/// Octaves from middle C at which the key position reaches its maximum
+pub const KEY_POSITION_OCTAVES: f32 = 4.0;
+
+/// Modulated property of a module of the synthesizer. New modules add their properties here and
+/// apply them in `Synthesizer::modulate()`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ModulationDestination {
+    /// Pitch of the oscillators, full modulation is `PITCH_MODULATION_RANGE`
+    Pitch,
+    /// Level of oscillator 1 in the mixer, full modulation doubles or silences it
+    Oscillator1Level,
+    /// Gain of the loudness contour, full modulation doubles or silences it
+    Amplitude,
+}
+
+/// Semitones by which a full modulation of `ModulationDestination::Pitch` changes the pitch
+pub const PITCH_MODULATION_RANGE: f32 = 12.0;
+
+impl ModulationDestination {
+    /// Returns the destination for a value of a `ParameterId::Modulation*Destination`.
+    pub fn from_value(value: f32) -> Self {
+        match value.round() as i32 {
+            1 => ModulationDestination::Oscillator1Level,
+            2 => ModulationDestination::Amplitude,
+            _ => ModulationDestination::Pitch,
+        }
+    }
+}
+
+/// Routing of a source to a destination.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ModulationSlot {
+    pub source: Option<ModulationSource>,
+    pub destination: ModulationDestination,
+    /// Bipolar amount (-1.0 to 1.0) by which the source is scaled
+    pub amount: f32,
+}
+
+/// Routes the modulation sources to the destinations through a fixed number of slots.
+///
+/// The current value of each source is set by the synthesizer, which reads the sum of the
+/// modulation of each destination.
+pub struct ModulationMatrix {
+    slots: [ModulationSlot; MODULATION_SLOTS],
+    inputs: [f32; SOURCE_COUNT],
+}
+
+impl ModulationMatrix {
+    pub fn new() -> Self {
+        let mut matrix = Self {
+            slots: [ModulationSlot {
+                source: None,
+                destination: ModulationDestination::Pitch,
+                amount: 0.0,
+            }; MODULATION_SLOTS],
+            inputs: [0.0; SOURCE_COUNT],
+        };
+        // Full velocity until the first note is played
+        matrix.set_input(ModulationSource::Velocity, 1.0);
+        matrix
+    }
+
+    pub fn set_source(&mut self, slot: usize, source: Option<ModulationSource>) {
+        if let Some(slot) = self.slots.get_mut(slot) {
+            slot.source = source;
+        }
+    }
+
+    pub fn set_destination(&mut self, slot: usize, destination: ModulationDestination) {
+        if let Some(slot) = self.slots.get_mut(slot) {
+            slot.destination = destination;
+        }
+    }
+
+    pub fn set_amount(&mut self, slot: usize, amount: f32) {
+        if let Some(slot) = self.slots.get_mut(slot) {
+            slot.amount = amount.clamp(-1.0, 1.0);
+        }
+    }
+
+    /// Sets the current value of a source.
+    pub fn set_input(&mut self, source: ModulationSource, value: f32) {
+        self.inputs[source as usize] = value;
+    }
+
+    pub fn input(&self, source: ModulationSource) -> f32 {
+        self.inputs[source as usize]
+    }
+
+    /// Sum of the modulation of a destination by all slots.
+    pub fn output(&self, destination: ModulationDestination) -> f32 {
+        self.slots
+            .iter()
+            .filter(|slot| slot.destination == destination)
+            .filter_map(|slot| slot.source.map(|source| slot.amount * self.input(source)))
+            .sum()
+    }
+}
+
+/// Converts the modulation of a level (-1.0 to 1.0, or beyond with several slots) to a gain
+/// between 0.0 and 2.0.
+pub fn modulation_gain(modulation: f32) -> f32 {
+    (1.0 + modulation).clamp(0.0, 2.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slots_are_summed_per_destination() {
+        let mut matrix = ModulationMatrix::new();
+        matrix.set_input(ModulationSource::Lfo, -0.5);
+        matrix.set_input(ModulationSource::ModWheel, 1.0);
+
+        matrix.set_source(0, Some(ModulationSource::Lfo));
+        matrix.set_amount(0, 0.5);
+        matrix.set_source(1, Some(ModulationSource::ModWheel));
+        matrix.set_amount(1, -0.25);
+        matrix.set_source(2, Some(ModulationSource::Lfo));
+        matrix.set_destination(2, ModulationDestination::Amplitude);
+        matrix.set_amount(2, 1.0);
+
+        assert_float_eq!(-0.5, matrix.output(ModulationDestination::Pitch), 1e-6);
+        assert_float_eq!(-0.5, matrix.output(ModulationDestination::Amplitude), 1e-6);
+        assert_eq!(0.0, matrix.output(ModulationDestination::Oscillator1Level));
+
+        // Switched off slots do not modulate
+        matrix.set_source(1, None);
+        assert_float_eq!(-0.25, matrix.output(ModulationDestination::Pitch), 1e-6);
+    }
+
+    #[test]
+    fn amount_is_bipolar() {
+        let mut matrix = ModulationMatrix::new();
+        matrix.set_source(3, Some(ModulationSource::Velocity));
+        matrix.set_destination(3, ModulationDestination::Oscillator1Level);
+
+        // Full velocity until the first note
+        matrix.set_amount(3, 2.0);
+        assert_eq!(1.0, matrix.output(ModulationDestination::Oscillator1Level));
+        matrix.set_amount(3, -2.0);
+        assert_eq!(-1.0, matrix.output(ModulationDestination::Oscillator1Level));
+    }
+
+    #[test]
+    fn pressure_curves() {
+        assert_eq!(0.5, PressureCurve::Soft.apply(0.25));
+        assert_eq!(0.25, PressureCurve::Linear.apply(0.25));
+        assert_eq!(0.0625, PressureCurve::Hard.apply(0.25));
+        assert_eq!(1.0, PressureCurve::Hard.apply(1.0));
+    }
+
+    #[test]
+    fn gain() {
+        assert_eq!(0.0, modulation_gain(-1.5));
+        assert_eq!(1.5, modulation_gain(0.5));
+        assert_eq!(2.0, modulation_gain(3.0));
+    }
+}
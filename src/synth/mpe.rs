@@ -0,0 +1,264 @@
+/// Controllers that select a registered parameter (RPN) and set its value
+const RPN_MSB: u8 = 0x65;
+const RPN_LSB: u8 = 0x64;
+const DATA_ENTRY_MSB: u8 = 0x06;
+const DATA_ENTRY_LSB: u8 = 0x26;
+
+/// Registered parameters (MSB << 7 | LSB)
+const PITCH_BEND_SENSITIVITY: u16 = 0x0000;
+const MPE_CONFIGURATION: u16 = 0x0006;
+/// No parameter selected, data entry is ignored
+const NULL_RPN: u16 = 0x3FFF;
+
+/// Controller that sets the timbre of the note on a member channel (the third dimension of MPE)
+pub const TIMBRE: u8 = 74;
+
+const LOWER_MANAGER: u8 = 0;
+const UPPER_MANAGER: u8 = 15;
+
+/// Pitch bend ranges in semitones until set by the sender
+const DEFAULT_MEMBER_BEND_RANGE: f32 = 48.0;
+const DEFAULT_BEND_RANGE: f32 = 2.0;
+
+/// Layout of the MPE zones, i.e. the number of member channels of each zone (0 disables it).
+///
+/// The manager channel of the lower zone is channel 1, its members follow it. The manager
+/// channel of the upper zone is channel 16, its members precede it.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct MpeZones {
+    pub lower: u8,
+    pub upper: u8,
+}
+
+impl MpeZones {
+    /// Sets the number of member channels of the zone with the given manager channel. The other
+    /// zone shrinks if the zones would overlap.
+    pub fn configure(&mut self, manager: u8, members: u8) {
+        let members = members.min(15);
+        match manager {
+            LOWER_MANAGER => {
+                self.lower = members;
+                self.upper = self.upper.min(14_u8.saturating_sub(members));
+            }
+            UPPER_MANAGER => {
+                self.upper = members;
+                self.lower = self.lower.min(14_u8.saturating_sub(members));
+            }
+            _ => {}
+        }
+    }
+
+    /// Returns true if each note on the channel has its own expression.
+    pub fn is_member(&self, channel: u8) -> bool {
+        self.is_lower_member(channel)
+            || (channel < UPPER_MANAGER && channel >= UPPER_MANAGER - self.upper)
+    }
+
+    fn is_lower_member(&self, channel: u8) -> bool {
+        channel > LOWER_MANAGER && channel <= self.lower
+    }
+}
+
+/// Expression of a member channel, which applies to the note played on it.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct MemberChannel {
+    pub note: Option<u8>,
+    /// Pitch bend in semitones
+    pub bend: f32,
+    /// Timbre (0.0 to 1.0)
+    pub timbre: f32,
+    /// Channel pressure (0.0 to 1.0)
+    pub pressure: f32,
+}
+
+/// Receiver of MIDI Polyphonic Expression: the zones (as configured statically or by the MPE
+/// Configuration RPN), the pitch bend ranges and the expression of each member channel.
+///
+/// Messages on channels that are no members (e.g. the manager channels) are global.
+pub struct Mpe {
+    zones: MpeZones,
+    /// Registered parameter selected on each channel
+    rpn: [u16; 16],
+    /// Pitch bend range of each channel in semitones
+    bend_range: [f32; 16],
+    channels: [MemberChannel; 16],
+}
+
+impl Mpe {
+    pub fn new(zones: MpeZones) -> Self {
+        let mut mpe = Self {
+            zones,
+            rpn: [NULL_RPN; 16],
+            bend_range: [DEFAULT_BEND_RANGE; 16],
+            channels: [MemberChannel::default(); 16],
+        };
+        mpe.reset_bend_ranges();
+        mpe
+    }
+
+    pub fn zones(&self) -> MpeZones {
+        self.zones
+    }
+
+    /// Returns the state of a member channel, or `None` if the channel is no member.
+    pub fn member(&mut self, channel: u8) -> Option<&mut MemberChannel> {
+        if self.zones.is_member(channel) {
+            self.channels.get_mut(usize::from(channel))
+        } else {
+            None
+        }
+    }
+
+    /// Converts a 14-bit pitch bend value to semitones with the range of the channel.
+    pub fn bend(&self, channel: u8, value: u16) -> f32 {
+        let range = self.bend_range[usize::from(channel & 0x0F)];
+        (f32::from(value.min(0x3FFF)) - 8192.0) / 8192.0 * range
+    }
+
+    /// Handles the controllers that set registered parameters. Returns false if the controller
+    /// is not part of an RPN message.
+    pub fn control_change(&mut self, channel: u8, control_number: u8, value: u8) -> bool {
+        let channel = channel & 0x0F;
+        let rpn = self.rpn[usize::from(channel)];
+
+        match control_number {
+            RPN_MSB => self.rpn[usize::from(channel)] = u16::from(value) << 7 | (rpn & 0x7F),
+            RPN_LSB => self.rpn[usize::from(channel)] = (rpn & !0x7F) | u16::from(value),
+            DATA_ENTRY_MSB if rpn == MPE_CONFIGURATION => {
+                self.zones.configure(channel, value);
+                self.channels = [MemberChannel::default(); 16];
+                self.reset_bend_ranges();
+            }
+            DATA_ENTRY_MSB if rpn == PITCH_BEND_SENSITIVITY => {
+                self.set_bend_range(channel, f32::from(value))
+            }
+            DATA_ENTRY_LSB if rpn == PITCH_BEND_SENSITIVITY => {
+                let semitones = self.bend_range[usize::from(channel)].trunc();
+                self.set_bend_range(channel, semitones + f32::from(value.min(99)) / 100.0);
+            }
+            _ => return false,
+        }
+
+        true
+    }
+
+    /// Sets the pitch bend range of a channel. The range of a member channel applies to all
+    /// members of its zone.
+    fn set_bend_range(&mut self, channel: u8, range: f32) {
+        if self.zones.is_member(channel) {
+            let lower = self.zones.is_lower_member(channel);
+            for member in 0..16 {
+                if self.zones.is_member(member) && self.zones.is_lower_member(member) == lower {
+                    self.bend_range[usize::from(member)] = range;
+                }
+            }
+        } else {
+            self.bend_range[usize::from(channel)] = range;
+        }
+    }
+
+    fn reset_bend_ranges(&mut self) {
+        for channel in 0..16 {
+            self.bend_range[usize::from(channel)] = if self.zones.is_member(channel) {
+                DEFAULT_MEMBER_BEND_RANGE
+            } else {
+                DEFAULT_BEND_RANGE
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn send_rpn(mpe: &mut Mpe, channel: u8, rpn: u16, value: u8) {
+        assert!(mpe.control_change(channel, RPN_MSB, (rpn >> 7) as u8));
+        assert!(mpe.control_change(channel, RPN_LSB, (rpn & 0x7F) as u8));
+        assert!(mpe.control_change(channel, DATA_ENTRY_MSB, value));
+    }
+
+    #[test]
+    fn zones() {
+        let zones = MpeZones { lower: 3, upper: 2 };
+
+        assert!(!zones.is_member(0));
+        assert!(zones.is_member(1));
+        assert!(zones.is_member(3));
+        assert!(!zones.is_member(4));
+        assert!(!zones.is_member(12));
+        assert!(zones.is_member(13));
+        assert!(zones.is_member(14));
+        assert!(!zones.is_member(15));
+
+        assert!(!MpeZones::default().is_member(1));
+    }
+
+    #[test]
+    fn configuration_shrinks_other_zone() {
+        let mut zones = MpeZones { lower: 0, upper: 7 };
+
+        zones.configure(0, 10);
+        assert_eq!(
+            MpeZones {
+                lower: 10,
+                upper: 4
+            },
+            zones
+        );
+
+        zones.configure(15, 15);
+        assert_eq!(
+            MpeZones {
+                lower: 0,
+                upper: 15
+            },
+            zones
+        );
+        assert!(zones.is_member(0));
+
+        // Only the manager channels configure zones
+        zones.configure(3, 2);
+        assert_eq!(
+            MpeZones {
+                lower: 0,
+                upper: 15
+            },
+            zones
+        );
+    }
+
+    #[test]
+    fn mpe_configuration_rpn() {
+        let mut mpe = Mpe::new(MpeZones::default());
+        assert_eq!(None, mpe.member(1));
+
+        send_rpn(&mut mpe, 0, MPE_CONFIGURATION, 4);
+        assert_eq!(MpeZones { lower: 4, upper: 0 }, mpe.zones());
+        assert!(mpe.member(4).is_some());
+        assert_eq!(-48.0, mpe.bend(1, 0));
+        assert_eq!(-2.0, mpe.bend(0, 0));
+
+        // Data entry without a selected RPN is left to other uses
+        assert!(!mpe.control_change(1, DATA_ENTRY_MSB, 12));
+        send_rpn(&mut mpe, 0, MPE_CONFIGURATION, 0);
+        assert_eq!(None, mpe.member(4));
+        assert!(!mpe.control_change(1, 0x07, 100));
+    }
+
+    #[test]
+    fn pitch_bend_sensitivity() {
+        let mut mpe = Mpe::new(MpeZones { lower: 3, upper: 0 });
+
+        send_rpn(&mut mpe, 2, PITCH_BEND_SENSITIVITY, 24);
+        assert!(mpe.control_change(2, DATA_ENTRY_LSB, 50));
+        assert_eq!(-24.5, mpe.bend(1, 0));
+        assert_eq!(-24.5, mpe.bend(3, 0));
+        assert_eq!(-2.0, mpe.bend(0, 0));
+        assert_eq!(0.0, mpe.bend(3, 0x2000));
+
+        send_rpn(&mut mpe, 0, PITCH_BEND_SENSITIVITY, 12);
+        assert_eq!(-12.0, mpe.bend(0, 0));
+        assert_eq!(-24.5, mpe.bend(1, 0));
+    }
+}
@@ -18,6 +18,10 @@ impl Oscillator {
         self.triangle.set_master_tune(master_tune);
     }
 
+    pub fn set_master_tune_ramp_length(&self, samples: usize) {
+        self.triangle.set_master_tune_ramp_length(samples);
+    }
+
     pub fn set_range(&self, range: f32) {
         self.triangle.set_range(range);
     }
@@ -25,12 +29,21 @@ impl Oscillator {
     pub fn set_note(&self, note: f32) {
         self.triangle.set_note(note);
     }
+
+    /// Shifts the pitch by a frequency ratio, on top of note, range and master tune.
+    pub fn set_pitch_modulation(&self, ratio: f32) {
+        self.triangle.set_modulation(ratio);
+    }
 }
 
 impl SampleStream for Oscillator {
     fn next_sample(&self) -> f32 {
         self.triangle.next_sample()
     }
+
+    fn fill_buffer(&self, buffer: &mut [f32]) {
+        self.triangle.fill_buffer(buffer)
+    }
 }
 
 iterator!(Oscillator);
@@ -1,13 +1,16 @@
 use std::cell::Cell;
 
 use synth::sample_stream::SampleStream;
+use synth::smoothed_value::SmoothedValue;
 
 #[derive(Debug)]
 pub struct Triangle {
     base_frequency: Cell<f32>,
-    master_tune: Cell<f32>,
+    master_tune: SmoothedValue,
     range: Cell<f32>,
     note: Cell<f32>,
+    /// Frequency ratio applied by a modulation source (e.g. vibrato)
+    modulation: Cell<f32>,
     sample_counter: Cell<f32>,
     phase_offset: Cell<f32>,
 }
@@ -16,44 +19,70 @@ impl Triangle {
     pub fn new(master_tune: f32, range: f32) -> Triangle {
         Triangle {
             base_frequency: Cell::new(master_tune * range),
-            master_tune: Cell::new(master_tune),
+            master_tune: SmoothedValue::new(master_tune),
             range: Cell::new(range),
             note: Cell::new(1.0),
+            modulation: Cell::new(1.0),
             sample_counter: Cell::new(0.0),
             phase_offset: Cell::new(0.25),
         }
     }
 
     pub fn set_range(&self, range: f32) {
-        self.update_base_frequency(self.master_tune.get(), range, self.note.get());
+        self.update_base_frequency(self.master_tune.value(), range, self.note.get());
         self.range.set(range);
     }
 
     pub fn set_master_tune(&self, master_tune: f32) {
-        self.update_base_frequency(master_tune, self.range.get(), self.note.get());
-        self.master_tune.set(master_tune);
+        self.master_tune.set_target(master_tune);
+        if !self.master_tune.is_smoothing() {
+            self.update_base_frequency(master_tune, self.range.get(), self.note.get());
+        }
+    }
+
+    /// Sets the number of samples over which a change of the master tune is spread.
+    pub fn set_master_tune_ramp_length(&self, samples: usize) {
+        self.master_tune.set_ramp_length(samples);
     }
 
     pub fn set_note(&self, note: f32) {
-        self.update_base_frequency(self.master_tune.get(), self.range.get(), note);
+        self.update_base_frequency(self.master_tune.value(), self.range.get(), note);
         self.note.set(note);
     }
 
+    /// Sets the frequency ratio of the modulation.
+    pub fn set_modulation(&self, modulation: f32) {
+        if modulation != self.modulation.get() {
+            self.modulation.set(modulation);
+            self.update_base_frequency(self.master_tune.value(), self.range.get(), self.note.get());
+        }
+    }
+
     fn update_base_frequency(&self, master_tune: f32, range: f32, note: f32) {
         self.phase_offset
             .set(self.phase_offset.get() + self.sample_counter.get() * self.base_frequency.get());
-        self.base_frequency.set(master_tune * range * note);
+        self.base_frequency
+            .set(master_tune * range * note * self.modulation.get());
         self.sample_counter.set(0.0);
     }
 }
 
-impl SampleStream for Triangle {
-    fn next_sample(&self) -> f32 {
+impl Triangle {
+    /// Calculates the next output value and advances the phase.
+    ///
+    /// Takes the state as arguments, so that `fill_buffer()` can keep it in local variables
+    /// instead of reading and writing the cells for every sample.
+    #[inline]
+    fn advance(
+        &self,
+        phase_offset: &mut f32,
+        sample_counter: &mut f32,
+        base_frequency: f32,
+    ) -> f32 {
         // Calculate phase angle
         // (Do it this seemingly more complicated than necessary way, since this seems to minimize
         // floating point errors)
-        let mut sample_counter = self.sample_counter.get();
-        let mut phase_angle = self.phase_offset.get() + sample_counter * self.base_frequency.get();
+        let mut phase_angle = *phase_offset + *sample_counter * base_frequency;
 
         let mut wraparound = false;
         while phase_angle >= 1.0 {
@@ -62,11 +91,11 @@ impl SampleStream for Triangle {
         }
 
         if wraparound {
-            sample_counter = 0.0;
-            self.phase_offset.set(phase_angle);
+            *sample_counter = 0.0;
+            *phase_offset = phase_angle;
         }
 
-        self.sample_counter.set(sample_counter + 1.0);
+        *sample_counter += 1.0;
 
         // Calculate output value
         let output_value = if phase_angle < 0.5 {
@@ -86,6 +115,50 @@ impl SampleStream for Triangle {
     }
 }
 
+impl SampleStream for Triangle {
+    fn next_sample(&self) -> f32 {
+        // While the master tune is ramping, the frequency changes with every sample
+        if self.master_tune.is_smoothing() {
+            let master_tune = self.master_tune.next_value();
+            self.update_base_frequency(master_tune, self.range.get(), self.note.get());
+        }
+
+        let mut phase_offset = self.phase_offset.get();
+        let mut sample_counter = self.sample_counter.get();
+
+        let output_value = self.advance(
+            &mut phase_offset,
+            &mut sample_counter,
+            self.base_frequency.get(),
+        );
+
+        self.phase_offset.set(phase_offset);
+        self.sample_counter.set(sample_counter);
+
+        output_value
+    }
+
+    fn fill_buffer(&self, buffer: &mut [f32]) {
+        let mut start = 0;
+        while self.master_tune.is_smoothing() && start < buffer.len() {
+            buffer[start] = self.next_sample();
+            start += 1;
+        }
+        let buffer = &mut buffer[start..];
+
+        let base_frequency = self.base_frequency.get();
+        let mut phase_offset = self.phase_offset.get();
+        let mut sample_counter = self.sample_counter.get();
+
+        for sample in buffer.iter_mut() {
+            *sample = self.advance(&mut phase_offset, &mut sample_counter, base_frequency);
+        }
+
+        self.phase_offset.set(phase_offset);
+        self.sample_counter.set(sample_counter);
+    }
+}
+
 iterator!(Triangle);
 
 #[cfg(test)]
@@ -156,6 +229,41 @@ mod tests {
         compare!(triangle, samples, 1e-6);
     }
 
+    #[test]
+    fn master_tune_changes_are_smoothed() {
+        let mut triangle = Triangle::new(1.0, 0.0375);
+        triangle.set_master_tune_ramp_length(10);
+
+        triangle.next().unwrap();
+        triangle.set_master_tune(1.5);
+
+        // The slope of the waveform is 4 times the frequency, which must not change by more
+        // than 1/10 of the total change of the frequency per sample
+        let max_delta = 4.0 * 0.5 * 0.0375 / 10.0 + 1e-5;
+        let samples = (&mut triangle).take(40).collect::<Vec<_>>();
+        let slopes = samples.windows(2).map(|w| w[1] - w[0]).collect::<Vec<_>>();
+        for slopes in slopes.windows(4) {
+            // Skip the peaks of the waveform
+            if slopes
+                .iter()
+                .all(|slope| slope.signum() == slopes[0].signum())
+            {
+                assert!((slopes[2].abs() - slopes[1].abs()).abs() <= max_delta);
+            }
+        }
+
+        // Final frequency is reached after the ramp
+        let mut buffer = [0.0; 50];
+        triangle.fill_buffer(&mut buffer);
+        let slopes = buffer.windows(2).map(|w| w[1] - w[0]).collect::<Vec<_>>();
+        for slopes in slopes.windows(3) {
+            if slopes[0].signum() == slopes[1].signum() && slopes[1].signum() == slopes[2].signum()
+            {
+                assert_float_eq!(4.0 * 1.5 * 0.0375, slopes[1].abs(), 1e-4);
+            }
+        }
+    }
+
     #[test]
     fn play_notes() {
         let mut triangle = Triangle::new(1.0, 0.0375);
@@ -174,4 +282,22 @@ mod tests {
 
         compare!(triangle, samples, 1e-6);
     }
+
+    #[test]
+    fn fill_buffer_matches_next_sample() {
+        let triangle = Triangle::new(1.0, 0.0375);
+        let mut reference = Triangle::new(1.0, 0.0375);
+
+        let mut buffer = [0.0; 50];
+        triangle.fill_buffer(&mut buffer[..20]);
+        triangle.set_note(1.5);
+        triangle.fill_buffer(&mut buffer[20..]);
+
+        for (i, sample) in buffer.iter().enumerate() {
+            if i == 20 {
+                reference.set_note(1.5);
+            }
+            assert_float_eq!(reference.next().unwrap(), *sample, 1e-6);
+        }
+    }
 }
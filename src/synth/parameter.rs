@@ -0,0 +1,1373 @@
+use std::f64;
+use std::io::{self, Write};
+
+use synth::dispatcher::SynthControl;
+use synth::lfo::{LfoShape, DIVISION_BEATS};
+use synth::modulation::{ModulationDestination, ModulationSource, PressureCurve, MODULATION_SLOTS};
+use synth::synthesizer::VOICE_GROUPS;
+use synth::takeover::Takeover;
+use synth::tuning::MIDDLE_C;
+
+use errors::*;
+
+/// Parameter of the synthesizer that can be controlled by the user.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ParameterId {
+    MasterTune,
+    /// Level of the mix of all voice groups, which leaves headroom for layered zones
+    MasterVolume,
+    Oscillator1Range,
+    Oscillator1Enable,
+    Oscillator1Volume,
+    /// Oscillator 1 of the second voice group (e.g. the lower zone of a split keyboard)
+    Group1Oscillator1Range,
+    Group1Oscillator1Enable,
+    Group1Oscillator1Volume,
+    ArpeggiatorEnable,
+    ArpeggiatorOrder,
+    ArpeggiatorOctaves,
+    ArpeggiatorGate,
+    ArpeggiatorRate,
+    ArpeggiatorLatch,
+    Tempo,
+    ClockSync,
+    LfoShape,
+    LfoRate,
+    LfoDivision,
+    LfoKeySync,
+    LfoDelay,
+    LfoFadeIn,
+    Modulation1Source,
+    Modulation1Destination,
+    Modulation1Amount,
+    Modulation2Source,
+    Modulation2Destination,
+    Modulation2Amount,
+    Modulation3Source,
+    Modulation3Destination,
+    Modulation3Amount,
+    Modulation4Source,
+    Modulation4Destination,
+    Modulation4Amount,
+    PressureCurve,
+    /// Tuning program, loaded from the Scala files of the tuning bank
+    Tuning,
+    /// Semitones by which the keys are transposed
+    Transpose,
+    /// Octaves by which the keys are shifted, in addition to the transposition
+    OctaveShift,
+}
+
+pub const PARAMETER_COUNT: usize = 38;
+
+pub const PARAMETERS: [ParameterId; PARAMETER_COUNT] = [
+    ParameterId::MasterTune,
+    ParameterId::MasterVolume,
+    ParameterId::Oscillator1Range,
+    ParameterId::Oscillator1Enable,
+    ParameterId::Oscillator1Volume,
+    ParameterId::Group1Oscillator1Range,
+    ParameterId::Group1Oscillator1Enable,
+    ParameterId::Group1Oscillator1Volume,
+    ParameterId::ArpeggiatorEnable,
+    ParameterId::ArpeggiatorOrder,
+    ParameterId::ArpeggiatorOctaves,
+    ParameterId::ArpeggiatorGate,
+    ParameterId::ArpeggiatorRate,
+    ParameterId::ArpeggiatorLatch,
+    ParameterId::Tempo,
+    ParameterId::ClockSync,
+    ParameterId::LfoShape,
+    ParameterId::LfoRate,
+    ParameterId::LfoDivision,
+    ParameterId::LfoKeySync,
+    ParameterId::LfoDelay,
+    ParameterId::LfoFadeIn,
+    ParameterId::Modulation1Source,
+    ParameterId::Modulation1Destination,
+    ParameterId::Modulation1Amount,
+    ParameterId::Modulation2Source,
+    ParameterId::Modulation2Destination,
+    ParameterId::Modulation2Amount,
+    ParameterId::Modulation3Source,
+    ParameterId::Modulation3Destination,
+    ParameterId::Modulation3Amount,
+    ParameterId::Modulation4Source,
+    ParameterId::Modulation4Destination,
+    ParameterId::Modulation4Amount,
+    ParameterId::PressureCurve,
+    ParameterId::Tuning,
+    ParameterId::Transpose,
+    ParameterId::OctaveShift,
+];
+
+impl ParameterId {
+    pub fn definition(self) -> &'static Parameter {
+        &DEFINITIONS[self as usize]
+    }
+
+    /// Looks up a parameter by its key (see `Parameter::key`).
+    pub fn from_key(key: &str) -> Option<ParameterId> {
+        PARAMETERS
+            .iter()
+            .cloned()
+            .find(|id| id.definition().key == key)
+    }
+
+    /// Returns whether the parameter belongs to the player rather than the sound, so that it is
+    /// neither saved in patches nor changed by loading them.
+    pub fn is_global(self) -> bool {
+        matches!(
+            self,
+            ParameterId::MasterVolume | ParameterId::Transpose | ParameterId::OctaveShift
+        )
+    }
+
+    /// Returns the control that sets the parameter in the synthesizer, or `None` if the
+    /// parameter is handled by the dispatcher (e.g. the arpeggiator settings).
+    pub fn synth_control(self, value: f32) -> Option<SynthControl> {
+        if let Some(control) = self
+            .modulation_control(value)
+            .or_else(|| self.voice_group_control(value))
+        {
+            return Some(control);
+        }
+
+        match self {
+            ParameterId::MasterTune => Some(SynthControl::MasterTune(value)),
+            ParameterId::MasterVolume => Some(SynthControl::MasterVolume(value)),
+            ParameterId::LfoShape => Some(SynthControl::LfoShape(LfoShape::from_value(value))),
+            ParameterId::LfoRate => Some(SynthControl::LfoRate(value)),
+            ParameterId::LfoDivision => {
+                // The first division is the free running rate
+                let index = value.round() as usize;
+                Some(SynthControl::LfoBeats(
+                    index
+                        .checked_sub(1)
+                        .and_then(|i| DIVISION_BEATS.get(i).cloned()),
+                ))
+            }
+            ParameterId::LfoKeySync => Some(SynthControl::LfoKeySync(value >= 0.5)),
+            ParameterId::LfoDelay => Some(SynthControl::LfoDelay(value)),
+            ParameterId::LfoFadeIn => Some(SynthControl::LfoFadeIn(value)),
+            ParameterId::PressureCurve => Some(SynthControl::PressureCurve(
+                PressureCurve::from_value(value),
+            )),
+            _ => None,
+        }
+    }
+
+    /// Returns the control of a parameter of a slot of the modulation matrix.
+    fn modulation_control(self, value: f32) -> Option<SynthControl> {
+        let slot = MODULATION_SLOT_PARAMETERS
+            .iter()
+            .position(|parameters| parameters.contains(&self))?;
+
+        Some(match MODULATION_SLOT_PARAMETERS[slot] {
+            [source, _, _] if source == self => {
+                SynthControl::ModulationSource(slot, ModulationSource::from_value(value))
+            }
+            [_, destination, _] if destination == self => {
+                SynthControl::ModulationDestination(slot, ModulationDestination::from_value(value))
+            }
+            _ => SynthControl::ModulationAmount(slot, value),
+        })
+    }
+
+    /// Returns the control of a parameter of the oscillator of a voice group.
+    fn voice_group_control(self, value: f32) -> Option<SynthControl> {
+        let group = VOICE_GROUP_PARAMETERS
+            .iter()
+            .position(|parameters| parameters.contains(&self))?;
+
+        Some(match VOICE_GROUP_PARAMETERS[group] {
+            [range, _, _] if range == self => SynthControl::Oscillator1Range(group, value),
+            [_, enable, _] if enable == self => {
+                SynthControl::Oscillator1Enable(group, value >= 0.5)
+            }
+            _ => SynthControl::Oscillator1Volume(group, value),
+        })
+    }
+}
+
+/// Range, enable and volume of oscillator 1 of each voice group
+const VOICE_GROUP_PARAMETERS: [[ParameterId; 3]; VOICE_GROUPS] = [
+    [
+        ParameterId::Oscillator1Range,
+        ParameterId::Oscillator1Enable,
+        ParameterId::Oscillator1Volume,
+    ],
+    [
+        ParameterId::Group1Oscillator1Range,
+        ParameterId::Group1Oscillator1Enable,
+        ParameterId::Group1Oscillator1Volume,
+    ],
+];
+
+/// Source, destination and amount of each slot of the modulation matrix
+const MODULATION_SLOT_PARAMETERS: [[ParameterId; 3]; MODULATION_SLOTS] = [
+    [
+        ParameterId::Modulation1Source,
+        ParameterId::Modulation1Destination,
+        ParameterId::Modulation1Amount,
+    ],
+    [
+        ParameterId::Modulation2Source,
+        ParameterId::Modulation2Destination,
+        ParameterId::Modulation2Amount,
+    ],
+    [
+        ParameterId::Modulation3Source,
+        ParameterId::Modulation3Destination,
+        ParameterId::Modulation3Amount,
+    ],
+    [
+        ParameterId::Modulation4Source,
+        ParameterId::Modulation4Destination,
+        ParameterId::Modulation4Amount,
+    ],
+];
+
+/// Maps the position of a control (0.0 to 1.0) to the value of a parameter.
+#[derive(Debug)]
+pub enum Taper {
+    /// Value changes linearly from `min` to `max`
+    Linear,
+    /// Value changes by the same ratio per step from `min` to `max`
+    Exponential,
+    /// Value is a gain that changes linearly in dB, `min` and `max` are given in dB
+    Decibel,
+    /// Value snaps to detents, controller values between detents are ignored
+    Stepped(&'static [Detent]),
+    /// Value is either `min` (off) or `max` (on)
+    Switch,
+}
+
+/// Position of a stepped control, covering the controller values from `min` to `max`.
+///
+/// The first detent extends down to 0 and the last one up to 127.
+#[derive(Debug)]
+pub struct Detent {
+    pub min: u8,
+    pub max: u8,
+    pub value: f32,
+}
+
+#[derive(Debug)]
+pub enum Unit {
+    /// Frequency ratio, displayed in cents
+    Cents,
+    Hertz,
+    /// Gain, displayed in dB
+    Decibels,
+    OnOff,
+    Percent,
+    BeatsPerMinute,
+    Seconds,
+    /// Whole number
+    Count,
+    /// One of several options, the value is the index of its name
+    Choice(&'static [&'static str]),
+}
+
+#[derive(Debug)]
+pub struct Parameter {
+    /// Unique key used for persistence
+    pub key: &'static str,
+    pub name: &'static str,
+    pub min: f32,
+    pub max: f32,
+    pub taper: Taper,
+    pub default: f32,
+    pub unit: Unit,
+    /// How a control takes over the value after it was changed by a patch
+    pub takeover: Takeover,
+}
+
+const RANGE_DETENTS: [Detent; 6] = [
+    Detent {
+        min: 21,
+        max: 21,
+        value: 0.0625 * MIDDLE_C,
+    },
+    Detent {
+        min: 35,
+        max: 38,
+        value: 0.25 * MIDDLE_C,
+    },
+    Detent {
+        min: 53,
+        max: 56,
+        value: 0.5 * MIDDLE_C,
+    },
+    Detent {
+        min: 70,
+        max: 73,
+        value: MIDDLE_C,
+    },
+    Detent {
+        min: 88,
+        max: 91,
+        value: 2.0 * MIDDLE_C,
+    },
+    Detent {
+        min: 105,
+        max: 105,
+        value: 4.0 * MIDDLE_C,
+    },
+];
+
+const ARPEGGIATOR_ORDERS: [&str; 5] = ["up", "down", "up/down", "random", "as played"];
+
+const ARPEGGIATOR_ORDER_DETENTS: [Detent; 5] = [
+    Detent {
+        min: 0,
+        max: 25,
+        value: 0.0,
+    },
+    Detent {
+        min: 26,
+        max: 50,
+        value: 1.0,
+    },
+    Detent {
+        min: 51,
+        max: 76,
+        value: 2.0,
+    },
+    Detent {
+        min: 77,
+        max: 101,
+        value: 3.0,
+    },
+    Detent {
+        min: 102,
+        max: 127,
+        value: 4.0,
+    },
+];
+
+const ARPEGGIATOR_OCTAVE_DETENTS: [Detent; 4] = [
+    Detent {
+        min: 0,
+        max: 31,
+        value: 1.0,
+    },
+    Detent {
+        min: 32,
+        max: 63,
+        value: 2.0,
+    },
+    Detent {
+        min: 64,
+        max: 95,
+        value: 3.0,
+    },
+    Detent {
+        min: 96,
+        max: 127,
+        value: 4.0,
+    },
+];
+
+/// Note values of one arpeggiator step (see `arpeggiator::RATE_PULSES`)
+const ARPEGGIATOR_RATES: [&str; 4] = ["1/4", "1/8", "1/8 triplet", "1/16"];
+
+const ARPEGGIATOR_RATE_DETENTS: [Detent; 4] = [
+    Detent {
+        min: 0,
+        max: 31,
+        value: 0.0,
+    },
+    Detent {
+        min: 32,
+        max: 63,
+        value: 1.0,
+    },
+    Detent {
+        min: 64,
+        max: 95,
+        value: 2.0,
+    },
+    Detent {
+        min: 96,
+        max: 127,
+        value: 3.0,
+    },
+];
+
+/// Sources of the tempo of the arpeggiator and the step sequencer
+const CLOCK_SOURCES: [&str; 2] = ["internal", "MIDI clock"];
+
+const LFO_SHAPES: [&str; 5] = [
+    "triangle",
+    "saw",
+    "square",
+    "sample & hold",
+    "smooth random",
+];
+
+const LFO_SHAPE_DETENTS: [Detent; 5] = [
+    Detent {
+        min: 0,
+        max: 25,
+        value: 0.0,
+    },
+    Detent {
+        min: 26,
+        max: 50,
+        value: 1.0,
+    },
+    Detent {
+        min: 51,
+        max: 76,
+        value: 2.0,
+    },
+    Detent {
+        min: 77,
+        max: 101,
+        value: 3.0,
+    },
+    Detent {
+        min: 102,
+        max: 127,
+        value: 4.0,
+    },
+];
+
+/// Free running rate, or note value of one LFO cycle (see `lfo::DIVISION_BEATS`)
+const LFO_DIVISIONS: [&str; 7] = ["free", "1 bar", "1/2", "1/4", "1/8", "1/8 triplet", "1/16"];
+
+const LFO_DIVISION_DETENTS: [Detent; 7] = [
+    Detent {
+        min: 0,
+        max: 17,
+        value: 0.0,
+    },
+    Detent {
+        min: 18,
+        max: 36,
+        value: 1.0,
+    },
+    Detent {
+        min: 37,
+        max: 54,
+        value: 2.0,
+    },
+    Detent {
+        min: 55,
+        max: 72,
+        value: 3.0,
+    },
+    Detent {
+        min: 73,
+        max: 90,
+        value: 4.0,
+    },
+    Detent {
+        min: 91,
+        max: 109,
+        value: 5.0,
+    },
+    Detent {
+        min: 110,
+        max: 127,
+        value: 6.0,
+    },
+];
+
+const LFO_PHASES: [&str; 2] = ["free run", "key sync"];
+
+/// Sources of a slot of the modulation matrix (see `ModulationSource::from_value()`)
+const MODULATION_SOURCES: [&str; 8] = [
+    "off",
+    "velocity",
+    "aftertouch",
+    "mod wheel",
+    "key position",
+    "gate",
+    "LFO",
+    "timbre",
+];
+
+const MODULATION_SOURCE_DETENTS: [Detent; 8] = [
+    Detent {
+        min: 0,
+        max: 15,
+        value: 0.0,
+    },
+    Detent {
+        min: 16,
+        max: 31,
+        value: 1.0,
+    },
+    Detent {
+        min: 32,
+        max: 47,
+        value: 2.0,
+    },
+    Detent {
+        min: 48,
+        max: 63,
+        value: 3.0,
+    },
+    Detent {
+        min: 64,
+        max: 79,
+        value: 4.0,
+    },
+    Detent {
+        min: 80,
+        max: 95,
+        value: 5.0,
+    },
+    Detent {
+        min: 96,
+        max: 111,
+        value: 6.0,
+    },
+    Detent {
+        min: 112,
+        max: 127,
+        value: 7.0,
+    },
+];
+
+/// Destinations of a slot of the modulation matrix (see `ModulationDestination::from_value()`)
+const MODULATION_DESTINATIONS: [&str; 3] = ["pitch", "osc 1 level", "amplitude"];
+
+const MODULATION_DESTINATION_DETENTS: [Detent; 3] = [
+    Detent {
+        min: 0,
+        max: 42,
+        value: 0.0,
+    },
+    Detent {
+        min: 43,
+        max: 84,
+        value: 1.0,
+    },
+    Detent {
+        min: 85,
+        max: 127,
+        value: 2.0,
+    },
+];
+
+/// Definition of the range, enable or volume of oscillator 1 of a voice group after the first
+/// (counted from 0, like the voice groups of the zones), with the default volume.
+macro_rules! voice_group_parameter {
+    ($group:literal, range) => {
+        Parameter {
+            key: concat!("group", $group, "_osc1_range"),
+            name: concat!("Group ", $group, " Osc 1 Range"),
+            min: 0.0625 * MIDDLE_C,
+            max: 4.0 * MIDDLE_C,
+            taper: Taper::Stepped(&RANGE_DETENTS),
+            default: MIDDLE_C,
+            unit: Unit::Hertz,
+            takeover: Takeover::Jump,
+        }
+    };
+    ($group:literal, enable) => {
+        Parameter {
+            key: concat!("group", $group, "_osc1_enable"),
+            name: concat!("Group ", $group, " Osc 1"),
+            min: 0.0,
+            max: 1.0,
+            taper: Taper::Switch,
+            default: 1.0,
+            unit: Unit::OnOff,
+            takeover: Takeover::Jump,
+        }
+    };
+    ($group:literal, volume, $default:expr) => {
+        Parameter {
+            key: concat!("group", $group, "_osc1_volume"),
+            name: concat!("Group ", $group, " Osc 1 Volume"),
+            min: -50.0,
+            max: 0.0,
+            taper: Taper::Decibel,
+            default: $default,
+            unit: Unit::Decibels,
+            takeover: Takeover::Scale,
+        }
+    };
+}
+
+/// Definition of the source, destination or amount of a slot of the modulation matrix (counted
+/// from 1), with the default source or destination.
+macro_rules! modulation_parameter {
+    ($slot:literal, source, $default:expr) => {
+        Parameter {
+            key: concat!("mod", $slot, "_source"),
+            name: concat!("Mod ", $slot, " Source"),
+            min: 0.0,
+            max: 7.0,
+            taper: Taper::Stepped(&MODULATION_SOURCE_DETENTS),
+            default: $default,
+            unit: Unit::Choice(&MODULATION_SOURCES),
+            takeover: Takeover::Jump,
+        }
+    };
+    ($slot:literal, destination, $default:expr) => {
+        Parameter {
+            key: concat!("mod", $slot, "_destination"),
+            name: concat!("Mod ", $slot, " Destination"),
+            min: 0.0,
+            max: 2.0,
+            taper: Taper::Stepped(&MODULATION_DESTINATION_DETENTS),
+            default: $default,
+            unit: Unit::Choice(&MODULATION_DESTINATIONS),
+            takeover: Takeover::Jump,
+        }
+    };
+    ($slot:literal, amount) => {
+        Parameter {
+            key: concat!("mod", $slot, "_amount"),
+            name: concat!("Mod ", $slot, " Amount"),
+            // -100 % to +98 %, so that the center position (64) is off
+            min: -1.0,
+            max: 0.984_375,
+            taper: Taper::Linear,
+            default: 0.0,
+            unit: Unit::Percent,
+            takeover: Takeover::Pickup,
+        }
+    };
+}
+
+/// Responses of the aftertouch source (see `PressureCurve::from_value()`)
+const PRESSURE_CURVES: [&str; 3] = ["soft", "linear", "hard"];
+
+const PRESSURE_CURVE_DETENTS: [Detent; 3] = [
+    Detent {
+        min: 0,
+        max: 42,
+        value: 0.0,
+    },
+    Detent {
+        min: 43,
+        max: 84,
+        value: 1.0,
+    },
+    Detent {
+        min: 85,
+        max: 127,
+        value: 2.0,
+    },
+];
+
+const DEFINITIONS: [Parameter; PARAMETER_COUNT] = [
+    Parameter {
+        key: "master_tune",
+        name: "Master Tune",
+        // -1200 to +1200 cents, controls set it through a `TuneMode`
+        min: 0.5,
+        max: 2.0,
+        taper: Taper::Exponential,
+        default: 1.0,
+        unit: Unit::Cents,
+        takeover: Takeover::Pickup,
+    },
+    Parameter {
+        key: "master_volume",
+        name: "Master Volume",
+        min: -50.0,
+        max: 0.0,
+        taper: Taper::Decibel,
+        default: 1.0,
+        unit: Unit::Decibels,
+        takeover: Takeover::Scale,
+    },
+    Parameter {
+        key: "osc1_range",
+        name: "Oscillator 1 Range",
+        min: 0.0625 * MIDDLE_C,
+        max: 4.0 * MIDDLE_C,
+        taper: Taper::Stepped(&RANGE_DETENTS),
+        default: MIDDLE_C,
+        unit: Unit::Hertz,
+        takeover: Takeover::Jump,
+    },
+    Parameter {
+        key: "osc1_enable",
+        name: "Oscillator 1",
+        min: 0.0,
+        max: 1.0,
+        taper: Taper::Switch,
+        default: 1.0,
+        unit: Unit::OnOff,
+        takeover: Takeover::Jump,
+    },
+    Parameter {
+        key: "osc1_volume",
+        name: "Oscillator 1 Volume",
+        min: -50.0,
+        max: 0.0,
+        taper: Taper::Decibel,
+        // Silent
+        default: 0.0,
+        unit: Unit::Decibels,
+        takeover: Takeover::Scale,
+    },
+    voice_group_parameter!(1, range),
+    voice_group_parameter!(1, enable),
+    // Audible (-6 dB), so that a zone playing the group sounds without setting it up first
+    voice_group_parameter!(1, volume, 0.5),
+    Parameter {
+        key: "arp_enable",
+        name: "Arpeggiator",
+        min: 0.0,
+        max: 1.0,
+        taper: Taper::Switch,
+        default: 0.0,
+        unit: Unit::OnOff,
+        takeover: Takeover::Jump,
+    },
+    Parameter {
+        key: "arp_order",
+        name: "Arpeggiator Order",
+        min: 0.0,
+        max: 4.0,
+        taper: Taper::Stepped(&ARPEGGIATOR_ORDER_DETENTS),
+        default: 0.0,
+        unit: Unit::Choice(&ARPEGGIATOR_ORDERS),
+        takeover: Takeover::Jump,
+    },
+    Parameter {
+        key: "arp_octaves",
+        name: "Arpeggiator Octaves",
+        min: 1.0,
+        max: 4.0,
+        taper: Taper::Stepped(&ARPEGGIATOR_OCTAVE_DETENTS),
+        default: 1.0,
+        unit: Unit::Count,
+        takeover: Takeover::Jump,
+    },
+    Parameter {
+        key: "arp_gate",
+        name: "Arpeggiator Gate",
+        // Fraction of a step
+        min: 0.05,
+        max: 1.0,
+        taper: Taper::Linear,
+        default: 0.5,
+        unit: Unit::Percent,
+        takeover: Takeover::Pickup,
+    },
+    Parameter {
+        key: "arp_rate",
+        name: "Arpeggiator Rate",
+        min: 0.0,
+        max: 3.0,
+        taper: Taper::Stepped(&ARPEGGIATOR_RATE_DETENTS),
+        // 1/16
+        default: 3.0,
+        unit: Unit::Choice(&ARPEGGIATOR_RATES),
+        takeover: Takeover::Jump,
+    },
+    Parameter {
+        key: "arp_latch",
+        name: "Arpeggiator Latch",
+        min: 0.0,
+        max: 1.0,
+        taper: Taper::Switch,
+        default: 0.0,
+        unit: Unit::OnOff,
+        takeover: Takeover::Jump,
+    },
+    Parameter {
+        key: "tempo",
+        name: "Tempo",
+        min: 40.0,
+        max: 240.0,
+        taper: Taper::Linear,
+        default: 120.0,
+        unit: Unit::BeatsPerMinute,
+        takeover: Takeover::Pickup,
+    },
+    Parameter {
+        key: "clock_sync",
+        name: "Clock",
+        min: 0.0,
+        max: 1.0,
+        taper: Taper::Switch,
+        default: 0.0,
+        unit: Unit::Choice(&CLOCK_SOURCES),
+        takeover: Takeover::Jump,
+    },
+    Parameter {
+        key: "lfo_shape",
+        name: "LFO Shape",
+        min: 0.0,
+        max: 4.0,
+        taper: Taper::Stepped(&LFO_SHAPE_DETENTS),
+        default: 0.0,
+        unit: Unit::Choice(&LFO_SHAPES),
+        takeover: Takeover::Jump,
+    },
+    Parameter {
+        key: "lfo_rate",
+        name: "LFO Rate",
+        min: 0.1,
+        max: 20.0,
+        taper: Taper::Exponential,
+        default: 5.0,
+        unit: Unit::Hertz,
+        takeover: Takeover::Pickup,
+    },
+    Parameter {
+        key: "lfo_division",
+        name: "LFO Tempo Sync",
+        min: 0.0,
+        max: 6.0,
+        taper: Taper::Stepped(&LFO_DIVISION_DETENTS),
+        default: 0.0,
+        unit: Unit::Choice(&LFO_DIVISIONS),
+        takeover: Takeover::Jump,
+    },
+    Parameter {
+        key: "lfo_key_sync",
+        name: "LFO Phase",
+        min: 0.0,
+        max: 1.0,
+        taper: Taper::Switch,
+        default: 0.0,
+        unit: Unit::Choice(&LFO_PHASES),
+        takeover: Takeover::Jump,
+    },
+    Parameter {
+        key: "lfo_delay",
+        name: "LFO Delay",
+        min: 0.0,
+        max: 2.0,
+        taper: Taper::Linear,
+        default: 0.0,
+        unit: Unit::Seconds,
+        takeover: Takeover::Pickup,
+    },
+    Parameter {
+        key: "lfo_fade_in",
+        name: "LFO Fade In",
+        min: 0.0,
+        max: 2.0,
+        taper: Taper::Linear,
+        default: 0.0,
+        unit: Unit::Seconds,
+        takeover: Takeover::Pickup,
+    },
+    modulation_parameter!(1, source, 6.0),
+    modulation_parameter!(1, destination, 0.0),
+    modulation_parameter!(1, amount),
+    modulation_parameter!(2, source, 6.0),
+    modulation_parameter!(2, destination, 2.0),
+    modulation_parameter!(2, amount),
+    modulation_parameter!(3, source, 1.0),
+    modulation_parameter!(3, destination, 2.0),
+    modulation_parameter!(3, amount),
+    modulation_parameter!(4, source, 0.0),
+    modulation_parameter!(4, destination, 0.0),
+    modulation_parameter!(4, amount),
+    Parameter {
+        key: "pressure_curve",
+        name: "Pressure Curve",
+        min: 0.0,
+        max: 2.0,
+        taper: Taper::Stepped(&PRESSURE_CURVE_DETENTS),
+        default: 1.0,
+        unit: Unit::Choice(&PRESSURE_CURVES),
+        takeover: Takeover::Jump,
+    },
+    Parameter {
+        key: "tuning",
+        name: "Tuning",
+        min: 0.0,
+        max: 127.0,
+        taper: Taper::Linear,
+        default: 0.0,
+        unit: Unit::Count,
+        takeover: Takeover::Jump,
+    },
+    Parameter {
+        key: "transpose",
+        name: "Transpose",
+        min: -12.0,
+        max: 12.0,
+        taper: Taper::Linear,
+        default: 0.0,
+        unit: Unit::Count,
+        takeover: Takeover::Jump,
+    },
+    Parameter {
+        key: "octave_shift",
+        name: "Octave Shift",
+        min: -3.0,
+        max: 3.0,
+        taper: Taper::Linear,
+        default: 0.0,
+        unit: Unit::Count,
+        takeover: Takeover::Jump,
+    },
+];
+
+impl Parameter {
+    /// Converts a 7-bit controller value to the value of the parameter and the controller value
+    /// it corresponds to (stepped controls snap to their detents). Returns `None` if the
+    /// controller value lies between two detents.
+    pub fn controller_to_value(&self, controller_value: u8) -> Option<(f32, u8)> {
+        let position = f64::from(controller_value.min(127)) / 127.0;
+        let (min, max) = (f64::from(self.min), f64::from(self.max));
+
+        let value = match self.taper {
+            Taper::Linear => min + position * (max - min),
+            Taper::Exponential => min * (max / min).powf(position),
+            Taper::Decibel => decibels_to_gain(min + position * (max - min)),
+            Taper::Stepped(detents) => {
+                let last = detents.len() - 1;
+                return detents
+                    .iter()
+                    .enumerate()
+                    .find(|&(i, detent)| {
+                        (i == 0 || controller_value >= detent.min)
+                            && (i == last || controller_value <= detent.max)
+                    })
+                    .map(|(_, detent)| {
+                        let controller_value = controller_value.max(detent.min).min(detent.max);
+                        (detent.value, controller_value)
+                    });
+            }
+            Taper::Switch if controller_value >= 64 => max,
+            Taper::Switch => min,
+        };
+
+        Some((value as f32, controller_value))
+    }
+
+    /// Converts a value of the parameter to the nearest 7-bit controller value.
+    pub fn value_to_controller(&self, value: f32) -> u8 {
+        let (min, max) = (f64::from(self.min), f64::from(self.max));
+        let value = f64::from(self.clamp(value));
+
+        let position = match self.taper {
+            Taper::Linear => (value - min) / (max - min),
+            Taper::Exponential => (value / min).ln() / (max / min).ln(),
+            Taper::Decibel if value <= 0.0 => 0.0,
+            Taper::Decibel => (gain_to_decibels(value) - min) / (max - min),
+            Taper::Stepped(detents) => {
+                let detent = detents
+                    .iter()
+                    .min_by(|a, b| {
+                        let a = (f64::from(a.value) - value).abs();
+                        let b = (f64::from(b.value) - value).abs();
+                        a.partial_cmp(&b).unwrap()
+                    })
+                    .unwrap();
+                return (detent.min + detent.max).div_ceil(2);
+            }
+            Taper::Switch if value >= (min + max) / 2.0 => 1.0,
+            Taper::Switch => 0.0,
+        };
+
+        (position.clamp(0.0, 1.0) * 127.0).round() as u8
+    }
+
+    /// Limits a value to the range of the parameter.
+    pub fn clamp(&self, value: f32) -> f32 {
+        match self.taper {
+            // Silence is allowed below the range
+            Taper::Decibel => value
+                .max(0.0)
+                .min(decibels_to_gain(f64::from(self.max)) as f32),
+            _ => value.max(self.min).min(self.max),
+        }
+    }
+
+    /// Formats a value of the parameter for display.
+    pub fn format(&self, value: f32) -> String {
+        let value = f64::from(value);
+        match self.unit {
+            Unit::Cents => format!("{:+.1} ct", 1200.0 * value.log2()),
+            Unit::Hertz => format!("{:.1} Hz", value),
+            Unit::Decibels if value <= 0.0 => "-inf dB".to_string(),
+            Unit::Decibels => format!("{:.1} dB", gain_to_decibels(value)),
+            Unit::OnOff if value >= 0.5 => "on".to_string(),
+            Unit::OnOff => "off".to_string(),
+            Unit::Percent => format!("{:.0} %", 100.0 * value),
+            Unit::BeatsPerMinute => format!("{:.1} BPM", value),
+            Unit::Seconds => format!("{:.2} s", value),
+            Unit::Count => format!("{:.0}", value),
+            Unit::Choice(names) => {
+                let index = (value.round().max(0.0) as usize).min(names.len() - 1);
+                names[index].to_string()
+            }
+        }
+    }
+}
+
+/// How a control sets the master tune. The position of the control shows the deviation from
+/// concert pitch in cents, with the center position (64) in tune.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TuneMode {
+    /// -250 to +250 cents over the full range
+    Coarse,
+    /// -50 to +49 cents around the nearest semitone
+    Fine,
+}
+
+/// Cents per step of a control in coarse mode
+const COARSE_TUNE_STEP: f64 = 250.0 / 64.0;
+
+/// Cents per step of a control in fine mode
+const FINE_TUNE_STEP: f64 = 100.0 / 128.0;
+
+impl TuneMode {
+    /// Cents per step of the control
+    fn cents_per_step(self) -> f64 {
+        match self {
+            TuneMode::Coarse => COARSE_TUNE_STEP,
+            TuneMode::Fine => FINE_TUNE_STEP,
+        }
+    }
+
+    /// Converts a 7-bit controller value to the master tune, given its current value. Returns the
+    /// new value and the controller value it corresponds to.
+    pub fn controller_to_value(self, current: f32, controller_value: u8) -> (f32, u8) {
+        let (semitones, _) = split_cents(current);
+        let offset = (f64::from(controller_value.min(127)) - 64.0) * self.cents_per_step();
+        let cents = match self {
+            TuneMode::Coarse => offset,
+            TuneMode::Fine => semitones * 100.0 + offset,
+        };
+
+        let value = ParameterId::MasterTune
+            .definition()
+            .clamp((cents / 1200.0).exp2() as f32);
+        (value, self.value_to_controller(value))
+    }
+
+    /// Converts the master tune to the position of its control.
+    pub fn value_to_controller(self, value: f32) -> u8 {
+        let (semitones, fine) = split_cents(value);
+        let cents = match self {
+            TuneMode::Coarse => semitones * 100.0 + fine,
+            TuneMode::Fine => fine,
+        };
+
+        (64.0 + cents / self.cents_per_step())
+            .round()
+            .clamp(0.0, 127.0) as u8
+    }
+}
+
+/// Splits a frequency ratio into the nearest semitone and the deviation from it in cents (within
+/// the range of the fine mode).
+fn split_cents(ratio: f32) -> (f64, f64) {
+    let steps = (1200.0 * f64::from(ratio).log2() / FINE_TUNE_STEP).round() as i64;
+    let semitones = (steps + 64).div_euclid(128);
+    let fine = steps - semitones * 128;
+    (semitones as f64, fine as f64 * FINE_TUNE_STEP)
+}
+
+fn decibels_to_gain(db: f64) -> f64 {
+    10.0_f64.powf(0.05 * db)
+}
+
+fn gain_to_decibels(gain: f64) -> f64 {
+    20.0 * gain.log10()
+}
+
+/// Current values of all parameters.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParameterValues {
+    values: [f32; PARAMETER_COUNT],
+}
+
+impl Default for ParameterValues {
+    fn default() -> Self {
+        let mut values = [0.0; PARAMETER_COUNT];
+        for id in &PARAMETERS {
+            values[*id as usize] = id.definition().default;
+        }
+        ParameterValues { values }
+    }
+}
+
+impl ParameterValues {
+    pub fn get(&self, id: ParameterId) -> f32 {
+        self.values[id as usize]
+    }
+
+    pub fn set(&mut self, id: ParameterId, value: f32) {
+        self.values[id as usize] = id.definition().clamp(value);
+    }
+
+    /// Writes the values of all parameters that are not global as `key = value` lines.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        for id in PARAMETERS.iter().filter(|id| !id.is_global()) {
+            let definition = id.definition();
+            writeln!(
+                writer,
+                "{} = {} # {}: {}",
+                definition.key,
+                self.get(*id),
+                definition.name,
+                definition.format(self.get(*id))
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Sets a parameter from a `key = value` pair as written by `write_to()`. Returns false if
+    /// there is no parameter with that key. Global parameters are skipped.
+    pub fn set_from_str(&mut self, key: &str, value: &str) -> Result<bool> {
+        let id = match ParameterId::from_key(key) {
+            Some(id) if id.is_global() => return Ok(true),
+            Some(id) => id,
+            None => return Ok(false),
+        };
+
+        let value = value
+            .parse()
+            .chain_err(|| format!("Invalid value for parameter '{}': {}", key, value))?;
+        self.set(id, value);
+
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parameters_can_be_looked_up_by_key() {
+        for id in &PARAMETERS {
+            assert_eq!(Some(*id), ParameterId::from_key(id.definition().key));
+        }
+    }
+
+    #[test]
+    fn exponential_taper() {
+        let tune = ParameterId::MasterTune.definition();
+
+        assert_float_eq!(0.5, tune.controller_to_value(0).unwrap().0, 1e-6);
+        assert_float_eq!(0.709039, tune.controller_to_value(32).unwrap().0, 1e-6);
+        assert_float_eq!(2.0, tune.controller_to_value(127).unwrap().0, 1e-6);
+
+        for value in 0..128 {
+            let (tune_value, _) = tune.controller_to_value(value).unwrap();
+            assert_eq!(value, tune.value_to_controller(tune_value));
+        }
+    }
+
+    #[test]
+    fn decibel_taper() {
+        let volume = ParameterId::Oscillator1Volume.definition();
+
+        assert_float_eq!(1.0, volume.controller_to_value(127).unwrap().0, 1e-6);
+        assert_float_eq!(0.0031622776, volume.controller_to_value(0).unwrap().0, 1e-9);
+        assert_eq!(0, volume.value_to_controller(0.0));
+
+        for value in 0..128 {
+            let (gain, _) = volume.controller_to_value(value).unwrap();
+            assert_eq!(value, volume.value_to_controller(gain));
+        }
+    }
+
+    #[test]
+    fn stepped_taper_snaps_to_detents() {
+        let range = ParameterId::Oscillator1Range.definition();
+
+        assert_eq!(Some((0.0625 * MIDDLE_C, 21)), range.controller_to_value(0));
+        assert_eq!(Some((0.0625 * MIDDLE_C, 21)), range.controller_to_value(21));
+        assert_eq!(Some((0.5 * MIDDLE_C, 54)), range.controller_to_value(54));
+        assert_eq!(Some((4.0 * MIDDLE_C, 105)), range.controller_to_value(127));
+        assert_eq!(None, range.controller_to_value(30));
+        assert_eq!(None, range.controller_to_value(100));
+
+        assert_eq!(72, range.value_to_controller(MIDDLE_C));
+        assert_eq!(21, range.value_to_controller(1.0));
+    }
+
+    #[test]
+    fn tune_modes() {
+        let semitone = 2.0_f32.powf(1.0 / 12.0);
+
+        // Coarse tuning sets the deviation over the full range
+        assert_eq!((1.0, 64), TuneMode::Coarse.controller_to_value(1.0, 64));
+        let (value, position) = TuneMode::Coarse.controller_to_value(semitone.powi(6), 96);
+        assert_float_eq!(semitone.powf(1.25), value, 1e-6);
+        assert_eq!(96, position);
+        let (value, position) = TuneMode::Coarse.controller_to_value(1.0, 0);
+        assert_float_eq!(semitone.powf(-2.5), value, 1e-6);
+        assert_eq!(0, position);
+        assert_eq!(127, TuneMode::Coarse.value_to_controller(semitone.powi(3)));
+
+        // Fine tuning changes the cents within the nearest semitone
+        let (value, position) = TuneMode::Fine.controller_to_value(semitone.powi(2), 32);
+        assert_float_eq!(semitone.powf(1.75), value, 1e-6);
+        assert_eq!(32, position);
+        assert_eq!(64, TuneMode::Fine.value_to_controller(semitone.powi(-3)));
+        assert_eq!(0, TuneMode::Fine.value_to_controller(semitone.powf(-0.5)));
+        assert_eq!(96, TuneMode::Fine.value_to_controller(semitone.powf(1.25)));
+        let (value, position) = TuneMode::Fine.controller_to_value(semitone.powf(1.25), 32);
+        assert_float_eq!(semitone.powf(0.75), value, 1e-6);
+        assert_eq!(32, position);
+    }
+
+    #[test]
+    fn switch_taper() {
+        let enable = ParameterId::Oscillator1Enable.definition();
+
+        assert_eq!(Some((0.0, 0)), enable.controller_to_value(0));
+        assert_eq!(Some((1.0, 127)), enable.controller_to_value(127));
+        assert_eq!(127, enable.value_to_controller(1.0));
+    }
+
+    #[test]
+    fn format_values() {
+        assert_eq!("+0.0 ct", ParameterId::MasterTune.definition().format(1.0));
+        assert_eq!(
+            "261.6 Hz",
+            ParameterId::Oscillator1Range.definition().format(MIDDLE_C)
+        );
+        assert_eq!(
+            "off",
+            ParameterId::Oscillator1Enable.definition().format(0.0)
+        );
+        assert_eq!(
+            "-6.0 dB",
+            ParameterId::Oscillator1Volume.definition().format(0.501187)
+        );
+        assert_eq!(
+            "-inf dB",
+            ParameterId::Oscillator1Volume.definition().format(0.0)
+        );
+        assert_eq!(
+            "up/down",
+            ParameterId::ArpeggiatorOrder.definition().format(2.0)
+        );
+        assert_eq!(
+            "1/8 triplet",
+            ParameterId::ArpeggiatorRate.definition().format(2.0)
+        );
+        assert_eq!(
+            "25 %",
+            ParameterId::ArpeggiatorGate.definition().format(0.25)
+        );
+        assert_eq!(
+            "1/8 triplet",
+            ParameterId::LfoDivision.definition().format(5.0)
+        );
+        assert_eq!(
+            "-50 %",
+            ParameterId::Modulation1Amount.definition().format(-0.5)
+        );
+        assert_eq!(
+            "LFO",
+            ParameterId::Modulation1Source.definition().format(6.0)
+        );
+    }
+
+    #[test]
+    fn lfo_division_selects_beats() {
+        assert_eq!(
+            Some(SynthControl::LfoBeats(None)),
+            ParameterId::LfoDivision.synth_control(0.0)
+        );
+        assert_eq!(
+            Some(SynthControl::LfoBeats(Some(4.0))),
+            ParameterId::LfoDivision.synth_control(1.0)
+        );
+        assert_eq!(
+            Some(SynthControl::LfoBeats(Some(0.25))),
+            ParameterId::LfoDivision.synth_control(6.0)
+        );
+    }
+
+    #[test]
+    fn modulation_amount_is_off_at_center() {
+        let amount = ParameterId::Modulation2Amount.definition();
+
+        assert_eq!(Some((0.0, 64)), amount.controller_to_value(64));
+        assert_eq!(Some((-1.0, 0)), amount.controller_to_value(0));
+        assert_eq!(
+            Some(SynthControl::ModulationAmount(1, 0.0)),
+            ParameterId::Modulation2Amount.synth_control(0.0)
+        );
+        assert_eq!(
+            Some(SynthControl::ModulationSource(3, None)),
+            ParameterId::Modulation4Source.synth_control(0.0)
+        );
+    }
+
+    #[test]
+    fn modulation_slots_are_numbered_from_one() {
+        let destination = ParameterId::Modulation3Destination;
+
+        assert_eq!("mod3_destination", destination.definition().key);
+        assert_eq!("Mod 3 Destination", destination.definition().name);
+        assert_eq!(
+            Some(SynthControl::ModulationDestination(
+                2,
+                ModulationDestination::Amplitude
+            )),
+            destination.synth_control(2.0)
+        );
+    }
+
+    #[test]
+    fn oscillator_parameters_control_their_voice_group() {
+        assert_eq!(
+            Some(SynthControl::Oscillator1Volume(0, 0.5)),
+            ParameterId::Oscillator1Volume.synth_control(0.5)
+        );
+        assert_eq!(
+            Some(SynthControl::Oscillator1Volume(1, 0.5)),
+            ParameterId::Group1Oscillator1Volume.synth_control(0.5)
+        );
+        assert_eq!(
+            Some(SynthControl::Oscillator1Enable(1, false)),
+            ParameterId::Group1Oscillator1Enable.synth_control(0.0)
+        );
+    }
+
+    #[test]
+    fn values_are_clamped_to_range() {
+        let mut values = ParameterValues::default();
+
+        values.set(ParameterId::MasterTune, 3.0);
+        assert_float_eq!(2.0, values.get(ParameterId::MasterTune), 1e-6);
+
+        values.set(ParameterId::Oscillator1Volume, -1.0);
+        assert_float_eq!(0.0, values.get(ParameterId::Oscillator1Volume), 1e-6);
+    }
+
+    #[test]
+    fn set_from_str() {
+        let mut values = ParameterValues::default();
+
+        assert!(values.set_from_str("osc1_volume", "0.5").unwrap());
+        assert_float_eq!(0.5, values.get(ParameterId::Oscillator1Volume), 1e-6);
+
+        assert!(!values.set_from_str("unknown", "3").unwrap());
+        assert!(values.set_from_str("master_tune", "loud").is_err());
+    }
+
+    #[test]
+    fn global_parameters_are_not_persisted() {
+        let mut values = ParameterValues::default();
+        values.set(ParameterId::Transpose, 5.0);
+
+        let mut written = vec![];
+        values.write_to(&mut written).unwrap();
+        let written = String::from_utf8(written).unwrap();
+        assert!(written.contains("osc1_volume = "));
+        assert!(!written.contains("transpose = "));
+
+        assert!(values.set_from_str("octave_shift", "2").unwrap());
+        assert_float_eq!(0.0, values.get(ParameterId::OctaveShift), 1e-6);
+    }
+}
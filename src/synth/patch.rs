@@ -0,0 +1,223 @@
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, ErrorKind as IoErrorKind, Write};
+use std::path::PathBuf;
+
+use synth::parameter::ParameterValues;
+
+use errors::*;
+
+/// Version of the patch format written by `Patch::write_to()`.
+///
+/// Patches of older versions can still be read: parameters that were added since are set to
+/// their default value. Increase the version if the meaning of an existing value changes, and
+/// convert old values when reading.
+pub const PATCH_VERSION: u32 = 1;
+
+/// Sound of the synthesizer, i.e. the values of all parameters.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Patch {
+    pub values: ParameterValues,
+}
+
+impl Patch {
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writeln!(writer, "# midi-synth patch")?;
+        writeln!(writer, "version = {}", PATCH_VERSION)?;
+        self.values.write_to(writer)?;
+
+        Ok(())
+    }
+
+    pub fn read_from<R: BufRead>(reader: R) -> Result<Self> {
+        let mut version = None;
+        let mut values = ParameterValues::default();
+
+        for (line_number, line) in reader.lines().enumerate() {
+            let line = line?;
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let mut parts = line.splitn(2, '=');
+            let key = parts.next().unwrap_or("").trim();
+            let value = parts.next().unwrap_or("").trim();
+
+            if key == "version" {
+                let v: u32 = value
+                    .parse()
+                    .chain_err(|| format!("Invalid patch version: {}", value))?;
+                if v > PATCH_VERSION {
+                    bail!(
+                        "Patch version {} is not supported (newer than {})",
+                        v,
+                        PATCH_VERSION
+                    );
+                }
+                version = Some(v);
+            } else if version.is_none() {
+                bail!("Patch version missing before line {}", line_number + 1);
+            } else if !values.set_from_str(key, value)? {
+                println!("Ignoring unknown patch parameter: {}", key);
+            }
+        }
+
+        if version.is_none() {
+            bail!("Patch version missing");
+        }
+
+        Ok(Patch { values })
+    }
+}
+
+/// Patches stored as files, one per program number, in a sub-directory per bank (as selected by
+/// MIDI Bank Select).
+pub struct PatchBank {
+    dir: Option<PathBuf>,
+}
+
+impl PatchBank {
+    /// Creates a bank of patches in the given directory. If `dir` is `None`, patches are neither
+    /// saved nor loaded.
+    pub fn new(dir: Option<PathBuf>) -> Self {
+        PatchBank { dir }
+    }
+
+    fn bank_dir(&self, bank: u16) -> Option<PathBuf> {
+        self.dir
+            .as_ref()
+            .map(|dir| dir.join(format!("{:03}", bank)))
+    }
+
+    fn path(&self, bank: u16, program: u8) -> Option<PathBuf> {
+        self.bank_dir(bank)
+            .map(|dir| dir.join(format!("{:03}.patch", program)))
+    }
+
+    /// Returns whether any patch has been saved in a bank.
+    pub fn contains_bank(&self, bank: u16) -> bool {
+        let entries = match self.bank_dir(bank).map(fs::read_dir) {
+            Some(Ok(entries)) => entries,
+            _ => return false,
+        };
+
+        entries
+            .filter_map(|entry| entry.ok())
+            .any(|entry| entry.path().extension() == Some(OsStr::new("patch")))
+    }
+
+    /// Loads the patch of a program. Returns `None` if it has not been saved yet.
+    pub fn load(&self, bank: u16, program: u8) -> Result<Option<Patch>> {
+        let path = match self.path(bank, program) {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+
+        match File::open(&path) {
+            Ok(file) => Patch::read_from(BufReader::new(file))
+                .map(Some)
+                .chain_err(|| format!("Failed to load patch {}", path.display())),
+            Err(ref e) if e.kind() == IoErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).chain_err(|| format!("Failed to open patch {}", path.display())),
+        }
+    }
+
+    pub fn save(&self, bank: u16, program: u8, patch: &Patch) -> Result<()> {
+        let path = match self.path(bank, program) {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .chain_err(|| format!("Failed to create directory {}", dir.display()))?;
+        }
+
+        let file = File::create(&path)
+            .chain_err(|| format!("Failed to create patch {}", path.display()))?;
+        patch
+            .write_to(&mut BufWriter::new(file))
+            .chain_err(|| format!("Failed to save patch {}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::process;
+
+    use synth::parameter::ParameterId;
+
+    #[test]
+    fn write_and_read() {
+        let mut values = ParameterValues::default();
+        values.set(ParameterId::MasterTune, 1.05);
+        values.set(ParameterId::Oscillator1Range, 130.81279);
+        values.set(ParameterId::Oscillator1Enable, 0.0);
+        values.set(ParameterId::Oscillator1Volume, 0.25);
+        let patch = Patch { values };
+
+        let mut buffer = vec![];
+        patch.write_to(&mut buffer).unwrap();
+
+        assert!(String::from_utf8_lossy(&buffer).contains("version = 1\n"));
+        assert_eq!(patch, Patch::read_from(&buffer[..]).unwrap());
+    }
+
+    #[test]
+    fn missing_parameters_are_set_to_default() {
+        // Patch written before other parameters were added
+        let input = "# midi-synth patch\nversion = 1\nosc1_volume = 0.5 # -6.0 dB\n";
+        let patch = Patch::read_from(input.as_bytes()).unwrap();
+
+        let mut expected = ParameterValues::default();
+        expected.set(ParameterId::Oscillator1Volume, 0.5);
+        assert_eq!(expected, patch.values);
+    }
+
+    #[test]
+    fn unknown_parameters_are_ignored() {
+        let input = "version = 1\nunknown = 3\nmaster_tune = 1.1\n";
+        let patch = Patch::read_from(input.as_bytes()).unwrap();
+
+        assert_float_eq!(1.1, patch.values.get(ParameterId::MasterTune), 1e-6);
+    }
+
+    #[test]
+    fn invalid_patches_are_rejected() {
+        assert!(Patch::read_from("master_tune = 1.1\n".as_bytes()).is_err());
+        assert!(Patch::read_from("".as_bytes()).is_err());
+        assert!(Patch::read_from("version = 2\n".as_bytes()).is_err());
+        assert!(Patch::read_from("version = 1\nmaster_tune = high\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn save_and_load() {
+        let dir = env::temp_dir().join(format!("midi-synth-patch-test-{}", process::id()));
+        let bank = PatchBank::new(Some(dir.clone()));
+
+        assert_eq!(None, bank.load(2, 3).unwrap());
+        assert!(!bank.contains_bank(2));
+
+        let mut values = ParameterValues::default();
+        values.set(ParameterId::Oscillator1Volume, 0.5);
+        let patch = Patch { values };
+        bank.save(2, 3, &patch).unwrap();
+
+        assert_eq!(Some(patch), bank.load(2, 3).unwrap());
+        assert_eq!(None, bank.load(0, 3).unwrap());
+        assert!(bank.contains_bank(2));
+        assert!(!bank.contains_bank(0));
+        assert!(dir.join("002").join("003.patch").exists());
+
+        // Banks without patches are empty, even if their directory exists
+        fs::create_dir_all(dir.join("004")).unwrap();
+        fs::write(dir.join("004").join("notes.txt"), "").unwrap();
+        assert!(!bank.contains_bank(4));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
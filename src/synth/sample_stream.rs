@@ -2,6 +2,12 @@ use std::rc::Rc;
 
 pub trait SampleStream {
     fn next_sample(&self) -> f32;
+
+    /// Fills the whole buffer with the next samples of the stream.
+    ///
+    /// Equivalent to calling `next_sample()` for each sample of the buffer, but avoids the
+    /// per-sample overhead of dynamic dispatch and reading and writing the state of the stream.
+    fn fill_buffer(&self, buffer: &mut [f32]);
 }
 
 impl<T> SampleStream for Rc<T>
@@ -11,6 +17,10 @@ where
     fn next_sample(&self) -> f32 {
         (**self).next_sample()
     }
+
+    fn fill_buffer(&self, buffer: &mut [f32]) {
+        (**self).fill_buffer(buffer)
+    }
 }
 
 macro_rules! iterator {
@@ -0,0 +1,716 @@
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, ErrorKind as IoErrorKind, Write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use synth::step_clock::StepClock;
+
+use errors::*;
+
+/// Number of steps of a pattern
+pub const SEQUENCER_STEPS: usize = 16;
+
+/// Longest note, in steps
+pub const MAX_STEP_LENGTH: u8 = 4;
+
+/// Version of the pattern format written by `Pattern::write_to()`.
+const PATTERN_VERSION: u32 = 1;
+
+/// Clock pulses per step (1/16 notes)
+const PULSES_PER_STEP: u32 = 6;
+
+/// Step of a pattern.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Step {
+    /// Whether a note is played at this step
+    pub gate: bool,
+    pub note: u8,
+    pub accent: bool,
+    /// Note is held until the next note starts, which is then played legato
+    pub slide: bool,
+    /// Length of the note in steps (the last one is played for half a step)
+    pub length: u8,
+}
+
+impl Default for Step {
+    fn default() -> Self {
+        Self {
+            gate: false,
+            note: 60,
+            accent: false,
+            slide: false,
+            length: 1,
+        }
+    }
+}
+
+/// Property of a step that can be edited on a control surface.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StepField {
+    Gate,
+    Accent,
+    Slide,
+    /// Length in steps
+    Length(u8),
+}
+
+/// Steps played by the sequencer, which can be saved to a file.
+#[derive(Debug, PartialEq)]
+pub struct Pattern {
+    pub steps: [Step; SEQUENCER_STEPS],
+    /// File the pattern is saved to
+    path: Option<PathBuf>,
+}
+
+impl Default for Pattern {
+    fn default() -> Self {
+        Self {
+            steps: [Step::default(); SEQUENCER_STEPS],
+            path: None,
+        }
+    }
+}
+
+impl Pattern {
+    /// Loads the pattern from a file. Returns an empty pattern if the file does not exist.
+    ///
+    /// Edited patterns are saved to the same file.
+    pub fn load(path: PathBuf) -> Result<Self> {
+        let mut pattern = match File::open(&path) {
+            Ok(file) => Self::read_from(BufReader::new(file))
+                .chain_err(|| format!("Failed to read pattern from {}", path.display()))?,
+            Err(ref e) if e.kind() == IoErrorKind::NotFound => Self::default(),
+            Err(e) => return Err(e.into()),
+        };
+        pattern.path = Some(path);
+
+        Ok(pattern)
+    }
+
+    /// Saves the pattern to the file it was loaded from (if any).
+    pub fn save(&self) -> Result<()> {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write_to(&mut writer)?;
+        writer.flush()?;
+
+        Ok(())
+    }
+
+    /// Changes a property of a step: gate, accent and slide are toggled, the length is set (or
+    /// reset to a single step if the step already has that length).
+    pub fn edit(&mut self, step: usize, field: StepField) {
+        let step = &mut self.steps[step % SEQUENCER_STEPS];
+        match field {
+            StepField::Gate => step.gate = !step.gate,
+            StepField::Accent => step.accent = !step.accent,
+            StepField::Slide => step.slide = !step.slide,
+            StepField::Length(length) if length == step.length => step.length = 1,
+            StepField::Length(length) => step.length = length.clamp(1, MAX_STEP_LENGTH),
+        }
+    }
+
+    /// Turns a step on with a note, or off if it already plays that note.
+    pub fn toggle_note(&mut self, step: usize, note: u8) {
+        let step = &mut self.steps[step % SEQUENCER_STEPS];
+        if step.gate && step.note == note {
+            step.gate = false;
+        } else {
+            step.note = note;
+            step.gate = true;
+        }
+    }
+
+    /// Writes one line per step: `index = note [gate] [accent] [slide] length`.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writeln!(writer, "# midi-synth pattern")?;
+        writeln!(writer, "version = {}", PATTERN_VERSION)?;
+
+        for (index, step) in self.steps.iter().enumerate() {
+            write!(writer, "{} = {}", index, step.note)?;
+            for &(set, flag) in &[
+                (step.gate, "gate"),
+                (step.accent, "accent"),
+                (step.slide, "slide"),
+            ] {
+                if set {
+                    write!(writer, " {}", flag)?;
+                }
+            }
+            writeln!(writer, " {}", step.length)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn read_from<R: BufRead>(reader: R) -> Result<Self> {
+        let mut version = None;
+        let mut pattern = Self::default();
+
+        for (line_number, line) in reader.lines().enumerate() {
+            let line = line?;
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let mut parts = line.splitn(2, '=');
+            let key = parts.next().unwrap_or("").trim();
+            let value = parts.next().unwrap_or("").trim();
+
+            if key == "version" {
+                let v: u32 = value
+                    .parse()
+                    .chain_err(|| format!("Invalid pattern version: {}", value))?;
+                if v > PATTERN_VERSION {
+                    bail!(
+                        "Pattern version {} is not supported (newer than {})",
+                        v,
+                        PATTERN_VERSION
+                    );
+                }
+                version = Some(v);
+            } else if version.is_none() {
+                bail!("Pattern version missing before line {}", line_number + 1);
+            } else {
+                let index: usize = key
+                    .parse()
+                    .ok()
+                    .filter(|index| *index < SEQUENCER_STEPS)
+                    .ok_or_else(|| format!("Invalid step in line {}: {}", line_number + 1, key))?;
+                pattern.steps[index] = parse_step(value)
+                    .chain_err(|| format!("Invalid step in line {}", line_number + 1))?;
+            }
+        }
+
+        if version.is_none() {
+            bail!("Pattern version missing");
+        }
+
+        Ok(pattern)
+    }
+}
+
+fn parse_step(value: &str) -> Result<Step> {
+    let tokens: Vec<&str> = value.split_whitespace().collect();
+    if tokens.len() < 2 {
+        bail!("Note and length expected: {}", value);
+    }
+
+    let mut step = Step {
+        note: tokens[0]
+            .parse()
+            .ok()
+            .filter(|note| *note <= 127)
+            .ok_or_else(|| format!("Invalid note: {}", tokens[0]))?,
+        length: tokens[tokens.len() - 1]
+            .parse()
+            .ok()
+            .filter(|length| (1..=MAX_STEP_LENGTH).contains(length))
+            .ok_or_else(|| format!("Invalid length: {}", tokens[tokens.len() - 1]))?,
+        ..Step::default()
+    };
+
+    for flag in &tokens[1..tokens.len() - 1] {
+        match *flag {
+            "gate" => step.gate = true,
+            "accent" => step.accent = true,
+            "slide" => step.slide = true,
+            _ => bail!("Unknown flag: {}", flag),
+        }
+    }
+
+    Ok(step)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SequencerNote {
+    On { note: u8, accent: bool },
+    Off(u8),
+}
+
+/// Plays a pattern of steps in a loop.
+///
+/// Like the arpeggiator, the sequencer is driven by the dispatcher: `next_event()` tells when
+/// `run()` has to be called next.
+pub struct Sequencer {
+    pattern: Pattern,
+    /// Whether the pattern was edited since it was loaded or saved
+    modified: bool,
+    clock: StepClock,
+    running: bool,
+    /// Step that is played next
+    position: usize,
+    /// Step that was played last
+    playhead: Option<usize>,
+    playing: Option<u8>,
+    /// Whether the playing note slides into the next one
+    sliding: bool,
+    note_off_at: Option<Instant>,
+}
+
+impl Sequencer {
+    pub fn new(pattern: Pattern) -> Self {
+        Self {
+            pattern,
+            modified: false,
+            clock: StepClock::new(PULSES_PER_STEP),
+            running: false,
+            position: 0,
+            playhead: None,
+            playing: None,
+            sliding: false,
+            note_off_at: None,
+        }
+    }
+
+    pub fn pattern(&self) -> &Pattern {
+        &self.pattern
+    }
+
+    /// Changes a property of a step (see `Pattern::edit()`).
+    pub fn edit(&mut self, step: usize, field: StepField) {
+        self.pattern.edit(step, field);
+        self.modified = true;
+    }
+
+    /// Sets the note of a step (and turns it on).
+    pub fn set_note(&mut self, step: usize, note: u8) {
+        let step = &mut self.pattern.steps[step % SEQUENCER_STEPS];
+        step.note = note;
+        step.gate = true;
+        self.modified = true;
+    }
+
+    /// Turns a step on with a note, or off if it already plays that note.
+    pub fn toggle_note(&mut self, step: usize, note: u8) {
+        self.pattern.toggle_note(step, note);
+        self.modified = true;
+    }
+
+    /// Saves the pattern if it was edited. Editing does not save it right away, as writing the
+    /// file would delay the steps that are played.
+    pub fn save(&mut self) -> Result<()> {
+        if self.modified {
+            self.pattern.save()?;
+            self.modified = false;
+        }
+
+        Ok(())
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    /// Step that is currently played, if the sequencer is running.
+    pub fn playhead(&self) -> Option<usize> {
+        self.playhead
+    }
+
+    pub fn set_tempo(&mut self, tempo: f32) {
+        self.clock.set_tempo(tempo);
+    }
+
+    /// Selects whether steps are timed by MIDI clock (see `clock()`) or by the internal tempo.
+    pub fn set_external_clock(&mut self, external_clock: bool, now: Instant) {
+        if external_clock != self.clock.is_external() {
+            self.clock.set_external(external_clock);
+            if self.running {
+                self.clock.start(now);
+            }
+        }
+    }
+
+    /// Starts the pattern from the first step.
+    pub fn start(&mut self, now: Instant) {
+        self.running = true;
+        self.position = 0;
+        self.clock.start(now);
+    }
+
+    /// Stops the pattern, releasing the playing note.
+    pub fn stop(&mut self, now: Instant) {
+        self.running = false;
+        self.playhead = None;
+        self.clock.stop();
+        if self.playing.is_some() {
+            self.note_off_at = Some(now);
+        }
+    }
+
+    /// Handles a pulse of the MIDI clock (24 per quarter note).
+    pub fn clock(&mut self, now: Instant) -> Vec<(SequencerNote, Instant)> {
+        let due = self.clock.pulse();
+        let mut notes = self.run(now);
+
+        if due && self.running {
+            let duration = self.clock.step_duration();
+            self.play_step(now, duration, &mut notes);
+        }
+
+        notes
+    }
+
+    /// Handles MIDI Start: the pattern starts from the first step with the next clock pulse.
+    pub fn clock_start(&mut self, now: Instant) {
+        self.clock.clock_start();
+        if self.clock.is_external() {
+            self.start(now);
+        }
+    }
+
+    /// Handles MIDI Continue.
+    pub fn clock_continue(&mut self) {
+        self.clock.clock_continue();
+        if self.clock.is_external() {
+            self.running = true;
+        }
+    }
+
+    /// Handles MIDI Stop.
+    pub fn clock_stop(&mut self, now: Instant) {
+        self.clock.clock_stop();
+        if self.clock.is_external() {
+            self.stop(now);
+        }
+    }
+
+    /// Forgets the playing note after all notes were released otherwise (e.g. by a program
+    /// change), so that it is not released again.
+    pub fn notes_released(&mut self) {
+        self.playing = None;
+        self.sliding = false;
+        self.note_off_at = None;
+    }
+
+    /// Returns the time at which `run()` has to be called next, if any.
+    pub fn next_event(&self) -> Option<Instant> {
+        match (self.note_off_at, self.clock.next_step()) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    /// Returns the notes that are due until `now`, with the time they are due.
+    pub fn run(&mut self, now: Instant) -> Vec<(SequencerNote, Instant)> {
+        let mut notes = vec![];
+
+        loop {
+            // Notes are released before the next one is played
+            if let Some(off) = self.note_off_at {
+                if off <= now && self.clock.next_step().map_or(true, |step| off <= step) {
+                    self.note_off_at = None;
+                    if let Some(note) = self.playing.take() {
+                        notes.push((SequencerNote::Off(note), off));
+                    }
+                    continue;
+                }
+            }
+
+            match self.clock.due_step(now) {
+                Some(step) => {
+                    let duration = self.clock.step_duration();
+                    self.play_step(step, duration, &mut notes);
+                }
+                None => return notes,
+            }
+        }
+    }
+
+    fn play_step(
+        &mut self,
+        time: Instant,
+        duration: Duration,
+        notes: &mut Vec<(SequencerNote, Instant)>,
+    ) {
+        let step = self.pattern.steps[self.position];
+        self.playhead = Some(self.position);
+        self.position = (self.position + 1) % SEQUENCER_STEPS;
+
+        if !step.gate {
+            return;
+        }
+
+        // A sliding note is released after the next one started, or keeps playing if the next
+        // one is the same
+        let previous = self.playing.take();
+        let slide = previous.is_some() && self.sliding;
+        if let Some(note) = previous.filter(|_| !slide) {
+            notes.push((SequencerNote::Off(note), time));
+        }
+
+        if !(slide && previous == Some(step.note)) {
+            notes.push((
+                SequencerNote::On {
+                    note: step.note,
+                    accent: step.accent,
+                },
+                time,
+            ));
+        }
+        if let Some(note) = previous.filter(|previous| slide && *previous != step.note) {
+            notes.push((SequencerNote::Off(note), time));
+        }
+
+        self.playing = Some(step.note);
+        self.sliding = step.slide;
+        self.note_off_at = if step.slide {
+            // Held until the next note (or for the length of the note if there is none)
+            Some(time + duration * u32::from(step.length) + duration / 2)
+        } else {
+            Some(time + duration * u32::from(step.length) - duration / 2)
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::process;
+
+    use self::SequencerNote::*;
+
+    /// Step duration of 1/16 notes at 120 BPM
+    const STEP: Duration = Duration::from_millis(125);
+
+    fn on(note: u8) -> SequencerNote {
+        On {
+            note,
+            accent: false,
+        }
+    }
+
+    #[test]
+    fn plays_steps_with_gate() {
+        let start = Instant::now();
+        let mut pattern = Pattern::default();
+        pattern.steps[0].gate = true;
+        pattern.steps[2] = Step {
+            gate: true,
+            note: 67,
+            accent: true,
+            slide: false,
+            length: 2,
+        };
+        let mut sequencer = Sequencer::new(pattern);
+
+        sequencer.start(start);
+        assert_eq!(vec![(on(60), start)], sequencer.run(start));
+        assert_eq!(Some(0), sequencer.playhead());
+        assert_eq!(
+            vec![(Off(60), start + STEP / 2)],
+            sequencer.run(start + STEP)
+        );
+        assert_eq!(Some(1), sequencer.playhead());
+        assert_eq!(
+            vec![(
+                On {
+                    note: 67,
+                    accent: true
+                },
+                start + STEP * 2
+            )],
+            sequencer.run(start + STEP * 2)
+        );
+        assert_eq!(Some(start + STEP * 3), sequencer.next_event());
+        assert_eq!(
+            vec![(Off(67), start + STEP * 7 / 2)],
+            sequencer.run(start + STEP * 4)
+        );
+    }
+
+    #[test]
+    fn slide_plays_next_note_legato() {
+        let start = Instant::now();
+        let mut pattern = Pattern::default();
+        pattern.steps[0].gate = true;
+        pattern.steps[0].slide = true;
+        pattern.steps[1].gate = true;
+        pattern.steps[1].note = 62;
+        let mut sequencer = Sequencer::new(pattern);
+
+        sequencer.start(start);
+        sequencer.run(start);
+        assert_eq!(
+            vec![(on(62), start + STEP), (Off(60), start + STEP)],
+            sequencer.run(start + STEP)
+        );
+    }
+
+    #[test]
+    fn slide_into_same_note_keeps_it_playing() {
+        let start = Instant::now();
+        let mut pattern = Pattern::default();
+        pattern.steps[0].gate = true;
+        pattern.steps[0].slide = true;
+        pattern.steps[1].gate = true;
+        let mut sequencer = Sequencer::new(pattern);
+
+        sequencer.start(start);
+        assert_eq!(vec![(on(60), start)], sequencer.run(start));
+        assert_eq!(
+            Vec::<(SequencerNote, Instant)>::new(),
+            sequencer.run(start + STEP)
+        );
+        assert_eq!(
+            vec![(Off(60), start + STEP * 3 / 2)],
+            sequencer.run(start + STEP * 2)
+        );
+    }
+
+    #[test]
+    fn stop_releases_note() {
+        let start = Instant::now();
+        let mut pattern = Pattern::default();
+        pattern.steps[0].gate = true;
+        let mut sequencer = Sequencer::new(pattern);
+
+        sequencer.start(start);
+        sequencer.run(start);
+        sequencer.stop(start + STEP / 4);
+        assert_eq!(
+            vec![(Off(60), start + STEP / 4)],
+            sequencer.run(start + STEP / 4)
+        );
+        assert_eq!(None, sequencer.next_event());
+        assert_eq!(None, sequencer.playhead());
+    }
+
+    #[test]
+    fn external_clock() {
+        let start = Instant::now();
+        let pulse = Duration::from_millis(20);
+        let mut pattern = Pattern::default();
+        pattern.steps[1].gate = true;
+        let mut sequencer = Sequencer::new(pattern);
+        sequencer.set_external_clock(true, start);
+        sequencer.set_tempo(125.0);
+
+        sequencer.clock_start(start);
+        assert!(sequencer.is_running());
+
+        let mut notes = vec![];
+        for n in 1..13 {
+            notes.extend(sequencer.clock(start + pulse * n));
+        }
+        assert_eq!(
+            vec![(on(60), start + pulse * 7), (Off(60), start + pulse * 10)],
+            notes
+        );
+
+        sequencer.clock_stop(start + pulse * 13);
+        assert!(!sequencer.is_running());
+    }
+
+    #[test]
+    fn edit_steps() {
+        let mut pattern = Pattern::default();
+
+        pattern.edit(3, StepField::Gate);
+        pattern.edit(3, StepField::Accent);
+        pattern.edit(3, StepField::Slide);
+        pattern.edit(3, StepField::Slide);
+        pattern.edit(3, StepField::Length(3));
+        pattern.edit(3, StepField::Length(2));
+
+        pattern.toggle_note(4, 62);
+        pattern.toggle_note(5, 64);
+        pattern.toggle_note(5, 64);
+
+        assert_eq!(
+            Step {
+                gate: true,
+                note: 60,
+                accent: true,
+                slide: false,
+                length: 2,
+            },
+            pattern.steps[3]
+        );
+        assert!(pattern.steps[4].gate);
+        assert_eq!(62, pattern.steps[4].note);
+        assert!(!pattern.steps[5].gate);
+        assert_eq!(64, pattern.steps[5].note);
+
+        // Selecting the length of a step again resets it
+        pattern.edit(3, StepField::Length(2));
+        assert_eq!(1, pattern.steps[3].length);
+    }
+
+    #[test]
+    fn patterns_round_trip() {
+        let mut pattern = Pattern::default();
+        pattern.steps[0] = Step {
+            gate: true,
+            note: 48,
+            accent: true,
+            slide: true,
+            length: 3,
+        };
+        pattern.steps[15].gate = true;
+
+        let mut buf = vec![];
+        pattern.write_to(&mut buf).unwrap();
+        assert!(String::from_utf8(buf.clone())
+            .unwrap()
+            .contains("0 = 48 gate accent slide 3\n"));
+        assert_eq!(pattern, Pattern::read_from(&buf[..]).unwrap());
+    }
+
+    #[test]
+    fn invalid_patterns() {
+        for text in &[
+            "0 = 60 gate 1",
+            "version = 2",
+            "version = 1\n16 = 60 1",
+            "version = 1\n0 = 60 5",
+            "version = 1\n0 = 60 loud 1",
+            "version = 1\n0 = 128 1",
+        ] {
+            assert!(Pattern::read_from(text.as_bytes()).is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn patterns_are_saved_to_their_file() {
+        let path = env::temp_dir()
+            .join(format!("midi-synth-pattern-test-{}", process::id()))
+            .join("pattern.txt");
+
+        let mut pattern = Pattern::load(path.clone()).unwrap();
+        assert_eq!(Pattern::default().steps, pattern.steps);
+
+        pattern.edit(5, StepField::Gate);
+        pattern.save().unwrap();
+        assert_eq!(pattern, Pattern::load(path.clone()).unwrap());
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn edited_patterns_are_saved_on_request() {
+        let path = env::temp_dir()
+            .join(format!("midi-synth-sequencer-test-{}", process::id()))
+            .join("pattern.txt");
+
+        let mut sequencer = Sequencer::new(Pattern::load(path.clone()).unwrap());
+        sequencer.edit(2, StepField::Gate);
+        sequencer.set_note(2, 64);
+        assert!(!path.exists());
+
+        sequencer.save().unwrap();
+        assert_eq!(sequencer.pattern(), &Pattern::load(path.clone()).unwrap());
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
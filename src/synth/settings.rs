@@ -0,0 +1,159 @@
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, ErrorKind as IoErrorKind, Write};
+use std::path::PathBuf;
+
+use errors::*;
+
+/// Frequency of a' in Hz that the tunings are based on
+pub const DEFAULT_CONCERT_PITCH: f32 = 440.0;
+/// Range of the concert pitch, from baroque pitch (a semitone below 440 Hz) to a semitone above
+pub const MIN_CONCERT_PITCH: f32 = 415.0;
+pub const MAX_CONCERT_PITCH: f32 = 466.0;
+
+/// Configuration of the synthesizer that does not belong to a patch.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings {
+    /// Frequency of a' in Hz
+    pub concert_pitch: f32,
+    path: Option<PathBuf>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            concert_pitch: DEFAULT_CONCERT_PITCH,
+            path: None,
+        }
+    }
+}
+
+impl Settings {
+    /// Loads the settings from a file. Returns the default settings if the file does not exist.
+    ///
+    /// Changed settings are saved to the same file.
+    pub fn load(path: PathBuf) -> Result<Self> {
+        let mut settings = Settings::default();
+
+        match File::open(&path) {
+            Ok(file) => settings
+                .read_from(BufReader::new(file))
+                .chain_err(|| format!("Failed to load settings from {}", path.display()))?,
+            Err(ref e) if e.kind() == IoErrorKind::NotFound => {}
+            Err(e) => {
+                return Err(e)
+                    .chain_err(|| format!("Failed to open settings file {}", path.display()))
+            }
+        }
+
+        settings.path = Some(path);
+        Ok(settings)
+    }
+
+    fn read_from<R: BufRead>(&mut self, reader: R) -> Result<()> {
+        for (line_number, line) in reader.lines().enumerate() {
+            let line = line?;
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let mut parts = line.splitn(2, '=');
+            let key = parts.next().unwrap_or("").trim();
+            let value = parts.next().unwrap_or("").trim();
+
+            match key {
+                "concert_pitch" => {
+                    self.concert_pitch = parse_concert_pitch(value).chain_err(|| {
+                        format!("Invalid concert pitch in line {}", line_number + 1)
+                    })?
+                }
+                _ => println!("Ignoring unknown setting: {}", key),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Saves the settings to the file they were loaded from.
+    pub fn save(&self) -> Result<()> {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .chain_err(|| format!("Failed to create directory {}", dir.display()))?;
+        }
+
+        let file = File::create(path)
+            .chain_err(|| format!("Failed to create settings file {}", path.display()))?;
+        self.write_to(&mut BufWriter::new(file))
+            .chain_err(|| format!("Failed to save settings to {}", path.display()))
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writeln!(writer, "# midi-synth settings")?;
+        writeln!(writer, "concert_pitch = {}", self.concert_pitch)?;
+
+        Ok(())
+    }
+}
+
+/// Parses a concert pitch in Hz and checks that it lies within the supported range.
+pub fn parse_concert_pitch(value: &str) -> Result<f32> {
+    let pitch: f32 = value
+        .parse()
+        .chain_err(|| format!("Invalid frequency: {}", value))?;
+    if !(MIN_CONCERT_PITCH..=MAX_CONCERT_PITCH).contains(&pitch) {
+        bail!(
+            "Concert pitch {} Hz is not between {} and {} Hz",
+            pitch,
+            MIN_CONCERT_PITCH,
+            MAX_CONCERT_PITCH
+        );
+    }
+
+    Ok(pitch)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn save_and_load() {
+        let path = env::temp_dir()
+            .join(format!("midi-synth-settings-test-{}", process::id()))
+            .join("settings.txt");
+
+        let mut settings = Settings::load(path.clone()).unwrap();
+        assert_eq!(DEFAULT_CONCERT_PITCH, settings.concert_pitch);
+
+        settings.concert_pitch = 415.0;
+        settings.save().unwrap();
+        assert_eq!(settings, Settings::load(path.clone()).unwrap());
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn concert_pitch_range() {
+        assert_eq!(432.0, parse_concert_pitch("432").unwrap());
+        assert!(parse_concert_pitch("414.9").is_err());
+        assert!(parse_concert_pitch("467").is_err());
+        assert!(parse_concert_pitch("a").is_err());
+
+        let mut settings = Settings::default();
+        assert!(settings
+            .read_from("concert_pitch = 500\n".as_bytes())
+            .is_err());
+        settings
+            .read_from("# comment\nconcert_pitch = 443.5\n".as_bytes())
+            .unwrap();
+        assert_eq!(443.5, settings.concert_pitch);
+    }
+}
@@ -0,0 +1,165 @@
+use std::cell::Cell;
+
+/// Parameter value that ramps linearly to a new target over a configurable number of samples,
+/// instead of jumping to it. This avoids audible steps ("zipper noise") when a continuous
+/// parameter is controlled by a knob or fader with a coarse resolution.
+#[derive(Debug)]
+pub struct SmoothedValue {
+    current: Cell<f32>,
+    target: Cell<f32>,
+    step: Cell<f32>,
+    remaining_samples: Cell<usize>,
+    ramp_length: Cell<usize>,
+}
+
+impl SmoothedValue {
+    /// Creates a value without smoothing (i.e. a ramp length of 0 samples).
+    pub fn new(value: f32) -> Self {
+        Self {
+            current: Cell::new(value),
+            target: Cell::new(value),
+            step: Cell::new(0.0),
+            remaining_samples: Cell::new(0),
+            ramp_length: Cell::new(0),
+        }
+    }
+
+    /// Sets the number of samples it takes to reach a new target. Takes effect with the next
+    /// call to `set_target()`.
+    pub fn set_ramp_length(&self, samples: usize) {
+        self.ramp_length.set(samples);
+    }
+
+    /// Starts a ramp from the current value to the target value.
+    pub fn set_target(&self, target: f32) {
+        let ramp_length = self.ramp_length.get();
+
+        self.target.set(target);
+        if ramp_length == 0 {
+            self.current.set(target);
+            self.remaining_samples.set(0);
+        } else {
+            self.step
+                .set((target - self.current.get()) / ramp_length as f32);
+            self.remaining_samples.set(ramp_length);
+        }
+    }
+
+    /// Returns the value at the current sample.
+    pub fn value(&self) -> f32 {
+        self.current.get()
+    }
+
+    /// Returns the value the ramp ends at.
+    pub fn target(&self) -> f32 {
+        self.target.get()
+    }
+
+    pub fn is_smoothing(&self) -> bool {
+        self.remaining_samples.get() > 0
+    }
+
+    /// Advances the ramp by one sample and returns the new value.
+    #[inline]
+    pub fn next_value(&self) -> f32 {
+        let remaining_samples = self.remaining_samples.get();
+
+        if remaining_samples > 0 {
+            self.remaining_samples.set(remaining_samples - 1);
+            if remaining_samples == 1 {
+                // Avoid accumulated rounding errors
+                self.current.set(self.target.get());
+            } else {
+                self.current.set(self.current.get() + self.step.get());
+            }
+        }
+
+        self.current.get()
+    }
+
+    /// Advances the ramp by the given number of samples.
+    pub fn skip(&self, samples: usize) {
+        let remaining_samples = self.remaining_samples.get();
+
+        if samples >= remaining_samples {
+            self.current.set(self.target.get());
+            self.remaining_samples.set(0);
+        } else {
+            self.current
+                .set(self.current.get() + samples as f32 * self.step.get());
+            self.remaining_samples.set(remaining_samples - samples);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn without_ramp_target_is_set_immediately() {
+        let value = SmoothedValue::new(0.0);
+
+        value.set_target(0.8);
+        assert!(!value.is_smoothing());
+        assert_float_eq!(0.8, value.value(), 1e-6);
+        assert_float_eq!(0.8, value.next_value(), 1e-6);
+    }
+
+    #[test]
+    fn ramp_reaches_target_after_ramp_length() {
+        let value = SmoothedValue::new(0.0);
+        value.set_ramp_length(4);
+
+        value.set_target(1.0);
+        assert!(value.is_smoothing());
+        assert_float_eq!(0.0, value.value(), 1e-6);
+        assert_float_eq!(0.25, value.next_value(), 1e-6);
+        assert_float_eq!(0.5, value.next_value(), 1e-6);
+        assert_float_eq!(0.75, value.next_value(), 1e-6);
+        assert_float_eq!(1.0, value.next_value(), 1e-6);
+        assert!(!value.is_smoothing());
+        assert_float_eq!(1.0, value.next_value(), 1e-6);
+    }
+
+    #[test]
+    fn per_sample_delta_is_bounded() {
+        let value = SmoothedValue::new(0.0);
+        value.set_ramp_length(441);
+
+        // Fader moved over its full range in a few steps, faster than the ramp
+        let max_delta = 1.0 / 441.0 + 1e-6;
+        let mut previous = value.value();
+        for &target in &[1.0, 0.0, 0.7, 1.0] {
+            value.set_target(target);
+
+            for _ in 0..100 {
+                let next = value.next_value();
+                assert!((next - previous).abs() <= max_delta);
+                previous = next;
+            }
+        }
+
+        for _ in 0..441 {
+            let next = value.next_value();
+            assert!((next - previous).abs() <= max_delta);
+            previous = next;
+        }
+        assert_float_eq!(1.0, previous, 1e-6);
+    }
+
+    #[test]
+    fn skip_advances_ramp() {
+        let value = SmoothedValue::new(1.0);
+        value.set_ramp_length(10);
+
+        value.set_target(2.0);
+        value.skip(4);
+        assert_float_eq!(1.4, value.value(), 1e-6);
+        assert_float_eq!(1.5, value.next_value(), 1e-6);
+
+        value.skip(100);
+        assert!(!value.is_smoothing());
+        assert_float_eq!(2.0, value.value(), 1e-6);
+    }
+}
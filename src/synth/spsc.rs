@@ -0,0 +1,235 @@
+use std::cell::UnsafeCell;
+use std::marker::PhantomData;
+use std::mem::MaybeUninit;
+use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{TryRecvError, TrySendError};
+use std::sync::Arc;
+
+/// Bounded, lock-free single-producer/single-consumer queue.
+///
+/// Unlike `std::sync::mpsc`, neither sending nor receiving ever locks or allocates, which makes
+/// it suitable to pass data into the audio callback. All memory is allocated by `channel()`.
+pub fn channel<T>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+    assert!(capacity > 0, "Capacity of queue must be greater than 0");
+
+    let buffer = (0..capacity)
+        .map(|_| UnsafeCell::new(MaybeUninit::uninit()))
+        .collect::<Vec<_>>()
+        .into_boxed_slice();
+
+    let queue = Arc::new(Queue {
+        buffer,
+        head: AtomicUsize::new(0),
+        tail: AtomicUsize::new(0),
+    });
+
+    (
+        Producer {
+            queue: Arc::clone(&queue),
+            _not_sync: PhantomData,
+        },
+        Consumer {
+            queue,
+            _not_sync: PhantomData,
+        },
+    )
+}
+
+struct Queue<T> {
+    buffer: Box<[UnsafeCell<MaybeUninit<T>>]>,
+    /// Number of elements read so far (only written by the consumer)
+    head: AtomicUsize,
+    /// Number of elements written so far (only written by the producer)
+    tail: AtomicUsize,
+}
+
+// Safety: Slots are only accessed by one side at a time: the producer only writes slots that are
+// free, the consumer only reads slots that have been published by the producer.
+unsafe impl<T: Send> Sync for Queue<T> {}
+
+impl<T> Queue<T> {
+    fn slot(&self, index: usize) -> *mut MaybeUninit<T> {
+        self.buffer[index % self.buffer.len()].get()
+    }
+}
+
+impl<T> Drop for Queue<T> {
+    fn drop(&mut self) {
+        let head = *self.head.get_mut();
+        let tail = *self.tail.get_mut();
+
+        for index in head..tail {
+            unsafe { ptr::drop_in_place((*self.slot(index)).as_mut_ptr()) };
+        }
+    }
+}
+
+pub struct Producer<T> {
+    queue: Arc<Queue<T>>,
+    // Only one thread may send at a time
+    _not_sync: PhantomData<*const ()>,
+}
+
+unsafe impl<T: Send> Send for Producer<T> {}
+
+impl<T> Producer<T> {
+    /// Appends a value to the queue, fails if the queue is full or the consumer has been dropped.
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        if Arc::strong_count(&self.queue) < 2 {
+            return Err(TrySendError::Disconnected(value));
+        }
+
+        let tail = self.queue.tail.load(Ordering::Relaxed);
+        let head = self.queue.head.load(Ordering::Acquire);
+
+        if tail.wrapping_sub(head) == self.queue.buffer.len() {
+            return Err(TrySendError::Full(value));
+        }
+
+        unsafe { ptr::write((*self.queue.slot(tail)).as_mut_ptr(), value) };
+        self.queue
+            .tail
+            .store(tail.wrapping_add(1), Ordering::Release);
+
+        Ok(())
+    }
+}
+
+pub struct Consumer<T> {
+    queue: Arc<Queue<T>>,
+    // Only one thread may receive at a time
+    _not_sync: PhantomData<*const ()>,
+}
+
+unsafe impl<T: Send> Send for Consumer<T> {}
+
+impl<T> Consumer<T> {
+    /// Removes the oldest value from the queue, if there is one.
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        let head = self.queue.head.load(Ordering::Relaxed);
+        let tail = self.queue.tail.load(Ordering::Acquire);
+
+        if head == tail {
+            // The producer might have sent a last value before being dropped
+            return if Arc::strong_count(&self.queue) < 2
+                && self.queue.tail.load(Ordering::Acquire) == head
+            {
+                Err(TryRecvError::Disconnected)
+            } else {
+                Err(TryRecvError::Empty)
+            };
+        }
+
+        let value = unsafe { ptr::read((*self.queue.slot(head)).as_ptr()) };
+        self.queue
+            .head
+            .store(head.wrapping_add(1), Ordering::Release);
+
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::rc::Rc;
+    use std::thread;
+
+    #[test]
+    fn values_are_received_in_order() {
+        let (tx, rx) = channel(4);
+
+        tx.try_send(1).unwrap();
+        tx.try_send(2).unwrap();
+        tx.try_send(3).unwrap();
+
+        assert_eq!(Ok(1), rx.try_recv());
+        assert_eq!(Ok(2), rx.try_recv());
+
+        tx.try_send(4).unwrap();
+        tx.try_send(5).unwrap();
+        tx.try_send(6).unwrap();
+
+        assert_eq!(Ok(3), rx.try_recv());
+        assert_eq!(Ok(4), rx.try_recv());
+        assert_eq!(Ok(5), rx.try_recv());
+        assert_eq!(Ok(6), rx.try_recv());
+        assert_eq!(Err(TryRecvError::Empty), rx.try_recv());
+    }
+
+    #[test]
+    fn send_fails_if_queue_is_full() {
+        let (tx, rx) = channel(2);
+
+        tx.try_send(1).unwrap();
+        tx.try_send(2).unwrap();
+        assert_eq!(Err(TrySendError::Full(3)), tx.try_send(3));
+
+        assert_eq!(Ok(1), rx.try_recv());
+        tx.try_send(3).unwrap();
+    }
+
+    #[test]
+    fn disconnect_is_detected() {
+        let (tx, rx) = channel(2);
+        tx.try_send(1).unwrap();
+        drop(tx);
+
+        assert_eq!(Ok(1), rx.try_recv());
+        assert_eq!(Err(TryRecvError::Disconnected), rx.try_recv());
+
+        let (tx, rx) = channel(2);
+        drop(rx);
+        assert_eq!(Err(TrySendError::Disconnected(1)), tx.try_send(1));
+    }
+
+    #[test]
+    fn remaining_values_are_dropped() {
+        let value = Rc::new(());
+
+        {
+            let (tx, rx) = channel(4);
+            tx.try_send(Rc::clone(&value)).unwrap();
+            tx.try_send(Rc::clone(&value)).unwrap();
+            tx.try_send(Rc::clone(&value)).unwrap();
+            rx.try_recv().unwrap();
+
+            assert_eq!(3, Rc::strong_count(&value));
+        }
+
+        assert_eq!(1, Rc::strong_count(&value));
+    }
+
+    #[test]
+    fn transfer_between_threads() {
+        const N: usize = 100_000;
+
+        let (tx, rx) = channel(16);
+
+        let producer = thread::spawn(move || {
+            for i in 0..N {
+                let mut value = i;
+                while let Err(TrySendError::Full(v)) = tx.try_send(value) {
+                    value = v;
+                    thread::yield_now();
+                }
+            }
+        });
+
+        let mut expected = 0;
+        while expected < N {
+            match rx.try_recv() {
+                Ok(value) => {
+                    assert_eq!(expected, value);
+                    expected += 1;
+                }
+                Err(TryRecvError::Empty) => thread::yield_now(),
+                Err(TryRecvError::Disconnected) => panic!("producer disconnected early"),
+            }
+        }
+
+        producer.join().unwrap();
+    }
+}
@@ -0,0 +1,166 @@
+use std::time::{Duration, Instant};
+
+/// Pulses per quarter note of a MIDI clock
+pub const CLOCK_PULSES_PER_QUARTER_NOTE: u32 = 24;
+
+/// Times the steps of a pattern, either by an internal tempo or by the pulses of an external
+/// MIDI clock.
+///
+/// With an external clock, the tempo (as measured by `Clock`) only determines the length of
+/// the steps.
+pub struct StepClock {
+    /// Tempo in BPM
+    tempo: f32,
+    pulses_per_step: u32,
+    external: bool,
+    /// Time of the next step of the internal clock, if it is running
+    next_step: Option<Instant>,
+    /// Pulses of the external clock since the last beat
+    pulses: u32,
+    /// Cleared by MIDI Stop, set by MIDI Start and Continue
+    external_running: bool,
+}
+
+impl StepClock {
+    pub fn new(pulses_per_step: u32) -> Self {
+        Self {
+            tempo: 120.0,
+            pulses_per_step,
+            external: false,
+            next_step: None,
+            pulses: 0,
+            external_running: true,
+        }
+    }
+
+    pub fn set_tempo(&mut self, tempo: f32) {
+        self.tempo = tempo.max(1.0);
+    }
+
+    pub fn set_pulses_per_step(&mut self, pulses_per_step: u32) {
+        self.pulses_per_step = pulses_per_step.max(1);
+    }
+
+    pub fn is_external(&self) -> bool {
+        self.external
+    }
+
+    /// Selects whether steps are timed by MIDI clock (see `pulse()`) or by the internal tempo.
+    /// Stops the internal clock.
+    pub fn set_external(&mut self, external: bool) {
+        self.external = external;
+        self.next_step = None;
+    }
+
+    /// Starts the internal clock with a step at `now`. Steps of an external clock are due with
+    /// the next beat (or the next step of the current beat).
+    pub fn start(&mut self, now: Instant) {
+        if !self.external {
+            self.next_step = Some(now);
+        }
+    }
+
+    pub fn stop(&mut self) {
+        self.next_step = None;
+    }
+
+    /// Time of the next step of the internal clock.
+    pub fn next_step(&self) -> Option<Instant> {
+        self.next_step
+    }
+
+    pub fn step_duration(&self) -> Duration {
+        let nanos = 60e9 * f64::from(self.pulses_per_step)
+            / (f64::from(self.tempo) * f64::from(CLOCK_PULSES_PER_QUARTER_NOTE));
+        Duration::from_nanos(nanos.round() as u64)
+    }
+
+    /// Returns the time of the step of the internal clock that is due until `now`, if any, and
+    /// schedules the next one.
+    pub fn due_step(&mut self, now: Instant) -> Option<Instant> {
+        let step = self.next_step.filter(|step| *step <= now)?;
+
+        // Skip steps that are long overdue instead of playing them all at once
+        let next_step = step + self.step_duration();
+        self.next_step = Some(if next_step > now { next_step } else { now });
+
+        Some(step)
+    }
+
+    /// Handles a pulse of the MIDI clock (24 per quarter note). Returns true if a step is due.
+    pub fn pulse(&mut self) -> bool {
+        if !self.external || !self.external_running {
+            return false;
+        }
+
+        let due = self.pulses % self.pulses_per_step == 0;
+        self.pulses = (self.pulses + 1) % CLOCK_PULSES_PER_QUARTER_NOTE;
+        due
+    }
+
+    /// Handles MIDI Start: the next pulse is the first beat.
+    pub fn clock_start(&mut self) {
+        self.external_running = true;
+        self.pulses = 0;
+    }
+
+    /// Handles MIDI Continue.
+    pub fn clock_continue(&mut self) {
+        self.external_running = true;
+    }
+
+    /// Handles MIDI Stop.
+    pub fn clock_stop(&mut self) {
+        self.external_running = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn internal_clock() {
+        let start = Instant::now();
+        let step = Duration::from_millis(125);
+        let mut clock = StepClock::new(6);
+
+        assert_eq!(None, clock.due_step(start));
+        clock.start(start);
+        assert_eq!(Some(start), clock.due_step(start));
+        assert_eq!(None, clock.due_step(start + step / 2));
+        assert_eq!(Some(start + step), clock.due_step(start + step));
+        assert_eq!(Some(start + step * 2), clock.next_step());
+
+        clock.set_tempo(60.0);
+        assert_eq!(step * 2, clock.step_duration());
+    }
+
+    #[test]
+    fn overdue_steps_are_skipped() {
+        let start = Instant::now();
+        let mut clock = StepClock::new(6);
+        clock.start(start);
+
+        let late = start + Duration::from_secs(1);
+        assert_eq!(Some(start), clock.due_step(late));
+        assert_eq!(Some(late), clock.due_step(late));
+        assert_eq!(None, clock.due_step(late));
+    }
+
+    #[test]
+    fn external_clock() {
+        let mut clock = StepClock::new(12);
+        clock.set_external(true);
+        clock.start(Instant::now());
+        assert_eq!(None, clock.next_step());
+
+        let due: Vec<u32> = (0..30).filter(|_| clock.pulse()).collect();
+        assert_eq!(vec![0, 12, 24], due);
+
+        clock.clock_stop();
+        assert!(!clock.pulse());
+        clock.clock_start();
+        assert!(clock.pulse());
+    }
+}
@@ -1,62 +1,439 @@
+use std::collections::VecDeque;
 use std::f32;
 use std::rc::Rc;
-use std::sync::mpsc::Receiver;
+use std::time::{Duration, Instant};
 
 use synth::contour::loudness_contour::LoudnessContour;
-use synth::dispatcher::SynthControl;
+use synth::dispatcher::{NoteId, SynthControl};
+use synth::lfo::Lfo;
 use synth::mixer::Mixer;
+use synth::modulation::{
+    modulation_gain, ModulationDestination, ModulationMatrix, ModulationSource, PressureCurve,
+    KEY_POSITION_OCTAVES, PITCH_MODULATION_RANGE,
+};
 use synth::oscillator::Oscillator;
 use synth::sample_stream::SampleStream;
+use synth::settings::DEFAULT_CONCERT_PITCH;
+use synth::smoothed_value::SmoothedValue;
+use synth::spsc::Consumer;
 
 type LoudnessContourInput = Rc<Mixer>;
 
-pub struct Synthesizer {
+/// Fixed delay between receiving a control and applying it. Controls are applied at the sample
+/// that corresponds to their timestamp plus this latency, which absorbs the jitter of the
+/// MIDI and audio threads.
+const CONTROL_LATENCY: Duration = Duration::from_millis(5);
+
+/// Gain of accented notes (+3 dB)
+const ACCENT_GAIN: f32 = 1.412_537_5;
+
+/// Number of samples for which the modulation is constant (or ramped)
+const MODULATION_CONTROL_INTERVAL: usize = 64;
+
+/// Maximum number of controls that can be queued for the synthesizer.
+pub const CONTROL_QUEUE_CAPACITY: usize = 1024;
+
+/// Number of voice groups, which play notes independently of each other (e.g. the zones of a
+/// split keyboard)
+pub const VOICE_GROUPS: usize = 2;
+
+/// Times over which changes of the continuous parameters are spread, to avoid zipper noise.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RampTimes {
+    pub volume: Duration,
+    pub master_tune: Duration,
+    /// Aftertouch, whose 7-bit steps would otherwise be audible
+    pub pressure: Duration,
+}
+
+impl Default for RampTimes {
+    fn default() -> Self {
+        Self {
+            volume: Duration::from_millis(10),
+            master_tune: Duration::from_millis(20),
+            pressure: Duration::from_millis(30),
+        }
+    }
+}
+
+/// Monophonic voice, which plays the lowest of its notes. Each voice group has its own
+/// oscillator settings, all voice groups share the modulation matrix.
+struct VoiceGroup {
     osc1: Rc<Oscillator>,
     mixer: Rc<Mixer>,
     loudness_contour: LoudnessContour<LoudnessContourInput>,
     note_selector: NoteSelector,
-    ctrl_in: Receiver<SynthControl>,
+    /// Key position of the playing (or last played) note
+    key_position: f32,
+    /// Aftertouch source after the curve
+    pressure: SmoothedValue,
 }
 
-impl Synthesizer {
-    pub fn new(ctrl_in: Receiver<SynthControl>) -> Self {
+impl VoiceGroup {
+    fn new(ramp_times: &RampTimes, ramp_length: &dyn Fn(Duration) -> usize) -> Self {
         let osc1 = Rc::new(Oscillator::new(1.0, 0.0));
+        osc1.set_master_tune_ramp_length(ramp_length(ramp_times.master_tune));
         let mixer = Rc::new(Mixer::new(Rc::clone(&osc1)));
+        mixer.set_volume_ramp_length(ramp_length(ramp_times.volume));
+        mixer.set_modulation_ramp_length(MODULATION_CONTROL_INTERVAL);
+        let loudness_contour = LoudnessContour::new(Rc::clone(&mixer));
+        loudness_contour.set_modulation_ramp_length(MODULATION_CONTROL_INTERVAL);
+        let pressure = SmoothedValue::new(0.0);
+        pressure.set_ramp_length(ramp_length(ramp_times.pressure));
+
         Self {
             osc1,
-            mixer: Rc::clone(&mixer),
-            loudness_contour: LoudnessContour::new(mixer),
+            mixer,
+            loudness_contour,
             note_selector: NoteSelector::new(),
+            key_position: 0.0,
+            pressure,
+        }
+    }
+
+    /// Plays a note (frequency ratio to middle C), which sets the key position.
+    fn set_note(&mut self, note: f32) {
+        self.osc1.set_note(note);
+        self.key_position = (note.log2() / KEY_POSITION_OCTAVES).clamp(-1.0, 1.0);
+    }
+}
+
+pub struct Synthesizer {
+    groups: Vec<VoiceGroup>,
+    lfo: Lfo,
+    modulation: ModulationMatrix,
+    /// Channel pressure (0.0 to 1.0)
+    channel_pressure: f32,
+    pressure_curve: PressureCurve,
+    /// Pitch bend of all notes in semitones
+    pitch_bend: f32,
+    /// Master tune as a frequency ratio
+    master_tune: f32,
+    /// Frequency of a' in Hz
+    concert_pitch: f32,
+    /// Gain of the mix of all voice groups
+    master_volume: SmoothedValue,
+    ctrl_in: Consumer<(SynthControl, Instant)>,
+    pending_ctrls: VecDeque<(SynthControl, u64)>,
+    sample_counter: u64,
+    block_start_sample: u64,
+    block_start_time: Instant,
+    sample_rate: f64,
+}
+
+impl Synthesizer {
+    pub fn new(
+        ctrl_in: Consumer<(SynthControl, Instant)>,
+        sample_rate: f64,
+        ramp_times: &RampTimes,
+    ) -> Self {
+        let ramp_length = |time: Duration| (time.as_secs_f64() * sample_rate) as usize;
+
+        let groups = (0..VOICE_GROUPS)
+            .map(|_| VoiceGroup::new(ramp_times, &ramp_length))
+            .collect();
+        let master_volume = SmoothedValue::new(1.0);
+        master_volume.set_ramp_length(ramp_length(ramp_times.volume));
+        Self {
+            groups,
+            lfo: Lfo::new(sample_rate as f32),
+            modulation: ModulationMatrix::new(),
+            channel_pressure: 0.0,
+            pressure_curve: PressureCurve::Linear,
+            pitch_bend: 0.0,
+            master_tune: 1.0,
+            concert_pitch: DEFAULT_CONCERT_PITCH,
+            master_volume,
             ctrl_in,
+            pending_ctrls: VecDeque::with_capacity(CONTROL_QUEUE_CAPACITY),
+            sample_counter: 0,
+            block_start_sample: 0,
+            block_start_time: Instant::now(),
+            sample_rate,
+        }
+    }
+
+    /// Sets the frequency of a' in Hz, which all notes are tuned relative to.
+    pub fn set_concert_pitch(&mut self, concert_pitch: f32) {
+        self.concert_pitch = concert_pitch;
+        self.update_master_tune();
+    }
+
+    fn update_master_tune(&self) {
+        let master_tune = self.master_tune * self.concert_pitch / DEFAULT_CONCERT_PITCH;
+        for group in &self.groups {
+            group.osc1.set_master_tune(master_tune);
+        }
+    }
+
+    /// Marks the start of a new audio buffer, which is used as the reference point to convert
+    /// timestamps of controls into sample offsets.
+    ///
+    /// All controls that have been received since the last buffer are scheduled here.
+    pub fn begin_block(&mut self, timestamp: Instant) {
+        self.block_start_sample = self.sample_counter;
+        self.block_start_time = timestamp;
+
+        while let Ok((ctrl, timestamp)) = self.ctrl_in.try_recv() {
+            // Never grow the queue (this would allocate), apply oldest control early instead
+            if self.pending_ctrls.len() == self.pending_ctrls.capacity() {
+                if let Some((ctrl, _)) = self.pending_ctrls.pop_front() {
+                    self.apply_control(ctrl);
+                }
+            }
+
+            let due = self.due_sample(timestamp);
+            self.pending_ctrls.push_back((ctrl, due));
         }
     }
 
-    fn turn_on_note(&mut self, note: f32) {
-        self.osc1.set_note(self.note_selector.turn_on_note(note));
-        self.loudness_contour.trigger_on();
+    /// Returns the sample at which a control with the given timestamp is due.
+    fn due_sample(&self, timestamp: Instant) -> u64 {
+        let due = timestamp + CONTROL_LATENCY;
+        if due <= self.block_start_time {
+            return self.block_start_sample;
+        }
+
+        let delay = (due - self.block_start_time).min(CONTROL_LATENCY);
+        self.block_start_sample + (delay.as_secs_f64() * self.sample_rate) as u64
     }
 
-    fn turn_off_note(&mut self, note: f32) {
-        if let Some(note) = self.note_selector.turn_off_note(note) {
-            self.osc1.set_note(note);
+    fn apply_control(&mut self, ctrl: SynthControl) {
+        match ctrl {
+            SynthControl::MasterTune(frequency) => {
+                self.master_tune = frequency;
+                self.update_master_tune();
+            }
+            SynthControl::MasterVolume(volume) => self.master_volume.set_target(volume),
+            SynthControl::Oscillator1Range(group, frequency) => {
+                if let Some(group) = self.groups.get(group) {
+                    group
+                        .osc1
+                        .set_range((f64::from(frequency) / self.sample_rate) as f32);
+                }
+            }
+            SynthControl::Oscillator1Enable(group, enabled) => {
+                if let Some(group) = self.groups.get(group) {
+                    group.mixer.set_enabled(enabled);
+                }
+            }
+            SynthControl::Oscillator1Volume(group, volume) => {
+                if let Some(group) = self.groups.get(group) {
+                    group.mixer.set_volume(volume);
+                }
+            }
+            SynthControl::NoteOn(group, id, note) => self.turn_on_note(group, id, note),
+            SynthControl::NoteOff(group, id) => self.turn_off_note(group, id),
+            SynthControl::AllNotesOff => self.turn_off_all_notes(),
+            SynthControl::Accent(group, accent) => {
+                if let Some(group) = self.groups.get(group) {
+                    group
+                        .loudness_contour
+                        .set_level(if accent { ACCENT_GAIN } else { 1.0 });
+                }
+            }
+            SynthControl::Tempo(tempo) => self.lfo.set_tempo(tempo),
+            SynthControl::LfoShape(shape) => self.lfo.set_shape(shape),
+            SynthControl::LfoRate(rate) => self.lfo.set_rate(rate),
+            SynthControl::LfoBeats(beats) => self.lfo.set_beats(beats),
+            SynthControl::LfoKeySync(key_sync) => self.lfo.set_key_sync(key_sync),
+            SynthControl::LfoDelay(delay) => self.lfo.set_delay(delay),
+            SynthControl::LfoFadeIn(fade_in) => self.lfo.set_fade_in(fade_in),
+            SynthControl::ModulationSource(slot, source) => {
+                self.modulation.set_source(slot, source)
+            }
+            SynthControl::ModulationDestination(slot, destination) => {
+                self.modulation.set_destination(slot, destination)
+            }
+            SynthControl::ModulationAmount(slot, amount) => {
+                self.modulation.set_amount(slot, amount)
+            }
+            SynthControl::Velocity(velocity) => self
+                .modulation
+                .set_input(ModulationSource::Velocity, velocity),
+            SynthControl::Aftertouch(pressure) => {
+                self.channel_pressure = pressure;
+                self.update_pressure();
+            }
+            SynthControl::KeyPressure(id, pressure) => {
+                if let Some(playing) = self.find_note(id) {
+                    playing.pressure = pressure;
+                }
+                self.update_pressure();
+            }
+            SynthControl::PitchBend(semitones) => self.pitch_bend = semitones,
+            SynthControl::NotePitchBend(id, semitones) => {
+                if let Some(playing) = self.find_note(id) {
+                    playing.bend = semitones;
+                }
+            }
+            SynthControl::NoteTimbre(id, timbre) => {
+                if let Some(playing) = self.find_note(id) {
+                    playing.timbre = timbre;
+                }
+            }
+            SynthControl::PressureCurve(curve) => {
+                self.pressure_curve = curve;
+                self.update_pressure();
+            }
+            SynthControl::ModWheel(position) => self
+                .modulation
+                .set_input(ModulationSource::ModWheel, position),
+        }
+    }
+
+    /// Applies all controls that are due at the current sample.
+    fn apply_due_controls(&mut self) {
+        while let Some(&(_, due)) = self.pending_ctrls.front() {
+            if due > self.sample_counter {
+                break;
+            }
+
+            if let Some((ctrl, _)) = self.pending_ctrls.pop_front() {
+                self.apply_control(ctrl);
+            }
+        }
+    }
+
+    fn turn_on_note(&mut self, group: usize, id: NoteId, note: f32) {
+        // Legato notes continue the modulation
+        if self
+            .groups
+            .iter()
+            .all(|group| group.note_selector.is_empty())
+        {
+            self.lfo.trigger();
+        }
+
+        let group = match self.groups.get_mut(group) {
+            Some(group) => group,
+            None => return,
+        };
+        let note = group.note_selector.turn_on_note(id, note);
+        group.set_note(note);
+        group.loudness_contour.trigger_on();
+        self.update_pressure();
+    }
+
+    fn turn_off_note(&mut self, group: usize, id: NoteId) {
+        let group = match self.groups.get_mut(group) {
+            Some(group) => group,
+            None => return,
+        };
+        if let Some(note) = group.note_selector.turn_off_note(id) {
+            group.set_note(note);
         } else {
-            self.loudness_contour.trigger_off();
+            group.loudness_contour.trigger_off();
         }
+        self.update_pressure();
+    }
+
+    fn turn_off_all_notes(&mut self) {
+        for group in self.groups.iter_mut() {
+            group.note_selector.clear();
+            group.loudness_contour.trigger_off();
+        }
+    }
+
+    /// Returns a held note of any voice group, e.g. to set its expression.
+    fn find_note(&mut self, id: NoteId) -> Option<&mut Note> {
+        self.groups
+            .iter_mut()
+            .find_map(|group| group.note_selector.find_mut(id))
     }
 
-    pub fn next_sample(&mut self) -> f32 {
-        if let Ok(f) = self.ctrl_in.try_recv() {
-            match f {
-                SynthControl::MasterTune(frequency) => self.osc1.set_master_tune(frequency),
-                SynthControl::Oscillator1Range(range) => self.osc1.set_range(range),
-                SynthControl::Oscillator1Enable(enabled) => self.mixer.set_enabled(enabled),
-                SynthControl::Oscillator1Volume(volume) => self.mixer.set_volume(volume),
-                SynthControl::NoteOn(note) => self.turn_on_note(note),
-                SynthControl::NoteOff(note) => self.turn_off_note(note),
+    /// Ramps the aftertouch source of each voice group to the stronger of the channel pressure
+    /// and the polyphonic pressure of its playing note. Each voice group is monophonic, so the
+    /// pressure of its other held notes is ignored.
+    fn update_pressure(&mut self) {
+        for group in &self.groups {
+            let key_pressure = group
+                .note_selector
+                .lowest()
+                .map_or(0.0, |note| note.pressure);
+            let pressure = self
+                .pressure_curve
+                .apply(self.channel_pressure.max(key_pressure));
+            if pressure != group.pressure.target() {
+                group.pressure.set_target(pressure);
             }
         }
+    }
+
+    /// Updates the modulation of all destinations for the next samples (at most
+    /// `MODULATION_CONTROL_INTERVAL`).
+    fn modulate(&mut self, samples: usize) {
+        let mut lfo = [0.0; MODULATION_CONTROL_INTERVAL];
+        self.lfo.fill_buffer(&mut lfo[..samples]);
+        self.modulation.set_input(ModulationSource::Lfo, lfo[0]);
+
+        // The sources of the first group are set last, so that they remain set
+        for group in self.groups.iter().rev() {
+            self.modulation
+                .set_input(ModulationSource::KeyPosition, group.key_position);
+            self.modulation
+                .set_input(ModulationSource::Gate, group.loudness_contour.output());
+            self.modulation
+                .set_input(ModulationSource::Aftertouch, group.pressure.value());
+            group.pressure.skip(samples);
+            let (note_bend, timbre) = group
+                .note_selector
+                .lowest()
+                .map_or((0.0, 0.0), |note| (note.bend, note.timbre));
+            self.modulation.set_input(ModulationSource::Timbre, timbre);
+
+            let semitones = self.modulation.output(ModulationDestination::Pitch)
+                * PITCH_MODULATION_RANGE
+                + self.pitch_bend
+                + note_bend;
+            group
+                .osc1
+                .set_pitch_modulation(2.0_f32.powf(semitones / 12.0));
+            group.mixer.set_modulation(modulation_gain(
+                self.modulation
+                    .output(ModulationDestination::Oscillator1Level),
+            ));
+            group.loudness_contour.set_modulation(modulation_gain(
+                self.modulation.output(ModulationDestination::Amplitude),
+            ));
+        }
+    }
+
+    /// Fills the buffer with the next samples. The buffer is split at the samples at which
+    /// controls are due, so that the controls take effect at the correct sample, and into
+    /// intervals of constant modulation.
+    pub fn fill_buffer(&mut self, buffer: &mut [f32]) {
+        let mut start = 0;
+
+        while start < buffer.len() {
+            self.apply_due_controls();
 
-        self.loudness_contour.next_sample()
+            let remaining = (buffer.len() - start).min(MODULATION_CONTROL_INTERVAL) as u64;
+            let end = match self.pending_ctrls.front() {
+                Some(&(_, due)) if due - self.sample_counter < remaining => {
+                    start + (due - self.sample_counter) as usize
+                }
+                _ => start + remaining as usize,
+            };
+            self.modulate(end - start);
+
+            let (first, others) = self.groups.split_first().unwrap();
+            first.loudness_contour.fill_buffer(&mut buffer[start..end]);
+            let mut group_buffer = [0.0; MODULATION_CONTROL_INTERVAL];
+            for group in others {
+                group
+                    .loudness_contour
+                    .fill_buffer(&mut group_buffer[..end - start]);
+                for (sample, group_sample) in buffer[start..end].iter_mut().zip(&group_buffer) {
+                    *sample += group_sample;
+                }
+            }
+            for sample in &mut buffer[start..end] {
+                *sample *= self.master_volume.next_value();
+            }
+            self.sample_counter += (end - start) as u64;
+            start = end;
+        }
     }
 }
 
@@ -64,7 +441,26 @@ const NUMBER_OF_NOTES: usize = 32;
 
 #[derive(Copy, Clone)]
 struct Note {
+    id: NoteId,
     note: f32,
+    /// Polyphonic pressure (0.0 to 1.0)
+    pressure: f32,
+    /// Pitch bend in semitones
+    bend: f32,
+    /// Timbre (0.0 to 1.0)
+    timbre: f32,
+}
+
+impl Note {
+    fn new(id: NoteId, note: f32) -> Self {
+        Self {
+            id,
+            note,
+            pressure: 0.0,
+            bend: 0.0,
+            timbre: 0.0,
+        }
+    }
 }
 
 struct NoteSelector {
@@ -79,21 +475,30 @@ struct NoteSelector {
 impl NoteSelector {
     fn new() -> Self {
         Self {
-            notes: [Note {
-                note: f32::INFINITY,
-            }; NUMBER_OF_NOTES],
+            notes: [Note::new(0, f32::INFINITY); NUMBER_OF_NOTES],
             number_of_notes: 0,
         }
     }
 
+    fn is_empty(&self) -> bool {
+        self.number_of_notes == 0
+    }
+
+    fn clear(&mut self) {
+        for note in self.notes.iter_mut() {
+            *note = Note::new(0, f32::INFINITY);
+        }
+        self.number_of_notes = 0;
+    }
+
     /// Inserts new note, returns the lowest note.
-    fn turn_on_note(&mut self, note: f32) -> f32 {
+    fn turn_on_note(&mut self, id: NoteId, note: f32) -> f32 {
         if self.number_of_notes + 1 < NUMBER_OF_NOTES {
             self.number_of_notes += 1;
         }
 
         // Insert new note at next free slot (or reuse last slot if no free slots)
-        self.notes[self.number_of_notes].note = note;
+        self.notes[self.number_of_notes] = Note::new(id, note);
 
         let mut pos = self.number_of_notes;
         let mut parent = pos / 2;
@@ -114,12 +519,28 @@ impl NoteSelector {
         self.notes[1].note
     }
 
+    /// Returns a playing note, e.g. to set its expression.
+    fn find_mut(&mut self, id: NoteId) -> Option<&mut Note> {
+        self.notes[1..=self.number_of_notes]
+            .iter_mut()
+            .find(|playing| playing.id == id)
+    }
+
+    /// Returns the lowest note, which is the one that sounds.
+    fn lowest(&self) -> Option<&Note> {
+        if self.is_empty() {
+            None
+        } else {
+            Some(&self.notes[1])
+        }
+    }
+
     /// Removes note from list of playing notes, returns lowest note.
-    fn turn_off_note(&mut self, note: f32) -> Option<f32> {
+    fn turn_off_note(&mut self, id: NoteId) -> Option<f32> {
         // Perform linear search for note to turn off
         let mut pos = 0;
         for i in 1..=self.number_of_notes {
-            if (note - self.notes[i].note).abs() < 1e-6 {
+            if self.notes[i].id == id {
                 pos = i;
                 break;
             }
@@ -128,8 +549,8 @@ impl NoteSelector {
         // If found...
         if pos > 0 {
             // .. swap note to remove with note at last position
-            self.notes[pos].note = self.notes[self.number_of_notes].note;
-            self.notes[self.number_of_notes].note = f32::INFINITY;
+            self.notes[pos] = self.notes[self.number_of_notes];
+            self.notes[self.number_of_notes] = Note::new(0, f32::INFINITY);
             self.number_of_notes -= 1;
 
             let mut left_child = 2 * pos;
@@ -174,12 +595,400 @@ impl NoteSelector {
 mod tests {
     use super::*;
 
+    use synth::audio_driver::DEFAULT_SAMPLE_RATE;
+    use synth::lfo::LfoShape;
+    use synth::spsc;
+
+    const NO_RAMPS: RampTimes = RampTimes {
+        volume: Duration::from_millis(0),
+        master_tune: Duration::from_millis(0),
+        pressure: Duration::from_millis(0),
+    };
+
+    #[test]
+    fn controls_are_applied_at_due_sample() {
+        let (ctrl_tx, ctrl_rx) = spsc::channel(CONTROL_QUEUE_CAPACITY);
+        let mut synthesizer = Synthesizer::new(ctrl_rx, DEFAULT_SAMPLE_RATE, &NO_RAMPS);
+
+        let t0 = Instant::now();
+
+        // Due at the start of the block
+        ctrl_tx
+            .try_send((SynthControl::Oscillator1Range(0, 1653.75), t0))
+            .unwrap();
+        ctrl_tx
+            .try_send((SynthControl::Oscillator1Enable(0, true), t0))
+            .unwrap();
+        ctrl_tx
+            .try_send((SynthControl::Oscillator1Volume(0, 1.0), t0))
+            .unwrap();
+
+        // Due 1 ms (i.e. 44.1 samples) after the start of the block
+        ctrl_tx
+            .try_send((
+                SynthControl::NoteOn(0, 0, 1.0),
+                t0 + Duration::from_millis(1),
+            ))
+            .unwrap();
+
+        synthesizer.begin_block(t0 + CONTROL_LATENCY);
+
+        let mut buffer = [1.0; 64];
+        synthesizer.fill_buffer(&mut buffer);
+
+        for sample in &buffer[..44] {
+            assert_float_eq!(0.0, *sample, 1e-6);
+        }
+        assert_float_eq!(0.0, buffer[44], 1e-6);
+        assert_float_eq!(0.15, buffer[45], 1e-6);
+        assert_float_eq!(0.3, buffer[46], 1e-6);
+    }
+
+    #[test]
+    fn concert_pitch_scales_frequency() {
+        let (ctrl_tx, ctrl_rx) = spsc::channel(CONTROL_QUEUE_CAPACITY);
+        let mut synthesizer = Synthesizer::new(ctrl_rx, DEFAULT_SAMPLE_RATE, &NO_RAMPS);
+        synthesizer.set_concert_pitch(466.0);
+
+        let t0 = Instant::now();
+
+        ctrl_tx
+            .try_send((SynthControl::Oscillator1Range(0, 1653.75), t0))
+            .unwrap();
+        ctrl_tx
+            .try_send((SynthControl::Oscillator1Enable(0, true), t0))
+            .unwrap();
+        ctrl_tx
+            .try_send((SynthControl::Oscillator1Volume(0, 1.0), t0))
+            .unwrap();
+        ctrl_tx
+            .try_send((SynthControl::MasterTune(0.5), t0))
+            .unwrap();
+        ctrl_tx
+            .try_send((SynthControl::NoteOn(0, 0, 1.0), t0))
+            .unwrap();
+
+        synthesizer.begin_block(t0 + 2 * CONTROL_LATENCY);
+
+        let mut buffer = [1.0; 2];
+        synthesizer.fill_buffer(&mut buffer);
+
+        assert_float_eq!(0.0, buffer[0], 1e-6);
+        assert_float_eq!(0.15 * 0.5 * 466.0 / 440.0, buffer[1], 1e-6);
+    }
+
+    #[test]
+    fn voice_groups_are_mixed() {
+        let (ctrl_tx, ctrl_rx) = spsc::channel(CONTROL_QUEUE_CAPACITY);
+        let mut synthesizer = Synthesizer::new(ctrl_rx, DEFAULT_SAMPLE_RATE, &NO_RAMPS);
+
+        let t0 = Instant::now();
+
+        ctrl_tx
+            .try_send((SynthControl::Oscillator1Range(0, 1653.75), t0))
+            .unwrap();
+        ctrl_tx
+            .try_send((SynthControl::Oscillator1Enable(0, true), t0))
+            .unwrap();
+        ctrl_tx
+            .try_send((SynthControl::Oscillator1Volume(0, 1.0), t0))
+            .unwrap();
+        // The voice groups have their own oscillator settings
+        ctrl_tx
+            .try_send((SynthControl::Oscillator1Range(1, 1653.75), t0))
+            .unwrap();
+        ctrl_tx
+            .try_send((SynthControl::Oscillator1Enable(1, true), t0))
+            .unwrap();
+        ctrl_tx
+            .try_send((SynthControl::Oscillator1Volume(1, 0.5), t0))
+            .unwrap();
+        ctrl_tx
+            .try_send((SynthControl::NoteOn(0, 0, 1.0), t0))
+            .unwrap();
+        ctrl_tx
+            .try_send((SynthControl::NoteOn(1, 1, 2.0), t0))
+            .unwrap();
+        // Notes of voice groups that do not exist are ignored
+        ctrl_tx
+            .try_send((SynthControl::NoteOn(VOICE_GROUPS, 2, 4.0), t0))
+            .unwrap();
+
+        synthesizer.begin_block(t0 + 2 * CONTROL_LATENCY);
+
+        let mut buffer = [1.0; 2];
+        synthesizer.fill_buffer(&mut buffer);
+
+        assert_float_eq!(0.0, buffer[0], 1e-6);
+        assert_float_eq!(0.15 + 0.3 * 0.5, buffer[1], 1e-6);
+        assert_eq!(
+            Some(2.0),
+            synthesizer.groups[1]
+                .note_selector
+                .lowest()
+                .map(|note| note.note)
+        );
+
+        // The master volume leaves headroom for the mix
+        ctrl_tx
+            .try_send((SynthControl::MasterVolume(0.5), t0))
+            .unwrap();
+        synthesizer.begin_block(t0 + 2 * CONTROL_LATENCY);
+        synthesizer.fill_buffer(&mut buffer[..1]);
+        assert_float_eq!((0.3 + 0.6 * 0.5) * 0.5, buffer[0], 1e-6);
+    }
+
+    #[test]
+    fn late_controls_are_applied_immediately() {
+        let (ctrl_tx, ctrl_rx) = spsc::channel(CONTROL_QUEUE_CAPACITY);
+        let mut synthesizer = Synthesizer::new(ctrl_rx, DEFAULT_SAMPLE_RATE, &NO_RAMPS);
+
+        let t0 = Instant::now();
+
+        ctrl_tx
+            .try_send((SynthControl::Oscillator1Range(0, 1653.75), t0))
+            .unwrap();
+        ctrl_tx
+            .try_send((SynthControl::Oscillator1Enable(0, true), t0))
+            .unwrap();
+        ctrl_tx
+            .try_send((SynthControl::Oscillator1Volume(0, 1.0), t0))
+            .unwrap();
+        ctrl_tx
+            .try_send((SynthControl::NoteOn(0, 0, 1.0), t0))
+            .unwrap();
+
+        synthesizer.begin_block(t0 + 2 * CONTROL_LATENCY);
+
+        let mut buffer = [1.0; 2];
+        synthesizer.fill_buffer(&mut buffer);
+
+        assert_float_eq!(0.0, buffer[0], 1e-6);
+        assert_float_eq!(0.15, buffer[1], 1e-6);
+    }
+
+    #[test]
+    fn rate_dependent_values_follow_sample_rate() {
+        let (ctrl_tx, ctrl_rx) = spsc::channel(CONTROL_QUEUE_CAPACITY);
+        let mut synthesizer = Synthesizer::new(ctrl_rx, 48_000.0, &NO_RAMPS);
+
+        let t0 = Instant::now();
+
+        ctrl_tx
+            .try_send((SynthControl::Oscillator1Range(0, 1800.0), t0))
+            .unwrap();
+        ctrl_tx
+            .try_send((SynthControl::Oscillator1Enable(0, true), t0))
+            .unwrap();
+        ctrl_tx
+            .try_send((SynthControl::Oscillator1Volume(0, 1.0), t0))
+            .unwrap();
+
+        // Due 1 ms (i.e. 48 samples) after the start of the block
+        ctrl_tx
+            .try_send((
+                SynthControl::NoteOn(0, 0, 1.0),
+                t0 + Duration::from_millis(1),
+            ))
+            .unwrap();
+
+        synthesizer.begin_block(t0 + CONTROL_LATENCY);
+
+        let mut buffer = [1.0; 64];
+        synthesizer.fill_buffer(&mut buffer);
+
+        for sample in &buffer[..49] {
+            assert_float_eq!(0.0, *sample, 1e-6);
+        }
+        assert_float_eq!(0.15, buffer[49], 1e-6);
+        assert_float_eq!(0.3, buffer[50], 1e-6);
+    }
+
+    #[test]
+    fn lfo_modulates_amplitude() {
+        let (ctrl_tx, ctrl_rx) = spsc::channel(CONTROL_QUEUE_CAPACITY);
+        let mut synthesizer = Synthesizer::new(ctrl_rx, DEFAULT_SAMPLE_RATE, &NO_RAMPS);
+
+        let t0 = Instant::now();
+        for ctrl in [
+            SynthControl::Oscillator1Range(0, 1653.75),
+            SynthControl::Oscillator1Enable(0, true),
+            SynthControl::Oscillator1Volume(0, 1.0),
+            // Starts at the minimum, which silences the output with full depth
+            SynthControl::LfoShape(LfoShape::Saw),
+            SynthControl::LfoRate(0.1),
+            SynthControl::LfoKeySync(true),
+            SynthControl::ModulationSource(0, Some(ModulationSource::Lfo)),
+            SynthControl::ModulationDestination(0, ModulationDestination::Amplitude),
+            SynthControl::ModulationAmount(0, 1.0),
+            SynthControl::NoteOn(0, 0, 1.0),
+        ] {
+            ctrl_tx.try_send((ctrl, t0)).unwrap();
+        }
+
+        synthesizer.begin_block(t0 + 2 * CONTROL_LATENCY);
+
+        let mut buffer = [1.0; 256];
+        synthesizer.fill_buffer(&mut buffer);
+
+        // Modulation is ramped in over the first interval
+        assert_float_eq!(0.15 * (1.0 - 2.0 / 64.0), buffer[1], 1e-6);
+        for sample in &buffer[MODULATION_CONTROL_INTERVAL..] {
+            assert_float_eq!(0.0, *sample, 1e-3);
+        }
+    }
+
+    #[test]
+    fn pressure_of_playing_note_is_smoothed() {
+        let (ctrl_tx, ctrl_rx) = spsc::channel(CONTROL_QUEUE_CAPACITY);
+        // Ramps over one control interval
+        let ramp_times = RampTimes {
+            pressure: Duration::from_millis(1),
+            ..NO_RAMPS
+        };
+        let mut synthesizer = Synthesizer::new(ctrl_rx, 64_000.0, &ramp_times);
+
+        let t0 = Instant::now();
+        for ctrl in [
+            SynthControl::NoteOn(0, 0, 1.0),
+            SynthControl::NoteOn(0, 1, 2.0),
+            // Held, but not playing
+            SynthControl::KeyPressure(1, 1.0),
+            SynthControl::PressureCurve(PressureCurve::Hard),
+            SynthControl::KeyPressure(0, 0.5),
+        ] {
+            ctrl_tx.try_send((ctrl, t0)).unwrap();
+        }
+
+        let mut buffer = [0.0; 2 * MODULATION_CONTROL_INTERVAL];
+        synthesizer.begin_block(t0 + 2 * CONTROL_LATENCY);
+        synthesizer.fill_buffer(&mut buffer);
+        assert_eq!(
+            0.25,
+            synthesizer.modulation.input(ModulationSource::Aftertouch)
+        );
+
+        // The held note plays with its pressure
+        ctrl_tx.try_send((SynthControl::NoteOff(0, 0), t0)).unwrap();
+        synthesizer.begin_block(t0 + 2 * CONTROL_LATENCY);
+        synthesizer.fill_buffer(&mut buffer[..1]);
+        assert_eq!(
+            0.25,
+            synthesizer.modulation.input(ModulationSource::Aftertouch)
+        );
+        synthesizer.fill_buffer(&mut buffer);
+        assert_eq!(
+            1.0,
+            synthesizer.modulation.input(ModulationSource::Aftertouch)
+        );
+
+        // Channel pressure applies if stronger
+        ctrl_tx
+            .try_send((SynthControl::KeyPressure(1, 0.0), t0))
+            .unwrap();
+        ctrl_tx
+            .try_send((SynthControl::Aftertouch(0.5), t0))
+            .unwrap();
+        synthesizer.begin_block(t0 + 2 * CONTROL_LATENCY);
+        synthesizer.fill_buffer(&mut buffer);
+        assert_eq!(
+            0.25,
+            synthesizer.modulation.input(ModulationSource::Aftertouch)
+        );
+    }
+
+    #[test]
+    fn pressure_applies_to_voice_group_of_note() {
+        let (ctrl_tx, ctrl_rx) = spsc::channel(CONTROL_QUEUE_CAPACITY);
+        let mut synthesizer = Synthesizer::new(ctrl_rx, DEFAULT_SAMPLE_RATE, &NO_RAMPS);
+
+        let t0 = Instant::now();
+        for ctrl in [
+            SynthControl::NoteOn(0, 0, 1.0),
+            SynthControl::NoteOn(1, 1, 2.0),
+            SynthControl::KeyPressure(1, 1.0),
+        ] {
+            ctrl_tx.try_send((ctrl, t0)).unwrap();
+        }
+
+        let mut buffer = [0.0; MODULATION_CONTROL_INTERVAL];
+        synthesizer.begin_block(t0 + 2 * CONTROL_LATENCY);
+        synthesizer.fill_buffer(&mut buffer);
+        assert_eq!(0.0, synthesizer.groups[0].pressure.target());
+        assert_eq!(1.0, synthesizer.groups[1].pressure.target());
+        // The sources of the first group remain set
+        assert_eq!(
+            0.0,
+            synthesizer.modulation.input(ModulationSource::Aftertouch)
+        );
+    }
+
+    #[test]
+    fn expression_of_playing_note_applies() {
+        let (ctrl_tx, ctrl_rx) = spsc::channel(CONTROL_QUEUE_CAPACITY);
+        let mut synthesizer = Synthesizer::new(ctrl_rx, DEFAULT_SAMPLE_RATE, &NO_RAMPS);
+
+        let t0 = Instant::now();
+        for ctrl in [
+            SynthControl::NoteOn(0, 0, 1.0),
+            SynthControl::NoteOn(0, 1, 2.0),
+            SynthControl::NoteTimbre(0, 0.5),
+            SynthControl::NoteTimbre(1, 1.0),
+            SynthControl::NotePitchBend(1, 12.0),
+        ] {
+            ctrl_tx.try_send((ctrl, t0)).unwrap();
+        }
+
+        let mut buffer = [0.0; MODULATION_CONTROL_INTERVAL];
+        synthesizer.begin_block(t0 + 2 * CONTROL_LATENCY);
+        synthesizer.fill_buffer(&mut buffer);
+        assert_eq!(0.5, synthesizer.modulation.input(ModulationSource::Timbre));
+
+        ctrl_tx.try_send((SynthControl::NoteOff(0, 0), t0)).unwrap();
+        synthesizer.begin_block(t0 + 2 * CONTROL_LATENCY);
+        synthesizer.fill_buffer(&mut buffer);
+        assert_eq!(1.0, synthesizer.modulation.input(ModulationSource::Timbre));
+        assert_eq!(
+            Some(12.0),
+            synthesizer.groups[0]
+                .note_selector
+                .lowest()
+                .map(|note| note.bend)
+        );
+    }
+
+    #[test]
+    fn expression_applies_to_note_with_its_id() {
+        let (ctrl_tx, ctrl_rx) = spsc::channel(CONTROL_QUEUE_CAPACITY);
+        let mut synthesizer = Synthesizer::new(ctrl_rx, DEFAULT_SAMPLE_RATE, &NO_RAMPS);
+
+        // Notes with the same frequency in different voice groups
+        let t0 = Instant::now();
+        for ctrl in [
+            SynthControl::NoteOn(0, 0, 1.0),
+            SynthControl::NoteOn(1, 1, 1.0),
+            SynthControl::NotePitchBend(1, 12.0),
+        ] {
+            ctrl_tx.try_send((ctrl, t0)).unwrap();
+        }
+
+        let mut buffer = [0.0; MODULATION_CONTROL_INTERVAL];
+        synthesizer.begin_block(t0 + 2 * CONTROL_LATENCY);
+        synthesizer.fill_buffer(&mut buffer);
+        let bends: Vec<_> = synthesizer
+            .groups
+            .iter()
+            .map(|group| group.note_selector.lowest().map(|note| note.bend))
+            .collect();
+        assert_eq!(vec![Some(0.0), Some(12.0)], bends);
+    }
+
     #[test]
     fn new_note_is_higher() {
         let mut note_selector = NoteSelector::new();
 
-        note_selector.turn_on_note(1.2);
-        let current_note = note_selector.turn_on_note(1.5);
+        note_selector.turn_on_note(0, 1.2);
+        let current_note = note_selector.turn_on_note(1, 1.5);
         assert_float_eq!(1.2, current_note, 1e-6);
     }
 
@@ -187,8 +996,8 @@ mod tests {
     fn new_note_is_lower() {
         let mut note_selector = NoteSelector::new();
 
-        note_selector.turn_on_note(1.2);
-        let current_note = note_selector.turn_on_note(0.8);
+        note_selector.turn_on_note(0, 1.2);
+        let current_note = note_selector.turn_on_note(1, 0.8);
         assert_float_eq!(0.8, current_note, 1e-6);
     }
 
@@ -196,14 +1005,14 @@ mod tests {
     fn release_lower_note() {
         let mut note_selector = NoteSelector::new();
 
-        note_selector.turn_on_note(0.8);
-        let mut current_note = note_selector.turn_on_note(1.2);
+        note_selector.turn_on_note(0, 0.8);
+        let mut current_note = note_selector.turn_on_note(1, 1.2);
         assert_float_eq!(0.8, current_note, 1e-6);
 
-        current_note = note_selector.turn_off_note(0.8).unwrap();
+        current_note = note_selector.turn_off_note(0).unwrap();
         assert_float_eq!(1.2, current_note, 1e-6);
 
-        let current_note = note_selector.turn_off_note(1.2);
+        let current_note = note_selector.turn_off_note(1);
         assert_eq!(None, current_note);
     }
 
@@ -211,14 +1020,14 @@ mod tests {
     fn release_higher_note() {
         let mut note_selector = NoteSelector::new();
 
-        note_selector.turn_on_note(0.8);
-        let mut current_note = note_selector.turn_on_note(1.2);
+        note_selector.turn_on_note(0, 0.8);
+        let mut current_note = note_selector.turn_on_note(1, 1.2);
         assert_float_eq!(0.8, current_note, 1e-6);
 
-        current_note = note_selector.turn_off_note(1.2).unwrap();
+        current_note = note_selector.turn_off_note(1).unwrap();
         assert_float_eq!(0.8, current_note, 1e-6);
 
-        let current_note = note_selector.turn_off_note(0.8);
+        let current_note = note_selector.turn_off_note(0);
         assert_eq!(None, current_note);
     }
 
@@ -226,36 +1035,86 @@ mod tests {
     fn release_middle_note() {
         let mut note_selector = NoteSelector::new();
 
-        note_selector.turn_on_note(0.8);
-        note_selector.turn_on_note(1.2);
-        let mut current_note = note_selector.turn_on_note(1.5);
+        note_selector.turn_on_note(0, 0.8);
+        note_selector.turn_on_note(1, 1.2);
+        let mut current_note = note_selector.turn_on_note(2, 1.5);
         assert_float_eq!(0.8, current_note, 1e-6);
 
-        current_note = note_selector.turn_off_note(1.2).unwrap();
+        current_note = note_selector.turn_off_note(1).unwrap();
         assert_eq!(0.8, current_note);
 
-        current_note = note_selector.turn_off_note(0.8).unwrap();
+        current_note = note_selector.turn_off_note(0).unwrap();
         assert_eq!(1.5, current_note);
     }
+
+    #[test]
+    fn clear_releases_all_notes() {
+        let mut note_selector = NoteSelector::new();
+
+        note_selector.turn_on_note(0, 0.8);
+        note_selector.turn_on_note(1, 1.2);
+        note_selector.clear();
+        assert_eq!(None, note_selector.turn_off_note(0));
+
+        let current_note = note_selector.turn_on_note(2, 1.5);
+        assert_float_eq!(1.5, current_note, 1e-6);
+    }
 }
 
 #[cfg(all(feature = "benchmarks", test))]
 mod bench {
     use super::*;
-    use test::Bencher;
+    use test::{self, Bencher};
+
+    use synth::audio_driver::DEFAULT_FRAMES_PER_BUFFER;
+
+    fn signal_chain() -> LoudnessContour<LoudnessContourInput> {
+        let osc1 = Rc::new(Oscillator::new(1.0, 0.01));
+        let mixer = Rc::new(Mixer::new(Rc::clone(&osc1)));
+        mixer.set_enabled(true);
+        mixer.set_volume(0.5);
+
+        let loudness_contour = LoudnessContour::new(mixer);
+        loudness_contour.trigger_on();
+        loudness_contour
+    }
+
+    #[bench]
+    fn signal_chain_per_sample(b: &mut Bencher) {
+        let loudness_contour = signal_chain();
+        let mut buffer = [0.0; DEFAULT_FRAMES_PER_BUFFER as usize];
+
+        b.iter(|| {
+            for sample in buffer.iter_mut() {
+                *sample = loudness_contour.next_sample();
+            }
+            test::black_box(&buffer);
+        })
+    }
+
+    #[bench]
+    fn signal_chain_block(b: &mut Bencher) {
+        let loudness_contour = signal_chain();
+        let mut buffer = [0.0; DEFAULT_FRAMES_PER_BUFFER as usize];
+
+        b.iter(|| {
+            loudness_contour.fill_buffer(&mut buffer);
+            test::black_box(&buffer);
+        })
+    }
 
     #[bench]
     fn few_notes_1(b: &mut Bencher) {
         let mut note_selector = NoteSelector::new();
 
-        note_selector.turn_on_note(2.0);
-        note_selector.turn_on_note(1.6);
-        note_selector.turn_on_note(1.2);
+        note_selector.turn_on_note(0, 2.0);
+        note_selector.turn_on_note(1, 1.6);
+        note_selector.turn_on_note(2, 1.2);
 
         b.iter(|| {
-            note_selector.turn_on_note(0.8);
+            note_selector.turn_on_note(3, 0.8);
 
-            note_selector.turn_off_note(0.8);
+            note_selector.turn_off_note(3);
         })
     }
 
@@ -263,14 +1122,14 @@ mod bench {
     fn few_notes_2(b: &mut Bencher) {
         let mut note_selector = NoteSelector::new();
 
-        note_selector.turn_on_note(2.0);
-        note_selector.turn_on_note(1.6);
-        note_selector.turn_on_note(1.2);
+        note_selector.turn_on_note(0, 2.0);
+        note_selector.turn_on_note(1, 1.6);
+        note_selector.turn_on_note(2, 1.2);
 
         b.iter(|| {
-            note_selector.turn_on_note(3.0);
+            note_selector.turn_on_note(3, 3.0);
 
-            note_selector.turn_off_note(3.0);
+            note_selector.turn_off_note(3);
         })
     }
 
@@ -279,13 +1138,13 @@ mod bench {
         let mut note_selector = NoteSelector::new();
 
         for i in 0..NUMBER_OF_NOTES / 4 {
-            note_selector.turn_on_note(((i + 1) as f32) * 0.5);
+            note_selector.turn_on_note(i as NoteId, ((i + 1) as f32) * 0.5);
         }
 
         b.iter(|| {
-            note_selector.turn_on_note(0.2);
+            note_selector.turn_on_note(100, 0.2);
 
-            note_selector.turn_off_note(0.2);
+            note_selector.turn_off_note(100);
         })
     }
 
@@ -294,13 +1153,13 @@ mod bench {
         let mut note_selector = NoteSelector::new();
 
         for i in 0..NUMBER_OF_NOTES / 4 {
-            note_selector.turn_on_note(((i + 1) as f32) * 0.5);
+            note_selector.turn_on_note(i as NoteId, ((i + 1) as f32) * 0.5);
         }
 
         b.iter(|| {
-            note_selector.turn_on_note(40.0);
+            note_selector.turn_on_note(100, 40.0);
 
-            note_selector.turn_off_note(40.0);
+            note_selector.turn_off_note(100);
         })
     }
 
@@ -309,13 +1168,13 @@ mod bench {
         let mut note_selector = NoteSelector::new();
 
         for i in 0..NUMBER_OF_NOTES / 2 {
-            note_selector.turn_on_note(((i + 1) as f32) * 0.5);
+            note_selector.turn_on_note(i as NoteId, ((i + 1) as f32) * 0.5);
         }
 
         b.iter(|| {
-            note_selector.turn_on_note(0.2);
+            note_selector.turn_on_note(100, 0.2);
 
-            note_selector.turn_off_note(0.2);
+            note_selector.turn_off_note(100);
         })
     }
 
@@ -324,13 +1183,13 @@ mod bench {
         let mut note_selector = NoteSelector::new();
 
         for i in 0..NUMBER_OF_NOTES / 2 {
-            note_selector.turn_on_note(((i + 1) as f32) * 0.5);
+            note_selector.turn_on_note(i as NoteId, ((i + 1) as f32) * 0.5);
         }
 
         b.iter(|| {
-            note_selector.turn_on_note(40.0);
+            note_selector.turn_on_note(100, 40.0);
 
-            note_selector.turn_off_note(40.0);
+            note_selector.turn_off_note(100);
         })
     }
 
@@ -339,13 +1198,13 @@ mod bench {
         let mut note_selector = NoteSelector::new();
 
         for i in 0..NUMBER_OF_NOTES / 2 {
-            note_selector.turn_on_note(((i + 1) as f32) * 0.5);
+            note_selector.turn_on_note(i as NoteId, ((i + 1) as f32) * 0.5);
         }
 
         b.iter(|| {
-            note_selector.turn_on_note(4.1);
+            note_selector.turn_on_note(100, 4.1);
 
-            note_selector.turn_off_note(4.1);
+            note_selector.turn_off_note(100);
         })
     }
 
@@ -354,13 +1213,13 @@ mod bench {
         let mut note_selector = NoteSelector::new();
 
         for i in 0..3 * NUMBER_OF_NOTES / 4 {
-            note_selector.turn_on_note(((i + 1) as f32) * 0.5);
+            note_selector.turn_on_note(i as NoteId, ((i + 1) as f32) * 0.5);
         }
 
         b.iter(|| {
-            note_selector.turn_on_note(0.2);
+            note_selector.turn_on_note(100, 0.2);
 
-            note_selector.turn_off_note(0.2);
+            note_selector.turn_off_note(100);
         })
     }
 
@@ -369,13 +1228,13 @@ mod bench {
         let mut note_selector = NoteSelector::new();
 
         for i in 0..3 * NUMBER_OF_NOTES / 4 {
-            note_selector.turn_on_note(((i + 1) as f32) * 0.5);
+            note_selector.turn_on_note(i as NoteId, ((i + 1) as f32) * 0.5);
         }
 
         b.iter(|| {
-            note_selector.turn_on_note(40.0);
+            note_selector.turn_on_note(100, 40.0);
 
-            note_selector.turn_off_note(40.0);
+            note_selector.turn_off_note(100);
         })
     }
 
@@ -384,13 +1243,13 @@ mod bench {
         let mut note_selector = NoteSelector::new();
 
         for i in 0..3 * NUMBER_OF_NOTES / 4 {
-            note_selector.turn_on_note(((i + 1) as f32) * 0.5);
+            note_selector.turn_on_note(i as NoteId, ((i + 1) as f32) * 0.5);
         }
 
         b.iter(|| {
-            note_selector.turn_on_note(6.2);
+            note_selector.turn_on_note(100, 6.2);
 
-            note_selector.turn_off_note(6.2);
+            note_selector.turn_off_note(100);
         })
     }
 
@@ -399,13 +1258,13 @@ mod bench {
         let mut note_selector = NoteSelector::new();
 
         for i in 0..NUMBER_OF_NOTES - 1 {
-            note_selector.turn_on_note(((i + 1) as f32) * 0.5);
+            note_selector.turn_on_note(i as NoteId, ((i + 1) as f32) * 0.5);
         }
 
         b.iter(|| {
-            note_selector.turn_on_note(0.2);
+            note_selector.turn_on_note(100, 0.2);
 
-            note_selector.turn_off_note(0.2);
+            note_selector.turn_off_note(100);
         })
     }
 
@@ -414,13 +1273,13 @@ mod bench {
         let mut note_selector = NoteSelector::new();
 
         for i in 0..NUMBER_OF_NOTES - 1 {
-            note_selector.turn_on_note(((i + 1) as f32) * 0.5);
+            note_selector.turn_on_note(i as NoteId, ((i + 1) as f32) * 0.5);
         }
 
         b.iter(|| {
-            note_selector.turn_on_note(40.0);
+            note_selector.turn_on_note(100, 40.0);
 
-            note_selector.turn_off_note(40.0);
+            note_selector.turn_off_note(100);
         })
     }
 
@@ -429,13 +1288,13 @@ mod bench {
         let mut note_selector = NoteSelector::new();
 
         for i in 0..NUMBER_OF_NOTES - 1 {
-            note_selector.turn_on_note(((i + 1) as f32) * 0.5);
+            note_selector.turn_on_note(i as NoteId, ((i + 1) as f32) * 0.5);
         }
 
         b.iter(|| {
-            note_selector.turn_on_note(8.2);
+            note_selector.turn_on_note(100, 8.2);
 
-            note_selector.turn_off_note(8.2);
+            note_selector.turn_off_note(100);
         })
     }
 }
@@ -0,0 +1,173 @@
+/// Distance (in controller steps) at which a control picks up the value of its parameter, even if
+/// it did not cross it. Controls that send coarse steps could skip the value otherwise.
+const PICKUP_TOLERANCE: u8 = 2;
+
+/// How a control takes over the value of its parameter after the value changed without moving
+/// the control (e.g. when a patch is loaded).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Takeover {
+    /// Value jumps to the position of the control
+    Jump,
+    /// Value does not change until the control passes the current value
+    Pickup,
+    /// Value moves in the direction of the control, by as much as is needed to reach the end of
+    /// the range together with the control
+    Scale,
+}
+
+/// Tracks whether the position of a control matches the value of its parameter.
+///
+/// Positions and values are given as 7-bit controller values.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TakeoverState {
+    in_sync: bool,
+    position: Option<u8>,
+}
+
+impl Default for TakeoverState {
+    /// Control is in sync, i.e. the first move sets the value.
+    fn default() -> Self {
+        Self {
+            in_sync: true,
+            position: None,
+        }
+    }
+}
+
+impl TakeoverState {
+    pub fn is_in_sync(&self) -> bool {
+        self.in_sync
+    }
+
+    /// Marks the control as out of sync, because the value of the parameter was changed by
+    /// something else.
+    pub fn lose_sync(&mut self) {
+        self.in_sync = false;
+    }
+
+    /// Handles a new position of the control. Returns the controller value the parameter should
+    /// be set to, or `None` if it should not change. `value` is the current value of the
+    /// parameter.
+    pub fn take_over(&mut self, takeover: Takeover, value: u8, position: u8) -> Option<u8> {
+        let previous = self.position.replace(position);
+
+        if self.in_sync {
+            return Some(position);
+        }
+
+        match takeover {
+            Takeover::Jump => {
+                self.in_sync = true;
+                Some(position)
+            }
+            Takeover::Pickup => {
+                let crossed = match previous {
+                    Some(previous) => {
+                        (previous <= value && position >= value)
+                            || (previous >= value && position <= value)
+                    }
+                    None => false,
+                };
+
+                if crossed || position.abs_diff(value) <= PICKUP_TOLERANCE {
+                    self.in_sync = true;
+                    Some(position)
+                } else {
+                    None
+                }
+            }
+            Takeover::Scale => {
+                // The first move only tells the position of the control
+                let previous = previous?;
+                if position == previous {
+                    return None;
+                }
+
+                let value = f32::from(value);
+                let scaled = if position > previous {
+                    value
+                        + f32::from(position - previous) * (127.0 - value)
+                            / (127.0 - f32::from(previous))
+                } else {
+                    value - f32::from(previous - position) * value / f32::from(previous)
+                };
+                let scaled = scaled.round() as u8;
+
+                if scaled == position {
+                    self.in_sync = true;
+                }
+                Some(scaled)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn in_sync_control_sets_value() {
+        let mut state = TakeoverState::default();
+
+        assert_eq!(Some(100), state.take_over(Takeover::Pickup, 20, 100));
+        assert_eq!(Some(30), state.take_over(Takeover::Scale, 100, 30));
+        assert!(state.is_in_sync());
+    }
+
+    #[test]
+    fn jump() {
+        let mut state = TakeoverState::default();
+        state.lose_sync();
+
+        assert_eq!(Some(100), state.take_over(Takeover::Jump, 20, 100));
+        assert!(state.is_in_sync());
+    }
+
+    #[test]
+    fn pickup_when_crossing_value() {
+        let mut state = TakeoverState::default();
+        state.lose_sync();
+
+        assert_eq!(None, state.take_over(Takeover::Pickup, 64, 10));
+        assert_eq!(None, state.take_over(Takeover::Pickup, 64, 50));
+        assert!(!state.is_in_sync());
+        assert_eq!(Some(70), state.take_over(Takeover::Pickup, 64, 70));
+        assert!(state.is_in_sync());
+        assert_eq!(Some(20), state.take_over(Takeover::Pickup, 70, 20));
+    }
+
+    #[test]
+    fn pickup_near_value() {
+        let mut state = TakeoverState::default();
+        state.lose_sync();
+
+        assert_eq!(Some(66), state.take_over(Takeover::Pickup, 64, 66));
+        assert!(state.is_in_sync());
+    }
+
+    #[test]
+    fn scale_towards_end_of_range() {
+        let mut state = TakeoverState::default();
+        state.lose_sync();
+
+        // Control at 27, value at 77: both reach 127 together
+        assert_eq!(None, state.take_over(Takeover::Scale, 77, 27));
+        assert_eq!(Some(102), state.take_over(Takeover::Scale, 77, 77));
+        assert!(!state.is_in_sync());
+        assert_eq!(Some(127), state.take_over(Takeover::Scale, 102, 127));
+        assert!(state.is_in_sync());
+    }
+
+    #[test]
+    fn scale_towards_start_of_range() {
+        let mut state = TakeoverState::default();
+        state.lose_sync();
+
+        // Control at 100, value at 50: both reach 0 together
+        assert_eq!(None, state.take_over(Takeover::Scale, 50, 100));
+        assert_eq!(Some(25), state.take_over(Takeover::Scale, 50, 50));
+        assert_eq!(Some(0), state.take_over(Takeover::Scale, 25, 0));
+        assert!(state.is_in_sync());
+    }
+}
@@ -0,0 +1,565 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, ErrorKind as IoErrorKind};
+use std::path::{Path, PathBuf};
+
+use usb_midi::SystemExlusiveId;
+
+use errors::*;
+
+/// Middle C in 12-tone equal temperament with a' = 440 Hz. Notes are frequency ratios to it.
+pub const MIDDLE_C: f32 = 261.625_58;
+
+const NOTE_COUNT: usize = 128;
+
+/// Sub-IDs of the MIDI Tuning Standard messages (after the universal sysex ID and device ID)
+const MIDI_TUNING: u8 = 0x08;
+const BULK_TUNING_DUMP: u8 = 0x01;
+const SINGLE_NOTE_TUNING_CHANGE: u8 = 0x02;
+const SINGLE_NOTE_TUNING_CHANGE_BANK: u8 = 0x07;
+
+/// Universal System Exclusive IDs
+const NON_REAL_TIME: SystemExlusiveId = SystemExlusiveId::OneByte(0x7E);
+const REAL_TIME: SystemExlusiveId = SystemExlusiveId::OneByte(0x7F);
+
+/// Length of the name of a tuning in a bulk tuning dump
+const TUNING_NAME_LENGTH: usize = 16;
+
+/// Scale of a Scala `.scl` file: the pitches of the degrees above the root, in cents. The last
+/// one is the period (usually the octave) after which the scale repeats.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Scale {
+    pub description: String,
+    pitches: Vec<f64>,
+}
+
+impl Scale {
+    pub fn read_from<R: BufRead>(reader: R) -> Result<Self> {
+        let mut lines = scala_lines(reader)?.into_iter();
+
+        let description = lines.next().ok_or("Scale description missing")?;
+        let count: usize = lines
+            .next()
+            .ok_or("Number of notes missing")?
+            .split_whitespace()
+            .next()
+            .unwrap_or("")
+            .parse()
+            .chain_err(|| "Invalid number of notes")?;
+        if count == 0 {
+            bail!("Scale has no notes");
+        }
+
+        let pitches = lines
+            .take(count)
+            .map(|line| parse_pitch(line.split_whitespace().next().unwrap_or("")))
+            .collect::<Result<Vec<_>>>()?;
+        if pitches.len() < count {
+            bail!("Scale has {} of {} notes", pitches.len(), count);
+        }
+
+        Ok(Scale {
+            description: description.trim().to_string(),
+            pitches,
+        })
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let file =
+            File::open(path).chain_err(|| format!("Failed to open scale {}", path.display()))?;
+        Scale::read_from(BufReader::new(file))
+            .chain_err(|| format!("Failed to load scale {}", path.display()))
+    }
+
+    /// Number of degrees per period.
+    fn len(&self) -> i32 {
+        self.pitches.len() as i32
+    }
+
+    /// Pitch of a degree (0 is the root, negative degrees are below it) in cents.
+    fn cents(&self, degree: i32) -> f64 {
+        let period = self.pitches[self.pitches.len() - 1];
+        let index = degree.rem_euclid(self.len());
+        let root = f64::from(degree.div_euclid(self.len())) * period;
+
+        match index {
+            0 => root,
+            _ => root + self.pitches[index as usize - 1],
+        }
+    }
+}
+
+/// Keyboard mapping of a Scala `.kbm` file, which assigns scale degrees to keys.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyboardMap {
+    /// Degrees of the keys of one repetition of the map (`None` for keys that are not played).
+    /// Without keys, each key plays the next degree.
+    keys: Vec<Option<i32>>,
+    first_note: u8,
+    last_note: u8,
+    /// Key that plays the root of the scale
+    middle_note: u8,
+    reference_note: u8,
+    /// Frequency of the reference note in Hz
+    reference_frequency: f64,
+    /// Degree by which each repetition of the map is higher
+    octave_degree: i32,
+}
+
+impl Default for KeyboardMap {
+    /// Maps the scale to consecutive keys, starting at middle C, with a' at 440 Hz.
+    fn default() -> Self {
+        KeyboardMap {
+            keys: vec![],
+            first_note: 0,
+            last_note: 127,
+            middle_note: 60,
+            reference_note: 69,
+            reference_frequency: 440.0,
+            octave_degree: 0,
+        }
+    }
+}
+
+impl KeyboardMap {
+    pub fn read_from<R: BufRead>(reader: R) -> Result<Self> {
+        let lines = scala_lines(reader)?;
+        let mut values = lines
+            .iter()
+            .map(|line| line.split_whitespace().next().unwrap_or(""));
+
+        let mut next = |name: &str| {
+            values
+                .next()
+                .map(|value| value.to_string())
+                .ok_or_else(|| Error::from(format!("Keyboard map: {} missing", name)))
+        };
+        let note = |value: String, name: &str| -> Result<u8> {
+            value
+                .parse()
+                .ok()
+                .filter(|note| *note < NOTE_COUNT as u8)
+                .ok_or_else(|| format!("Keyboard map: invalid {}: {}", name, value).into())
+        };
+
+        let size: usize = next("map size")?
+            .parse()
+            .chain_err(|| "Keyboard map: invalid map size")?;
+        let first_note = note(next("first note")?, "first note")?;
+        let last_note = note(next("last note")?, "last note")?;
+        let middle_note = note(next("middle note")?, "middle note")?;
+        let reference_note = note(next("reference note")?, "reference note")?;
+        let reference_frequency: f64 = next("reference frequency")?
+            .parse()
+            .ok()
+            .filter(|frequency| *frequency > 0.0)
+            .ok_or("Keyboard map: invalid reference frequency")?;
+        let octave_degree: i32 = next("octave degree")?
+            .parse()
+            .chain_err(|| "Keyboard map: invalid octave degree")?;
+
+        // Keys missing at the end are not played
+        let mut keys = vec![None; size];
+        for (key, value) in keys.iter_mut().zip(values) {
+            *key = match value {
+                "x" | "X" => None,
+                degree => Some(
+                    degree
+                        .parse()
+                        .chain_err(|| format!("Keyboard map: invalid degree: {}", degree))?,
+                ),
+            };
+        }
+
+        Ok(KeyboardMap {
+            keys,
+            first_note,
+            last_note,
+            middle_note,
+            reference_note,
+            reference_frequency,
+            octave_degree,
+        })
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = File::open(path)
+            .chain_err(|| format!("Failed to open keyboard map {}", path.display()))?;
+        KeyboardMap::read_from(BufReader::new(file))
+            .chain_err(|| format!("Failed to load keyboard map {}", path.display()))
+    }
+
+    /// Returns the scale degree played by a key, or `None` if the key is not played.
+    fn degree(&self, note: u8, scale: &Scale) -> Option<i32> {
+        let offset = i32::from(note) - i32::from(self.middle_note);
+        if self.keys.is_empty() {
+            return Some(offset);
+        }
+
+        let size = self.keys.len() as i32;
+        let octave_degree = match self.octave_degree {
+            0 => scale.len(),
+            degree => degree,
+        };
+        self.keys[offset.rem_euclid(size) as usize]
+            .map(|degree| degree + offset.div_euclid(size) * octave_degree)
+    }
+}
+
+/// Returns the lines of a Scala file without comments.
+fn scala_lines<R: BufRead>(reader: R) -> Result<Vec<String>> {
+    let mut lines = vec![];
+    for line in reader.lines() {
+        let line = line?;
+        if !line.starts_with('!') {
+            lines.push(line);
+        }
+    }
+    Ok(lines)
+}
+
+/// Parses a pitch of a Scala scale: cents if it contains a period, a ratio otherwise.
+fn parse_pitch(pitch: &str) -> Result<f64> {
+    let cents = if pitch.contains('.') {
+        pitch.parse().ok()
+    } else {
+        let mut parts = pitch.splitn(2, '/');
+        let numerator: Option<f64> = parts.next().and_then(|n| n.parse().ok());
+        let denominator: Option<f64> = match parts.next() {
+            Some(d) => d.parse().ok(),
+            None => Some(1.0),
+        };
+        match (numerator, denominator) {
+            (Some(n), Some(d)) if n > 0.0 && d > 0.0 => Some(1200.0 * (n / d).log2()),
+            _ => None,
+        }
+    };
+
+    cents.ok_or_else(|| format!("Invalid pitch: {}", pitch).into())
+}
+
+/// Frequency of each MIDI note, as set by a Scala scale or MIDI Tuning Standard messages.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tuning {
+    /// Frequency ratio of each note to `MIDDLE_C` (`None` for notes that are not played)
+    notes: [Option<f32>; NOTE_COUNT],
+}
+
+impl Default for Tuning {
+    /// 12-tone equal temperament.
+    fn default() -> Self {
+        let mut notes = [None; NOTE_COUNT];
+        for (note, ratio) in notes.iter_mut().enumerate() {
+            *ratio = Some(semitones_to_ratio(note as f64));
+        }
+        Tuning { notes }
+    }
+}
+
+impl Tuning {
+    pub fn from_scale(scale: &Scale, map: &KeyboardMap) -> Result<Self> {
+        let reference = map
+            .degree(map.reference_note, scale)
+            .ok_or("Reference note of the keyboard map is not mapped")?;
+        let reference_cents = scale.cents(reference);
+        let reference_ratio = map.reference_frequency / f64::from(MIDDLE_C);
+
+        let mut notes = [None; NOTE_COUNT];
+        for note in map.first_note..=map.last_note.max(map.first_note) {
+            notes[usize::from(note)] = map.degree(note, scale).map(|degree| {
+                let cents = scale.cents(degree) - reference_cents;
+                (reference_ratio * (cents / 1200.0).exp2()) as f32
+            });
+        }
+
+        Ok(Tuning { notes })
+    }
+
+    /// Returns the frequency ratio of a note to middle C, or `None` if it is not played.
+    pub fn note(&self, note: u8) -> Option<f32> {
+        self.notes.get(usize::from(note)).cloned().flatten()
+    }
+
+    /// Applies a MIDI Tuning Standard message: a bulk tuning dump or a single note tuning
+    /// change. Tuning programs and banks are ignored, all messages change this tuning. Returns
+    /// false if the message is no such message.
+    pub fn apply_mts(&mut self, id: &SystemExlusiveId, payload: &[u8]) -> bool {
+        // Device ID, sub-ID #1 and #2
+        if payload.len() < 3 || payload[1] != MIDI_TUNING {
+            return false;
+        }
+
+        match (id, payload[2]) {
+            (&NON_REAL_TIME, BULK_TUNING_DUMP) => {
+                // Program and name, the checksum at the end is not checked
+                let data = match payload.get(4 + TUNING_NAME_LENGTH..) {
+                    Some(data) if data.len() >= 3 * NOTE_COUNT => data,
+                    _ => return false,
+                };
+                for (note, frequency) in data.chunks(3).take(NOTE_COUNT).enumerate() {
+                    self.set_mts_frequency(note, frequency);
+                }
+                true
+            }
+            (&REAL_TIME, SINGLE_NOTE_TUNING_CHANGE) => self.apply_note_changes(&payload[3..], 1),
+            (&REAL_TIME, SINGLE_NOTE_TUNING_CHANGE_BANK)
+            | (&NON_REAL_TIME, SINGLE_NOTE_TUNING_CHANGE_BANK) => {
+                self.apply_note_changes(&payload[3..], 2)
+            }
+            _ => false,
+        }
+    }
+
+    /// Applies the changes of a single note tuning change, which follow the bank and program
+    /// (`skip` bytes) and the number of changes.
+    fn apply_note_changes(&mut self, data: &[u8], skip: usize) -> bool {
+        let count = match data.get(skip) {
+            Some(count) => usize::from(*count),
+            None => return false,
+        };
+
+        for change in data[skip + 1..].chunks(4).take(count) {
+            if let [note, ref frequency @ ..] = *change {
+                self.set_mts_frequency(usize::from(note), frequency);
+            }
+        }
+        true
+    }
+
+    /// Sets a note to a frequency in the MTS format: the semitone (as a note of 12-tone equal
+    /// temperament) and the 14-bit fraction of the semitone above it.
+    fn set_mts_frequency(&mut self, note: usize, frequency: &[u8]) {
+        match *frequency {
+            // No change
+            [0x7F, 0x7F, 0x7F] => {}
+            [semitone, msb, lsb] if note < NOTE_COUNT => {
+                let fraction = f64::from(u16::from(msb & 0x7F) << 7 | u16::from(lsb & 0x7F));
+                self.notes[note] =
+                    Some(semitones_to_ratio(f64::from(semitone) + fraction / 16384.0));
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Converts a (fractional) note of 12-tone equal temperament to a frequency ratio to middle C.
+fn semitones_to_ratio(note: f64) -> f32 {
+    ((note - 60.0) / 12.0).exp2() as f32
+}
+
+/// Tunings stored as Scala files, one per tuning program: `<program>.scl` and optionally
+/// `<program>.kbm`.
+pub struct TuningBank {
+    dir: Option<PathBuf>,
+}
+
+impl TuningBank {
+    /// Creates a bank of tunings in the given directory. If `dir` is `None`, no tunings are
+    /// loaded.
+    pub fn new(dir: Option<PathBuf>) -> Self {
+        TuningBank { dir }
+    }
+
+    /// Loads the tuning of a program. Returns `None` if there is no scale for it.
+    pub fn load(&self, program: u8) -> Result<Option<Tuning>> {
+        let dir = match self.dir {
+            Some(ref dir) => dir,
+            None => return Ok(None),
+        };
+
+        let scale_path = dir.join(format!("{:03}.scl", program));
+        let scale = match File::open(&scale_path) {
+            Ok(_) => Scale::load(&scale_path)?,
+            Err(ref e) if e.kind() == IoErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(e)
+                    .chain_err(|| format!("Failed to open scale {}", scale_path.display()))
+            }
+        };
+
+        let map_path = dir.join(format!("{:03}.kbm", program));
+        let map = if map_path.is_file() {
+            KeyboardMap::load(&map_path)?
+        } else {
+            KeyboardMap::default()
+        };
+
+        Tuning::from_scale(&scale, &map).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    const MEANTONE: &str = "! meanquar.scl
+!
+1/4-comma meantone scale. Pietro Aaron's temperament (1523)
+ 12
+!
+ 76.04900
+ 193.15686
+ 310.26471
+ 5/4
+ 503.42157
+ 579.47057
+ 696.57843
+ 25/16
+ 889.73529
+ 1006.84314
+ 1082.89214
+ 2/1
+";
+
+    #[test]
+    fn equal_temperament() {
+        let tuning = Tuning::default();
+
+        assert_eq!(Some(1.0), tuning.note(60));
+        assert_float_eq!(2.0, tuning.note(72).unwrap(), 1e-6);
+        assert_float_eq!(440.0 / MIDDLE_C, tuning.note(69).unwrap(), 1e-5);
+        assert_eq!(None, tuning.note(128));
+    }
+
+    #[test]
+    fn read_scale() {
+        let scale = Scale::read_from(MEANTONE.as_bytes()).unwrap();
+
+        assert_eq!(
+            "1/4-comma meantone scale. Pietro Aaron's temperament (1523)",
+            scale.description
+        );
+        assert_eq!(12, scale.len());
+        assert_float_eq!(386.313714, scale.cents(4), 1e-6);
+        assert_float_eq!(1200.0 + 76.049, scale.cents(13), 1e-6);
+        assert_float_eq!(-1200.0 + 1082.89214, scale.cents(-1), 1e-6);
+
+        assert!(Scale::read_from("no notes\n 0\n".as_bytes()).is_err());
+        assert!(Scale::read_from("too few\n 2\n 100.0\n".as_bytes()).is_err());
+        assert!(Scale::read_from("negative\n 1\n -3/2\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn just_intonation_with_reference_frequency() {
+        let scale = Scale::read_from("just\n3\n5/4\n3/2\n2\n".as_bytes()).unwrap();
+        let map = KeyboardMap::read_from(
+            "! Major triad on white keys
+12
+0
+127
+60
+60
+261.625580
+3
+0
+x
+x
+x
+1
+x
+x
+2
+x
+x
+x
+x
+"
+            .as_bytes(),
+        )
+        .unwrap();
+        let tuning = Tuning::from_scale(&scale, &map).unwrap();
+
+        assert_float_eq!(1.0, tuning.note(60).unwrap(), 1e-6);
+        assert_eq!(None, tuning.note(61));
+        assert_float_eq!(1.25, tuning.note(64).unwrap(), 1e-6);
+        assert_float_eq!(1.5, tuning.note(67).unwrap(), 1e-6);
+        assert_float_eq!(2.5, tuning.note(76).unwrap(), 1e-6);
+        assert_float_eq!(0.75, tuning.note(55).unwrap(), 1e-6);
+    }
+
+    #[test]
+    fn default_keyboard_map_keeps_a_at_440_hz() {
+        let scale = Scale::read_from(MEANTONE.as_bytes()).unwrap();
+        let tuning = Tuning::from_scale(&scale, &KeyboardMap::default()).unwrap();
+
+        assert_float_eq!(440.0 / MIDDLE_C, tuning.note(69).unwrap(), 1e-5);
+        assert_float_eq!(
+            tuning.note(69).unwrap() * 2.0,
+            tuning.note(81).unwrap(),
+            1e-5
+        );
+    }
+
+    #[test]
+    fn keyboard_map_limits_range() {
+        let map = KeyboardMap::read_from("0\n36\n96\n60\n69\n440.0\n12\n".as_bytes()).unwrap();
+        let tuning =
+            Tuning::from_scale(&Scale::read_from(MEANTONE.as_bytes()).unwrap(), &map).unwrap();
+
+        assert_eq!(None, tuning.note(35));
+        assert!(tuning.note(36).is_some());
+        assert_eq!(None, tuning.note(97));
+
+        assert!(KeyboardMap::read_from("0\n36\n".as_bytes()).is_err());
+        assert!(KeyboardMap::read_from("0\n0\n128\n60\n69\n440.0\n12\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn single_note_tuning_change() {
+        let mut tuning = Tuning::default();
+
+        // Note 60 a quarter tone higher, note 61 unchanged
+        let payload = [
+            0x7F, 0x08, 0x02, 0x00, 0x02, 60, 60, 0x40, 0x00, 61, 0x7F, 0x7F, 0x7F,
+        ];
+        assert!(tuning.apply_mts(&REAL_TIME, &payload));
+        assert_float_eq!(2.0_f32.powf(0.5 / 12.0), tuning.note(60).unwrap(), 1e-6);
+        assert_eq!(Tuning::default().note(61), tuning.note(61));
+
+        // With bank
+        let payload = [0x7F, 0x08, 0x07, 0x00, 0x00, 0x01, 62, 69, 0x00, 0x00];
+        assert!(tuning.apply_mts(&NON_REAL_TIME, &payload));
+        assert_eq!(Tuning::default().note(69), tuning.note(62));
+
+        assert!(!tuning.apply_mts(&REAL_TIME, &[0x7F, 0x06, 0x02]));
+        assert!(!tuning.apply_mts(&SystemExlusiveId::OneByte(0x47), &payload));
+    }
+
+    #[test]
+    fn bulk_tuning_dump() {
+        let mut tuning = Tuning::default();
+
+        // All notes a semitone lower
+        let mut payload = vec![0x7F, 0x08, 0x01, 0x00];
+        payload.extend_from_slice(b"Semitone down   ");
+        for note in 0..128 {
+            payload.extend_from_slice(&[(note as u8).saturating_sub(1), 0x00, 0x00]);
+        }
+        payload.push(0x00);
+
+        assert!(tuning.apply_mts(&NON_REAL_TIME, &payload));
+        assert_eq!(Tuning::default().note(59), tuning.note(60));
+
+        // Truncated
+        assert!(!tuning.apply_mts(&NON_REAL_TIME, &payload[..100]));
+    }
+
+    #[test]
+    fn load_from_bank() {
+        let dir = env::temp_dir().join(format!("midi-synth-tuning-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("001.scl"), MEANTONE).unwrap();
+        fs::write(dir.join("002.scl"), "broken\n").unwrap();
+        let bank = TuningBank::new(Some(dir.clone()));
+
+        assert_eq!(None, bank.load(0).unwrap());
+        assert!(bank.load(1).unwrap().is_some());
+        assert!(bank.load(2).is_err());
+        assert_eq!(None, TuningBank::new(None).load(1).unwrap());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,237 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, ErrorKind as IoErrorKind};
+use std::path::PathBuf;
+
+use synth::synthesizer::VOICE_GROUPS;
+
+use errors::*;
+
+/// Range of keys of the keyboard that plays a voice group of the synthesizer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KeyZone {
+    pub low_key: u8,
+    pub high_key: u8,
+    pub voice_group: usize,
+    /// Channel the keys are received on (`None` for all channels)
+    pub channel: Option<u8>,
+    pub low_velocity: u8,
+    pub high_velocity: u8,
+    /// Semitones added to the notes of the zone
+    pub transpose: i8,
+}
+
+impl Default for KeyZone {
+    fn default() -> Self {
+        Self {
+            low_key: 0,
+            high_key: 127,
+            voice_group: 0,
+            channel: None,
+            low_velocity: 0,
+            high_velocity: 127,
+            transpose: 0,
+        }
+    }
+}
+
+impl KeyZone {
+    /// Returns true if a key pressed with the given velocity on the channel plays the zone.
+    pub fn contains(&self, channel: u8, key: u8, velocity: u8) -> bool {
+        (self.channel.is_none() || self.channel == Some(channel))
+            && (self.low_key..=self.high_key).contains(&key)
+            && (self.low_velocity..=self.high_velocity).contains(&velocity)
+    }
+}
+
+/// Layout of the keyboard: zones split it into key ranges, overlapping zones are layered.
+///
+/// Zones have no patch of their own: the voice groups only differ in the settings of their
+/// oscillator, everything else (e.g. the modulation) comes from the current patch. Only the
+/// first voice group is played by the arpeggiator and the sequencer, the keys of the other
+/// voice groups always play their notes directly.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Zones {
+    pub zones: Vec<KeyZone>,
+}
+
+impl Default for Zones {
+    /// A single zone that plays all keys with the first voice group.
+    fn default() -> Self {
+        Self {
+            zones: vec![KeyZone::default()],
+        }
+    }
+}
+
+impl Zones {
+    /// Loads the zones from a file. Returns a single zone for the whole keyboard if the file does
+    /// not exist.
+    pub fn load(path: PathBuf) -> Result<Self> {
+        match File::open(&path) {
+            Ok(file) => Self::read_from(BufReader::new(file))
+                .chain_err(|| format!("Failed to load zones from {}", path.display())),
+            Err(ref e) if e.kind() == IoErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e).chain_err(|| format!("Failed to open zones file {}", path.display())),
+        }
+    }
+
+    /// Reads lines of the format
+    /// `zone = <low key>-<high key> <voice group> [channel <0-15>] [velocity <low>-<high>]
+    /// [transpose <semitones>]`.
+    pub fn read_from<R: BufRead>(reader: R) -> Result<Self> {
+        let mut zones = vec![];
+
+        for (line_number, line) in reader.lines().enumerate() {
+            let line = line?;
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let mut parts = line.splitn(2, '=');
+            let key = parts.next().unwrap_or("").trim();
+            let value = parts.next().unwrap_or("").trim();
+
+            match key {
+                "zone" => zones.push(
+                    parse_zone(value)
+                        .chain_err(|| format!("Invalid zone in line {}", line_number + 1))?,
+                ),
+                _ => bail!("Unknown key in line {}: {}", line_number + 1, key),
+            }
+        }
+
+        if zones.is_empty() {
+            bail!("No zones defined");
+        }
+
+        Ok(Self { zones })
+    }
+
+    /// Returns the zones that a key pressed with the given velocity on the channel plays.
+    pub fn find(&self, channel: u8, key: u8, velocity: u8) -> impl Iterator<Item = &KeyZone> {
+        self.zones
+            .iter()
+            .filter(move |zone| zone.contains(channel, key, velocity))
+    }
+}
+
+fn parse_zone(value: &str) -> Result<KeyZone> {
+    let mut tokens = value.split_whitespace();
+    let (low_key, high_key) = parse_range(tokens.next().unwrap_or(""))?;
+    let voice_group: usize = tokens
+        .next()
+        .and_then(|token| token.parse().ok())
+        .filter(|group| *group < VOICE_GROUPS)
+        .ok_or_else(|| format!("Voice group missing or not below {}", VOICE_GROUPS))?;
+
+    let mut zone = KeyZone {
+        low_key,
+        high_key,
+        voice_group,
+        ..KeyZone::default()
+    };
+    while let Some(option) = tokens.next() {
+        let argument = tokens
+            .next()
+            .ok_or_else(|| format!("Argument of {} missing", option))?;
+        match option {
+            "channel" => {
+                zone.channel = Some(
+                    argument
+                        .parse()
+                        .ok()
+                        .filter(|channel| *channel < 16)
+                        .ok_or_else(|| format!("Invalid channel: {}", argument))?,
+                )
+            }
+            "velocity" => {
+                let (low, high) = parse_range(argument)?;
+                zone.low_velocity = low;
+                zone.high_velocity = high;
+            }
+            "transpose" => {
+                zone.transpose = argument
+                    .parse()
+                    .chain_err(|| format!("Invalid transposition: {}", argument))?
+            }
+            _ => bail!("Unknown option: {}", option),
+        }
+    }
+
+    Ok(zone)
+}
+
+/// Parses an inclusive range of MIDI values, e.g. `36-59`.
+fn parse_range(value: &str) -> Result<(u8, u8)> {
+    let mut bounds = value.splitn(2, '-');
+    let mut bound = || -> Option<u8> {
+        bounds
+            .next()
+            .and_then(|bound| bound.parse().ok())
+            .filter(|bound| *bound < 128)
+    };
+    match (bound(), bound()) {
+        (Some(low), Some(high)) if low <= high => Ok((low, high)),
+        _ => bail!("Invalid range: {}", value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_zones() {
+        let zones = Zones::read_from(
+            "# split at middle C\nzone = 0-59 1 transpose 12\nzone = 60-127 0 channel 2 velocity 1-100\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(
+            vec![
+                KeyZone {
+                    high_key: 59,
+                    voice_group: 1,
+                    transpose: 12,
+                    ..KeyZone::default()
+                },
+                KeyZone {
+                    low_key: 60,
+                    channel: Some(2),
+                    low_velocity: 1,
+                    high_velocity: 100,
+                    ..KeyZone::default()
+                },
+            ],
+            zones.zones
+        );
+
+        assert_eq!(1, zones.find(5, 59, 127).count());
+        assert_eq!(0, zones.find(5, 60, 100).count());
+        assert_eq!(0, zones.find(2, 60, 101).count());
+        assert_eq!(0, zones.find(2, 60, 0).count());
+        assert_eq!(
+            Some(0),
+            zones.find(2, 60, 100).next().map(|zone| zone.voice_group)
+        );
+    }
+
+    #[test]
+    fn invalid_zones() {
+        for zones in &[
+            "",
+            "zone = 60-59 0\n",
+            "zone = 0-128 0\n",
+            "zone = 0-127\n",
+            "zone = 0-127 2\n",
+            "zone = 0-127 0 channel 16\n",
+            "zone = 0-127 0 velocity 10\n",
+            "zone = 0-127 0 transpose\n",
+            "zone = 0-127 0 volume 1\n",
+            "split = 0-127 0\n",
+        ] {
+            assert!(Zones::read_from(zones.as_bytes()).is_err(), "{}", zones);
+        }
+    }
+}
@@ -610,6 +610,142 @@ impl fmt::Display for PolyphonicKeyPressure {
     }
 }
 
+/// Sent 24 times per quarter note by a MIDI clock
+#[derive(Clone, PartialEq, Debug)]
+pub struct TimingClock;
+
+impl TimingClock {
+    pub fn create() -> MidiMessage {
+        MidiMessage::TimingClock(TimingClock)
+    }
+
+    fn as_bytes(&self, buf: &mut [u8]) {
+        assert!(buf.len() >= 3);
+
+        buf[0] = 0xF8;
+        buf[1] = 0;
+        buf[2] = 0;
+    }
+}
+
+impl fmt::Display for TimingClock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "System Real Time, Timing Clock")
+    }
+}
+
+/// Starts playback from the beginning of the song
+#[derive(Clone, PartialEq, Debug)]
+pub struct Start;
+
+impl Start {
+    pub fn create() -> MidiMessage {
+        MidiMessage::Start(Start)
+    }
+
+    fn as_bytes(&self, buf: &mut [u8]) {
+        assert!(buf.len() >= 3);
+
+        buf[0] = 0xFA;
+        buf[1] = 0;
+        buf[2] = 0;
+    }
+}
+
+impl fmt::Display for Start {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "System Real Time, Start")
+    }
+}
+
+/// Resumes playback where it was stopped
+#[derive(Clone, PartialEq, Debug)]
+pub struct Continue;
+
+impl Continue {
+    pub fn create() -> MidiMessage {
+        MidiMessage::Continue(Continue)
+    }
+
+    fn as_bytes(&self, buf: &mut [u8]) {
+        assert!(buf.len() >= 3);
+
+        buf[0] = 0xFB;
+        buf[1] = 0;
+        buf[2] = 0;
+    }
+}
+
+impl fmt::Display for Continue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "System Real Time, Continue")
+    }
+}
+
+/// Stops playback
+#[derive(Clone, PartialEq, Debug)]
+pub struct Stop;
+
+impl Stop {
+    pub fn create() -> MidiMessage {
+        MidiMessage::Stop(Stop)
+    }
+
+    fn as_bytes(&self, buf: &mut [u8]) {
+        assert!(buf.len() >= 3);
+
+        buf[0] = 0xFC;
+        buf[1] = 0;
+        buf[2] = 0;
+    }
+}
+
+impl fmt::Display for Stop {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "System Real Time, Stop")
+    }
+}
+
+/// Message that is not supported (e.g. Song Position Pointer or Active Sensing). It is passed on,
+/// so that the receiver can ignore it.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Unknown {
+    bytes: [u8; 3],
+}
+
+impl Unknown {
+    pub fn create(bytes: &[u8]) -> MidiMessage {
+        MidiMessage::Unknown(Unknown {
+            bytes: [bytes[0], bytes[1], bytes[2]],
+        })
+    }
+
+    pub fn status(&self) -> u8 {
+        self.bytes[0]
+    }
+
+    fn code_index_number(&self) -> u8 {
+        match self.bytes[0] {
+            0xF1 | 0xF3 => 0x2,
+            0xF2 => 0x3,
+            0xF6 => 0x5,
+            _ => 0xF,
+        }
+    }
+
+    fn as_bytes(&self, buf: &mut [u8]) {
+        assert!(buf.len() >= 3);
+
+        buf[..3].copy_from_slice(&self.bytes);
+    }
+}
+
+impl fmt::Display for Unknown {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Unknown, Status: 0x{:02x}", self.status())
+    }
+}
+
 #[derive(Clone, PartialEq, Debug)]
 pub enum SystemExlusiveId {
     OneByte(u8),
@@ -808,6 +944,26 @@ impl Iterator for Serializer {
                 inner.as_bytes(&mut self.bytes[1..]);
                 self.cable_number << 4 | (0xA & 0x0F)
             }
+            (&mut MidiMessage::TimingClock(ref inner), 0) => {
+                inner.as_bytes(&mut self.bytes[1..]);
+                self.cable_number << 4 | 0xF
+            }
+            (&mut MidiMessage::Start(ref inner), 0) => {
+                inner.as_bytes(&mut self.bytes[1..]);
+                self.cable_number << 4 | 0xF
+            }
+            (&mut MidiMessage::Continue(ref inner), 0) => {
+                inner.as_bytes(&mut self.bytes[1..]);
+                self.cable_number << 4 | 0xF
+            }
+            (&mut MidiMessage::Stop(ref inner), 0) => {
+                inner.as_bytes(&mut self.bytes[1..]);
+                self.cable_number << 4 | 0xF
+            }
+            (&mut MidiMessage::Unknown(ref inner), 0) => {
+                inner.as_bytes(&mut self.bytes[1..]);
+                self.cable_number << 4 | inner.code_index_number()
+            }
             (&mut MidiMessage::SystemExclusive(ref mut inner), n) => {
                 if self.sysex.is_none() {
                     let sysex = mem::replace(
@@ -873,6 +1029,11 @@ pub enum MidiMessage {
     ChannelPressure(ChannelPressure),
     PolyphonicKeyPressure(PolyphonicKeyPressure),
     SystemExclusive(SystemExclusive),
+    TimingClock(TimingClock),
+    Start(Start),
+    Continue(Continue),
+    Stop(Stop),
+    Unknown(Unknown),
 }
 
 impl fmt::Display for MidiMessage {
@@ -894,6 +1055,11 @@ impl fmt::Display for MidiMessage {
             MidiMessage::ChannelPressure(ref inner) => write!(f, "{}", inner),
             MidiMessage::PolyphonicKeyPressure(ref inner) => write!(f, "{}", inner),
             MidiMessage::SystemExclusive(ref inner) => write!(f, "{}", inner),
+            MidiMessage::TimingClock(ref inner) => write!(f, "{}", inner),
+            MidiMessage::Start(ref inner) => write!(f, "{}", inner),
+            MidiMessage::Continue(ref inner) => write!(f, "{}", inner),
+            MidiMessage::Stop(ref inner) => write!(f, "{}", inner),
+            MidiMessage::Unknown(ref inner) => write!(f, "{}", inner),
         }
     }
 }
@@ -914,7 +1080,7 @@ impl MidiMessage {
         }
     }
 
-    fn from_bytes(input: &[u8]) -> Self {
+    pub fn from_bytes(input: &[u8]) -> Self {
         assert!(input.len() >= 3);
 
         let message_type = (input[0] & 0xF0) >> 4;
@@ -951,7 +1117,14 @@ impl MidiMessage {
                 channel,
                 u16::from(input[2] & 0x7F) << 7 | u16::from(input[1] & 0x7F),
             ),
-            _ => unimplemented!(),
+            0xf => match input[0] {
+                0xF8 => TimingClock::create(),
+                0xFA => Start::create(),
+                0xFB => Continue::create(),
+                0xFC => Stop::create(),
+                _ => Unknown::create(input),
+            },
+            _ => Unknown::create(input),
         }
     }
 }
@@ -1090,6 +1263,14 @@ impl UsbMidiParser {
                 0x5 => self.system_exclusive(&input[n + 1..n + 2], cable_number, true),
                 0x6 => self.system_exclusive(&input[n + 1..n + 3], cable_number, true),
                 0x7 => self.system_exclusive(&input[n + 1..n + 4], cable_number, true),
+                0xf => match input[n + 1] {
+                    0xF8 | 0xFA | 0xFB | 0xFC => MidiParseStatus::Complete(EventPacket {
+                        cable_number,
+                        midi_message: MidiMessage::from_bytes(&input[n + 1..n + 4]),
+                    }),
+                    // Other single bytes (e.g. Active Sensing) are skipped
+                    _ => return (MidiParseStatus::Unknown, 4),
+                },
                 _ => return (MidiParseStatus::Unknown, 1),
             };
 
@@ -1699,6 +1880,58 @@ mod tests {
         assert_eq!(i, 4);
     }
 
+    #[test]
+    fn parse_returns_system_real_time_messages() {
+        let buf: [u8; 16] = [
+            0x1f, 0xf8, 0x0, 0x0, 0x1f, 0xfa, 0x0, 0x0, 0x1f, 0xfb, 0x0, 0x0, 0x1f, 0xfc, 0x0, 0x0,
+        ];
+        let expected = [
+            TimingClock::create(),
+            Start::create(),
+            Continue::create(),
+            Stop::create(),
+        ];
+        let mut usb_midi_parser = UsbMidiParser::new();
+
+        for (i, expected) in expected.iter().enumerate() {
+            let packet = &buf[4 * i..4 * (i + 1)];
+            let midi_message = match usb_midi_parser.parse(packet) {
+                (MidiParseStatus::Complete(packet), 4) => {
+                    assert_eq!(1, packet.cable_number());
+                    packet.into_midi_message()
+                }
+                _ => panic!("wrong variant"),
+            };
+            assert_eq!(*expected, midi_message);
+
+            let serialized: Vec<u8> = midi_message.serialize_on_cable(1).collect();
+            assert_eq!(packet, &serialized[..]);
+        }
+    }
+
+    #[test]
+    fn parse_skips_unsupported_single_byte_messages() {
+        let buf: [u8; 4] = [0x0f, 0xfe, 0x0, 0x0];
+        let mut usb_midi_parser = UsbMidiParser::new();
+
+        match usb_midi_parser.parse(&buf) {
+            (MidiParseStatus::Unknown, 4) => {}
+            _ => panic!("wrong variant"),
+        }
+    }
+
+    #[test]
+    fn unsupported_system_messages_are_unknown() {
+        let song_position = MidiMessage::from_bytes(&[0xF2, 0x10, 0x02]);
+        let active_sensing = MidiMessage::from_bytes(&[0xFE, 0x00, 0x00]);
+
+        assert_eq!(Unknown::create(&[0xF2, 0x10, 0x02]), song_position);
+        assert_eq!(Unknown::create(&[0xFE, 0x00, 0x00]), active_sensing);
+
+        let serialized: Vec<u8> = song_position.serialize_on_cable(1).collect();
+        assert_eq!(vec![0x13, 0xF2, 0x10, 0x02], serialized);
+    }
+
     #[test]
     fn parse_starts_system_exclusive_message() {
         let buf: [u8; 4] = [0x24, 0xf0, 0x7e, 0x1];